@@ -0,0 +1,84 @@
+//! Micro-benchmarks with criterion: `cargo bench -- --save-baseline <name>`
+//! records a baseline and `cargo bench -- --baseline <name>` compares
+//! against it, reports in `target/criterion`.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+
+use rs_v::{CoreState, Engine};
+
+// add t0, t0, a0; xor t1, t1, t0; sub a0, a0, a1; bne a0, zero, loop
+const ALU_LOOP: [u32; 4] = [0x00a2_82b3, 0x0053_4333, 0x40b5_0533, 0xfe05_1ae3];
+// lw t0, 0(a0); sw t0, 0(a2); add a0, a0, a4; add a2, a2, a4; sub a3, a3, a1; bne a3, zero, loop
+const MEMCPY_LOOP: [u32; 6] = [0x0005_2283, 0x0056_2023, 0x00e5_0533, 0x00e6_0633, 0x40b6_86b3, 0xfe06_96e3];
+// csrrw t0, mscratch, a0; csrrw t1, mscratch, t0; sub a0, a0, a1; bne a0, zero, loop
+const CSR_LOOP: [u32; 4] = [0x3405_12f3, 0x3402_9373, 0x40b5_0533, 0xfe05_1ae3];
+// trips around each loop
+const ITERATIONS: u32 = 1000;
+
+fn machine(program: &[u32], regs: &[(usize, u32)]) -> CoreState {
+    let mut core = CoreState::new(64 << 10);
+    for (i, word) in program.iter().enumerate() {
+        core.memory[4 * i..4 * i + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    for &(reg, value) in regs {
+        core.regs.write(reg, value);
+    }
+    core
+}
+
+fn run_to(core: &mut CoreState, end: u32, engine: Engine) {
+    while core.pc != end {
+        core.dispatch(engine);
+    }
+}
+
+fn decode(c: &mut Criterion) {
+    let words: Vec<u32> = ALU_LOOP.iter().chain(&MEMCPY_LOOP).chain(&CSR_LOOP).copied().collect();
+    let mut group = c.benchmark_group("decode");
+    group.throughput(Throughput::Elements(words.len() as u64));
+    group.bench_function("loops", |b| b.iter(|| {
+        for &word in &words {
+            let _ = black_box(CoreState::decode(black_box(word)));
+        }
+    }));
+    group.finish();
+}
+
+/// The loops from a fresh machine each, in instructions retired per second;
+/// building the machine isn't measured
+fn loops(c: &mut Criterion) {
+    let mut group = c.benchmark_group("alu_loop");
+    group.throughput(Throughput::Elements((ALU_LOOP.len() as u32 * ITERATIONS) as u64));
+    let engines = [("step", Engine::Step), ("block", Engine::Block), #[cfg(feature = "jit")] ("jit", Engine::Jit)];
+    for (name, engine) in engines {
+        group.bench_function(name, |b| b.iter_batched(
+            || machine(&ALU_LOOP, &[(10, ITERATIONS), (11, 1)]),
+            |mut core| {
+                run_to(&mut core, 16, engine);
+                black_box(core.regs[6]);
+            },
+            BatchSize::LargeInput));
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("memcpy_loop");
+    group.throughput(Throughput::Bytes(4 * ITERATIONS as u64));
+    group.bench_function("block", |b| b.iter_batched(
+        || machine(&MEMCPY_LOOP, &[(10, 0x4000), (11, 1), (12, 0x8000), (13, ITERATIONS), (14, 4)]),
+        |mut core| run_to(&mut core, 24, Engine::Block),
+        BatchSize::LargeInput));
+    group.finish();
+
+    let mut group = c.benchmark_group("csr_loop");
+    group.throughput(Throughput::Elements((CSR_LOOP.len() as u32 * ITERATIONS) as u64));
+    group.bench_function("block", |b| b.iter_batched(
+        || machine(&CSR_LOOP, &[(10, ITERATIONS), (11, 1)]),
+        |mut core| run_to(&mut core, 16, Engine::Block),
+        BatchSize::LargeInput));
+    group.finish();
+}
+
+criterion_group!(benches, decode, loops);
+criterion_main!(benches);
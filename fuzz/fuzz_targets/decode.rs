@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rs_v::CoreState;
+
+// every 4 bytes are one instruction word, decode must never panic
+fuzz_target!(|data: &[u8]| {
+    for word in data.chunks_exact(4) {
+        let _ = CoreState::decode(u32::from_le_bytes(word.try_into().unwrap()));
+    }
+});
@@ -0,0 +1,141 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::difftest::reference_signature;
+use crate::shard::{self, Outcome, Shard};
+use crate::{run_machine, Config};
+
+// riscv-arch-test links at 0x8000_0000, the zeroed memory is only backed
+// where the test touches it
+const MEMORY_SIZE: usize = 0x8100_0000;
+const DEFAULT_REFERENCE: &str = "riscv_sim_RV32 --test-signature={signature} {elf}";
+
+/// Extension of a test: the directory after `rv32*_m` (`I`, `M`,
+/// `privilege`, ...) as in the riscv-arch-test tree, else the parent directory
+fn extension(path: &Path) -> String {
+    let parts: Vec<String> = path.components()
+        .map(|part| part.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    let suite = parts.iter().position(|part| part.starts_with("rv32") && part.ends_with("_m"));
+    match suite {
+        Some(i) if i + 2 < parts.len() => parts[i + 1].clone(),
+        _ => parts.iter().rev().nth(1).cloned().unwrap_or_default(),
+    }
+}
+
+fn find_elfs(dir: &Path, elfs: &mut Vec<PathBuf>) -> Result<(), String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    for entry in entries {
+        let path = entry.map_err(|e| format!("{}: {}", dir.display(), e))?.path();
+        if path.is_dir() {
+            find_elfs(&path, elfs)?;
+        } else if path.extension().is_some_and(|e| e == "elf") {
+            elfs.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Runs the test on rs-v until it halts through tohost, returns the words
+/// between `begin_signature` and `end_signature`
+fn signature(path: &Path, config: Config) -> Result<Vec<u32>, String> {
+    let (_, core, image) = run_machine(&[path.to_string_lossy().into_owned()], config).map_err(|e| e.to_string())?;
+    let symbol = |name: &str| image.symbols.get(name).map(|&value| value as usize)
+        .ok_or(format!("{}: no {} symbol", path.display(), name));
+    let (begin, end) = (symbol("begin_signature")?, symbol("end_signature")?);
+    let bytes = core.memory.get(begin..end).ok_or(format!("{}: signature outside memory", path.display()))?;
+    Ok(bytes.chunks_exact(4).map(|b| u32::from_le_bytes(b.try_into().unwrap())).collect())
+}
+
+/// `rs-v act [--reference cmd] [--matrix file] [--shard i/n] [--results file]
+/// <dir>`: runs every `.elf` under dir (or the shard's share of them) on rs-v
+/// and on the reference model (Sail by default), diffs the signatures and
+/// prints a pass/fail matrix per extension
+pub fn run(args: &[String], config: Config) -> Result<i32, String> {
+    let Config {memory_size, engine, lenient, ..} = config;
+    let mut template = DEFAULT_REFERENCE.to_string();
+    let mut matrix_path = None;
+    let mut shard = Shard::default();
+    let mut results_path = None;
+    let mut dir = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--reference" => template = value()?.clone(),
+            "--matrix" => matrix_path = Some(value()?.clone()),
+            "--shard" => shard = Shard::parse(value()?)?,
+            "--results" => results_path = Some(value()?.clone()),
+            _ if arg.starts_with("--") => return Err(format!("unknown act argument `{}`", arg)),
+            _ => dir = Some(arg.clone()),
+        }
+    }
+    let dir = dir.ok_or("missing test directory")?;
+    let mut elfs = Vec::new();
+    find_elfs(Path::new(&dir), &mut elfs)?;
+    elfs.sort();
+
+    // extension to (passed, failed)
+    let mut matrix: BTreeMap<String, (usize, usize)> = BTreeMap::new();
+    let mut outcomes = Vec::new();
+    let reference_file = std::env::temp_dir().join("rs-v-act.sig");
+    for (_, elf) in elfs.iter().enumerate().filter(|&(i, _)| shard.contains(i)) {
+        let config = Config {memory_size: Some(memory_size.unwrap_or(MEMORY_SIZE)), engine, lenient, ..Config::default()};
+        let result = signature(elf, config).and_then(|ours| {
+            let theirs = reference_signature(&template, elf, &reference_file)?;
+            match ours.iter().zip(&theirs).position(|(a, b)| a != b) {
+                _ if ours.len() != theirs.len() => {
+                    Err(format!("{} signature words, reference {}", ours.len(), theirs.len()))
+                }
+                Some(i) => Err(format!("word {}: rs-v {:08x} reference {:08x}", i, ours[i], theirs[i])),
+                None => Ok(()),
+            }
+        });
+        let entry = matrix.entry(extension(elf)).or_default();
+        match &result {
+            Ok(()) => entry.0 += 1,
+            Err(e) => {
+                entry.1 += 1;
+                println!("{}: {}", elf.display(), e);
+            }
+        }
+        outcomes.push(Outcome {name: elf.display().to_string(), extension: extension(elf), error: result.err()});
+    }
+
+    let mut table = "| Extension | Passed | Failed | Total |\n|-----------|--------|--------|-------|\n".to_string();
+    for (extension, (passed, failed)) in &matrix {
+        table += &format!("| {} | {} | {} | {} |\n", extension, passed, failed, passed + failed);
+    }
+    print!("{}", table);
+    if let Some(path) = matrix_path {
+        fs::write(&path, &table).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    if let Some(path) = results_path {
+        shard::write_results(&path, "act", shard, &outcomes)?;
+    }
+    Ok(matrix.values().any(|&(_, failed)| failed > 0) as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::difftest::{self, Program};
+
+    #[test]
+    fn extensions_follow_the_suite_layout() {
+        let extension = |path: &str| extension(Path::new(path));
+        assert_eq!(extension("work/rv32i_m/I/add-01.elf"), "I");
+        assert_eq!(extension("work/rv32i_m/privilege/src/ecall.S/dut/my.elf"), "privilege");
+        assert_eq!(extension("elfs/M/mul-01.elf"), "M");
+    }
+
+    #[test]
+    fn signature_is_read_between_the_symbols() {
+        let program = Program::generate(9, 24);
+        let path = std::env::temp_dir().join("rs-v-act-test.elf");
+        fs::write(&path, program.elf()).unwrap();
+        let config = Config {memory_size: Some(difftest::MEMORY_SIZE), ..Config::default()};
+        assert_eq!(signature(&path, config), program.run());
+    }
+}
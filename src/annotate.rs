@@ -0,0 +1,164 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+use crate::disasm::pseudo;
+use crate::encode::BRANCH;
+use crate::loader::{function_at, Function, Image};
+use crate::{CoreState, Observer};
+
+#[derive(Default)]
+struct Count {
+    executions: u64,
+    // branches only
+    taken: u64,
+}
+
+/// Per-instruction execution counts, like `perf annotate`: the report lists
+/// every function that ran, hottest first, as its full disassembly with the
+/// count and share of the run in front of each instruction and the taken
+/// percentage after each conditional branch. Written to `path` when dropped.
+pub struct Annotate {
+    path: String,
+    functions: Vec<Function>,
+    // words of the functions as loaded and of every executed instruction
+    code: BTreeMap<u32, u32>,
+    counts: HashMap<u32, Count>,
+    // pc of the last instruction if it was a branch
+    branch: Option<u32>,
+    started: bool,
+}
+
+impl Annotate {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            functions: Vec::new(),
+            code: BTreeMap::new(),
+            counts: HashMap::new(),
+            branch: None,
+            started: false,
+        }
+    }
+
+    fn line(&self, pc: u32, word: u32, total: u64) -> String {
+        let text = pseudo(word, pc, &self.functions);
+        match self.counts.get(&pc) {
+            None => format!("{:>10}  {:>6}  0x{:08x}  {}\n", "", "", pc, text),
+            Some(count) => {
+                let share = 100.0 * count.executions as f64 / total.max(1) as f64;
+                let mut line = format!("{:>10}  {:>5.1}%  0x{:08x}  {}", count.executions, share, pc, text);
+                if word & 0x7F == BRANCH {
+                    line += &format!("  # taken {:.1}%", 100.0 * count.taken as f64 / count.executions as f64);
+                }
+                line + "\n"
+            }
+        }
+    }
+
+    pub fn report(&self) -> String {
+        let total: u64 = self.counts.values().map(|count| count.executions).sum();
+        // executions per function, None for code outside every function
+        let mut regions: HashMap<Option<usize>, u64> = HashMap::new();
+        for (&pc, count) in &self.counts {
+            *regions.entry(function_at(&self.functions, pc)).or_default() += count.executions;
+        }
+        let mut regions: Vec<(Option<usize>, u64)> = regions.into_iter().collect();
+        regions.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        let mut report = format!("{} instructions\n", total);
+        for (function, executions) in regions {
+            let share = 100.0 * executions as f64 / total.max(1) as f64;
+            let range = match function {
+                Some(i) => {
+                    let f = &self.functions[i];
+                    report += &format!("\n{} (0x{:08x}): {} instructions ({:.1}%)\n", f.name, f.start, executions,
+                                       share);
+                    f.start..f.start.saturating_add(f.size.max(4))
+                }
+                None => {
+                    report += &format!("\n? (outside functions): {} instructions ({:.1}%)\n", executions, share);
+                    0..u32::MAX
+                }
+            };
+            for (&pc, &word) in self.code.range(range) {
+                if function.is_some() || function_at(&self.functions, pc).is_none() {
+                    report += &self.line(pc, word, total);
+                }
+            }
+        }
+        report
+    }
+}
+
+impl Observer for Annotate {
+    fn loaded(&mut self, image: &Image) {
+        self.functions = image.functions.clone();
+    }
+
+    fn step(&mut self, core: &CoreState) {
+        if !self.started {
+            // the program is in memory by the first step, functions that never
+            // run are listed from it as well
+            self.started = true;
+            for function in &self.functions {
+                for pc in (function.start..function.start.saturating_add(function.size)).step_by(4) {
+                    if let Some(bytes) = core.memory.get(pc as usize..pc as usize + 4) {
+                        self.code.insert(pc, u32::from_le_bytes(bytes.try_into().unwrap()));
+                    }
+                }
+            }
+        }
+        if let Some(branch) = self.branch.take() {
+            if core.pc != branch.wrapping_add(4) {
+                self.counts.entry(branch).or_default().taken += 1;
+            }
+        }
+        let Some(bytes) = core.memory.get(core.pc as usize..core.pc as usize + 4) else {
+            return;
+        };
+        let word = u32::from_le_bytes(bytes.try_into().unwrap());
+        self.code.insert(core.pc, word);
+        self.counts.entry(core.pc).or_default().executions += 1;
+        if word & 0x7F == BRANCH {
+            self.branch = Some(core.pc);
+        }
+    }
+}
+
+impl Drop for Annotate {
+    fn drop(&mut self) {
+        if let Err(e) = fs::write(&self.path, self.report()) {
+            eprintln!("{}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{self, OP_IMM};
+    use crate::test_utils::machine;
+
+    #[test]
+    fn counts_and_taken_percentages() {
+        // addi x1, x0, 3; loop: addi x1, x1, -1; bne x1, x0, loop; ecall; f: ret
+        let program = [encode::i(OP_IMM, 0b000, 1, 0, 3), encode::i(OP_IMM, 0b000, 1, 1, -1),
+                       encode::b(0b001, 1, 0, -4), encode::ECALL, encode::i(encode::JALR, 0b000, 0, 1, 0)];
+        let mut core = machine(0, &program, &[]);
+        let mut annotate = Annotate::new("/dev/null");
+        annotate.functions = vec![Function {start: 0, size: 16, name: "main".to_string()},
+                                  Function {start: 16, size: 4, name: "f".to_string()}];
+        for _ in 0..7 {
+            annotate.step(&core);
+            core.execute();
+        }
+        assert_eq!(annotate.report(), "\
+            7 instructions\n\
+            \n\
+            main (0x00000000): 7 instructions (100.0%)\n\
+            \x20        1   14.3%  0x00000000  li ra, 3\n\
+            \x20        3   42.9%  0x00000004  addi ra, ra, -1\n\
+            \x20        3   42.9%  0x00000008  bnez ra, 0x4 <main+0x4>  # taken 66.7%\n\
+            \x20                   0x0000000c  ecall\n");
+    }
+}
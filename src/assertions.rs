@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+
+use crate::loader::{function_at, Function, Image};
+use crate::CoreState;
+
+/// Longest string read from guest memory
+const MAX_STRING: usize = 256;
+/// Frames followed through the frame-pointer chain
+const MAX_FRAMES: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Kind {
+    /// newlib `__assert_func(file, line, func, expr)`
+    AssertFunc,
+    /// glibc and musl `__assert_fail(expr, file, line, func)`
+    AssertFail,
+    Abort,
+    /// `panic(fmt, ...)` of C kernels and RTOSes
+    Panic,
+    /// Rust's `#[panic_handler]` entry
+    RustPanic,
+}
+
+const SYMBOLS: [(&str, Kind); 6] = [
+    ("__assert_func", Kind::AssertFunc), ("__assert_fail", Kind::AssertFail), ("abort", Kind::Abort),
+    ("panic", Kind::Panic), ("rust_begin_unwind", Kind::RustPanic), ("rust_panic", Kind::RustPanic),
+];
+
+/// Stops bare-metal and user-mode runs when the guest enters its assertion,
+/// abort or panic routine, instead of letting it spin in the abort loop
+pub(crate) struct Assertions {
+    entries: HashMap<u32, Kind>,
+    functions: Vec<Function>,
+}
+
+impl Assertions {
+    pub(crate) fn new(image: &Image) -> Self {
+        let entries = SYMBOLS.iter()
+            .filter_map(|&(name, kind)| Some((*image.symbols.get(name)?, kind)))
+            .collect();
+        Self {entries, functions: image.functions.clone()}
+    }
+
+    fn symbol(&self, address: u32) -> String {
+        match function_at(&self.functions, address) {
+            Some(i) if address == self.functions[i].start => self.functions[i].name.clone(),
+            Some(i) => format!("{}+0x{:x}", self.functions[i].name, address - self.functions[i].start),
+            None => "?".to_string(),
+        }
+    }
+
+    /// The report if pc is at the entry of one of the routines: the decoded
+    /// message and a backtrace
+    pub(crate) fn check(&self, core: &CoreState) -> Option<String> {
+        let kind = *self.entries.get(&core.pc)?;
+        let arg = |i: usize| core.regs[10 + i];
+        let message = match kind {
+            Kind::AssertFunc => format!("assertion `{}` failed in {} at {}:{}", string(core, arg(3)),
+                                        string(core, arg(2)), string(core, arg(0)), arg(1)),
+            Kind::AssertFail => format!("assertion `{}` failed in {} at {}:{}", string(core, arg(0)),
+                                        string(core, arg(3)), string(core, arg(1)), arg(2)),
+            Kind::Abort => "abort called".to_string(),
+            Kind::Panic => format!("panic: {}", string(core, arg(0))),
+            Kind::RustPanic => "Rust panic".to_string(),
+        };
+        Some(format!("{}\n{}", message, self.location(core)))
+    }
+
+    /// pc and the call sites of the backtrace, a line each
+    pub(crate) fn location(&self, core: &CoreState) -> String {
+        let mut report = format!("  at 0x{:08x} {}", core.pc, self.symbol(core.pc));
+        for site in self.backtrace(core) {
+            report += &format!("\n  called from 0x{:08x} {}", site, self.symbol(site));
+        }
+        report
+    }
+
+    /// Call sites: ra, then the return addresses saved in the frame-pointer
+    /// chain (ra at fp - 4, the caller's fp at fp - 8) as long as they point
+    /// into functions
+    fn backtrace(&self, core: &CoreState) -> Vec<u32> {
+        let word = |address: u32| {
+            let bytes = core.memory.get(address as usize..address as usize + 4)?;
+            Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+        };
+        let mut sites = vec![core.regs[1].wrapping_sub(4)];
+        let mut fp = core.regs[8];
+        while sites.len() < MAX_FRAMES && fp.is_multiple_of(4) {
+            let (Some(ra), Some(next)) = (word(fp.wrapping_sub(4)), word(fp.wrapping_sub(8))) else {
+                break;
+            };
+            if function_at(&self.functions, ra.wrapping_sub(4)).is_none() || next <= fp {
+                break;
+            }
+            sites.push(ra.wrapping_sub(4));
+            fp = next;
+        }
+        sites
+    }
+}
+
+/// NUL-terminated guest string at `address`, cut at `MAX_STRING` bytes
+fn string(core: &CoreState, address: u32) -> String {
+    let bytes = core.memory.get(address as usize..).unwrap_or_default();
+    let bytes = &bytes[..bytes.len().min(MAX_STRING)];
+    let end = bytes.iter().position(|&byte| byte == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::machine;
+
+    #[test]
+    fn assertion_is_decoded_with_a_backtrace() {
+        let function = |start, size, name: &str| Function {start, size, name: name.to_string()};
+        let assertions = Assertions {
+            entries: HashMap::from([(0x100, Kind::AssertFunc)]),
+            functions: vec![function(0x40, 0x40, "check"), function(0x80, 0x40, "main"),
+                            function(0x100, 0x40, "__assert_func")],
+        };
+        // check+0x10 calls __assert_func, main+0x8 called check with the
+        // frame at 0x800 linking to main's at 0x900
+        let mut core = machine(0x100, &[], &[(1, 0x54), (8, 0x800), (10, 0x200), (11, 12), (12, 0x210), (13, 0x220)]);
+        core.memory[0x7FC..0x800].copy_from_slice(&0x8Cu32.to_le_bytes());
+        core.memory[0x7F8..0x7FC].copy_from_slice(&0x900u32.to_le_bytes());
+        for (address, text) in [(0x200, &b"t.c\0"[..]), (0x210, b"check\0"), (0x220, b"x == 1\0")] {
+            core.memory[address..address + text.len()].copy_from_slice(text);
+        }
+        assert_eq!(assertions.check(&core).unwrap(), "\
+            assertion `x == 1` failed in check at t.c:12\n\
+            \x20 at 0x00000100 __assert_func\n\
+            \x20 called from 0x00000050 check+0x10\n\
+            \x20 called from 0x00000088 main+0x8");
+        core.pc = 0x104;
+        assert_eq!(assertions.check(&core), None);
+    }
+}
@@ -0,0 +1,79 @@
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use crate::dispatch::MicroOp;
+
+pub const MAX_BLOCK_LEN: usize = 64;
+
+const PAGE_SHIFT: u32 = 12;
+
+/// Decoded basic blocks keyed by start pc. A block runs up to and including
+/// its first jump/branch; `Kind::System` instructions are never part of a
+/// block so that ecall/ebreak/CSR handling always goes through `execute`.
+pub struct BlockCache {
+    blocks: HashMap<u32, Rc<[MicroOp]>>,
+    // pages holding at least one cached block
+    code_pages: HashSet<u32>,
+    /// Stores into this (address, size) range end the running block
+    pub watch: Option<(u32, u32)>,
+    /// Set when a store hit a cached block or the watched range
+    pub interrupted: bool,
+}
+
+pub(crate) fn overlaps(a: u32, a_size: u32, b: u32, b_size: u32) -> bool {
+    (a as u64) < (b as u64 + b_size as u64) && (b as u64) < (a as u64 + a_size as u64)
+}
+
+impl BlockCache {
+    pub fn new() -> Self {
+        Self {
+            blocks: HashMap::new(),
+            code_pages: HashSet::new(),
+            watch: None,
+            interrupted: false,
+        }
+    }
+
+    pub fn get(&self, pc: u32) -> Option<Rc<[MicroOp]>> {
+        self.blocks.get(&pc).cloned()
+    }
+
+    pub fn insert(&mut self, pc: u32, block: Vec<MicroOp>) -> Rc<[MicroOp]> {
+        let end = pc.wrapping_add(4 * block.len() as u32 - 1);
+        self.code_pages.insert(pc >> PAGE_SHIFT);
+        self.code_pages.insert(end >> PAGE_SHIFT);
+        let block: Rc<[MicroOp]> = block.into();
+        self.blocks.insert(pc, block.clone());
+        block
+    }
+
+    /// Called on every store
+    pub fn invalidate(&mut self, address: u32, size: u32) {
+        if self.watch.is_some_and(|(start, len)| overlaps(address, size, start, len)) {
+            self.interrupted = true;
+        }
+        if size == 0 {
+            return;
+        }
+        let (first, last) = (address >> PAGE_SHIFT, address.saturating_add(size - 1) >> PAGE_SHIFT);
+        // stores touch one or two pages, host writes may span many
+        let code = match last - first {
+            0 | 1 => self.code_pages.contains(&first) || self.code_pages.contains(&last),
+            _ => self.code_pages.iter().any(|page| (first..=last).contains(page)),
+        };
+        if !code {
+            return;
+        }
+        let before = self.blocks.len();
+        self.blocks.retain(|&pc, block| !overlaps(address, size, pc, 4 * block.len() as u32));
+        if self.blocks.len() != before {
+            self.interrupted = true;
+        }
+    }
+
+    pub fn flush(&mut self) {
+        self.blocks.clear();
+        self.code_pages.clear();
+        self.interrupted = true;
+    }
+}
@@ -0,0 +1,99 @@
+use std::fs;
+
+use crate::encode::{self, JALR, WFI};
+use crate::script::parse_number;
+use crate::CoreState;
+
+/// Reset code in front of a bare-metal payload, like QEMU virt's: hart 0
+/// enters the payload with a0 = hart ID and a1 = the device tree blob (0
+/// without one), other harts park in WFI. The ROM is generated for the
+/// core's `hart_id` with the ID built in.
+pub struct BootRom {
+    pub address: u32,
+    dtb: Option<Vec<u8>>,
+}
+
+impl BootRom {
+    /// `spec` is `address` or `address:file.dtb`, e.g. `0x1000:virt.dtb`
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (address, dtb) = match spec.split_once(':') {
+            Some((address, path)) => (address, Some(fs::read(path).map_err(|e| format!("{}: {}", path, e))?)),
+            None => (spec, None),
+        };
+        let address = parse_number(address)?;
+        if !address.is_multiple_of(4) {
+            return Err(format!("boot ROM address 0x{:08x} isn't 4-byte aligned", address));
+        }
+        Ok(Self {address, dtb})
+    }
+
+    /// Code for `hart`
+    fn code(hart: u32, dtb: u32, entry: u32) -> Vec<u32> {
+        if hart != 0 {
+            return vec![WFI, encode::j(0, -4)];
+        }
+        let mut code = Vec::new();
+        code.extend(encode::li(10, hart));
+        code.extend(encode::li(11, dtb));
+        code.extend(encode::li(5, entry));
+        code.push(encode::i(JALR, 0b000, 0, 5, 0));
+        code
+    }
+
+    /// Writes the ROM and the device tree blob (to the top of memory, 8-byte
+    /// aligned) and starts the core at the ROM. Neither may overwrite the
+    /// loaded program, which ends at `end`.
+    pub(crate) fn install(&self, core: &mut CoreState, entry: u32, end: u32) -> Result<(), String> {
+        let dtb = match &self.dtb {
+            Some(blob) => {
+                let address = core.memory.len().checked_sub(blob.len())
+                    .ok_or("the device tree is larger than memory")? & !7;
+                if address < end as usize {
+                    return Err("the device tree overlaps the program at the top of memory".to_string());
+                }
+                core.memory[address..address + blob.len()].copy_from_slice(blob);
+                address as u32
+            }
+            None => 0,
+        };
+        let code = Self::code(core.hart_id, dtb, entry);
+        let start = self.address as usize;
+        let rom = core.memory.get_mut(start..start + 4 * code.len())
+            .ok_or(format!("boot ROM at 0x{:08x} is outside memory", self.address))?;
+        if rom.iter().any(|&byte| byte != 0) {
+            return Err(format!("boot ROM at 0x{:08x} overlaps the program", self.address));
+        }
+        for (slot, word) in rom.chunks_mut(4).zip(code) {
+            slot.copy_from_slice(&word.to_le_bytes());
+        }
+        core.pc = self.address;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{machine, MEMORY_SIZE};
+
+    #[test]
+    fn rom_enters_the_payload() {
+        let rom = BootRom {address: 0x100, dtb: Some(vec![0xD0, 0x0D, 0xFE, 0xED])};
+        let mut core = machine(0, &[], &[]);
+        rom.install(&mut core, 0x800, 0x900).unwrap();
+        while core.pc != 0x800 {
+            core.execute();
+        }
+        let dtb = MEMORY_SIZE as u32 - 8;
+        assert_eq!((core.regs[10], core.regs[11]), (0, dtb));
+        assert_eq!(core.memory[dtb as usize], 0xD0);
+        assert!(rom.install(&mut core, 0x800, 0x900).unwrap_err().contains("overlaps the program"));
+
+        // secondary harts wait
+        let mut core = machine(0, &BootRom::code(1, 0, 0x800), &[]);
+        for _ in 0..4 {
+            core.execute();
+        }
+        assert_eq!(core.pc, 0);
+    }
+}
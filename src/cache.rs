@@ -0,0 +1,185 @@
+use crate::hpm::Event;
+use crate::timing::TimingModel;
+use crate::CoreState;
+
+/// Set-associative cache with LRU replacement, tracking tags only. Stores
+/// allocate like loads.
+pub struct Cache {
+    size: u32,
+    line: u32,
+    sets: u32,
+    ways: usize,
+    // sets * ways of (tag, last use), None while invalid
+    lines: Vec<Option<(u32, u64)>>,
+    clock: u64,
+    pub hits: u64,
+    pub misses: u64,
+    /// Cycles a miss adds to the timing model
+    pub miss_penalty: u64,
+}
+
+impl Cache {
+    pub fn new(size: u32, ways: u32, line: u32) -> Result<Self, String> {
+        if !line.is_power_of_two() || ways == 0 || !size.is_multiple_of(ways * line) || !(size / ways / line).is_power_of_two() {
+            return Err(format!("{} bytes of {}-way {} byte lines is not a power-of-two set count", size, ways, line));
+        }
+        Ok(Self {
+            size,
+            line,
+            sets: size / ways / line,
+            ways: ways as usize,
+            lines: vec![None; (size / line) as usize],
+            clock: 0,
+            hits: 0,
+            misses: 0,
+            miss_penalty: 0,
+        })
+    }
+
+    /// `size:ways:line[:miss cycles]`, the size in bytes or with a `k` suffix,
+    /// e.g. `16k:2:32:20`
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = spec.split(':').collect();
+        if !(3..=4).contains(&fields.len()) {
+            return Err(format!("expected size:ways:line[:miss cycles], got `{}`", spec));
+        }
+        let number = |field: &str| {
+            let (digits, scale) = match field.strip_suffix(['k', 'K']) {
+                Some(digits) => (digits, 1024),
+                None => (field, 1),
+            };
+            digits.parse::<u32>().ok().and_then(|n| n.checked_mul(scale)).ok_or(format!("bad number `{}`", field))
+        };
+        let mut cache = Self::new(number(fields[0])?, number(fields[1])?, number(fields[2])?)?;
+        if let Some(penalty) = fields.get(3) {
+            cache.miss_penalty = number(penalty)? as u64;
+        }
+        Ok(cache)
+    }
+
+    /// Looks up the line holding `address` and fills it on a miss, returns
+    /// whether it hit
+    pub fn access(&mut self, address: u32) -> bool {
+        self.clock += 1;
+        let line = address / self.line;
+        let tag = line / self.sets;
+        let set = (line % self.sets) as usize * self.ways;
+        let ways = &mut self.lines[set..set + self.ways];
+        if let Some((_, used)) = ways.iter_mut().flatten().find(|(t, _)| *t == tag) {
+            *used = self.clock;
+            self.hits += 1;
+            return true;
+        }
+        // an invalid way first, the least recently used otherwise
+        let victim = ways.iter_mut().min_by_key(|way| way.map_or(0, |(_, used)| used)).unwrap();
+        *victim = Some((tag, self.clock));
+        self.misses += 1;
+        false
+    }
+
+    /// Penalty cycles of an access to `size` bytes at `address`, which may
+    /// span two lines
+    fn penalty(&mut self, address: u32, size: u32) -> u64 {
+        let last = address.wrapping_add(size - 1);
+        let mut penalty = if self.access(address) {0} else {self.miss_penalty};
+        if last / self.line != address / self.line && !self.access(last) {
+            penalty += self.miss_penalty;
+        }
+        penalty
+    }
+
+    pub fn report(&self, name: &str) -> String {
+        let accesses = self.hits + self.misses;
+        format!("{}: {} B, {}-way, {} B lines: {} accesses, {} hits, {} misses ({:.2}% miss rate)\n",
+                name, self.size, self.ways, self.line, accesses, self.hits, self.misses,
+                100.0 * self.misses as f64 / accesses.max(1) as f64)
+    }
+}
+
+/// Instruction and data caches in front of another timing model (one cycle
+/// per instruction if none), observing fetches and loads/stores and adding
+/// each cache's miss penalty. The statistics are printed to stderr when the
+/// model is dropped at the end of the run.
+pub struct Caches {
+    pub icache: Option<Cache>,
+    pub dcache: Option<Cache>,
+    inner: Option<Box<dyn TimingModel>>,
+}
+
+impl Caches {
+    pub fn new(inner: Option<Box<dyn TimingModel>>, icache: Option<Cache>, dcache: Option<Cache>) -> Self {
+        Self {icache, dcache, inner}
+    }
+
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        if let Some(icache) = &self.icache {
+            report += &icache.report("icache");
+        }
+        if let Some(dcache) = &self.dcache {
+            report += &dcache.report("dcache");
+        }
+        report
+    }
+}
+
+impl TimingModel for Caches {
+    fn cycles(&mut self, core: &CoreState, pc: u32, instruction: u32) -> u64 {
+        let mut cycles = self.inner.as_mut().map_or(1, |inner| inner.cycles(core, pc, instruction));
+        if let Some(icache) = self.icache.as_mut() {
+            cycles += icache.penalty(pc, 4);
+        }
+        if let (Some(dcache), Some((address, size))) = (self.dcache.as_mut(), core.last_access) {
+            cycles += dcache.penalty(address, size);
+        }
+        cycles
+    }
+
+    fn count(&self, event: Event) -> u64 {
+        match (event, &self.icache, &self.dcache) {
+            (Event::IcacheMisses, Some(icache), _) => icache.misses,
+            (Event::DcacheMisses, _, Some(dcache)) => dcache.misses,
+            _ => self.inner.as_ref().map_or(0, |inner| inner.count(event)),
+        }
+    }
+}
+
+impl Drop for Caches {
+    fn drop(&mut self) {
+        eprint!("{}", self.report());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lru_replacement() {
+        // 2 sets of 2 ways, 16 byte lines
+        let mut cache = Cache::parse("64:2:16").unwrap();
+        let hits: Vec<bool> = [0x00, 0x20, 0x00, 0x40, 0x00, 0x20, 0x10]
+            .into_iter()
+            .map(|address| cache.access(address))
+            .collect();
+        // 0x40 evicts 0x20, the least recently used line of set 0
+        assert_eq!(hits, [false, false, true, false, true, false, false]);
+        assert_eq!((cache.hits, cache.misses), (2, 5));
+        assert!(Cache::parse("48:2:16").is_err());
+        assert!(Cache::parse("16k:4").is_err());
+        assert_eq!(Cache::parse("16k:4:64:20").unwrap().miss_penalty, 20);
+    }
+
+    #[test]
+    fn misses_add_penalties() {
+        let mut caches = Caches::new(None, Some(Cache::parse("64:1:16:10").unwrap()),
+                                     Some(Cache::parse("64:1:16:5").unwrap()));
+        let mut core = CoreState::new(64);
+        core.last_access = Some((0x0E, 4));
+        // icache miss, dcache misses on both lines of the straddling access
+        assert_eq!(caches.cycles(&core, 0, 0), 1 + 10 + 5 + 5);
+        core.last_access = None;
+        assert_eq!(caches.cycles(&core, 4, 0), 1);
+        assert!(caches.report().starts_with("icache: 64 B, 1-way, 16 B lines: 2 accesses, 1 hits, 1 misses"));
+    }
+}
@@ -0,0 +1,145 @@
+use crate::difftest::Rng;
+use crate::{loader, Config, CoreState, Platform, RUN_MEMORY_SIZE};
+
+const DEFAULT_STEPS: u64 = 1 << 26;
+
+/// How a run ended: exit code and console output, or no exit
+#[derive(PartialEq, Debug)]
+enum Outcome {
+    Exit(i32, Vec<u8>),
+    Hang,
+}
+
+/// Fills registers and memory with values from `seed`, the program is loaded
+/// over them afterwards
+fn randomize(core: &mut CoreState, seed: u32) {
+    let mut rng = Rng(seed.max(1));
+    for reg in 1..32 {
+        core.regs.write(reg, rng.next());
+    }
+    for word in core.memory.chunks_mut(4) {
+        let value = rng.next().to_le_bytes();
+        word.copy_from_slice(&value[..word.len()]);
+    }
+}
+
+/// Runs the program from zeroed state, or the state of `seed`
+fn run_once(args: &[String], config: &Config, seed: Option<u32>, steps: u64) -> Result<Outcome, String> {
+    let path = args.first().ok_or("missing program")?;
+    let mut core = CoreState::new(config.memory_size.unwrap_or(RUN_MEMORY_SIZE));
+    if let Some(seed) = seed {
+        randomize(&mut core, seed);
+    }
+    let image = loader::load_segments(&mut core, path)?;
+    core.pc = image.entry;
+    core.lenient = config.lenient;
+    let mut platform = Platform::new(args, &image, &mut core);
+    platform.quiet();
+    platform.capture_console();
+    while platform.retired < steps {
+        if let Some(code) = platform.step(&mut core, config.engine) {
+            return Ok(Outcome::Exit(code, platform.take_console()));
+        }
+    }
+    Ok(Outcome::Hang)
+}
+
+/// How `outcome` differs from the zeroed run's
+fn difference(outcome: &Outcome, expected: &Outcome, steps: u64) -> String {
+    match (outcome, expected) {
+        (Outcome::Hang, _) => format!("no exit after {} instructions", steps),
+        (Outcome::Exit(..), Outcome::Hang) => "exits, the zeroed run doesn't".to_string(),
+        (Outcome::Exit(code, _), Outcome::Exit(expected, _)) if code != expected => {
+            format!("exits with {} instead of {}", code, expected)
+        }
+        (Outcome::Exit(_, output), Outcome::Exit(_, expected)) => {
+            let at = output.iter().zip(expected).position(|(a, b)| a != b)
+                .unwrap_or(output.len().min(expected.len()));
+            format!("output differs at byte {}", at)
+        }
+    }
+}
+
+/// `rs-v campaign [--seed n] [--count n] [--steps n] program [args]`: runs a
+/// bare-metal program from zeroed registers and memory, then `count` times
+/// from random ones (the ELF segments and .bss are loaded over them), and
+/// reports the seed of every run whose exit code or output differs, so guest
+/// code reading uninitialized state shows up. A seed is rerun with
+/// `--seed n --count 1`.
+pub fn run(args: &[String], config: Config) -> Result<i32, String> {
+    let mut seed = 1;
+    let mut count = 100;
+    let mut steps = DEFAULT_STEPS;
+    let mut args = args.iter();
+    let mut program = Vec::new();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--seed" => seed = value()?.parse().map_err(|_| "bad --seed")?,
+            "--count" => count = value()?.parse().map_err(|_| "bad --count")?,
+            "--steps" => steps = value()?.parse().map_err(|_| "bad --steps")?,
+            _ => {
+                program.push(arg.clone());
+                program.extend(args.cloned());
+                break;
+            }
+        }
+    }
+
+    let expected = run_once(&program, &config, None, steps)?;
+    if let Outcome::Exit(code, _) = expected {
+        eprintln!("zeroed run exits with {}", code);
+    }
+    let mut failing = Vec::new();
+    for seed in seed..seed + count {
+        let outcome = run_once(&program, &config, Some(seed), steps)?;
+        if outcome != expected {
+            println!("seed {}: {}", seed, difference(&outcome, &expected, steps));
+            failing.push(seed.to_string());
+        }
+    }
+    if failing.is_empty() {
+        println!("{} runs match the zeroed run", count);
+        return Ok(0);
+    }
+    println!("{} of {} runs differ, seeds {}", failing.len(), count, failing.join(" "));
+    Ok(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::difftest::{self, MEMORY_SIZE, TOHOST};
+    use crate::encode::{self, LUI, OP_IMM};
+
+    /// Program exiting through tohost with `exit` (a register holding the
+    /// code in bit 0)
+    fn program(exit: u32, name: &str) -> Vec<String> {
+        // lui t1, TOHOST; andi t0, exit, 1; slli t0, t0, 1; ori t0, t0, 1;
+        // sw t0, 0(t1); sw x0, 4(t1); j .
+        let code = [encode::u(LUI, 6, TOHOST), encode::i(OP_IMM, 0b111, 5, exit, 1),
+                    encode::i(OP_IMM, 0b001, 5, 5, 1), encode::i(OP_IMM, 0b110, 5, 5, 1),
+                    encode::s(0b010, 6, 5, 0), encode::s(0b010, 6, 0, 4), encode::j(0, 0)];
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, difftest::elf(&code, &[])).unwrap();
+        vec![path.to_string_lossy().into_owned()]
+    }
+
+    #[test]
+    fn uninitialized_registers_are_caught() {
+        let config = Config {memory_size: Some(MEMORY_SIZE), ..Config::default()};
+        let clean = program(0, "rs-v-campaign-clean.elf");
+        assert_eq!(run_once(&clean, &config, Some(3), 100), Ok(Outcome::Exit(0, Vec::new())));
+        // a1 is never written
+        let dirty = program(11, "rs-v-campaign-dirty.elf");
+        let outcomes: Vec<Outcome> = (1..9).map(|seed| run_once(&dirty, &config, Some(seed), 100).unwrap()).collect();
+        assert!(outcomes.contains(&Outcome::Exit(1, Vec::new())));
+        assert_eq!(difference(&Outcome::Exit(1, Vec::new()), &Outcome::Exit(0, Vec::new()), 100),
+                   "exits with 1 instead of 0");
+        assert_eq!(difference(&Outcome::Hang, &Outcome::Exit(0, Vec::new()), 100), "no exit after 100 instructions");
+        let args = [&["--count".to_string(), "8".to_string()], &dirty[..]].concat();
+        assert_eq!(run(&args, Config {memory_size: Some(MEMORY_SIZE), ..Config::default()}), Ok(1));
+    }
+}
@@ -0,0 +1,152 @@
+use std::fs;
+
+use crate::loader::{function_at, Function, Image};
+use crate::{CoreState, Instruction, Observer};
+
+/// x1 (ra) and x5 (t0) hold return addresses by the RISC-V convention
+fn is_link(reg: usize) -> bool {
+    reg == 1 || reg == 5
+}
+
+/// Control-flow integrity checking. Calls (JAL/JALR linking ra or t0) push
+/// their return address on a shadow stack and returns (JALR through ra or
+/// t0) must go back to the address on top; indirect jumps must land on a
+/// function start or stay inside the current function (jump tables).
+/// Violations are printed to stderr as they happen and listed in the report
+/// written when dropped.
+pub struct Cfi {
+    path: String,
+    functions: Vec<Function>,
+    stack: Vec<u32>,
+    violations: Vec<String>,
+}
+
+impl Cfi {
+    pub fn new(path: &str) -> Self {
+        Self {path: path.to_string(), functions: Vec::new(), stack: Vec::new(), violations: Vec::new()}
+    }
+
+    fn symbol(&self, address: u32) -> String {
+        match function_at(&self.functions, address) {
+            Some(i) if address == self.functions[i].start => self.functions[i].name.clone(),
+            Some(i) => format!("{}+0x{:x}", self.functions[i].name, address - self.functions[i].start),
+            None => "?".to_string(),
+        }
+    }
+
+    fn violation(&mut self, pc: u32, what: String) {
+        let violation = format!("0x{:08x} {}: {}", pc, self.symbol(pc), what);
+        eprintln!("cfi: {}", violation);
+        self.violations.push(violation);
+    }
+
+    /// Pops the return address for a return to `target`
+    fn ret(&mut self, pc: u32, target: u32) {
+        match self.stack.pop() {
+            Some(expected) if expected == target => {}
+            Some(expected) => {
+                self.violation(pc, format!("returns to 0x{:08x} {}, the call expects 0x{:08x} {}", target,
+                                           self.symbol(target), expected, self.symbol(expected)));
+                // a longjmp-style unwind to an outer frame resyncs the stack
+                if let Some(depth) = self.stack.iter().rposition(|&address| address == target) {
+                    self.stack.truncate(depth);
+                }
+            }
+            None => self.violation(pc, format!("returns to 0x{:08x} {} with no call", target, self.symbol(target))),
+        }
+    }
+
+    /// Checks an indirect jump that isn't a return
+    fn jump(&mut self, pc: u32, target: u32) {
+        if self.functions.is_empty() {
+            return;
+        }
+        let function = function_at(&self.functions, target);
+        let starts = function.is_some_and(|i| self.functions[i].start == target);
+        if !starts && (function.is_none() || function != function_at(&self.functions, pc)) {
+            self.violation(pc, format!("indirect jump to 0x{:08x} {}, not a function", target, self.symbol(target)));
+        }
+    }
+
+    pub fn report(&self) -> String {
+        let mut report = format!("{} violations, shadow stack depth {}\n", self.violations.len(), self.stack.len());
+        for violation in &self.violations {
+            report += &format!("{}\n", violation);
+        }
+        report
+    }
+}
+
+impl Observer for Cfi {
+    fn loaded(&mut self, image: &Image) {
+        self.functions = image.functions.clone();
+    }
+
+    fn step(&mut self, core: &CoreState) {
+        let Some(bytes) = core.memory.get(core.pc as usize..core.pc as usize + 4) else {
+            return;
+        };
+        let pc = core.pc;
+        let link = pc.wrapping_add(4);
+        match CoreState::decode_with(u32::from_le_bytes(bytes.try_into().unwrap()), true) {
+            Ok(Instruction::Jal(a)) if is_link(a.rd) => self.stack.push(link),
+            // the return-address stack hints of the JALR encoding
+            Ok(Instruction::Jalr(a)) => {
+                let target = core.regs[a.rs1].wrapping_add(a.imm as u32) & !1;
+                match (is_link(a.rd), is_link(a.rs1)) {
+                    (false, true) => self.ret(pc, target),
+                    (true, true) if a.rd != a.rs1 => {
+                        self.ret(pc, target);
+                        self.stack.push(link);
+                    }
+                    (true, _) => {
+                        self.jump(pc, target);
+                        self.stack.push(link);
+                    }
+                    (false, false) => self.jump(pc, target),
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Drop for Cfi {
+    fn drop(&mut self) {
+        if let Err(e) = fs::write(&self.path, self.report()) {
+            eprintln!("{}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{self, JALR, OP_IMM};
+    use crate::test_utils::machine;
+
+    #[test]
+    fn corrupted_return_and_wild_jump() {
+        // main: jal ra, f; jal ra, f; jalr x0, 0(t1)
+        // f: addi ra, ra, 4; jalr x0, 0(ra)
+        let program = [encode::j(1, 12), encode::j(1, 8), encode::i(JALR, 0b000, 0, 6, 0),
+                       encode::i(OP_IMM, 0b000, 1, 1, 4), encode::i(JALR, 0b000, 0, 1, 0)];
+        let mut core = machine(0, &program, &[(6, 0x10)]);
+        let mut cfi = Cfi::new("/dev/null");
+        let function = |start, size, name: &str| Function {start, size, name: name.to_string()};
+        cfi.functions = vec![function(0, 12, "main"), function(12, 8, "f")];
+        for _ in 0..3 {
+            cfi.step(&core);
+            core.execute();
+        }
+        // the first call returned past the second one, jalr t1 jumps into f
+        assert_eq!(core.pc, 8);
+        cfi.step(&core);
+        assert_eq!(cfi.report(), "2 violations, shadow stack depth 0\n\
+                                  0x00000010 f+0x4: returns to 0x00000008 main+0x8, the call expects 0x00000004 main+0x4\n\
+                                  0x00000008 main+0x8: indirect jump to 0x00000010 f+0x4, not a function\n");
+        // returning from main with nothing on the stack
+        cfi.ret(0, 0);
+        assert!(cfi.violations[2].ends_with("with no call"));
+    }
+}
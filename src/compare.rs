@@ -0,0 +1,170 @@
+use crate::cache::{Cache, Caches};
+use crate::csr_file::MEPC;
+use crate::hpm::Event;
+use crate::predictor::Predictor;
+use crate::timing::{InOrder, TimingModel};
+use crate::{load_bare_metal, Config, Engine, Platform};
+
+/// The hpm events compared, with their row names
+const EVENTS: [(Event, &str); 7] = [
+    (Event::Loads, "loads"),
+    (Event::Stores, "stores"),
+    (Event::Branches, "branches"),
+    (Event::TakenBranches, "taken"),
+    (Event::Mispredictions, "mispredicted"),
+    (Event::IcacheMisses, "icache misses"),
+    (Event::DcacheMisses, "dcache misses"),
+];
+
+/// One machine profile: the engine, decoding and timing flags of a run
+struct Profile {
+    name: String,
+    engine: Engine,
+    lenient: bool,
+    timing: Option<Box<dyn TimingModel>>,
+}
+
+impl Profile {
+    /// `spec` is `default` or machine flags separated by spaces, e.g.
+    /// `--timing inorder --dcache 16k:4:64:20`: `--timing`, `--icache`,
+    /// `--dcache`, `--predictor`, `--engine` and `--lenient`
+    fn parse(spec: &str, engine: Engine) -> Result<Self, String> {
+        let mut profile = Self {name: spec.to_string(), engine, lenient: false, timing: None};
+        let (mut icache, mut dcache, mut predictor) = (None, None, None);
+        let mut words = spec.split_whitespace().filter(|&word| word != "default");
+        while let Some(flag) = words.next() {
+            if flag == "--lenient" {
+                profile.lenient = true;
+                continue;
+            }
+            let value = words.next().ok_or(format!("{} needs a value", flag))?;
+            match flag {
+                "--timing" => profile.timing = Some(Box::new(InOrder::parse(value)?)),
+                "--icache" => icache = Some(Cache::parse(value)?),
+                "--dcache" => dcache = Some(Cache::parse(value)?),
+                "--predictor" => predictor = Some(value),
+                "--engine" => profile.engine = match value {
+                    "step" => Engine::Step,
+                    "block" => Engine::Block,
+                    #[cfg(feature = "jit")]
+                    "jit" => Engine::Jit,
+                    _ => return Err(format!("unknown engine `{}`", value)),
+                },
+                _ => return Err(format!("`{}` isn't a profile flag", flag)),
+            }
+        }
+        // stacked as main stacks the flags of `run`
+        if let Some(spec) = predictor {
+            profile.timing = Some(Box::new(Predictor::parse(profile.timing.take(), spec)?));
+        }
+        if icache.is_some() || dcache.is_some() {
+            profile.timing = Some(Box::new(Caches::new(profile.timing.take(), icache, dcache)));
+        }
+        Ok(profile)
+    }
+}
+
+/// What a run under one profile measured
+struct Totals {
+    exit: i32,
+    retired: u64,
+    cycles: u64,
+    events: [u64; EVENTS.len()],
+}
+
+/// Runs the program to its exit under `profile`, the guest output dropped
+/// if `quiet`
+fn measure(profile: Profile, args: &[String], memory_size: Option<usize>, quiet: bool) -> Result<Totals, String> {
+    let path = args.first().ok_or("missing program")?;
+    let (mut core, image) = load_bare_metal(path, memory_size)?;
+    core.lenient = profile.lenient;
+    core.timing = profile.timing;
+    core.counters.count_all();
+    let mut platform = Platform::new(args, &image, &mut core);
+    if quiet {
+        platform.quiet();
+    }
+    let exit = loop {
+        if let Some(code) = platform.step(&mut core, profile.engine) {
+            break code;
+        }
+        if core.double_fault {
+            return Err(format!("{}: double fault at the trap handler 0x{:08x}", profile.name, core.csrs.get(MEPC)));
+        }
+    };
+    Ok(Totals {
+        exit,
+        retired: platform.retired,
+        cycles: core.cycles,
+        events: EVENTS.map(|(event, _)| core.event_total(event)),
+    })
+}
+
+/// The two runs side by side, with B relative to A
+fn table(a: (&str, &Totals), b: (&str, &Totals)) -> String {
+    let (a_name, a) = a;
+    let (b_name, b) = b;
+    let cpi = |totals: &Totals| totals.cycles as f64 / totals.retired.max(1) as f64;
+    let mut rows = vec![
+        ("exit code".to_string(), a.exit.to_string(), b.exit.to_string(), String::new()),
+        row("instructions", a.retired, b.retired),
+        row("cycles", a.cycles, b.cycles),
+        ("CPI".to_string(), format!("{:.3}", cpi(a)), format!("{:.3}", cpi(b)), ratio(cpi(a), cpi(b))),
+    ];
+    rows.extend(EVENTS.iter().enumerate().map(|(i, (_, name))| row(name, a.events[i], b.events[i])));
+    let mut table = format!("A: {}\nB: {}\n{:<14} {:>14} {:>14} {:>8}\n", a_name, b_name, "", "A", "B", "B/A");
+    for (name, a, b, ratio) in rows {
+        table += &format!("{:<14} {:>14} {:>14} {:>8}\n", name, a, b, ratio);
+    }
+    table
+}
+
+fn row(name: &str, a: u64, b: u64) -> (String, String, String, String) {
+    (name.to_string(), a.to_string(), b.to_string(), ratio(a as f64, b as f64))
+}
+
+/// `-` if A is zero
+fn ratio(a: f64, b: f64) -> String {
+    if a == 0.0 {"-".to_string()} else {format!("{:.3}", b / a)}
+}
+
+/// Runs a bare-metal program to its exit under two machine profiles and
+/// prints their instruction counts, cycles and hpm events side by side.
+/// `args` is `profile-a profile-b program [args]`, each profile one argument
+/// of machine flags (see `Profile::parse`). A prints the guest output.
+/// Returns 0, or 1 if the two exit codes differ.
+pub fn run(args: &[String], config: Config) -> Result<i32, String> {
+    let Config {memory_size, engine, ..} = config;
+    let [a, b, args @ ..] = args else {
+        return Err("expected two profiles and a program, e.g. default '--timing inorder' prog.elf".to_string());
+    };
+    let (a_profile, b_profile) = (Profile::parse(a, engine)?, Profile::parse(b, engine)?);
+    let a_totals = measure(a_profile, args, memory_size, false)?;
+    let b_totals = measure(b_profile, args, memory_size, true)?;
+    print!("{}", table((a, &a_totals), (b, &b_totals)));
+    Ok(if a_totals.exit == b_totals.exit {0} else {1})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles_line_up() {
+        assert!(Profile::parse("default", Engine::Block).unwrap().timing.is_none());
+        let profile = Profile::parse("--engine step --lenient --timing inorder:mul=4 --dcache 1k:2:16", Engine::Block)
+            .unwrap();
+        assert!(profile.engine == Engine::Step && profile.lenient && profile.timing.is_some());
+        assert!(Profile::parse("--timing", Engine::Block).is_err());
+        assert!(Profile::parse("--jit on", Engine::Block).is_err());
+
+        let a = Totals {exit: 0, retired: 100, cycles: 100, events: [10, 5, 20, 8, 0, 0, 0]};
+        let b = Totals {exit: 0, retired: 100, cycles: 150, events: [10, 5, 20, 8, 4, 0, 2]};
+        let table = table(("default", &a), ("--timing inorder", &b));
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(&lines[..2], ["A: default", "B: --timing inorder"]);
+        assert_eq!(lines[5], format!("{:<14} {:>14} {:>14} {:>8}", "cycles", 100, 150, "1.500"));
+        assert_eq!(lines[6], format!("{:<14} {:>14} {:>14} {:>8}", "CPI", "1.000", "1.500", "1.500"));
+        assert_eq!(lines[13], format!("{:<14} {:>14} {:>14} {:>8}", "dcache misses", 0, 2, "-"));
+    }
+}
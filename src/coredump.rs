@@ -0,0 +1,163 @@
+use std::fs;
+
+use crate::{Cause, CoreState};
+
+/// Memory is dumped in pages, all-zero pages are left out
+const PAGE: usize = 4096;
+
+const ET_CORE: u16 = 4;
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+const EHDR_SIZE: usize = 52;
+const PHDR_SIZE: usize = 32;
+/// sizeof(struct elf_prstatus) on 32-bit RISC-V Linux, which is what GDB and
+/// BFD expect of bare-metal cores too
+const PRSTATUS_SIZE: usize = 204;
+const PRSTATUS_CURSIG: usize = 12;
+const PRSTATUS_PID: usize = 24;
+const PRSTATUS_REG: usize = 72;
+
+const SIGILL: u16 = 4;
+const SIGTRAP: u16 = 5;
+const SIGBUS: u16 = 7;
+const SIGSEGV: u16 = 11;
+
+/// Signal GDB shows for the last trap
+fn signal(cause: Cause) -> u16 {
+    match cause {
+        Cause::IllegalInstruction => SIGILL,
+        Cause::Breakpoint => SIGTRAP,
+        Cause::InstructionAddressMisaligned | Cause::LoadAddressMisaligned | Cause::StoreAmoAddressMisaligned => SIGBUS,
+        _ => SIGSEGV,
+    }
+}
+
+/// Runs of pages with something other than zeros, as (start, end)
+fn segments(memory: &[u8]) -> Vec<(usize, usize)> {
+    let mut segments: Vec<(usize, usize)> = Vec::new();
+    for (i, page) in memory.chunks(PAGE).enumerate() {
+        if page.iter().all(|&byte| byte == 0) {
+            continue;
+        }
+        let (start, end) = (i * PAGE, i * PAGE + page.len());
+        match segments.last_mut() {
+            Some(last) if last.1 == start => last.1 = end,
+            _ => segments.push((start, end)),
+        }
+    }
+    segments
+}
+
+fn note(name: &str, kind: u32, desc: &[u8]) -> Vec<u8> {
+    let mut note = Vec::new();
+    note.extend((name.len() as u32 + 1).to_le_bytes());
+    note.extend((desc.len() as u32).to_le_bytes());
+    note.extend(kind.to_le_bytes());
+    note.extend(name.as_bytes());
+    note.push(0);
+    note.resize(note.len().next_multiple_of(4), 0);
+    note.extend(desc);
+    note.resize(note.len().next_multiple_of(4), 0);
+    note
+}
+
+/// ELF core file of the machine: an NT_PRSTATUS note with pc and x1..x31 and
+/// the signal of the last trap, and a PT_LOAD segment per run of nonzero
+/// memory pages
+pub fn core_file(core: &CoreState) -> Vec<u8> {
+    let mut prstatus = vec![0; PRSTATUS_SIZE];
+    prstatus[PRSTATUS_CURSIG..PRSTATUS_CURSIG + 2].copy_from_slice(&signal(core.csrs.cause()).to_le_bytes());
+    prstatus[PRSTATUS_PID..PRSTATUS_PID + 4].copy_from_slice(&1u32.to_le_bytes());
+    // the gregset starts with pc where x0 would be
+    for (i, value) in core.regs.iter().enumerate() {
+        let value = if i == 0 {core.pc} else {*value};
+        prstatus[PRSTATUS_REG + 4 * i..PRSTATUS_REG + 4 * i + 4].copy_from_slice(&value.to_le_bytes());
+    }
+    let notes = note("CORE", NT_PRSTATUS, &prstatus);
+    let segments = segments(&core.memory);
+
+    let phnum = 1 + segments.len();
+    let mut offset = EHDR_SIZE + PHDR_SIZE * phnum;
+    let mut file = Vec::new();
+    file.extend(b"\x7fELF\x01\x01\x01\x00");
+    file.extend([0; 8]);
+    file.extend(ET_CORE.to_le_bytes());
+    file.extend(EM_RISCV.to_le_bytes());
+    file.extend(1u32.to_le_bytes());
+    // entry, phoff, shoff, flags
+    for word in [0, EHDR_SIZE as u32, 0, 0] {
+        file.extend(word.to_le_bytes());
+    }
+    for half in [EHDR_SIZE as u16, PHDR_SIZE as u16, phnum as u16, 0, 0, 0] {
+        file.extend(half.to_le_bytes());
+    }
+
+    let mut header = |kind: u32, offset: usize, address: usize, size: usize, flags: u32, align: u32| {
+        for word in [kind, offset as u32, address as u32, address as u32, size as u32, size as u32, flags, align] {
+            file.extend(word.to_le_bytes());
+        }
+    };
+    header(PT_NOTE, offset, 0, notes.len(), 0, 4);
+    // segments are page aligned in the file like their addresses
+    offset = (offset + notes.len()).next_multiple_of(PAGE);
+    for &(start, end) in &segments {
+        // rwx
+        header(PT_LOAD, offset, start, end - start, 7, PAGE as u32);
+        offset += end - start;
+    }
+    file.extend(notes);
+    file.resize(file.len().next_multiple_of(PAGE), 0);
+    for &(start, end) in &segments {
+        file.extend(&core.memory[start..end]);
+    }
+    file
+}
+
+/// Writes the core file to `path`, reporting where it went or why it didn't
+pub fn write(path: &str, core: &CoreState) {
+    match fs::write(path, core_file(core)) {
+        Ok(()) => eprintln!("core dumped to {}", path),
+        Err(e) => eprintln!("{}: {}", path, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use elf::endian::AnyEndian;
+    use elf::ElfBytes;
+
+    use super::*;
+    use crate::test_utils::machine;
+
+    #[test]
+    fn core_has_registers_and_memory() {
+        let mut core = machine(0x40, &[0x0000_0013], &[(10, 0x1234)]);
+        core.memory[0x900] = 0xAB;
+        core.csrs.set_cause(Cause::LoadAccessFault);
+        let file = core_file(&core);
+        let elf = ElfBytes::<AnyEndian>::minimal_parse(&file).unwrap();
+        assert_eq!((elf.ehdr.e_type, elf.ehdr.e_machine), (ET_CORE, EM_RISCV));
+        let headers: Vec<_> = elf.segments().unwrap().iter().collect();
+        assert_eq!(headers.len(), 2);
+        // the program and the byte written share the single page
+        assert_eq!((headers[1].p_type, headers[1].p_vaddr, headers[1].p_filesz), (PT_LOAD, 0, 0x1000));
+        let load = elf.segment_data(&headers[1]).unwrap();
+        assert_eq!((load[0x40], load[0x900]), (0x13, 0xAB));
+
+        let notes = elf.segment_data(&headers[0]).unwrap();
+        // namesz, descsz, type and "CORE\0" padded to 8
+        let desc = &notes[20..];
+        assert_eq!(u32::from_le_bytes(notes[4..8].try_into().unwrap()) as usize, PRSTATUS_SIZE);
+        assert_eq!(u16::from_le_bytes(desc[PRSTATUS_CURSIG..PRSTATUS_CURSIG + 2].try_into().unwrap()), SIGSEGV);
+        let reg = |i: usize| u32::from_le_bytes(desc[PRSTATUS_REG + 4 * i..PRSTATUS_REG + 4 * i + 4].try_into().unwrap());
+        assert_eq!((reg(0), reg(10)), (0x40, 0x1234));
+        assert_eq!(segments(&[0; 3 * PAGE]), []);
+
+        // an illegal instruction at the unset mtvec traps to itself
+        let mut core = machine(0, &[0], &[]);
+        core.execute();
+        assert!(core.double_fault);
+    }
+}
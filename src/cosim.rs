@@ -0,0 +1,183 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+
+use crate::json::{self, Value};
+use crate::{load_bare_metal, Config, CoreState, Engine, Platform};
+
+/// Golden model for an RTL core: each line from the testbench describes one
+/// instruction the RTL retired and is checked against the next instruction
+/// of the model
+struct Cosim {
+    core: CoreState,
+    platform: Platform,
+    retired: u64,
+}
+
+/// What the RTL reported for `field` and what the model computed
+struct Mismatch {
+    field: String,
+    rtl: u32,
+    model: u32,
+}
+
+fn field(retired: &Value, key: &str) -> Result<Option<u32>, String> {
+    match retired.get(key) {
+        None => Ok(None),
+        Some(value) => value.as_u32().map(Some).ok_or(format!("`{}` must be a 32-bit number", key)),
+    }
+}
+
+fn compare(field: &str, rtl: u32, model: u32) -> Result<(), Mismatch> {
+    match rtl == model {
+        true => Ok(()),
+        false => Err(Mismatch {field: field.to_string(), rtl, model}),
+    }
+}
+
+/// One instruction retired by the RTL: `pc` and optionally `instruction`,
+/// the `rd`/`value` register write and the `store` `{address, value}` of the
+/// low `size` bytes (default 4)
+struct Retired {
+    pc: u32,
+    instruction: Option<u32>,
+    write: Option<(u32, u32)>,
+    store: Option<(u32, u32, u32)>,
+}
+
+impl Retired {
+    fn parse(line: &str) -> Result<Self, String> {
+        let retired = json::parse(line)?;
+        let rd = field(&retired, "rd")?;
+        let store = match retired.get("store") {
+            Some(store) => Some((field(store, "address")?.ok_or("missing store `address`")?,
+                                 field(store, "value")?.ok_or("missing store `value`")?,
+                                 field(store, "size")?.unwrap_or(4))),
+            None => None,
+        };
+        if rd.is_some_and(|rd| rd > 31) || store.is_some_and(|(_, _, size)| ![1, 2, 4].contains(&size)) {
+            return Err("`rd` must be below 32 and store `size` 1, 2 or 4".to_string());
+        }
+        Ok(Self {
+            pc: field(&retired, "pc")?.ok_or("missing `pc`")?,
+            instruction: field(&retired, "instruction")?,
+            write: rd.zip(field(&retired, "value")?),
+            store,
+        })
+    }
+}
+
+impl Cosim {
+    /// Steps the model over the instruction, returns its exit code if the
+    /// model exited
+    fn retire(&mut self, retired: &Retired) -> Result<Option<i32>, Mismatch> {
+        compare("pc", retired.pc, self.core.pc)?;
+        if let Some(instruction) = retired.instruction {
+            let pc = retired.pc as usize;
+            let word = self.core.memory.get(pc..pc + 4).map_or(0, |bytes| u32::from_le_bytes(bytes.try_into().unwrap()));
+            compare("instruction", instruction, word)?;
+        }
+        let exit = self.platform.step(&mut self.core, Engine::Step);
+        // x0 writes are dropped by the RTL's register file too
+        if let Some((rd @ 1..=31, value)) = retired.write {
+            compare(&format!("x{}", rd), value, self.core.regs[rd as usize])?;
+        }
+        if let Some((address, value, size)) = retired.store {
+            let (model_address, model_size) = self.core.last_access.unwrap_or((u32::MAX, 0));
+            compare("store address", address, model_address)?;
+            compare("store size", size, model_size)?;
+            let start = address as usize;
+            let stored = self.core.memory[start..start + size as usize].iter().rev().fold(0, |v, &b| v << 8 | b as u32);
+            let mask = if size == 4 {u32::MAX} else {(1 << (8 * size)) - 1};
+            compare("store value", value & mask, stored)?;
+        }
+        Ok(exit)
+    }
+
+    /// Response line to one testbench line, and the exit code once the
+    /// session is over
+    fn handle(&mut self, line: &str) -> (String, Option<i32>) {
+        let retired = match Retired::parse(line) {
+            Ok(retired) => retired,
+            Err(e) => return (Value::object(&[("error", e.as_str().into())]).to_string(), None),
+        };
+        let outcome = self.retire(&retired);
+        if outcome.is_ok() {
+            self.retired += 1;
+        }
+        let count = ("retired", Value::Number(self.retired as f64));
+        match outcome {
+            Ok(None) => (Value::object(&[count]).to_string(), None),
+            Ok(Some(code)) => (Value::object(&[count, ("exit_code", Value::Number(code as f64))]).to_string(), Some(code)),
+            Err(Mismatch {field, rtl, model}) => {
+                let message = format!("instruction {} at pc 0x{:08x}: {} is 0x{:08x} on the RTL, 0x{:08x} in the model",
+                                      self.retired, retired.pc, field, rtl, model);
+                let response = Value::object(&[count, ("mismatch", message.as_str().into()),
+                                               ("field", field.as_str().into()), ("rtl", rtl.into()),
+                                               ("model", model.into())]);
+                (response.to_string(), Some(1))
+            }
+        }
+    }
+}
+
+/// Loads a bare-metal program as the golden model and serves one testbench at
+/// `address`. Each line the RTL sends is a retired instruction, e.g.
+/// `{"pc":2147483648,"instruction":4163,"rd":5,"value":1}`, answered with
+/// `{"retired":N}` or, at the first divergence, a `mismatch` and the
+/// connection closed. Returns 1 on a divergence, else the model's exit code
+/// (0 if the testbench hung up first).
+pub fn serve(address: &str, args: &[String], config: Config) -> Result<i32, String> {
+    let Config {memory_size, lenient, ..} = config;
+    let path = args.first().ok_or("missing program")?;
+    let (mut core, image) = load_bare_metal(path, memory_size)?;
+    core.lenient = lenient;
+    let platform = Platform::new(args, &image, &mut core);
+    let mut cosim = Cosim {core, platform, retired: 0};
+
+    let listener = TcpListener::bind(address).map_err(|e| format!("{}: {}", address, e))?;
+    eprintln!("co-simulation on {}", listener.local_addr().map_err(|e| e.to_string())?);
+    let (stream, peer) = listener.accept().map_err(|e| e.to_string())?;
+    eprintln!("testbench connected from {}", peer);
+    let mut writer = stream.try_clone().map_err(|e| e.to_string())?;
+    for line in BufReader::new(stream).lines() {
+        let line = line.map_err(|e| e.to_string())?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (response, done) = cosim.handle(line.trim());
+        writeln!(writer, "{}", response).map_err(|e| e.to_string())?;
+        if let Some(code) = done {
+            eprintln!("{}", response);
+            return Ok(code);
+        }
+    }
+    eprintln!("testbench hung up after {} instructions", cosim.retired);
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{self, OP_IMM};
+    use crate::semihosting::Semihosting;
+    use crate::test_utils::machine;
+
+    #[test]
+    fn first_divergence_is_reported() {
+        // addi x5, x0, 7; sw x5, 0x100(x0); addi x6, x5, 1
+        let program = [encode::i(OP_IMM, 0b000, 5, 0, 7), encode::s(0b010, 0, 5, 0x100),
+                       encode::i(OP_IMM, 0b000, 6, 5, 1)];
+        let mut cosim = Cosim {core: machine(0, &program, &[]),
+                               platform: Platform {semihosting: Semihosting::new(String::new()), htif: None, retired: 0,
+                                                   host_ecalls: false, sbi: None},
+                               retired: 0};
+        assert_eq!(cosim.handle(&format!(r#"{{"pc":0,"instruction":{},"rd":5,"value":7}}"#, program[0])),
+                   (r#"{"retired":1}"#.to_string(), None));
+        assert_eq!(cosim.handle(r#"{"pc":4,"store":{"address":256,"value":7}}"#).0, r#"{"retired":2}"#);
+        assert!(cosim.handle(r#"{"value":1}"#).0.starts_with(r#"{"error":"missing `pc`"#));
+        let (response, done) = cosim.handle(r#"{"pc":8,"rd":6,"value":9}"#);
+        assert_eq!(done, Some(1));
+        assert_eq!(response, "{\"retired\":2,\"mismatch\":\"instruction 2 at pc 0x00000008: x6 is 0x00000009 \
+                              on the RTL, 0x00000008 in the model\",\"field\":\"x6\",\"rtl\":9,\"model\":8}");
+    }
+}
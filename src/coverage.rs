@@ -0,0 +1,125 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::mem::{self, Discriminant};
+
+use crate::{Csr, CoreState, Instruction, Observer};
+
+/// Every `Instruction` variant, in declaration order
+const INSTRUCTIONS: [&str; 53] = [
+    "Lui", "Auipc", "Jal", "Jalr", "Beq", "Bne", "Blt", "Bge", "Bltu", "Bgeu",
+    "Lb", "Lh", "Lw", "Lbu", "Lhu", "Sb", "Sh", "Sw",
+    "Addi", "Slti", "Sltiu", "Xori", "Ori", "Andi", "Slli", "Srli", "Srai",
+    "Add", "Sub", "Sll", "Slt", "Sltu", "Xor", "Srl", "Sra", "Or", "And",
+    "Fence", "FenceI", "FenceTso", "Pause", "Ecall", "Ebreak", "Mret", "Sret", "Wfi", "SfenceVma",
+    "Csrrw", "Csrrs", "Csrrc", "Csrrwi", "Csrrsi", "Csrrci",
+];
+
+/// Variant name of an instruction, e.g. `Addi`
+pub(crate) fn mnemonic(instruction: &Instruction) -> String {
+    let name = format!("{:?}", instruction);
+    name[..name.find('(').unwrap_or(name.len())].to_string()
+}
+
+/// Counts executed instruction variants and accessed CSRs, the report is
+/// written to `path` when the coverage is dropped at the end of the run
+pub struct Coverage {
+    path: String,
+    counts: HashMap<Discriminant<Instruction>, u64>,
+    names: HashMap<Discriminant<Instruction>, String>,
+    csrs: BTreeMap<u16, u64>,
+    illegal: u64,
+}
+
+impl Coverage {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            counts: HashMap::new(),
+            names: HashMap::new(),
+            csrs: BTreeMap::new(),
+            illegal: 0,
+        }
+    }
+
+    pub fn report(&self) -> String {
+        let counts: HashMap<&str, u64> = self.counts
+            .iter()
+            .map(|(key, &count)| (self.names[key].as_str(), count))
+            .collect();
+        let covered = INSTRUCTIONS.iter().filter(|name| counts.contains_key(*name)).count();
+        let mut report = format!("instructions: {}/{} covered\n", covered, INSTRUCTIONS.len());
+        for name in INSTRUCTIONS {
+            match counts.get(name) {
+                Some(count) => report += &format!("  {:<8} {}\n", name, count),
+                None => report += &format!("  {:<8} MISSING\n", name),
+            }
+        }
+
+        let implemented: Vec<u16> = (0..0x1000).filter(|&a| Csr::get_csr(a).is_some()).collect();
+        let covered = implemented.iter().filter(|a| self.csrs.contains_key(a)).count();
+        report += &format!("csrs: {}/{} covered\n", covered, implemented.len());
+        for &address in &implemented {
+            let name = format!("{:?}", Csr::get_csr(address).unwrap());
+            match self.csrs.get(&address) {
+                Some(count) => report += &format!("  0x{:03x} {:<10} {}\n", address, name, count),
+                None => report += &format!("  0x{:03x} {:<10} MISSING\n", address, name),
+            }
+        }
+        for (address, count) in self.csrs.iter().filter(|(a, _)| Csr::get_csr(**a).is_none()) {
+            report += &format!("  0x{:03x} {:<10} {}\n", address, "unknown", count);
+        }
+        report += &format!("illegal: {}\n", self.illegal);
+        report
+    }
+}
+
+impl Observer for Coverage {
+    /// Called before each instruction
+    fn step(&mut self, core: &CoreState) {
+        let instruction = match core.memory.get(core.pc as usize..core.pc as usize + 4) {
+            Some(bytes) => CoreState::decode_with(u32::from_le_bytes(bytes.try_into().unwrap()), core.lenient),
+            None => return,
+        };
+        let Ok(instruction) = instruction else {
+            self.illegal += 1;
+            return;
+        };
+        let key = mem::discriminant(&instruction);
+        *self.counts.entry(key).or_insert(0) += 1;
+        self.names.entry(key).or_insert_with(|| mnemonic(&instruction));
+        if let Instruction::Csrrw(args) | Instruction::Csrrs(args) | Instruction::Csrrc(args) |
+               Instruction::Csrrwi(args) | Instruction::Csrrsi(args) | Instruction::Csrrci(args) = instruction {
+            *self.csrs.entry(args.csr).or_insert(0) += 1;
+        }
+    }
+}
+
+impl Drop for Coverage {
+    fn drop(&mut self) {
+        if let Err(e) = fs::write(&self.path, self.report()) {
+            eprintln!("{}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_instructions_and_csrs() {
+        let mut core = CoreState::new(16);
+        // addi x1, x0, 1; csrrw x0, mscratch, x1
+        core.memory[..8].copy_from_slice(&[0x93, 0x00, 0x10, 0x00, 0x73, 0x90, 0x00, 0x34]);
+        let mut coverage = Coverage::new("/dev/null");
+        for _ in 0..2 {
+            coverage.step(&core);
+            core.execute();
+        }
+        let report = coverage.report();
+        assert!(report.starts_with("instructions: 2/53 covered\n"));
+        assert!(report.contains("  Addi     1\n"));
+        assert!(report.contains("  FenceTso MISSING\n"));
+        assert!(report.contains("  0x340 MScratch   1\n"));
+    }
+}
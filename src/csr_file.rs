@@ -0,0 +1,265 @@
+use crate::interrupts::{MEI, MSI, MTI, SEI, SSI, STI};
+use crate::{Cause, Privilege};
+
+pub(crate) const SSTATUS: u16 = 0x100;
+pub(crate) const STVEC: u16 = 0x105;
+pub(crate) const SCOUNTEREN: u16 = 0x106;
+pub(crate) const SSCRATCH: u16 = 0x140;
+pub(crate) const SEPC: u16 = 0x141;
+pub(crate) const SCAUSE: u16 = 0x142;
+pub(crate) const STVAL: u16 = 0x143;
+pub(crate) const SATP: u16 = 0x180;
+pub(crate) const MSTATUS: u16 = 0x300;
+pub(crate) const MISA: u16 = 0x301;
+pub(crate) const MEDELEG: u16 = 0x302;
+pub(crate) const MIDELEG: u16 = 0x303;
+pub(crate) const MIE: u16 = 0x304;
+pub(crate) const MTVEC: u16 = 0x305;
+pub(crate) const MCOUNTEREN: u16 = 0x306;
+pub(crate) const MSTATUSH: u16 = 0x310;
+pub(crate) const MSCRATCH: u16 = 0x340;
+pub(crate) const MEPC: u16 = 0x341;
+pub(crate) const MCAUSE: u16 = 0x342;
+pub(crate) const MTVAL: u16 = 0x343;
+pub(crate) const MSECCFGH: u16 = 0x757;
+pub(crate) const TSELECT: u16 = 0x7A0;
+pub(crate) const TDATA1: u16 = 0x7A1;
+pub(crate) const MCONFIGPTR: u16 = 0xF15;
+
+/// mstatus fields
+pub(crate) mod status {
+    pub(crate) const SIE: u32 = 1 << 1;
+    pub(crate) const MIE: u32 = 1 << 3;
+    pub(crate) const SPIE: u32 = 1 << 5;
+    pub(crate) const MPIE: u32 = 1 << 7;
+    pub(crate) const SPP: u32 = 1 << 8;
+    pub(crate) const MPP: u32 = 0b11 << 11;
+    /// Loads and stores of M-mode use the privilege in MPP
+    pub(crate) const MPRV: u32 = 1 << 17;
+    /// S-mode may load and store U-mode pages
+    pub(crate) const SUM: u32 = 1 << 18;
+    /// Loads may read execute-only pages
+    pub(crate) const MXR: u32 = 1 << 19;
+    /// Trap satp and SFENCE.VMA in S-mode
+    pub(crate) const TVM: u32 = 1 << 20;
+    /// Trap WFI below M-mode
+    pub(crate) const TW: u32 = 1 << 21;
+    /// Trap SRET in S-mode
+    pub(crate) const TSR: u32 = 1 << 22;
+}
+
+/// RV32IM, S and U modes
+const MISA_VALUE: u32 = (1 << 30) | (1 << 8) | (1 << 12) | (1 << 18) | (1 << 20);
+const MSTATUS_WRITABLE: u32 = status::SIE | status::MIE | status::SPIE | status::MPIE | status::SPP | status::MPP
+    | status::MPRV | status::SUM | status::MXR | status::TVM | status::TW | status::TSR;
+const SSTATUS_VIEW: u32 = status::SIE | status::SPIE | status::SPP | status::SUM | status::MXR;
+/// The exceptions S-mode can handle: all below 16 but the M-mode ecall and
+/// the hypervisor's
+const DELEGABLE: u32 = 0xB3FF;
+
+/// What a CSR instruction's write does to a CSR
+#[derive(Clone, Copy)]
+enum Behavior {
+    /// Ignored, the value is fixed
+    Fixed,
+    /// Sets the bits of the mask, the others keep their value
+    Mask(u32),
+    /// WARL or WLRL: the value kept from the old and the written one
+    Legalize(fn(u32, u32) -> u32),
+    /// The bits of the mask of the CSR at the address, sstatus is a view of
+    /// mstatus
+    View(u16, u32),
+}
+
+/// A CSR kept in the file: its address, value at reset and behavior
+struct Spec {
+    address: u16,
+    reset: u32,
+    behavior: Behavior,
+}
+
+const fn spec(address: u16, reset: u32, behavior: Behavior) -> Spec {
+    Spec {address, reset, behavior}
+}
+
+/// The reserved MPP value 2 keeps the old mode
+fn legal_mstatus(old: u32, value: u32) -> u32 {
+    let mpp = if (value & status::MPP) >> 11 == 2 {old & status::MPP} else {value & status::MPP};
+    (value & MSTATUS_WRITABLE & !status::MPP) | mpp
+}
+
+/// The reserved modes 2 and 3 fall back to direct
+fn legal_mtvec(_old: u32, value: u32) -> u32 {
+    if value & 0b11 >= 2 {value & !0b11} else {value}
+}
+
+/// Unsupported codes are ignored
+fn legal_mcause(old: u32, value: u32) -> u32 {
+    if Cause::from_value(value).is_some() {value} else {old}
+}
+
+const CSRS: [Spec; 24] = [
+    spec(SSTATUS, 0, Behavior::View(MSTATUS, SSTATUS_VIEW)),
+    spec(STVEC, 0, Behavior::Legalize(legal_mtvec)),
+    spec(SCOUNTEREN, 0, Behavior::Mask(!0)),
+    spec(SSCRATCH, 0, Behavior::Mask(!0)),
+    // IALIGN is 32
+    spec(SEPC, 0, Behavior::Mask(!0b11)),
+    spec(SCAUSE, 0, Behavior::Legalize(legal_mcause)),
+    spec(STVAL, 0, Behavior::Mask(!0)),
+    // Bare or Sv32, every ASID bit is kept
+    spec(SATP, 0, Behavior::Mask(!0)),
+    // MPP is M
+    spec(MSTATUS, status::MPP, Behavior::Legalize(legal_mstatus)),
+    spec(MISA, MISA_VALUE, Behavior::Fixed),
+    spec(MEDELEG, 0, Behavior::Mask(DELEGABLE)),
+    spec(MIDELEG, 0, Behavior::Mask(SSI | STI | SEI)),
+    // the M- and S-mode interrupts, there is no LCOFI
+    spec(MIE, 0, Behavior::Mask(MSI | MTI | MEI | SSI | STI | SEI)),
+    spec(MTVEC, 0, Behavior::Legalize(legal_mtvec)),
+    spec(MCOUNTEREN, 0, Behavior::Mask(!0)),
+    // little-endian M and S modes, MBE and SBE are zero
+    spec(MSTATUSH, 0, Behavior::Fixed),
+    spec(MSCRATCH, 0, Behavior::Mask(!0)),
+    spec(MEPC, 0, Behavior::Mask(!0b11)),
+    spec(MCAUSE, 19, Behavior::Legalize(legal_mcause)),
+    spec(MTVAL, 0, Behavior::Mask(!0)),
+    // SSEED and USEED need Zkr
+    spec(MSECCFGH, 0, Behavior::Fixed),
+    // no triggers: tdata1 type 0 says there is none at tselect 0
+    spec(TSELECT, 0, Behavior::Fixed),
+    spec(TDATA1, 0, Behavior::Fixed),
+    spec(MCONFIGPTR, 0, Behavior::Fixed),
+];
+
+/// Index of each address in CSRS, NONE for the CSRs not kept here; every
+/// load and store reads mstatus, so the lookup is a table
+const NONE: u8 = u8::MAX;
+const SLOTS: [u8; 0x1000] = {
+    let mut slots = [NONE; 0x1000];
+    let mut i = 0;
+    while i < CSRS.len() {
+        slots[CSRS[i].address as usize] = i as u8;
+        i += 1;
+    }
+    slots
+};
+
+fn slot(address: u16) -> Option<usize> {
+    match SLOTS.get(address as usize) {
+        Some(&i) if i != NONE => Some(i as usize),
+        _ => None,
+    }
+}
+
+/// The CSRs that are plain state, addressed by CSR number: each has a
+/// `Spec` above saying how writes to it behave, so most new CSRs are one
+/// entry. Counters, mip and the machine's IDs depend on the rest of the
+/// core or its configuration and stay in `CoreState`.
+#[derive(Clone, PartialEq, Debug)]
+pub(crate) struct CsrFile {
+    values: [u32; CSRS.len()],
+}
+
+impl CsrFile {
+    pub(crate) fn new() -> Self {
+        Self {values: std::array::from_fn(|i| CSRS[i].reset)}
+    }
+
+    /// The value of a CSR kept here, None for the others
+    pub(crate) fn read(&self, address: u16) -> Option<u32> {
+        let i = slot(address)?;
+        Some(match CSRS[i].behavior {
+            Behavior::View(of, mask) => self.get(of) & mask,
+            _ => self.values[i],
+        })
+    }
+
+    /// A CSR instruction's write, legalized; false if the CSR isn't kept
+    /// here
+    pub(crate) fn write(&mut self, address: u16, value: u32) -> bool {
+        let Some(i) = slot(address) else {
+            return false;
+        };
+        let old = self.values[i];
+        match CSRS[i].behavior {
+            Behavior::Fixed => {}
+            Behavior::Mask(mask) => self.values[i] = (old & !mask) | (value & mask),
+            Behavior::Legalize(legal) => self.values[i] = legal(old, value),
+            Behavior::View(of, mask) => {
+                let whole = self.get(of);
+                self.write(of, (whole & !mask) | (value & mask));
+            }
+        }
+        true
+    }
+
+    /// The value of a CSR kept here, for the core's own accesses
+    pub(crate) fn get(&self, address: u16) -> u32 {
+        self.read(address).unwrap_or_else(|| panic!("csr 0x{:03x} isn't in the file", address))
+    }
+
+    /// Sets a CSR as the hardware does, e.g. mepc on a trap, without the
+    /// write behavior
+    pub(crate) fn set(&mut self, address: u16, value: u32) {
+        let i = slot(address).unwrap_or_else(|| panic!("csr 0x{:03x} isn't in the file", address));
+        self.values[i] = value;
+    }
+
+    /// Whether the mstatus field `bit` is set
+    pub(crate) fn status(&self, bit: u32) -> bool {
+        self.get(MSTATUS) & bit != 0
+    }
+
+    pub(crate) fn set_status(&mut self, bit: u32, on: bool) {
+        let value = self.get(MSTATUS);
+        self.set(MSTATUS, if on {value | bit} else {value & !bit});
+    }
+
+    pub(crate) fn mpp(&self) -> Privilege {
+        Privilege::from_bits((self.get(MSTATUS) & status::MPP) >> 11).unwrap()
+    }
+
+    pub(crate) fn set_mpp(&mut self, privilege: Privilege) {
+        self.set(MSTATUS, (self.get(MSTATUS) & !status::MPP) | (privilege as u32) << 11);
+    }
+
+    pub(crate) fn spp(&self) -> Privilege {
+        if self.status(status::SPP) {Privilege::Supervisor} else {Privilege::User}
+    }
+
+    pub(crate) fn set_spp(&mut self, privilege: Privilege) {
+        self.set_status(status::SPP, privilege == Privilege::Supervisor);
+    }
+
+    pub(crate) fn cause(&self) -> Cause {
+        Cause::from_value(self.get(MCAUSE)).unwrap()
+    }
+
+    pub(crate) fn set_cause(&mut self, cause: Cause) {
+        self.set(MCAUSE, crate::CoreState::get_cause_value(&cause));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_follow_the_spec() {
+        let mut csrs = CsrFile::new();
+        assert_eq!((csrs.read(MISA), csrs.read(0x7FF)), (Some(MISA_VALUE), None));
+        assert!(csrs.write(MISA, 0) && !csrs.write(0x7FF, 0));
+        assert_eq!(csrs.get(MISA), MISA_VALUE);
+        // sstatus writes only its bits of mstatus
+        csrs.write(SSTATUS, !0);
+        assert_eq!(csrs.get(MSTATUS), status::MPP | SSTATUS_VIEW);
+        assert_eq!(csrs.spp(), Privilege::Supervisor);
+        csrs.write(MSTATUS, 2 << 11);
+        assert_eq!((csrs.get(SSTATUS), csrs.mpp()), (0, Privilege::Machine));
+        // hardware writes skip legalization
+        csrs.set(MEPC, 0x1002);
+        csrs.write(MCAUSE, 14);
+        assert_eq!((csrs.get(MEPC), csrs.cause()), (0x1002, Cause::HardwareError));
+    }
+}
@@ -0,0 +1,402 @@
+//! Directed tests for every implemented CSR: reset values, read-only and
+//! WARL/WLRL behavior and the side effects of writes
+
+use crate::csr_file::{status, MCOUNTEREN, MEPC, MIE, MSCRATCH, MTVAL, MTVEC, SCOUNTEREN};
+use crate::encode::{self, ECALL, LOAD, OP_IMM};
+use crate::interrupts::STI;
+use crate::predictor::Predictor;
+use crate::test_utils::{machine, run, step};
+use crate::{Cause, CoreState, Csr, Engine, Identity, Privilege};
+
+const CSRRW: u32 = 0b001;
+const CSRRS: u32 = 0b010;
+const CSRRC: u32 = 0b011;
+const CSRRSI: u32 = 0b110;
+const CSRRCI: u32 = 0b111;
+
+fn read(core: &CoreState, address: u16) -> u32 {
+    core.get_csr_value(&Csr::get_csr(address).unwrap())
+}
+
+/// Value read back after `csrrw x0, address, value`
+fn write(address: u16, value: u32) -> u32 {
+    read(&step(encode::csr(CSRRW, 0, 1, address), &[(1, value)]), address)
+}
+
+#[test]
+fn reset_values() {
+    let core = CoreState::new(4096);
+    for (address, value) in [
+        // RV32, I, M, S and U
+        (0x301, 0x4014_1100),
+        (0xF11, 0), (0xF12, 0), (0xF13, 0), (0xF14, 0), (0xF15, 0),
+        // MPP is M
+        (0x300, 0x0000_1800),
+        (0x304, 0), (0x305, 0), (0x340, 0), (0x341, 0), (0x343, 0), (0x344, 0),
+    ] {
+        assert_eq!(read(&core, address), value, "csr 0x{:03x}", address);
+    }
+}
+
+#[test]
+fn hard_reset_restores_power_on_state() {
+    // mtvec = mscratch = 0x40, mcycle runs, then an illegal instruction traps
+    let program = [encode::csr(CSRRW, 0, 1, 0x305), encode::csr(CSRRW, 0, 1, 0x340), 0];
+    let mut core = run(0, &program, &[(1, 0x40)], 3);
+    core.lenient = true;
+    core.hart_id = 3;
+    core.identity = Identity::parse("marchid=0x8000002a").unwrap();
+    core.hard_reset();
+    let fresh = CoreState::new(4096);
+    for address in [0x300, 0x305, 0x340, 0x341, 0x342, 0x343, 0xB00, 0xB02] {
+        assert_eq!(read(&core, address), read(&fresh, address), "csr 0x{:03x}", address);
+    }
+    assert_eq!((core.pc, core.regs[1], core.memory.len(), core.lenient), (0, 0, 4096, true));
+    // the hart and machine IDs are configuration, not state
+    assert_eq!((read(&core, 0xF12), read(&core, 0xF14)), (0x8000_002a, 3));
+    assert!(core.memory.iter().all(|&byte| byte == 0));
+}
+
+#[test]
+fn identity_is_configurable() {
+    let mut core = CoreState::new(4096);
+    core.identity = Identity::parse("mvendorid=0x489,mimpid=7").unwrap();
+    assert_eq!([0xF11, 0xF12, 0xF13].map(|address| read(&core, address)), [0x489, 0, 7]);
+    assert!(Identity::parse("mhartid=1").is_err() && Identity::parse("marchid=x").is_err());
+}
+
+#[test]
+fn read_only_csrs_reject_writes() {
+    for address in 0xF11..=0xF15 {
+        let csrrw = encode::csr(CSRRW, 1, 2, address);
+        let core = step(csrrw, &[(1, 5), (2, 0xFFFF_FFFF)]);
+        assert_eq!((core.pc, core.csrs.get(MTVAL), core.regs[1]), (0, csrrw, 5), "csr 0x{:03x}", address);
+        assert_eq!(read(&core, address), 0);
+        // set/clear with x0 or uimm 0 don't write and are legal
+        for funct3 in [CSRRS, CSRRC, CSRRSI, CSRRCI] {
+            assert_eq!(step(encode::csr(funct3, 1, 0, address), &[]).pc, 4);
+        }
+    }
+}
+
+#[test]
+fn full_width_csrs() {
+    for address in [0x340, 0x343] {
+        for value in [0xFFFF_FFFF, 0x8000_0001, 0] {
+            assert_eq!(write(address, value), value, "csr 0x{:03x}", address);
+        }
+    }
+}
+
+#[test]
+fn warl_fields_are_legalized() {
+    // misa is fixed
+    assert_eq!(write(0x301, 0), 0x4014_1100);
+    // mie holds the M- and S-mode interrupt enables, mip is set by the
+    // platform but for SSIP and SEIP
+    assert_eq!(write(0x304, 0xFFFF_FFFF), 0xAAA);
+    assert_eq!(write(0x344, 0xFFFF_FFFF), 0x202);
+    // the M-mode ecall and the hypervisor's exceptions can't be delegated,
+    // nor the M-mode interrupts
+    assert_eq!(write(0x302, 0xFFFF_FFFF), 0xB3FF);
+    assert_eq!(write(0x303, 0xFFFF_FFFF), 0x222);
+    // mtvec keeps direct and vectored mode, reserved modes become direct
+    assert_eq!(write(0x305, 0x8000_0101), 0x8000_0101);
+    assert_eq!(write(0x305, 0x8000_0102), 0x8000_0100);
+    assert_eq!(write(0x305, 0x8000_0103), 0x8000_0100);
+    // mepc is 4-byte aligned without C
+    assert_eq!(write(0x341, 0x1237), 0x1234);
+}
+
+#[test]
+fn mseccfg_lockdown_is_sticky() {
+    let program = [encode::csr(CSRRW, 0, 1, 0x747), encode::csr(CSRRW, 0, 0, 0x747), encode::csr(CSRRW, 0, 1, 0x757)];
+    let core = run(0, &program, &[(1, 0xFFFF_FFFF)], 3);
+    // MML and MMWP stay set, RLB clears
+    assert_eq!((read(&core, 0x747), read(&core, 0x757)), (0b011, 0));
+    assert_eq!(write(0x747, 0b100), 0b100);
+}
+
+#[test]
+fn pmp_entries_check_accesses() {
+    // lw x2, 0(x1) with a TOR rule over [0, 0x100): RWX, then locked
+    // read-only
+    const TOR_RWX: u32 = 0x0F;
+    const LOCKED_TOR_R: u32 = 0x89;
+    let faults = |privilege, address, cfg| {
+        let mut core = machine(0, &[encode::i(LOAD, 0b010, 2, 1, 0)], &[(1, address)]);
+        core.memory.resize(0x1000, 0);
+        core.set_pmp_entries(16);
+        core.set_csr_value(&Csr::PmpAddr(0), 0x100 >> 2);
+        core.set_csr_value(&Csr::PmpCfg(0), cfg);
+        core.csrs.set(MTVEC, 0x40);
+        core.privilege = privilege;
+        core.execute();
+        (core.pc == 0x40).then(|| (core.csrs.cause(), read(&core, 0x343)))
+    };
+    assert_eq!(faults(Privilege::User, 0x80, TOR_RWX), None);
+    assert_eq!(faults(Privilege::User, 0x200, TOR_RWX), Some((Cause::LoadAccessFault, 0x200)));
+    // M-mode is unchecked without a locked rule
+    assert_eq!(faults(Privilege::Machine, 0x200, TOR_RWX), None);
+    // a locked rule without X binds M-mode's fetch too
+    assert_eq!(faults(Privilege::Machine, 0x80, LOCKED_TOR_R), Some((Cause::InstructionAccessFault, 0)));
+    // the CSRs read zero without entries
+    let core = CoreState::new(4096);
+    assert_eq!((read(&core, 0x3A0), read(&core, 0x3B0)), (0, 0));
+}
+
+#[test]
+fn stimecmp_raises_stip() {
+    // stimecmph = 0 and stimecmp = 6 from S-mode, then nops
+    let nop = encode::i(OP_IMM, 0b000, 0, 0, 0);
+    let program = [encode::csr(CSRRW, 0, 0, 0x15D), encode::csr(CSRRW, 0, 1, 0x14D), nop, nop, nop, nop, nop];
+    let mut core = machine(0, &program, &[(1, 6)]);
+    assert_eq!(read(&core, 0x15D), 0xFFFF_FFFF);
+    core.privilege = Privilege::Supervisor;
+    for _ in 0..6 {
+        core.dispatch(Engine::Step);
+    }
+    assert_eq!((read(&core, 0x14D), read(&core, 0x15D), core.mip), (6, 0, 0));
+    // pending from the cycle it's due, until a later deadline is written
+    core.dispatch(Engine::Step);
+    assert_eq!(core.mip, STI);
+    core.set_csr_value(&Csr::STimeCmp, 100);
+    assert_eq!(core.mip, 0);
+
+    // with STIE set the expiring deadline traps the S-mode code into M-mode
+    let program = [encode::csr(CSRRW, 0, 1, 0x14D), nop, nop, nop, nop, nop, nop, nop];
+    let mut core = machine(0, &program, &[(1, 3)]);
+    core.csrs.set(MIE, STI);
+    core.csrs.set(MTVEC, 0x80);
+    core.set_csr_value(&Csr::STimeCmpH, 0);
+    core.privilege = Privilege::Supervisor;
+    for _ in 0..3 {
+        core.dispatch(Engine::Step);
+    }
+    assert_eq!(core.pc, 0xC);
+    core.dispatch(Engine::Step);
+    assert_eq!((core.pc, core.csrs.cause(), core.csrs.get(MEPC)), (0x80, Cause::SupervisorTimerInterrupt, 0xC));
+    assert_eq!((core.privilege, core.csrs.mpp()), (Privilege::Machine, Privilege::Supervisor));
+
+    // S-mode CSRs are illegal in U-mode
+    let mut core = machine(0, &[encode::csr(CSRRS, 1, 0, 0x14D)], &[]);
+    core.privilege = Privilege::User;
+    core.execute();
+    assert_eq!(core.csrs.cause(), Cause::IllegalInstruction);
+}
+
+#[test]
+fn counter_enables_gate_the_user_counters() {
+    // csrr at each mode, with counters enabled in mcounteren and scounteren
+    let traps = |address, privilege, mcounteren, scounteren| {
+        let mut core = machine(0, &[encode::csr(CSRRS, 1, 0, address)], &[]);
+        core.csrs.set(MCOUNTEREN, mcounteren);
+        core.csrs.set(SCOUNTEREN, scounteren);
+        core.csrs.set(MTVEC, 0x40);
+        core.privilege = privilege;
+        core.execute();
+        core.pc == 0x40
+    };
+    assert!(!traps(0xC00, Privilege::Machine, 0, 0));
+    assert!(traps(0xC00, Privilege::Supervisor, 0, 1) && !traps(0xC00, Privilege::Supervisor, 1, 0));
+    assert!(traps(0xC80, Privilege::User, 1, 0) && !traps(0xC80, Privilege::User, 1, 1));
+    // bit 3 for hpmcounter3
+    assert!(traps(0xC03, Privilege::Supervisor, 1, 0) && !traps(0xC03, Privilege::Supervisor, 1 << 3, 0));
+
+    // satp is the M-mode's with mstatus.TVM
+    let mut core = machine(0, &[encode::csr(CSRRS, 1, 0, 0x180)], &[]);
+    core.csrs.set_status(status::TVM, true);
+    core.privilege = Privilege::Supervisor;
+    core.execute();
+    assert_eq!(core.csrs.cause(), Cause::IllegalInstruction);
+}
+
+#[test]
+fn mcause_holds_legal_codes() {
+    for code in [0, 2, 3, 7, 8, 9, 11, 13, 15, 19, 22, 23, 0x8000_0001, 0x8000_0003, 0x8000_0007, 0x8000_000B,
+                 0x8000_000D] {
+        assert_eq!(write(0x342, code), code);
+    }
+    // reserved codes leave the old value
+    for code in [14, 16, 24, 0x8000_0000, 0x8000_000F] {
+        let mut core = machine(0, &[encode::csr(CSRRW, 0, 1, 0x342)], &[(1, code)]);
+        core.csrs.set_cause(Cause::Mcall);
+        core.execute();
+        assert_eq!(read(&core, 0x342), 11, "code 0x{:x}", code);
+    }
+}
+
+#[test]
+fn causes_are_prioritized() {
+    for code in (0..24).chain((0..16).map(|code| 0x8000_0000 | code)) {
+        if let Some(cause) = Cause::from_value(code) {
+            assert_eq!(CoreState::get_cause_value(&cause), code);
+        }
+    }
+    let highest = |causes: &[Cause]| Cause::highest(causes.iter().copied()).unwrap();
+    assert_eq!(highest(&[Cause::LoadAccessFault, Cause::LoadPageFault, Cause::LoadAddressMisaligned]),
+               Cause::LoadAddressMisaligned);
+    assert_eq!(highest(&[Cause::IllegalInstruction, Cause::InstructionAccessFault]), Cause::InstructionAccessFault);
+    assert_eq!(highest(&[Cause::InstructionAccessFault, Cause::InstructionPageFault]), Cause::InstructionPageFault);
+    assert_eq!(highest(&[Cause::SupervisorExternalInterrupt, Cause::MachineTimerInterrupt]),
+               Cause::MachineTimerInterrupt);
+    assert_eq!(highest(&[Cause::CounterOverflowInterrupt, Cause::SupervisorTimerInterrupt]),
+               Cause::SupervisorTimerInterrupt);
+}
+
+#[test]
+fn mstatus_writes() {
+    // SIE, MIE, SPIE, MPIE, SPP, MPP, MPRV, SUM, MXR, TVM, TW and TSR are
+    // writable
+    assert_eq!(write(0x300, 0xFFFF_FFFF), 0x007E_19AA);
+    assert_eq!(write(0x300, 0), 0);
+    assert_eq!(write(0x300, 1 << 3), 0x0000_0008);
+    // MPP keeps M for the reserved mode 2
+    assert_eq!(write(0x300, 2 << 11), 0x0000_1800);
+    // sstatus is the S-mode part
+    assert_eq!(write(0x100, 0xFFFF_FFFF), 0x000C_0122);
+
+    // a trap moves MIE to MPIE and clears MIE
+    let program = [encode::csr(CSRRW, 0, 1, 0x300), ECALL];
+    let core = run(0, &program, &[(1, 1 << 3)], 2);
+    assert_eq!(read(&core, 0x300), 0x0000_1880);
+}
+
+#[test]
+fn csrrw_to_x0_still_writes() {
+    let core = step(encode::csr(CSRRW, 0, 1, 0x340), &[(1, 0x55)]);
+    assert_eq!((core.regs[0], core.csrs.get(MSCRATCH)), (0, 0x55));
+}
+
+#[test]
+fn set_and_clear_with_x0_dont_write() {
+    for funct3 in [CSRRS, CSRRC, CSRRSI, CSRRCI] {
+        let mut core = machine(0, &[encode::csr(funct3, 1, 0, 0x341)], &[]);
+        core.csrs.set(MEPC, 0x1234);
+        core.execute();
+        assert_eq!((core.pc, core.csrs.get(MEPC)), (4, 0x1234), "funct3 {:03b}", funct3);
+    }
+}
+
+#[test]
+fn set_and_clear_modify_the_old_value() {
+    // mscratch = 0xF0F0, rs1 = 0x0FF0 or uimm = 0b1_0101, the old value goes to rd
+    for (funct3, source, new) in [(CSRRS, 2, 0xFFF0), (CSRRC, 2, 0xF000), (CSRRSI, 0b1_0101, 0xF0F5),
+                                  (CSRRCI, 0b1_0101, 0xF0E0)] {
+        let mut core = machine(0, &[encode::csr(funct3, 1, source, 0x340)], &[(2, 0x0FF0)]);
+        core.csrs.set(MSCRATCH, 0xF0F0);
+        core.execute();
+        assert_eq!((core.regs[1], core.csrs.get(MSCRATCH)), (0xF0F0, new), "funct3 {:03b}", funct3);
+    }
+    // rd = x0 still writes, the result is legalized like a csrrw
+    let core = step(encode::csr(CSRRS, 0, 1, 0x340), &[(1, 0x8000_0000)]);
+    assert_eq!(core.csrs.get(MSCRATCH), 0x8000_0000);
+    let core = step(encode::csr(CSRRS, 1, 2, 0x304), &[(2, 0xFFFF_FFFF)]);
+    assert_eq!((core.regs[1], read(&core, 0x304)), (0, 0xAAA));
+    let core = run(0, &[encode::csr(CSRRW, 0, 1, 0x304), encode::csr(CSRRCI, 1, 0b1000, 0x304)], &[(1, 0x888)], 2);
+    assert_eq!((core.regs[1], read(&core, 0x304)), (0x888, 0x880));
+}
+
+#[test]
+fn set_and_clear_of_read_only_csrs() {
+    let identity = Identity::parse("mimpid=7").unwrap();
+    for funct3 in [CSRRS, CSRRC, CSRRSI, CSRRCI] {
+        // a nonzero rs1 or uimm writes, which traps and leaves rd alone
+        let instruction = encode::csr(funct3, 1, 2, 0xF13);
+        let mut trapping = machine(0, &[instruction], &[(1, 5), (2, 1)]);
+        trapping.identity = identity;
+        trapping.execute();
+        assert_eq!((trapping.csrs.cause(), trapping.csrs.get(MTVAL), trapping.regs[1]),
+                   (Cause::IllegalInstruction, instruction, 5), "funct3 {:03b}", funct3);
+        // x0 or uimm 0 only reads
+        let mut reading = machine(0, &[encode::csr(funct3, 1, 0, 0xF13)], &[]);
+        reading.identity = identity;
+        reading.execute();
+        assert_eq!((reading.pc, reading.regs[1], read(&reading, 0xF13)), (4, 7, 7), "funct3 {:03b}", funct3);
+    }
+}
+
+#[test]
+fn hpm_counters_count_selected_events() {
+    let program = [
+        // mhpmevent3 = loads, mhpmevent4 = taken branches
+        encode::csr(CSRRW, 0, 1, 0x323),
+        encode::csr(CSRRW, 0, 2, 0x324),
+        encode::i(LOAD, 0b010, 5, 0, 0x40),
+        encode::i(LOAD, 0b010, 5, 0, 0x44),
+        encode::b(0b000, 0, 0, 8),
+        0,
+        encode::csr(CSRRW, 6, 0, 0xB03),
+        encode::csr(CSRRW, 7, 0, 0xB04),
+        encode::i(LOAD, 0b010, 5, 0, 0x40),
+    ];
+    let core = run(0, &program, &[(1, 1), (2, 4)], 8);
+    assert_eq!((core.regs[6], core.regs[7]), (2, 1));
+    // the reads cleared both counters, one more load since
+    assert_eq!((read(&core, 0xB03), read(&core, 0xB04)), (1, 0));
+    assert_eq!(read(&core, 0x323), 1);
+
+    // unsupported events count nothing, the high half is writable
+    assert_eq!(write(0x325, 0x1234), 0);
+    assert_eq!(write(0xB85, 0x8000_0000), 0x8000_0000);
+}
+
+#[test]
+fn mcycle_and_minstret_count_and_take_writes() {
+    let nop = encode::i(OP_IMM, 0b000, 0, 0, 0);
+    // minstret = 0x1_0000_0010 by halves
+    let program = [nop, encode::csr(CSRRW, 5, 1, 0xB02), encode::csr(CSRRW, 6, 2, 0xB82), nop, ECALL];
+    let core = run(0, &program, &[(1, 0x10), (2, 1)], 5);
+    assert_eq!((core.regs[5], core.regs[6]), (1, 0));
+    // the writes don't count themselves, the trapping ecall doesn't retire
+    assert_eq!((read(&core, 0xB02), read(&core, 0xB82)), (0x11, 1));
+    assert_eq!((read(&core, 0xB00), read(&core, 0xB80)), (5, 0));
+    assert_eq!(write(0xB80, 0x8000_0000), 0x8000_0000);
+}
+
+#[test]
+fn wide_csrs_are_written_by_halves() {
+    let nop = encode::i(OP_IMM, 0b000, 0, 0, 0);
+    // mcycle = 0x5_FFFF_FFFF, high word first, carries into mcycleh
+    let program = [encode::csr(CSRRW, 0, 2, 0xB80), encode::csr(CSRRW, 0, 1, 0xB00), nop];
+    let core = run(0, &program, &[(1, 0xFFFF_FFFF), (2, 5)], 3);
+    assert_eq!((read(&core, 0xB80), read(&core, 0xB00)), (6, 1));
+    assert_eq!(write(0x310, 0xFFFF_FFFF), 0);
+}
+
+#[test]
+fn mcountinhibit_freezes_counters() {
+    let nop = encode::i(OP_IMM, 0b000, 0, 0, 0);
+    let program = [
+        // mhpmevent3 = loads, then mcycle, minstret and mhpmcounter3 stop
+        // around a load and two nops
+        encode::csr(CSRRW, 0, 1, 0x323),
+        encode::csr(CSRRW, 0, 2, 0x320),
+        encode::i(LOAD, 0b010, 5, 0, 0x40),
+        nop,
+        encode::csr(CSRRW, 6, 0, 0x320),
+        encode::i(LOAD, 0b010, 5, 0, 0x40),
+        ECALL,
+    ];
+    let core = run(0, &program, &[(1, 1), (2, 0b1101)], 7);
+    assert_eq!(core.regs[6], 0b1101);
+    // one instruction before and one after the region; mcycle takes the
+    // cycles of the write restarting it and of the trapping ecall
+    assert_eq!((read(&core, 0xB00), read(&core, 0xB02), read(&core, 0xB03)), (4, 2, 1));
+    // TM is zero, writes to frozen counters stick
+    assert_eq!(write(0x320, 0xFFFF_FFFF), 0xFFFF_FFFD);
+    let core = run(0, &[encode::csr(CSRRW, 0, 2, 0x320), encode::csr(CSRRW, 0, 1, 0xB02), nop, nop],
+                   &[(1, 7), (2, 0b100)], 4);
+    assert_eq!(read(&core, 0xB02), 7);
+}
+
+#[test]
+fn hpm_counters_read_timing_model_events() {
+    // mhpmevent3 = mispredictions, a not-taken backward branch predicted taken
+    let program = [encode::csr(CSRRW, 0, 1, 0x323), encode::b(0b001, 0, 0, -4)];
+    let mut core = machine(0, &program, &[(1, 5)]);
+    core.timing = Some(Box::new(Predictor::parse(None, "static").unwrap()));
+    core.execute();
+    core.execute();
+    assert_eq!(read(&core, 0xB03), 1);
+}
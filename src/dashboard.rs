@@ -0,0 +1,209 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use crate::history::DEFAULT_DEPTH;
+use crate::json::Value;
+use crate::rpc::{Error, Session, SLICE};
+use crate::Config;
+
+/// Console output kept for the page
+const CONSOLE_LIMIT: usize = 64 << 10;
+/// Largest request head read
+const REQUEST_LIMIT: usize = 8 << 10;
+
+const PAGE: &str = r#"<!doctype html>
+<html><head><meta charset="utf-8"><title>rs-V</title>
+<style>
+body { font-family: monospace; margin: 1em; }
+table { border-collapse: collapse; }
+td { padding: 0 1em 0 0; }
+pre { background: #111; color: #ddd; padding: 0.5em; height: 20em; overflow-y: scroll; }
+</style></head>
+<body>
+<button onclick="post('/pause')">pause</button>
+<button onclick="post('/back')">back</button>
+<button onclick="post('/step')">step</button>
+<button onclick="post('/run')">run</button>
+<button onclick="post('/quit')">quit</button>
+<span id="status"></span>
+<table id="registers"></table>
+<pre id="console"></pre>
+<script>
+const names = ["zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+               "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6"];
+const hex = n => "0x" + n.toString(16).padStart(8, "0");
+function show(state) {
+  let status = state.state + " pc " + hex(state.pc) + " cycles " + state.cycles;
+  if (state.exit_code !== undefined) status += " exit code " + state.exit_code;
+  if (state.error) status += " (" + state.error + ")";
+  document.getElementById("status").textContent = status;
+  let rows = "";
+  for (let i = 0; i < 32; i += 4) {
+    rows += "<tr>" + [0, 1, 2, 3].map(j => "<td>" + names[i + j] + "</td><td>" + hex(state.x[i + j]) + "</td>").join("") + "</tr>";
+  }
+  document.getElementById("registers").innerHTML = rows;
+  const console = document.getElementById("console");
+  const bottom = console.scrollTop + console.clientHeight >= console.scrollHeight - 2;
+  console.textContent = state.console;
+  if (bottom) console.scrollTop = console.scrollHeight;
+}
+function post(path) { fetch(path, {method: "POST"}).then(r => r.json()).then(show); }
+setInterval(() => fetch("/state").then(r => r.json()).then(show).catch(() => {}), 250);
+</script>
+</body></html>
+"#;
+
+/// The page's view of a `Session`: machine state, console output and the
+/// page controls
+struct Dashboard {
+    session: Session,
+    console: Vec<u8>,
+    quit: bool,
+}
+
+impl Dashboard {
+    fn new(mut session: Session) -> Self {
+        session.capture_console();
+        Self {session, console: Vec::new(), quit: false}
+    }
+
+    fn collect_console(&mut self) {
+        self.console.append(&mut self.session.console());
+        if self.console.len() > CONSOLE_LIMIT {
+            self.console.drain(..self.console.len() - CONSOLE_LIMIT);
+        }
+    }
+
+    /// Status, registers and console, plus the error of a refused control
+    fn state(&mut self, error: Option<String>) -> String {
+        self.collect_console();
+        let Ok(Value::Object(mut state)) = self.session.call("status", &Value::Null) else {
+            unreachable!();
+        };
+        if let Ok(registers) = self.session.call("registers", &Value::Null) {
+            state.push(("x".to_string(), registers.get("x").cloned().unwrap_or(Value::Null)));
+        }
+        state.push(("console".to_string(), String::from_utf8_lossy(&self.console).as_ref().into()));
+        if let Some(error) = error {
+            state.push(("error".to_string(), error.as_str().into()));
+        }
+        Value::Object(state).to_string()
+    }
+
+    /// Status line, content type and body for a request
+    fn respond(&mut self, method: &str, path: &str) -> (&'static str, &'static str, String) {
+        const JSON: &str = "application/json";
+        let control = match (method, path) {
+            ("GET", "/") => return ("200 OK", "text/html; charset=utf-8", PAGE.to_string()),
+            ("GET", "/state") => return ("200 OK", JSON, self.state(None)),
+            ("POST", "/pause") => "pause",
+            ("POST", "/back") => "reverse_step",
+            ("POST", "/step") => "step",
+            ("POST", "/run") => "resume",
+            ("POST", "/quit") => {
+                self.quit = true;
+                return ("200 OK", JSON, self.state(None));
+            }
+            ("GET" | "POST", _) => return ("404 Not Found", "text/plain", "not found\n".to_string()),
+            _ => return ("405 Method Not Allowed", "text/plain", "GET or POST only\n".to_string()),
+        };
+        match self.session.call(control, &Value::Null) {
+            Ok(_) => ("200 OK", JSON, self.state(None)),
+            Err(Error(_, message)) => ("409 Conflict", JSON, self.state(Some(message))),
+        }
+    }
+
+    /// Serves one HTTP/1.0-style request, closing the connection after it
+    fn connection(&mut self, mut stream: TcpStream) -> std::io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+        let mut head = Vec::new();
+        let mut buffer = [0; 1024];
+        while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < REQUEST_LIMIT {
+            match stream.read(&mut buffer)? {
+                0 => break,
+                n => head.extend_from_slice(&buffer[..n]),
+            }
+        }
+        let head = String::from_utf8_lossy(&head);
+        let mut words = head.split_whitespace();
+        let (method, path) = (words.next().unwrap_or(""), words.next().unwrap_or(""));
+        let (status, content_type, body) = self.respond(method, path);
+        write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\n\
+                        Connection: close\r\n\r\n{}", status, content_type, body.len(), body)
+    }
+}
+
+/// Loads a bare-metal program paused at its entry and serves a page at
+/// `address` showing its registers, pc and console with pause, back (undoing
+/// a recorded step), step, run and quit controls. Returns the guest exit code once quit, 0 if it hadn't
+/// exited.
+pub fn serve(address: &str, args: &[String], config: Config) -> Result<i32, String> {
+    let mut session = Session::new(args, config)?;
+    session.record(DEFAULT_DEPTH);
+    let mut dashboard = Dashboard::new(session);
+    let listener = TcpListener::bind(address).map_err(|e| format!("{}: {}", address, e))?;
+    listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+    eprintln!("dashboard on http://{}/", listener.local_addr().map_err(|e| e.to_string())?);
+    while !dashboard.quit {
+        if dashboard.session.running() {
+            dashboard.session.run(SLICE);
+            dashboard.collect_console();
+        }
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                if let Err(e) = dashboard.connection(stream) {
+                    eprintln!("{}: {}", peer, e);
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                if !dashboard.session.running() {
+                    thread::sleep(Duration::from_millis(10));
+                }
+            }
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+    Ok(dashboard.session.exit_code().unwrap_or(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{self, OP_IMM};
+    use crate::json;
+
+    #[test]
+    fn controls_and_state() {
+        // loop: addi x1, x1, 1; jal x0, loop
+        let mut dashboard = Dashboard::new(Session::inline(&[encode::i(OP_IMM, 0b000, 1, 1, 1), encode::j(0, -4)]));
+        assert_eq!(dashboard.respond("GET", "/").0, "200 OK");
+        let (status, _, body) = dashboard.respond("POST", "/step");
+        assert_eq!(status, "200 OK");
+        let state = json::parse(&body).unwrap();
+        assert_eq!(state.get("pc").and_then(Value::as_u32), Some(4));
+        match state.get("x") {
+            Some(Value::Array(x)) => assert_eq!((x.len(), x[1].as_u32()), (32, Some(1))),
+            x => panic!("{:?}", x),
+        }
+        assert_eq!(state.get("console").and_then(Value::as_str), Some(""));
+        // not recording
+        assert_eq!(dashboard.respond("POST", "/back").0, "409 Conflict");
+        dashboard.session.record(4);
+        dashboard.respond("POST", "/step");
+        let state = json::parse(&dashboard.respond("POST", "/back").2).unwrap();
+        assert_eq!((state.get("pc").and_then(Value::as_u32), state.get("history").and_then(Value::as_u32)),
+                   (Some(4), Some(0)));
+
+        dashboard.respond("POST", "/run");
+        assert!(dashboard.session.running());
+        dashboard.respond("POST", "/pause");
+        assert!(!dashboard.session.running());
+        assert_eq!(dashboard.respond("GET", "/nothing").0, "404 Not Found");
+        assert_eq!(dashboard.respond("PUT", "/run").0, "405 Method Not Allowed");
+        dashboard.respond("POST", "/quit");
+        assert!(dashboard.quit);
+    }
+}
@@ -0,0 +1,52 @@
+use crate::dispatch::MicroOp;
+
+const ENTRIES: usize = 4096;
+
+/// Direct-mapped cache of decoded instructions keyed by pc. Stores to a
+/// cached word and FENCE.I drop the stale entries.
+pub struct DecodeCache {
+    entries: Vec<Option<(u32, MicroOp)>>,
+}
+
+impl DecodeCache {
+    pub fn new() -> Self {
+        Self {entries: vec![None; ENTRIES]}
+    }
+
+    fn index(pc: u32) -> usize {
+        (pc as usize >> 2) & (ENTRIES - 1)
+    }
+
+    pub fn get(&self, pc: u32) -> Option<MicroOp> {
+        match self.entries[Self::index(pc)] {
+            Some((tag, instruction)) if tag == pc => Some(instruction),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&mut self, pc: u32, instruction: MicroOp) {
+        self.entries[Self::index(pc)] = Some((pc, instruction));
+    }
+
+    /// Drops entries for instructions overlapping `size` bytes at `address`
+    pub fn invalidate(&mut self, address: u32, size: u32) {
+        if size == 0 {
+            return;
+        }
+        let words = (address as u64 % 4 + size as u64).div_ceil(4);
+        if words >= ENTRIES as u64 {
+            return self.flush();
+        }
+        for i in 0..words as u32 {
+            let pc = (address & !3).wrapping_add(4 * i);
+            let entry = &mut self.entries[Self::index(pc)];
+            if entry.is_some_and(|(tag, _)| tag == pc) {
+                *entry = None;
+            }
+        }
+    }
+
+    pub fn flush(&mut self) {
+        self.entries.fill(None);
+    }
+}
@@ -0,0 +1,395 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use crate::encode::{self, AUIPC, LOAD, LUI, OP_IMM};
+use crate::snapshot::Snapshot;
+use crate::{triage, CoreState, Engine};
+
+// spike keeps its debug module and boot ROM below 0x2000
+pub(crate) const BASE: u32 = 0x0001_0000;
+pub(crate) const DATA: u32 = BASE + 0x1000;
+pub(crate) const TOHOST: u32 = DATA;
+pub(crate) const SIGNATURE: u32 = DATA + 0x100;
+pub(crate) const SCRATCH_SIZE: u32 = 64;
+// x1..x30 follow the scratch buffer, x31 holds the signature address
+const SIGNATURE_SIZE: u32 = SCRATCH_SIZE + 4 * 30;
+pub(crate) const MEMORY_SIZE: usize = 0x2_0000;
+pub(crate) const MAX_STEPS: usize = 1 << 16;
+
+const DEFAULT_REFERENCE: &str = "spike --isa=rv32i -m0x10000:0x10000 +signature={signature} {elf}";
+
+/// xorshift32
+pub(crate) struct Rng(pub(crate) u32);
+
+impl Rng {
+    pub(crate) fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+
+    pub(crate) fn below(&mut self, n: u32) -> u32 {
+        self.next() % n
+    }
+}
+
+/// One generated instruction. Branches skip a number of following body
+/// instructions, so removing instructions while shrinking keeps them valid.
+#[derive(Clone, Copy)]
+pub(crate) enum Op {
+    Word(u32),
+    Branch {funct3: u32, rs1: u32, rs2: u32, skip: u32},
+}
+
+pub(crate) fn random_op(rng: &mut Rng) -> Op {
+    let rd = 1 + rng.below(30);
+    let rs1 = rng.below(31);
+    let rs2 = rng.below(31);
+    match rng.below(6) {
+        0 | 1 => {
+            let (funct7, funct3) = [(0, 0), (0x20, 0), (0, 1), (0, 2), (0, 3), (0, 4), (0, 5), (0x20, 5),
+                                    (0, 6), (0, 7)][rng.below(10) as usize];
+            Op::Word(encode::r(funct7, funct3, rd, rs1, rs2))
+        }
+        2 => {
+            let funct3 = rng.below(8);
+            let imm = match funct3 {
+                0b001 => rng.below(32),
+                0b101 => rng.below(32) | (rng.below(2) << 10),
+                _ => rng.next(),
+            };
+            Op::Word(encode::i(OP_IMM, funct3, rd, rs1, imm as i32))
+        }
+        3 => Op::Word(encode::u([LUI, AUIPC][rng.below(2) as usize], rd, rng.next())),
+        4 => {
+            // aligned accesses into the scratch buffer through x31
+            let funct3 = [0b000, 0b001, 0b010, 0b100, 0b101][rng.below(5) as usize];
+            let size = 1 << (funct3 & 0b11);
+            let offset = rng.below(SCRATCH_SIZE / size) * size;
+            match rng.below(2) {
+                0 => Op::Word(encode::i(LOAD, funct3, rd, 31, offset as i32)),
+                _ => Op::Word(encode::s(funct3 & 0b11, 31, rs2, offset as i32)),
+            }
+        }
+        _ => Op::Branch {
+            funct3: [0, 1, 4, 5, 6, 7][rng.below(6) as usize],
+            rs1,
+            rs2,
+            skip: rng.below(4),
+        },
+    }
+}
+
+/// A generated test: deterministic register and scratch contents plus a body
+#[derive(Clone)]
+pub(crate) struct Program {
+    pub(crate) regs: Vec<u32>,
+    pub(crate) scratch: Vec<u8>,
+    pub(crate) body: Vec<Op>,
+}
+
+impl Program {
+    pub(crate) fn generate(seed: u32, length: usize) -> Self {
+        let mut rng = Rng(seed.max(1));
+        Self {
+            regs: (1..31).map(|_| rng.next()).collect(),
+            scratch: (0..SCRATCH_SIZE).map(|_| rng.next() as u8).collect(),
+            body: (0..length).map(|_| random_op(&mut rng)).collect(),
+        }
+    }
+
+    /// Register setup, x31 pointing at the scratch buffer, then the body
+    pub(crate) fn body_code(&self) -> Vec<u32> {
+        let mut code = Vec::new();
+        for (i, &value) in self.regs.iter().enumerate() {
+            code.extend(encode::li(i as u32 + 1, value));
+        }
+        code.extend(encode::li(31, SIGNATURE));
+        for (i, op) in self.body.iter().enumerate() {
+            code.push(match *op {
+                Op::Word(word) => word,
+                Op::Branch {funct3, rs1, rs2, skip} => {
+                    let skip = skip.min((self.body.len() - i - 1) as u32);
+                    encode::b(funct3, rs1, rs2, 4 * (skip as i32 + 1))
+                }
+            });
+        }
+        code
+    }
+
+    /// Body followed by the signature dump, a tohost exit and `j .`
+    fn code(&self) -> Vec<u32> {
+        let mut code = self.body_code();
+        for reg in 1..31 {
+            code.push(encode::s(0b010, 31, reg, (SCRATCH_SIZE + 4 * (reg - 1)) as i32));
+        }
+        code.extend(encode::li(1, TOHOST));
+        code.push(encode::i(OP_IMM, 0b000, 2, 0, 1));
+        code.push(encode::s(0b010, 1, 2, 0));
+        code.push(encode::s(0b010, 1, 0, 4));
+        code.push(encode::j(0, 0));
+        code
+    }
+
+    /// Runs on rs-v, returns the signature words
+    pub(crate) fn run(&self) -> Result<Vec<u32>, String> {
+        let core = self.run_core()?;
+        let scratch = SIGNATURE as usize;
+        Ok(signature_words(&core.memory[scratch..scratch + SIGNATURE_SIZE as usize]))
+    }
+
+    /// Runs on rs-v up to the final `j .`
+    fn run_core(&self) -> Result<CoreState, String> {
+        let code = self.code();
+        let mut core = load(&code, &self.scratch);
+        let end = BASE + 4 * (code.len() as u32 - 1);
+        let mut steps = 0;
+        while core.pc != end {
+            if steps > MAX_STEPS {
+                return Err(format!("no exit after {} steps, pc 0x{:08x}", steps, core.pc));
+            }
+            steps += core.dispatch(Engine::Block);
+        }
+        Ok(core)
+    }
+
+    pub(crate) fn elf(&self) -> Vec<u8> {
+        elf(&self.code(), &self.scratch)
+    }
+}
+
+/// Machine with `code` at BASE and `scratch` at SIGNATURE, pc at BASE
+pub(crate) fn load(code: &[u32], scratch: &[u8]) -> CoreState {
+    let mut core = CoreState::new(MEMORY_SIZE);
+    for (i, word) in code.iter().enumerate() {
+        let address = BASE as usize + 4 * i;
+        core.memory[address..address + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    let address = SIGNATURE as usize;
+    core.memory[address..address + scratch.len()].copy_from_slice(scratch);
+    core.pc = BASE;
+    core
+}
+
+/// Static ELF with `tohost` and `begin_signature`/`end_signature` symbols,
+/// `code` must fit below DATA
+pub(crate) fn elf(code: &[u32], scratch: &[u8]) -> Vec<u8> {
+    let mut image: Vec<u8> = code.iter().flat_map(|w| w.to_le_bytes()).collect();
+    image.resize((DATA - BASE) as usize, 0);
+    image.resize((SIGNATURE - BASE) as usize, 0);
+    image.extend(scratch);
+    image.resize((SIGNATURE - BASE + SIGNATURE_SIZE) as usize, 0);
+
+    let symbols = [("tohost", TOHOST), ("fromhost", TOHOST + 8), ("begin_signature", SIGNATURE),
+                   ("end_signature", SIGNATURE + SIGNATURE_SIZE)];
+    let mut strtab = vec![0];
+    let mut symtab = vec![0; 16];
+    for (name, value) in symbols {
+        symtab.extend((strtab.len() as u32).to_le_bytes());
+        symtab.extend(value.to_le_bytes());
+        symtab.extend(0_u32.to_le_bytes());
+        // STB_GLOBAL, STT_NOTYPE, section 1
+        symtab.extend([0x10, 0, 1, 0]);
+        strtab.extend(name.as_bytes());
+        strtab.push(0);
+    }
+    let shstrtab = b"\0.text\0.symtab\0.strtab\0.shstrtab\0";
+
+    let offset = 0x1000_u32;
+    let symtab_offset = offset + image.len() as u32;
+    let strtab_offset = symtab_offset + symtab.len() as u32;
+    let shstrtab_offset = strtab_offset + strtab.len() as u32;
+    let sh_offset = (shstrtab_offset + shstrtab.len() as u32 + 3) & !3;
+
+    let mut elf = Vec::new();
+    elf.extend(b"\x7fELF\x01\x01\x01\0\0\0\0\0\0\0\0\0");
+    // ET_EXEC, EM_RISCV, version, entry, phoff, shoff, flags
+    for half in [2_u16, 243] {
+        elf.extend(half.to_le_bytes());
+    }
+    for word in [1, BASE, 52, sh_offset, 0] {
+        elf.extend(word.to_le_bytes());
+    }
+    // ehsize, phentsize, phnum, shentsize, shnum, shstrndx
+    for half in [52_u16, 32, 1, 40, 5, 4] {
+        elf.extend(half.to_le_bytes());
+    }
+    // PT_LOAD, RWX
+    for word in [1, offset, BASE, BASE, image.len() as u32, image.len() as u32, 7, 0x1000] {
+        elf.extend(word.to_le_bytes());
+    }
+    elf.resize(offset as usize, 0);
+    elf.extend(&image);
+    elf.extend(&symtab);
+    elf.extend(&strtab);
+    elf.extend(shstrtab);
+    elf.resize(sh_offset as usize, 0);
+
+    let sections: [[u32; 10]; 5] = [
+        [0; 10],
+        [1, 1, 7, BASE, offset, image.len() as u32, 0, 0, 4, 0],
+        [7, 2, 0, 0, symtab_offset, symtab.len() as u32, 3, 1, 4, 16],
+        [15, 3, 0, 0, strtab_offset, strtab.len() as u32, 0, 0, 1, 0],
+        [23, 3, 0, 0, shstrtab_offset, shstrtab.len() as u32, 0, 0, 1, 0],
+    ];
+    for section in sections {
+        for word in section {
+            elf.extend(word.to_le_bytes());
+        }
+    }
+    elf
+}
+
+fn signature_words(bytes: &[u8]) -> Vec<u32> {
+    bytes.chunks_exact(4).map(|b| u32::from_le_bytes(b.try_into().unwrap())).collect()
+}
+
+/// Runs the program on the reference command, spike-style signature file of
+/// one hex word per line
+fn reference(template: &str, program: &Program, tag: &str) -> Result<Vec<u32>, String> {
+    let dir = std::env::temp_dir();
+    let elf = dir.join(format!("rs-v-difftest-{}.elf", tag));
+    let signature = dir.join(format!("rs-v-difftest-{}.sig", tag));
+    fs::write(&elf, program.elf()).map_err(|e| format!("{}: {}", elf.display(), e))?;
+    reference_signature(template, &elf, &signature)
+}
+
+/// Runs `template` with `{elf}` and `{signature}` substituted, returns the
+/// signature it wrote
+pub(crate) fn reference_signature(template: &str, elf: &Path, signature: &Path) -> Result<Vec<u32>, String> {
+    let _ = fs::remove_file(signature);
+
+    let command = template
+        .replace("{elf}", &elf.to_string_lossy())
+        .replace("{signature}", &signature.to_string_lossy());
+    let mut words = command.split_whitespace();
+    let program_name = words.next().ok_or("empty reference command")?;
+    let status = Command::new(program_name)
+        .args(words)
+        .status()
+        .map_err(|e| format!("{}: {}", program_name, e))?;
+    if !status.success() {
+        return Err(format!("reference exited with {}", status));
+    }
+    let text = fs::read_to_string(signature).map_err(|e| format!("{}: {}", signature.display(), e))?;
+    text.lines()
+        .map(|line| u32::from_str_radix(line.trim(), 16).map_err(|_| format!("bad signature line `{}`", line)))
+        .collect()
+}
+
+/// Drops body instructions one at a time while `fails` keeps failing
+pub(crate) fn shrink(program: &Program, fails: &mut dyn FnMut(&Program) -> bool) -> Program {
+    let mut smallest = program.clone();
+    let mut i = 0;
+    while i < smallest.body.len() {
+        let mut candidate = smallest.clone();
+        candidate.body.remove(i);
+        if fails(&candidate) {
+            smallest = candidate;
+        } else {
+            i += 1;
+        }
+    }
+    smallest
+}
+
+fn mismatches(ours: &[u32], theirs: &[u32]) -> Vec<String> {
+    let name = |i: usize| match i as u32 {
+        i if i < SCRATCH_SIZE / 4 => format!("scratch[{}]", 4 * i),
+        i => CoreState::reg_name((i - SCRATCH_SIZE / 4 + 1) as usize),
+    };
+    (0..ours.len().max(theirs.len()))
+        .filter(|&i| ours.get(i) != theirs.get(i))
+        .map(|i| format!("{}: rs-v {:08x?} reference {:08x?}", name(i), ours.get(i), theirs.get(i)))
+        .collect()
+}
+
+/// `rs-v difftest [--seed n] [--count n] [--length n] [--reference cmd]
+/// [--corpus dir]`: runs random programs on rs-v and a reference simulator,
+/// compares the signatures and writes a shrunk reproducer for the first
+/// mismatch, with the rs-v end state in the corpus if given
+pub fn run(args: &[String]) -> Result<i32, String> {
+    let mut seed = 1;
+    let mut count = 100;
+    let mut length = 32;
+    let mut template = DEFAULT_REFERENCE.to_string();
+    let mut corpus = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--seed" => seed = value()?.parse().map_err(|_| "bad --seed")?,
+            "--count" => count = value()?.parse().map_err(|_| "bad --count")?,
+            "--length" => length = value()?.parse().map_err(|_| "bad --length")?,
+            "--reference" => template = value()?.clone(),
+            "--corpus" => corpus = Some(value()?.clone()),
+            _ => return Err(format!("unknown difftest argument `{}`", arg)),
+        }
+    }
+
+    for seed in seed..seed + count {
+        let program = Program::generate(seed, length);
+        let tag = seed.to_string();
+        let ours = program.run()?;
+        let theirs = reference(&template, &program, &tag)?;
+        if ours == theirs {
+            continue;
+        }
+
+        let mut fails = |p: &Program| match (p.run(), reference(&template, p, &tag)) {
+            (Ok(ours), Ok(theirs)) => ours != theirs,
+            _ => false,
+        };
+        let smallest = shrink(&program, &mut fails);
+        let path = format!("difftest-{}.elf", seed);
+        fs::write(&path, smallest.elf()).map_err(|e| format!("{}: {}", path, e))?;
+        println!("seed {}: mismatch, {} instruction reproducer in {}", seed, smallest.body.len(), path);
+        if let Some(dir) = &corpus {
+            let stem = triage::save(dir, &smallest.elf(), &Snapshot::bare(&smallest.run_core()?))?;
+            println!("  saved as {}.elf and {}.snap", stem, stem);
+        }
+        let ours = smallest.run()?;
+        let theirs = reference(&template, &smallest, &tag)?;
+        for line in mismatches(&ours, &theirs) {
+            println!("  {}", line);
+        }
+        return Ok(1);
+    }
+    println!("{} programs match", count);
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_programs_exit() {
+        for seed in 1..50 {
+            let signature = Program::generate(seed, 64).run().unwrap();
+            assert_eq!(signature.len() as u32, SIGNATURE_SIZE / 4);
+        }
+    }
+
+    #[test]
+    fn elf_exits_through_tohost() {
+        let path = std::env::temp_dir().join("rs-v-difftest-test.elf");
+        fs::write(&path, Program::generate(3, 16).elf()).unwrap();
+        let config = crate::Config {memory_size: Some(MEMORY_SIZE), ..crate::Config::default()};
+        assert_eq!(crate::run(&[path.to_string_lossy().into_owned()], config), crate::RunOutcome::Exited(0));
+    }
+
+    #[test]
+    fn shrinking_keeps_the_failure() {
+        let mut program = Program::generate(7, 40);
+        let store = encode::s(0b010, 31, 5, 0);
+        program.body[17] = Op::Word(store);
+        // "fails" while the body still holds the store
+        let mut fails = |p: &Program| p.body.iter().any(|op| matches!(op, Op::Word(w) if *w == store));
+        let smallest = shrink(&program, &mut fails);
+        assert_eq!(smallest.body.len(), 1);
+        assert!(fails(&smallest));
+    }
+}
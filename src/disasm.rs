@@ -0,0 +1,212 @@
+use crate::coverage::mnemonic;
+use crate::loader::{function_at, Function};
+use crate::{CoreState, Instruction};
+
+fn reg(index: usize) -> String {
+    CoreState::reg_name(index)
+}
+
+/// Assembly text of the instruction at `pc`, with branch and jump targets
+/// as absolute addresses. Reserved encoding fields are ignored; words that
+/// don't decode show as `.word`.
+pub fn disassemble(instruction: u32, pc: u32) -> String {
+    let Ok(decoded) = CoreState::decode_with(instruction, true) else {
+        return format!(".word 0x{:08x}", instruction);
+    };
+    let name = mnemonic(&decoded).to_lowercase().replace("fencei", "fence.i").replace("fencetso", "fence.tso")
+        .replace("sfencevma", "sfence.vma");
+    let operands = match decoded {
+        Instruction::Lui(a) | Instruction::Auipc(a) => format!("{}, 0x{:x}", reg(a.rd), (a.imm as u32) >> 12),
+        Instruction::Jal(a) => format!("{}, 0x{:x}", reg(a.rd), pc.wrapping_add(a.imm as u32)),
+        Instruction::Beq(a) | Instruction::Bne(a) | Instruction::Blt(a) |
+        Instruction::Bge(a) | Instruction::Bltu(a) | Instruction::Bgeu(a) => {
+            format!("{}, {}, 0x{:x}", reg(a.rs1), reg(a.rs2), pc.wrapping_add(a.imm as u32))
+        }
+        Instruction::Jalr(a) | Instruction::Lb(a) | Instruction::Lh(a) | Instruction::Lw(a) |
+        Instruction::Lbu(a) | Instruction::Lhu(a) => format!("{}, {}({})", reg(a.rd), a.imm, reg(a.rs1)),
+        Instruction::Sb(a) | Instruction::Sh(a) | Instruction::Sw(a) => {
+            format!("{}, {}({})", reg(a.rs2), a.imm, reg(a.rs1))
+        }
+        Instruction::Slli(a) | Instruction::Srli(a) | Instruction::Srai(a) => {
+            format!("{}, {}, {}", reg(a.rd), reg(a.rs1), a.shamt)
+        }
+        Instruction::Addi(a) | Instruction::Slti(a) | Instruction::Sltiu(a) |
+        Instruction::Xori(a) | Instruction::Ori(a) | Instruction::Andi(a) => {
+            format!("{}, {}, {}", reg(a.rd), reg(a.rs1), a.imm)
+        }
+        Instruction::Add(a) | Instruction::Sub(a) | Instruction::Sll(a) | Instruction::Slt(a) |
+        Instruction::Sltu(a) | Instruction::Xor(a) | Instruction::Srl(a) | Instruction::Sra(a) |
+        Instruction::Or(a) | Instruction::And(a) => format!("{}, {}, {}", reg(a.rd), reg(a.rs1), reg(a.rs2)),
+        Instruction::SfenceVma(a) => format!("{}, {}", reg(a.rs1), reg(a.rs2)),
+        Instruction::Csrrw(a) | Instruction::Csrrs(a) | Instruction::Csrrc(a) => {
+            format!("{}, 0x{:03x}, {}", reg(a.rd), a.csr, reg(a.rs1))
+        }
+        // rs1 holds the zero-extended immediate
+        Instruction::Csrrwi(a) | Instruction::Csrrsi(a) | Instruction::Csrrci(a) => {
+            format!("{}, 0x{:03x}, {}", reg(a.rd), a.csr, a.rs1)
+        }
+        Instruction::Fence | Instruction::FenceI | Instruction::FenceTso | Instruction::Pause |
+        Instruction::Ecall | Instruction::Ebreak | Instruction::Mret | Instruction::Sret | Instruction::Wfi => return name,
+    };
+    format!("{} {}", name, operands)
+}
+
+/// `0x110 <f+0x4>`, the symbol if `address` is inside a function
+fn target(address: u32, functions: &[Function]) -> String {
+    match function_at(functions, address) {
+        Some(i) if functions[i].start == address => format!("0x{:x} <{}>", address, functions[i].name),
+        Some(i) => format!("0x{:x} <{}+0x{:x}>", address, functions[i].name, address - functions[i].start),
+        None => format!("0x{:x}", address),
+    }
+}
+
+fn immediate(value: u32) -> String {
+    match value as i32 {
+        value @ -2048..=2047 => value.to_string(),
+        _ => format!("0x{:x}", value),
+    }
+}
+
+/// `disassemble` with the canonical pseudo-instructions (`nop`, `li`, `mv`,
+/// `ret`, `j`, `beqz`, `csrr`, ...) and branch and jump targets annotated
+/// with their symbol from `functions`
+pub fn pseudo(instruction: u32, pc: u32, functions: &[Function]) -> String {
+    let Ok(decoded) = CoreState::decode_with(instruction, true) else {
+        return disassemble(instruction, pc);
+    };
+    let to = |offset: i32| target(pc.wrapping_add(offset as u32), functions);
+    match decoded {
+        Instruction::Addi(a) if a.rd == 0 && a.rs1 == 0 && a.imm == 0 => "nop".to_string(),
+        Instruction::Addi(a) if a.rs1 == 0 => format!("li {}, {}", reg(a.rd), a.imm),
+        Instruction::Addi(a) if a.imm == 0 => format!("mv {}, {}", reg(a.rd), reg(a.rs1)),
+        Instruction::Xori(a) if a.imm == -1 => format!("not {}, {}", reg(a.rd), reg(a.rs1)),
+        Instruction::Sltiu(a) if a.imm == 1 => format!("seqz {}, {}", reg(a.rd), reg(a.rs1)),
+        Instruction::Sub(a) if a.rs1 == 0 => format!("neg {}, {}", reg(a.rd), reg(a.rs2)),
+        Instruction::Sltu(a) if a.rs1 == 0 => format!("snez {}, {}", reg(a.rd), reg(a.rs2)),
+        Instruction::Jal(a) if a.rd == 0 => format!("j {}", to(a.imm)),
+        Instruction::Jal(a) if a.rd == 1 => format!("jal {}", to(a.imm)),
+        Instruction::Jal(a) => format!("jal {}, {}", reg(a.rd), to(a.imm)),
+        Instruction::Jalr(a) if a.rd == 0 && a.rs1 == 1 && a.imm == 0 => "ret".to_string(),
+        Instruction::Jalr(a) if a.rd == 0 && a.imm == 0 => format!("jr {}", reg(a.rs1)),
+        Instruction::Jalr(a) if a.rd == 1 && a.imm == 0 => format!("jalr {}", reg(a.rs1)),
+        Instruction::Beq(a) if a.rs2 == 0 => format!("beqz {}, {}", reg(a.rs1), to(a.imm)),
+        Instruction::Bne(a) if a.rs2 == 0 => format!("bnez {}, {}", reg(a.rs1), to(a.imm)),
+        Instruction::Blt(a) if a.rs2 == 0 => format!("bltz {}, {}", reg(a.rs1), to(a.imm)),
+        Instruction::Bge(a) if a.rs2 == 0 => format!("bgez {}, {}", reg(a.rs1), to(a.imm)),
+        Instruction::Blt(a) if a.rs1 == 0 => format!("bgtz {}, {}", reg(a.rs2), to(a.imm)),
+        Instruction::Bge(a) if a.rs1 == 0 => format!("blez {}, {}", reg(a.rs2), to(a.imm)),
+        Instruction::Beq(a) | Instruction::Bne(a) | Instruction::Blt(a) |
+        Instruction::Bge(a) | Instruction::Bltu(a) | Instruction::Bgeu(a) => {
+            let name = mnemonic(&decoded).to_lowercase();
+            format!("{} {}, {}, {}", name, reg(a.rs1), reg(a.rs2), to(a.imm))
+        }
+        Instruction::Csrrs(a) if a.rs1 == 0 => format!("csrr {}, 0x{:03x}", reg(a.rd), a.csr),
+        Instruction::Csrrw(a) | Instruction::Csrrs(a) | Instruction::Csrrc(a) if a.rd == 0 => {
+            let name = match decoded {
+                Instruction::Csrrw(_) => "csrw",
+                Instruction::Csrrs(_) => "csrs",
+                _ => "csrc",
+            };
+            format!("{} 0x{:03x}, {}", name, a.csr, reg(a.rs1))
+        }
+        Instruction::Csrrwi(a) | Instruction::Csrrsi(a) | Instruction::Csrrci(a) if a.rd == 0 => {
+            let name = match decoded {
+                Instruction::Csrrwi(_) => "csrwi",
+                Instruction::Csrrsi(_) => "csrsi",
+                _ => "csrci",
+            };
+            format!("{} 0x{:03x}, {}", name, a.csr, a.rs1)
+        }
+        _ => disassemble(instruction, pc),
+    }
+}
+
+/// The pseudo-instruction of a `lui`/`auipc` at `pc` and the `second`
+/// instruction after it, if they form one: `li`, `la`, `call`, `tail` or a
+/// pc-relative load of a global
+pub fn fold(first: u32, second: u32, pc: u32, functions: &[Function]) -> Option<String> {
+    let decoded = (CoreState::decode_with(first, true).ok()?, CoreState::decode_with(second, true).ok()?);
+    match decoded {
+        (Instruction::Lui(a), Instruction::Addi(b)) if b.rd == a.rd && b.rs1 == a.rd => {
+            Some(format!("li {}, {}", reg(a.rd), immediate((a.imm as u32).wrapping_add(b.imm as u32))))
+        }
+        (Instruction::Auipc(a), second) => {
+            let address = |imm: i32| pc.wrapping_add(a.imm as u32).wrapping_add(imm as u32);
+            match second {
+                Instruction::Addi(b) if b.rd == a.rd && b.rs1 == a.rd => {
+                    Some(format!("la {}, {}", reg(a.rd), target(address(b.imm), functions)))
+                }
+                Instruction::Jalr(b) if b.rs1 == a.rd && b.rd == 1 && a.rd == 1 => {
+                    Some(format!("call {}", target(address(b.imm), functions)))
+                }
+                Instruction::Jalr(b) if b.rs1 == a.rd && b.rd == 0 && a.rd == 6 => {
+                    Some(format!("tail {}", target(address(b.imm), functions)))
+                }
+                Instruction::Lb(b) | Instruction::Lh(b) | Instruction::Lw(b) | Instruction::Lbu(b) |
+                Instruction::Lhu(b) if b.rd == a.rd && b.rs1 == a.rd => {
+                    let name = mnemonic(&second).to_lowercase();
+                    Some(format!("{} {}, {}", name, reg(a.rd), target(address(b.imm), functions)))
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{self, AUIPC, BRANCH, ECALL, JALR, LOAD, LUI, OP_IMM};
+
+    #[test]
+    fn formats() {
+        for (instruction, pc, text) in [
+            (encode::i(OP_IMM, 0b000, 10, 10, -1), 0, "addi a0, a0, -1"),
+            (encode::i(OP_IMM, 0b101, 5, 6, 0x403), 0, "srai t0, t1, 3"),
+            (encode::r(0x20, 0b000, 1, 2, 3), 0, "sub ra, sp, gp"),
+            (encode::i(LOAD, 0b100, 11, 2, 8), 0, "lbu a1, 8(sp)"),
+            (encode::s(0b010, 2, 1, -4), 0, "sw ra, -4(sp)"),
+            (encode::b(0b001, 10, 0, -8), 0x20, "bne a0, zero, 0x18"),
+            (encode::j(1, 0x100), 0x10, "jal ra, 0x110"),
+            (encode::i(JALR, 0b000, 0, 1, 0), 0, "jalr zero, 0(ra)"),
+            (encode::u(LUI, 10, 0x1234_5000), 0, "lui a0, 0x12345"),
+            (encode::csr(0b101, 0, 3, 0x305), 0, "csrrwi zero, 0x305, 3"),
+            (ECALL, 0, "ecall"),
+            (0x0000_100F, 0, "fence.i"),
+            (0, 0, ".word 0x00000000"),
+        ] {
+            assert_eq!(disassemble(instruction, pc), text);
+        }
+    }
+
+    #[test]
+    fn pseudo_instructions_and_pairs() {
+        let functions = [Function {start: 0x100, size: 0x20, name: "f".to_string()}];
+        for (instruction, pc, text) in [
+            (encode::i(OP_IMM, 0b000, 0, 0, 0), 0, "nop"),
+            (encode::i(OP_IMM, 0b000, 10, 0, -5), 0, "li a0, -5"),
+            (encode::i(OP_IMM, 0b000, 10, 11, 0), 0, "mv a0, a1"),
+            (encode::i(JALR, 0b000, 0, 1, 0), 0, "ret"),
+            (encode::j(0, 0x100), 0, "j 0x100 <f>"),
+            (encode::j(1, 0x104), 0, "jal 0x104 <f+0x4>"),
+            (encode::b(0b001, 10, 0, -8), 0x20, "bnez a0, 0x18"),
+            (encode::i(BRANCH, 0b101, 0, 0, 0) | 10 << 20, 0x100, "blez a0, 0x100 <f>"),
+            (encode::csr(0b010, 5, 0, 0x342), 0, "csrr t0, 0x342"),
+            (encode::csr(0b001, 0, 5, 0x305), 0, "csrw 0x305, t0"),
+            (encode::i(LOAD, 0b010, 10, 2, 4), 0, "lw a0, 4(sp)"),
+        ] {
+            assert_eq!(pseudo(instruction, pc, &functions), text);
+        }
+        for (first, second, text) in [
+            (encode::u(LUI, 10, 0x1234_5000), encode::i(OP_IMM, 0b000, 10, 10, 0x678), Some("li a0, 0x12345678")),
+            (encode::u(AUIPC, 10, 0), encode::i(OP_IMM, 0b000, 10, 10, 0x108), Some("la a0, 0x10c <f+0xc>")),
+            (encode::u(AUIPC, 1, 0), encode::i(JALR, 0b000, 1, 1, 0xFC), Some("call 0x100 <f>")),
+            (encode::u(AUIPC, 6, 0), encode::i(JALR, 0b000, 0, 6, 0xFC), Some("tail 0x100 <f>")),
+            (encode::u(AUIPC, 10, 0x1000), encode::i(LOAD, 0b010, 10, 10, -4), Some("lw a0, 0x1000")),
+            (encode::u(AUIPC, 10, 0), encode::i(OP_IMM, 0b000, 11, 10, 8), None),
+        ] {
+            assert_eq!(fold(first, second, 4, &functions).as_deref(), text);
+        }
+    }
+}
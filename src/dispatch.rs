@@ -0,0 +1,954 @@
+use crate::csr_file::status;
+use crate::memory::Size;
+use crate::trap::{self, Exception};
+use crate::{ArgsIType, ArgsRType, ArgsSBType, ArgsUJType, Cause, CoreState, Instruction, Privilege};
+
+/// What the run loop does after a handler
+pub enum Flow {
+    /// Fall through to pc + 4
+    Next,
+    /// Handler wrote pc
+    Jump,
+    /// Handler raised an exception, pc is still the instruction's
+    Trap(Exception),
+}
+
+/// Where an instruction may sit in a basic block
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kind {
+    Plain,
+    /// Jumps and branches, last instruction of a block
+    Branch,
+    /// SYSTEM and FENCE.I, always executed outside blocks
+    System,
+}
+
+pub type Handler = fn(&mut CoreState, &MicroOp) -> Flow;
+
+/// Compact internal form of a decoded instruction: the handler is chosen
+/// once at decode time, so executing is a single indirect call. `rs2` holds
+/// shamt for shifts, `imm` holds the CSR address for CSR instructions.
+#[derive(Clone, Copy)]
+pub struct MicroOp {
+    pub handler: Handler,
+    pub kind: Kind,
+    pub rd: u8,
+    pub rs1: u8,
+    pub rs2: u8,
+    pub imm: i32,
+}
+
+impl MicroOp {
+    fn new(handler: Handler, rd: usize, rs1: usize, rs2: usize, imm: i32) -> Self {
+        Self {handler, kind: Kind::Plain, rd: rd as u8, rs1: rs1 as u8, rs2: rs2 as u8, imm}
+    }
+
+    fn r(handler: Handler, args: ArgsRType) -> Self {
+        Self::new(handler, args.rd, args.rs1, args.rs2, 0)
+    }
+
+    fn i(handler: Handler, args: ArgsIType) -> Self {
+        Self::new(handler, args.rd, args.rs1, args.shamt as usize, args.imm)
+    }
+
+    fn csr_op(handler: Handler, args: ArgsIType) -> Self {
+        Self::new(handler, args.rd, args.rs1, 0, args.csr as i32).kind(Kind::System)
+    }
+
+    fn sb(handler: Handler, args: ArgsSBType) -> Self {
+        Self::new(handler, 0, args.rs1, args.rs2, args.imm)
+    }
+
+    fn uj(handler: Handler, args: ArgsUJType) -> Self {
+        Self::new(handler, args.rd, 0, 0, args.imm)
+    }
+
+    fn bare(handler: Handler) -> Self {
+        Self::new(handler, 0, 0, 0, 0)
+    }
+
+    fn kind(self, kind: Kind) -> Self {
+        Self {kind, ..self}
+    }
+
+    pub fn rd(&self) -> usize {
+        self.rd as usize
+    }
+
+    pub fn rs1(&self) -> usize {
+        self.rs1 as usize
+    }
+
+    pub fn rs2(&self) -> usize {
+        self.rs2 as usize
+    }
+
+    pub fn csr(&self) -> u16 {
+        self.imm as u16
+    }
+}
+
+impl From<Instruction> for MicroOp {
+    fn from(instr: Instruction) -> Self {
+        match instr {
+            Instruction::Lui(args) => Self::uj(lui, args),
+            Instruction::Auipc(args) => Self::uj(auipc, args),
+            Instruction::Jal(args) => Self::uj(jal, args).kind(Kind::Branch),
+            Instruction::Jalr(args) => Self::i(jalr, args).kind(Kind::Branch),
+            Instruction::Beq(args) => Self::sb(beq, args).kind(Kind::Branch),
+            Instruction::Bne(args) => Self::sb(bne, args).kind(Kind::Branch),
+            Instruction::Blt(args) => Self::sb(blt, args).kind(Kind::Branch),
+            Instruction::Bge(args) => Self::sb(bge, args).kind(Kind::Branch),
+            Instruction::Bltu(args) => Self::sb(bltu, args).kind(Kind::Branch),
+            Instruction::Bgeu(args) => Self::sb(bgeu, args).kind(Kind::Branch),
+            Instruction::Lb(args) => Self::i(lb, args),
+            Instruction::Lh(args) => Self::i(lh, args),
+            Instruction::Lw(args) => Self::i(lw, args),
+            Instruction::Lbu(args) => Self::i(lbu, args),
+            Instruction::Lhu(args) => Self::i(lhu, args),
+            Instruction::Sb(args) => Self::sb(sb, args),
+            Instruction::Sh(args) => Self::sb(sh, args),
+            Instruction::Sw(args) => Self::sb(sw, args),
+            Instruction::Addi(args) => Self::i(addi, args),
+            Instruction::Slti(args) => Self::i(slti, args),
+            Instruction::Sltiu(args) => Self::i(sltiu, args),
+            Instruction::Xori(args) => Self::i(xori, args),
+            Instruction::Ori(args) => Self::i(ori, args),
+            Instruction::Andi(args) => Self::i(andi, args),
+            Instruction::Slli(args) => Self::i(slli, args),
+            Instruction::Srli(args) => Self::i(srli, args),
+            Instruction::Srai(args) => Self::i(srai, args),
+            Instruction::Add(args) => Self::r(add, args),
+            Instruction::Sub(args) => Self::r(sub, args),
+            Instruction::Sll(args) => Self::r(sll, args),
+            Instruction::Slt(args) => Self::r(slt, args),
+            Instruction::Sltu(args) => Self::r(sltu, args),
+            Instruction::Xor(args) => Self::r(xor, args),
+            Instruction::Srl(args) => Self::r(srl, args),
+            Instruction::Sra(args) => Self::r(sra, args),
+            Instruction::Or(args) => Self::r(or, args),
+            Instruction::And(args) => Self::r(and, args),
+            Instruction::Fence => Self::bare(fence),
+            Instruction::FenceI => Self::bare(fence_i).kind(Kind::System),
+            Instruction::FenceTso => Self::bare(fence_tso),
+            Instruction::Pause => Self::bare(pause),
+            Instruction::Ecall => Self::bare(ecall).kind(Kind::System),
+            Instruction::Ebreak => Self::bare(ebreak).kind(Kind::System),
+            Instruction::Mret => Self::bare(mret).kind(Kind::System),
+            Instruction::Sret => Self::bare(sret).kind(Kind::System),
+            Instruction::SfenceVma(args) => Self::r(sfence_vma, args).kind(Kind::System),
+            Instruction::Wfi => Self::bare(wfi).kind(Kind::System),
+            Instruction::Csrrw(args) => Self::csr_op(csrrw, args),
+            Instruction::Csrrs(args) => Self::csr_op(csrrs, args),
+            Instruction::Csrrc(args) => Self::csr_op(csrrc, args),
+            Instruction::Csrrwi(args) => Self::csr_op(csrrwi, args),
+            Instruction::Csrrsi(args) => Self::csr_op(csrrsi, args),
+            Instruction::Csrrci(args) => Self::csr_op(csrrci, args),
+        }
+    }
+}
+
+// TODO: Refactor branch section
+//
+// TODO: Fix rs/rd races
+
+fn lui(core: &mut CoreState, op: &MicroOp) -> Flow {
+    core.regs.write(op.rd(), op.imm as u32);
+    Flow::Next
+}
+
+fn auipc(core: &mut CoreState, op: &MicroOp) -> Flow {
+    core.regs.write(op.rd(), core.pc.wrapping_add(op.imm as u32));
+    Flow::Next
+}
+
+/// Jumps to `target`, links pc + 4 into `rd` and traps instead if the target
+/// isn't 4-byte aligned (no C extension), leaving rd untouched
+fn jump(core: &mut CoreState, rd: usize, target: u32) -> Flow {
+    if target & 0b11 != 0 {
+        return Flow::Trap(Exception::new(Cause::InstructionAddressMisaligned, target));
+    }
+    core.regs.write(rd, core.pc.wrapping_add(4));
+    core.pc = target;
+    Flow::Jump
+}
+
+fn jal(core: &mut CoreState, op: &MicroOp) -> Flow {
+    jump(core, op.rd(), core.pc.wrapping_add(op.imm as u32))
+}
+
+fn jalr(core: &mut CoreState, op: &MicroOp) -> Flow {
+    jump(core, op.rd(), core.regs[op.rs1()].wrapping_add(op.imm as u32) & 0xFFFF_FFFE)
+}
+
+fn branch(core: &mut CoreState, op: &MicroOp, taken: bool) -> Flow {
+    match taken {
+        true => jump(core, 0, core.pc.wrapping_add(op.imm as u32)),
+        false => Flow::Next,
+    }
+}
+
+fn beq(core: &mut CoreState, op: &MicroOp) -> Flow {
+    branch(core, op, core.regs[op.rs1()] == core.regs[op.rs2()])
+}
+
+fn bne(core: &mut CoreState, op: &MicroOp) -> Flow {
+    branch(core, op, core.regs[op.rs1()] != core.regs[op.rs2()])
+}
+
+fn blt(core: &mut CoreState, op: &MicroOp) -> Flow {
+    branch(core, op, (core.regs[op.rs1()] as i32) < (core.regs[op.rs2()] as i32))
+}
+
+fn bge(core: &mut CoreState, op: &MicroOp) -> Flow {
+    branch(core, op, (core.regs[op.rs1()] as i32) >= (core.regs[op.rs2()] as i32))
+}
+
+fn bltu(core: &mut CoreState, op: &MicroOp) -> Flow {
+    branch(core, op, core.regs[op.rs1()] < core.regs[op.rs2()])
+}
+
+fn bgeu(core: &mut CoreState, op: &MicroOp) -> Flow {
+    branch(core, op, core.regs[op.rs1()] >= core.regs[op.rs2()])
+}
+
+/// Loads `size` bytes at rs1 + imm into rd
+fn load(core: &mut CoreState, op: &MicroOp, size: Size, signed: bool) -> Flow {
+    let address = core.regs[op.rs1()].wrapping_add(op.imm as u32);
+    match core.load(address, size, signed) {
+        Ok(value) => {
+            core.regs.write(op.rd(), value);
+            Flow::Next
+        }
+        Err(exception) => Flow::Trap(exception),
+    }
+}
+
+fn lb(core: &mut CoreState, op: &MicroOp) -> Flow {
+    load(core, op, Size::Byte, true)
+}
+
+fn lh(core: &mut CoreState, op: &MicroOp) -> Flow {
+    load(core, op, Size::Half, true)
+}
+
+fn lw(core: &mut CoreState, op: &MicroOp) -> Flow {
+    load(core, op, Size::Word, false)
+}
+
+fn lbu(core: &mut CoreState, op: &MicroOp) -> Flow {
+    load(core, op, Size::Byte, false)
+}
+
+fn lhu(core: &mut CoreState, op: &MicroOp) -> Flow {
+    load(core, op, Size::Half, false)
+}
+
+/// Stores the low `size` bytes of rs2 at rs1 + imm
+fn store(core: &mut CoreState, op: &MicroOp, size: Size) -> Flow {
+    let address = core.regs[op.rs1()].wrapping_add(op.imm as u32);
+    match core.store(address, size, core.regs[op.rs2()]) {
+        Ok(()) => Flow::Next,
+        Err(exception) => Flow::Trap(exception),
+    }
+}
+
+fn sb(core: &mut CoreState, op: &MicroOp) -> Flow {
+    store(core, op, Size::Byte)
+}
+
+fn sh(core: &mut CoreState, op: &MicroOp) -> Flow {
+    store(core, op, Size::Half)
+}
+
+fn sw(core: &mut CoreState, op: &MicroOp) -> Flow {
+    store(core, op, Size::Word)
+}
+
+fn addi(core: &mut CoreState, op: &MicroOp) -> Flow {
+    core.regs.write(op.rd(), core.regs[op.rs1()].wrapping_add(op.imm as u32));
+    Flow::Next
+}
+
+fn slti(core: &mut CoreState, op: &MicroOp) -> Flow {
+    core.regs.write(op.rd(), if (core.regs[op.rs1()] as i32) < op.imm {1} else {0});
+    Flow::Next
+}
+
+fn sltiu(core: &mut CoreState, op: &MicroOp) -> Flow {
+    // the immediate is sign-extended, then compared unsigned
+    core.regs.write(op.rd(), if core.regs[op.rs1()] < op.imm as u32 {1} else {0});
+    Flow::Next
+}
+
+fn xori(core: &mut CoreState, op: &MicroOp) -> Flow {
+    core.regs.write(op.rd(), core.regs[op.rs1()] ^ op.imm as u32);
+    Flow::Next
+}
+
+fn ori(core: &mut CoreState, op: &MicroOp) -> Flow {
+    core.regs.write(op.rd(), core.regs[op.rs1()] | op.imm as u32);
+    Flow::Next
+}
+
+fn andi(core: &mut CoreState, op: &MicroOp) -> Flow {
+    core.regs.write(op.rd(), core.regs[op.rs1()] & op.imm as u32);
+    Flow::Next
+}
+
+fn slli(core: &mut CoreState, op: &MicroOp) -> Flow {
+    core.regs.write(op.rd(), core.regs[op.rs1()] << (op.rs2 & 0b1_1111));
+    Flow::Next
+}
+
+fn srli(core: &mut CoreState, op: &MicroOp) -> Flow {
+    core.regs.write(op.rd(), core.regs[op.rs1()] >> (op.rs2 & 0b1_1111));
+    Flow::Next
+}
+
+fn srai(core: &mut CoreState, op: &MicroOp) -> Flow {
+    core.regs.write(op.rd(), ((core.regs[op.rs1()] as i32) >> (op.rs2 & 0b1_1111)) as u32);
+    Flow::Next
+}
+
+fn add(core: &mut CoreState, op: &MicroOp) -> Flow {
+    core.regs.write(op.rd(), core.regs[op.rs1()].wrapping_add(core.regs[op.rs2()]));
+    Flow::Next
+}
+
+fn sub(core: &mut CoreState, op: &MicroOp) -> Flow {
+    core.regs.write(op.rd(), core.regs[op.rs1()].wrapping_sub(core.regs[op.rs2()]));
+    Flow::Next
+}
+
+fn sll(core: &mut CoreState, op: &MicroOp) -> Flow {
+    core.regs.write(op.rd(), core.regs[op.rs1()] << (core.regs[op.rs2()] & 0b1_1111));
+    Flow::Next
+}
+
+fn slt(core: &mut CoreState, op: &MicroOp) -> Flow {
+    let value = if (core.regs[op.rs1()] as i32) < (core.regs[op.rs2()] as i32) {1} else {0};
+    core.regs.write(op.rd(), value);
+    Flow::Next
+}
+
+fn sltu(core: &mut CoreState, op: &MicroOp) -> Flow {
+    let value = if core.regs[op.rs1()] < core.regs[op.rs2()] {1} else {0};
+    core.regs.write(op.rd(), value);
+    Flow::Next
+}
+
+fn xor(core: &mut CoreState, op: &MicroOp) -> Flow {
+    core.regs.write(op.rd(), core.regs[op.rs1()] ^ core.regs[op.rs2()]);
+    Flow::Next
+}
+
+fn srl(core: &mut CoreState, op: &MicroOp) -> Flow {
+    core.regs.write(op.rd(), core.regs[op.rs1()] >> (core.regs[op.rs2()] & 0b1_1111));
+    Flow::Next
+}
+
+fn sra(core: &mut CoreState, op: &MicroOp) -> Flow {
+    core.regs.write(op.rd(), ((core.regs[op.rs1()] as i32) >> (core.regs[op.rs2()] & 0b1_1111)) as u32);
+    Flow::Next
+}
+
+fn or(core: &mut CoreState, op: &MicroOp) -> Flow {
+    core.regs.write(op.rd(), core.regs[op.rs1()] | core.regs[op.rs2()]);
+    Flow::Next
+}
+
+fn and(core: &mut CoreState, op: &MicroOp) -> Flow {
+    core.regs.write(op.rd(), core.regs[op.rs1()] & core.regs[op.rs2()]);
+    Flow::Next
+}
+
+fn fence(_core: &mut CoreState, _op: &MicroOp) -> Flow {
+    Flow::Next
+}
+
+fn fence_i(core: &mut CoreState, _op: &MicroOp) -> Flow {
+    core.flush_code();
+    Flow::Next
+}
+
+// a single hart already sees its accesses in order
+fn fence_tso(_core: &mut CoreState, _op: &MicroOp) -> Flow {
+    Flow::Next
+}
+
+fn pause(_core: &mut CoreState, _op: &MicroOp) -> Flow {
+    Flow::Next
+}
+
+/// Raises illegal instruction for an instruction the privilege mode or
+/// mstatus doesn't allow
+fn illegal(core: &mut CoreState) -> Flow {
+    Flow::Trap(Exception::illegal(core.fetch()))
+}
+
+fn ecall(core: &mut CoreState, _op: &MicroOp) -> Flow {
+    let cause = match core.privilege {
+        Privilege::User => Cause::Ucall,
+        Privilege::Supervisor => Cause::Scall,
+        Privilege::Machine => Cause::Mcall,
+    };
+    Flow::Trap(Exception::new(cause, 0))
+}
+
+fn ebreak(core: &mut CoreState, _op: &MicroOp) -> Flow {
+    Flow::Trap(Exception::new(Cause::Breakpoint, core.pc))
+}
+
+/// Returns to the privilege mode in MPP, which becomes U
+fn mret(core: &mut CoreState, _op: &MicroOp) -> Flow {
+    if core.privilege != Privilege::Machine {
+        return illegal(core);
+    }
+    trap::mret(core);
+    Flow::Jump
+}
+
+/// Returns to the privilege mode in SPP, which becomes U. Illegal in U-mode
+/// and, with mstatus.TSR, in S-mode.
+fn sret(core: &mut CoreState, _op: &MicroOp) -> Flow {
+    if core.privilege == Privilege::User || (core.privilege == Privilege::Supervisor && core.csrs.status(status::TSR)) {
+        return illegal(core);
+    }
+    trap::sret(core);
+    Flow::Jump
+}
+
+// a legal WFI, pending interrupts are taken before the next instruction;
+// mstatus.TW makes it illegal below M-mode at once
+fn wfi(core: &mut CoreState, _op: &MicroOp) -> Flow {
+    if core.csrs.status(status::TW) && core.privilege != Privilege::Machine {
+        return illegal(core);
+    }
+    Flow::Next
+}
+
+/// Drops the page of the address in rs1 from the fetch, load and store page
+/// caches of the fast paths, every page for x0. Translation walks the tables
+/// without a TLB (see mmu.rs) and the decode and block caches are keyed by
+/// physical address or skipped while it is on, so they are kept; there are
+/// no ASIDs, so rs2 flushes as if it were x0. Illegal in U-mode and, with
+/// mstatus.TVM, in S-mode.
+fn sfence_vma(core: &mut CoreState, op: &MicroOp) -> Flow {
+    if core.privilege == Privilege::User || (core.privilege == Privilege::Supervisor && core.csrs.status(status::TVM)) {
+        return illegal(core);
+    }
+    let address = (op.rs1() != 0).then(|| core.regs[op.rs1()]);
+    core.fetch_unit.flush(address);
+    core.load_page.flush(address);
+    core.store_page.flush(address);
+    Flow::Next
+}
+
+/// The common part of the CSR instructions, in the order the spec gives:
+/// the old value is read only if `read` (a read may have side effects), then
+/// `write` maps it to the value to write, None for no write, and rd gets the
+/// old value last, so rd = rs1 sees the value from before. Illegal for a
+/// CSR that doesn't exist or that the privilege mode may not access.
+fn csr_access(core: &mut CoreState, op: &MicroOp, read: bool, write: impl FnOnce(u32) -> Option<u32>) -> Flow {
+    let address = op.csr();
+    if !core.has_csr(address) || !core.csr_permitted(address) {
+        return illegal(core);
+    }
+    let old = if read {core.read_csr(address)} else {0};
+    if let Some(value) = write(old) {
+        core.write_csr(address, value);
+    }
+    core.regs.write(op.rd(), old);
+    Flow::Next
+}
+
+/// Doesn't read the CSR for rd = x0
+fn csrrw(core: &mut CoreState, op: &MicroOp) -> Flow {
+    let rs1 = core.regs[op.rs1()];
+    csr_access(core, op, op.rd() != 0, |_| Some(rs1))
+}
+
+/// Doesn't write for rs1 = x0, whatever x0's bits, so it reads read-only
+/// CSRs
+fn csrrs(core: &mut CoreState, op: &MicroOp) -> Flow {
+    let (rs1, mask) = (op.rs1(), core.regs[op.rs1()]);
+    csr_access(core, op, true, |old| (rs1 != 0).then_some(old | mask))
+}
+
+fn csrrc(core: &mut CoreState, op: &MicroOp) -> Flow {
+    let (rs1, mask) = (op.rs1(), core.regs[op.rs1()]);
+    csr_access(core, op, true, |old| (rs1 != 0).then_some(old & !mask))
+}
+
+/// The rs1 field is a 5-bit zero-extended immediate in the `i` variants
+fn csrrwi(core: &mut CoreState, op: &MicroOp) -> Flow {
+    let uimm = op.rs1() as u32;
+    csr_access(core, op, op.rd() != 0, |_| Some(uimm))
+}
+
+/// Doesn't write for uimm = 0
+fn csrrsi(core: &mut CoreState, op: &MicroOp) -> Flow {
+    let uimm = op.rs1() as u32;
+    csr_access(core, op, true, |old| (uimm != 0).then_some(old | uimm))
+}
+
+fn csrrci(core: &mut CoreState, op: &MicroOp) -> Flow {
+    let uimm = op.rs1() as u32;
+    csr_access(core, op, true, |old| (uimm != 0).then_some(old & !uimm))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::csr_file::{status, MCAUSE, MEPC, MSCRATCH, MTVAL, MTVEC, SEPC};
+    use crate::encode::{self, AUIPC, EBREAK, ECALL, JALR, LOAD, LUI, MISC_MEM, MRET, OP_IMM, SFENCE_VMA, SRET};
+    use crate::test_utils::{machine, run, step, word};
+    use crate::{Cause, CoreState, CsrHook, Privilege};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn cause(core: &CoreState) -> u32 {
+        core.csrs.get(MCAUSE)
+    }
+
+    /// Executes one OP-IMM instruction with x1 = `rs1`, returns x2
+    fn op_imm(funct3: u32, rs1: u32, imm: i32) -> u32 {
+        let core = step(encode::i(OP_IMM, funct3, 2, 1, imm), &[(1, rs1)]);
+        assert_eq!(core.pc, 4);
+        core.regs[2]
+    }
+
+    /// Executes one OP instruction with x1 = `a`, x2 = `b`, returns x3
+    fn op(funct7: u32, funct3: u32, a: u32, b: u32) -> u32 {
+        let core = step(encode::r(funct7, funct3, 3, 1, 2), &[(1, a), (2, b)]);
+        assert_eq!(core.pc, 4);
+        core.regs[3]
+    }
+
+    /// Branches from 16 with x1 = `a`, x2 = `b`, returns whether it was taken
+    fn taken(funct3: u32, a: u32, b: u32) -> bool {
+        let core = run(16, &[encode::b(funct3, 1, 2, -16)], &[(1, a), (2, b)], 1);
+        assert!(core.pc == 0 || core.pc == 20);
+        core.pc == 0
+    }
+
+    /// Loads with funct3 from address 0x100 holding `value`, returns x2
+    fn load(funct3: u32, value: u32) -> u32 {
+        let mut core = machine(0, &[encode::i(LOAD, funct3, 2, 1, 0)], &[(1, 0x100)]);
+        core.memory[0x100..0x104].copy_from_slice(&value.to_le_bytes());
+        core.execute();
+        core.regs[2]
+    }
+
+    #[test]
+    fn addi() {
+        assert_eq!(op_imm(0b000, 5, -1), 4);
+        assert_eq!(op_imm(0b000, 0, -2048), 0xFFFF_F800);
+        assert_eq!(op_imm(0b000, 0, 2047), 0x0000_07FF);
+        assert_eq!(op_imm(0b000, 0xFFFF_FFFF, 1), 0);
+        assert_eq!(op_imm(0b000, 0x8000_0000, -1), 0x7FFF_FFFF);
+    }
+
+    #[test]
+    fn slti_sltiu() {
+        assert_eq!(op_imm(0b010, 0xFFFF_FFFF, 0), 1);
+        assert_eq!(op_imm(0b010, 0, -1), 0);
+        assert_eq!(op_imm(0b010, 0x8000_0000, -2048), 1);
+        // -1 becomes 0xFFFF_FFFF, the largest unsigned value
+        assert_eq!(op_imm(0b011, 0, -1), 1);
+        assert_eq!(op_imm(0b011, 0xFFFF_FFFF, -1), 0);
+        assert_eq!(op_imm(0b011, 0, 1), 1);
+    }
+
+    #[test]
+    fn logic() {
+        assert_eq!(op_imm(0b100, 0x1234_5678, -1), 0xEDCB_A987);
+        assert_eq!(op_imm(0b110, 0x0000_0001, -2048), 0xFFFF_F801);
+        assert_eq!(op_imm(0b111, 0xFFFF_0F0F, -16), 0xFFFF_0F00);
+        assert_eq!(op_imm(0b111, 0xFFFF_FFFF, 0x7FF), 0x0000_07FF);
+    }
+
+    #[test]
+    fn shifts() {
+        assert_eq!(op_imm(0b001, 1, 31), 0x8000_0000);
+        assert_eq!(op_imm(0b101, 0x8000_0000, 31), 1);
+        assert_eq!(op_imm(0b101, 0x8000_0000, 0x400 | 31), 0xFFFF_FFFF);
+        assert_eq!(op_imm(0b101, 0x8000_0000, 0x400 | 4), 0xF800_0000);
+        assert_eq!(op_imm(0b101, 0x7FFF_FFFF, 0x400 | 30), 1);
+    }
+
+    #[test]
+    fn writes_to_zero_are_dropped() {
+        assert_eq!(step(encode::i(OP_IMM, 0b000, 0, 0, 1), &[]).regs[0], 0);
+        assert_eq!(step(encode::u(LUI, 0, 0xFFFF_F000), &[]).regs[0], 0);
+    }
+
+    #[test]
+    fn add_sub_wrap() {
+        assert_eq!(op(0, 0b000, 0xFFFF_FFFF, 1), 0);
+        assert_eq!(op(0, 0b000, 0xFFFF_FFFF, 0xFFFF_FFFF), 0xFFFF_FFFE);
+        assert_eq!(op(0b010_0000, 0b000, 0, 1), 0xFFFF_FFFF);
+        assert_eq!(op(0b010_0000, 0b000, 0x8000_0000, 0xFFFF_FFFF), 0x8000_0001);
+    }
+
+    #[test]
+    fn register_shifts_use_the_low_five_bits() {
+        assert_eq!(op(0, 0b001, 1, 31), 0x8000_0000);
+        assert_eq!(op(0, 0b001, 1, 32), 1);
+        assert_eq!(op(0, 0b101, 0x8000_0000, 0x3F), 1);
+        assert_eq!(op(0b010_0000, 0b101, 0x8000_0000, 0x3F), 0xFFFF_FFFF);
+        assert_eq!(op(0b010_0000, 0b101, 0x4000_0000, 1), 0x2000_0000);
+    }
+
+    #[test]
+    fn register_compare_and_logic() {
+        assert_eq!(op(0, 0b010, 0xFFFF_FFFF, 0), 1);
+        assert_eq!(op(0, 0b010, 0, 0xFFFF_FFFF), 0);
+        assert_eq!(op(0, 0b011, 0xFFFF_FFFF, 0), 0);
+        assert_eq!(op(0, 0b011, 0, 0xFFFF_FFFF), 1);
+        assert_eq!(op(0, 0b100, 0xFF00_FF00, 0x0FF0_0FF0), 0xF0F0_F0F0);
+        assert_eq!(op(0, 0b110, 0xFF00_FF00, 0x0FF0_0FF0), 0xFFF0_FFF0);
+        assert_eq!(op(0, 0b111, 0xFF00_FF00, 0x0FF0_0FF0), 0x0F00_0F00);
+    }
+
+    #[test]
+    fn lui_auipc() {
+        assert_eq!(step(encode::u(LUI, 1, 0x8765_4000), &[]).regs[1], 0x8765_4000);
+        assert_eq!(run(0x10, &[encode::u(AUIPC, 1, 0x1000)], &[], 1).regs[1], 0x1010);
+    }
+
+    #[test]
+    fn auipc_wraps() {
+        assert_eq!(run(16, &[encode::u(AUIPC, 1, 0xFFFF_F000)], &[], 1).regs[1], 0xFFFF_F010);
+    }
+
+    #[test]
+    fn branches() {
+        for (funct3, a, b, expected) in [
+            (0b000, 7, 7, true), (0b000, 7, 8, false),
+            (0b001, 7, 8, true), (0b001, 7, 7, false),
+            (0b100, 0xFFFF_FFFF, 0, true), (0b100, 0, 0xFFFF_FFFF, false),
+            (0b101, 0, 0xFFFF_FFFF, true), (0b101, 5, 5, true), (0b101, 0xFFFF_FFFF, 0, false),
+            (0b110, 0, 0xFFFF_FFFF, true), (0b110, 0xFFFF_FFFF, 0, false),
+            (0b111, 0xFFFF_FFFF, 0, true), (0b111, 5, 5, true), (0b111, 0, 0xFFFF_FFFF, false),
+        ] {
+            assert_eq!(taken(funct3, a, b), expected, "funct3 {:03b} {:x} {:x}", funct3, a, b);
+        }
+    }
+
+    #[test]
+    fn negative_branch_offsets() {
+        let bne = encode::b(0b001, 1, 0, -16);
+        assert_eq!(run(16, &[bne], &[(1, 1)], 1).pc, 0);
+        assert_eq!(run(16, &[bne], &[], 1).pc, 20);
+        // wraps below address 0
+        assert_eq!(run(16, &[encode::b(0b000, 0, 0, -32)], &[], 1).pc, 0xFFFF_FFF0);
+    }
+
+    #[test]
+    fn negative_jump_offsets() {
+        let core = run(8, &[encode::j(1, -8)], &[], 1);
+        assert_eq!((core.pc, core.regs[1]), (0, 12));
+
+        let jalr = encode::i(JALR, 0b000, 1, 2, -4);
+        let core = run(8, &[jalr], &[(2, 0)], 1);
+        assert_eq!((core.pc, core.regs[1]), (0xFFFF_FFFC, 12));
+        let core = run(8, &[jalr], &[(2, 0xFFFF_FFFF)], 1);
+        assert_eq!((core.csrs.get(MTVAL), core.regs[1]), (0xFFFF_FFFA, 0));
+    }
+
+    #[test]
+    fn misaligned_targets_trap() {
+        for (instruction, regs) in [
+            (encode::j(1, 6), &[][..]),
+            (encode::i(JALR, 0b000, 1, 2, 0), &[(2, 0x102)][..]),
+            (encode::b(0b000, 0, 0, 6), &[][..]),
+        ] {
+            let mut core = machine(8, &[instruction], regs);
+            core.csrs.set(MTVEC, 0x40);
+            core.execute();
+            assert_eq!((core.pc, core.csrs.get(MEPC), core.regs[1]), (0x40, 8, 0), "0x{:08x}", instruction);
+            assert_eq!(core.csrs.get(MCAUSE), 0);
+        }
+        // untaken branches don't check their target
+        assert_eq!(run(8, &[encode::b(0b001, 0, 0, 6)], &[], 1).pc, 12);
+    }
+
+    #[test]
+    fn jalr_reads_rs1_before_writing_rd() {
+        let core = run(4, &[encode::i(JALR, 0b000, 1, 1, 8)], &[(1, 0x40)], 1);
+        assert_eq!((core.pc, core.regs[1]), (0x48, 8));
+    }
+
+    #[test]
+    fn loads_extend() {
+        assert_eq!(load(0b000, 0x0000_0080), 0xFFFF_FF80);
+        assert_eq!(load(0b000, 0x0000_007F), 0x0000_007F);
+        assert_eq!(load(0b001, 0x0000_8001), 0xFFFF_8001);
+        assert_eq!(load(0b001, 0x0000_7FFF), 0x0000_7FFF);
+        assert_eq!(load(0b010, 0x8000_0001), 0x8000_0001);
+        assert_eq!(load(0b100, 0x0000_0080), 0x0000_0080);
+        assert_eq!(load(0b101, 0x0000_8001), 0x0000_8001);
+    }
+
+    #[test]
+    fn stores_write_their_width() {
+        for (funct3, expected) in [(0b000, 0xAAAA_AA78), (0b001, 0xAAAA_5678), (0b010, 0x1234_5678)] {
+            let mut core = machine(0, &[encode::s(funct3, 1, 2, 4)], &[(1, 0x100), (2, 0x1234_5678)]);
+            core.memory[0x104..0x108].fill(0xAA);
+            core.execute();
+            assert_eq!(word(&core, 0x104), expected);
+        }
+    }
+
+    #[test]
+    fn negative_load_store_offsets() {
+        let mut core = machine(0, &[encode::i(LOAD, 0b010, 2, 1, -4)], &[(1, 0x20)]);
+        core.memory[0x1C..0x20].copy_from_slice(&0xDEAD_BEEF_u32.to_le_bytes());
+        core.execute();
+        assert_eq!(core.regs[2], 0xDEAD_BEEF);
+
+        let core = step(encode::s(0b010, 1, 2, -8), &[(1, 0x30), (2, 0x1234_5678)]);
+        assert_eq!(word(&core, 0x28), 0x1234_5678);
+    }
+
+    #[test]
+    fn fence_is_a_nop() {
+        let core = step(encode::i(MISC_MEM, 0b000, 0, 0, 0x0FF), &[(1, 3)]);
+        assert_eq!((core.pc, core.regs[1]), (4, 3));
+    }
+
+    #[test]
+    fn fence_tso_and_pause_are_nops() {
+        for word in [0x8330_000F, 0x0100_000F] {
+            let core = step(word, &[(1, 3)]);
+            assert_eq!((core.pc, core.regs[1]), (4, 3));
+        }
+    }
+
+    #[test]
+    fn fence_i_sees_modified_code() {
+        // sw x2, 12(x0); fence.i; addi x1, x1, 1 (overwritten with addi x1, x1, 2)
+        let program = [
+            encode::s(0b010, 0, 2, 12),
+            encode::i(MISC_MEM, 0b001, 0, 0, 0),
+            encode::i(OP_IMM, 0b000, 0, 0, 0),
+            encode::i(OP_IMM, 0b000, 1, 1, 1),
+        ];
+        let core = run(0, &program, &[(2, encode::i(OP_IMM, 0b000, 1, 1, 2))], 4);
+        assert_eq!(core.regs[1], 2);
+    }
+
+    #[test]
+    fn csrrw_swaps() {
+        let swap = encode::csr(0b001, 1, 2, 0x340);
+        let core = run(0, &[swap, swap], &[(2, 0x1234)], 2);
+        assert_eq!((core.regs[1], core.csrs.get(MSCRATCH)), (0x1234, 0x1234));
+        let core = run(0, &[swap], &[(2, 0x1234)], 1);
+        assert_eq!((core.regs[1], core.csrs.get(MSCRATCH)), (0, 0x1234));
+    }
+
+    #[test]
+    fn csr_set_and_clear() {
+        // mscratch = 0xF0F0, then set, clear, write, set and clear immediates
+        let program = [
+            encode::csr(0b010, 3, 1, 0x340),
+            encode::csr(0b011, 4, 2, 0x340),
+            encode::csr(0b101, 5, 0b11111, 0x340),
+            encode::csr(0b110, 6, 0b00100, 0x340),
+            encode::csr(0b111, 7, 0b00011, 0x340),
+        ];
+        let mut core = machine(0, &program, &[(1, 0x000F), (2, 0xF00F)]);
+        core.csrs.set(MSCRATCH, 0xF0F0);
+        let values: Vec<(u32, u32)> = (3..8).map(|rd| {
+            core.execute();
+            (core.regs[rd], core.csrs.get(MSCRATCH))
+        }).collect();
+        assert_eq!(values, [(0xF0F0, 0xF0FF), (0xF0FF, 0x00F0), (0x00F0, 0x1F), (0x1F, 0x1F), (0x1F, 0x1C)]);
+    }
+
+    /// Custom CSR logging its accesses, each read returns the next value
+    struct Logged(Rc<RefCell<Vec<String>>>, u32);
+
+    impl CsrHook for Logged {
+        fn read(&mut self) -> u32 {
+            self.0.borrow_mut().push("read".to_string());
+            self.1 += 1;
+            self.1
+        }
+
+        fn write(&mut self, value: u32) {
+            self.0.borrow_mut().push(format!("write 0x{:x}", value));
+        }
+    }
+
+    #[test]
+    fn csrrw_reads_only_for_rd() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let program = [encode::csr(0b001, 0, 2, 0x7C0), encode::csr(0b001, 2, 2, 0x7C0)];
+        let mut core = machine(0, &program, &[(2, 0x55)]);
+        core.hook_csr(0x7C0, Box::new(Logged(log.clone(), 0)));
+        core.execute();
+        assert_eq!(*log.borrow(), ["write 0x55"]);
+        // rd = rs1: the write takes rs1 from before, rd gets the value read
+        core.execute();
+        assert_eq!(*log.borrow(), ["write 0x55", "read", "write 0x55"]);
+        assert_eq!((core.pc, core.regs[2]), (8, 1));
+
+        // the address's privilege level applies to hooked CSRs too
+        let mut core = machine(0, &program, &[]);
+        core.hook_csr(0x7C0, Box::new(Logged(log.clone(), 0)));
+        core.privilege = Privilege::Supervisor;
+        core.execute();
+        assert_eq!((core.csrs.cause(), log.borrow().len()), (Cause::IllegalInstruction, 3));
+    }
+
+    #[test]
+    fn x0_and_zero_immediates_skip_the_write() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        // csrrs x2 with x0, csrrci x3 with 0, csrrwi to x0
+        let program = [encode::csr(0b010, 2, 0, 0x7C0), encode::csr(0b111, 3, 0, 0x7C0),
+                       encode::csr(0b101, 0, 5, 0x7C0)];
+        let mut core = machine(0, &program, &[]);
+        core.hook_csr(0x7C0, Box::new(Logged(log.clone(), 0)));
+        for _ in 0..3 {
+            core.execute();
+        }
+        assert_eq!(*log.borrow(), ["read", "read", "write 0x5"]);
+        assert_eq!((core.pc, core.regs[2], core.regs[3]), (12, 1, 2));
+    }
+
+    #[test]
+    fn loop_program() {
+        // x1 = 10; loop: x2 += x1; x1 -= 1; bne x1, x0, loop
+        let [lui, addi] = encode::li(1, 10);
+        let program = [
+            lui,
+            addi,
+            encode::r(0, 0b000, 2, 2, 1),
+            encode::i(OP_IMM, 0b000, 1, 1, -1),
+            encode::b(0b001, 1, 0, -8),
+        ];
+        let core = run(0, &program, &[], 2 + 3 * 10);
+        assert_eq!((core.pc, core.regs[1], core.regs[2]), (20, 0, 55));
+    }
+
+    #[test]
+    fn ecall_enters_the_trap_handler() {
+        assert_eq!(run(8, &[ECALL], &[], 1).pc, 0);
+
+        let mut core = machine(8, &[ECALL], &[]);
+        core.csrs.set(MTVEC, 0x21);
+        core.csrs.set_status(status::MIE, true);
+        core.execute();
+        assert_eq!(core.pc, 0x20);
+        assert_eq!(core.csrs.get(MEPC), 8);
+        assert_eq!(cause(&core), 11);
+        assert_eq!(core.csrs.get(MTVAL), 0);
+        assert!(!core.csrs.status(status::MIE) && core.csrs.status(status::MPIE));
+    }
+
+    #[test]
+    fn ebreak_sets_mtval() {
+        let mut core = machine(4, &[EBREAK], &[]);
+        core.csrs.set(MTVEC, 0x30);
+        core.execute();
+        assert_eq!((core.pc, core.csrs.get(MEPC), core.csrs.get(MTVAL)), (0x30, 4, 4));
+        assert_eq!(cause(&core), 3);
+    }
+
+    #[test]
+    fn mret_returns_from_the_handler() {
+        // ecall at 0, the handler at 0x20 is a bare mret; skipping the ecall
+        // is done here instead of in the handler
+        let mut core = machine(0, &[ECALL], &[]);
+        core.memory[0x20..0x24].copy_from_slice(&MRET.to_le_bytes());
+        core.csrs.set(MTVEC, 0x20);
+        core.csrs.set_status(status::MIE, true);
+        core.execute();
+        core.csrs.set(MEPC, core.csrs.get(MEPC) + 4);
+        core.execute();
+        assert_eq!(core.pc, 4);
+        assert!(core.csrs.status(status::MIE) && core.csrs.status(status::MPIE));
+    }
+
+    #[test]
+    fn sret_and_privilege_changes() {
+        // mret to S at 0x10, which may not touch mscratch
+        let mut core = machine(0, &[MRET], &[]);
+        core.memory[0x10..0x14].copy_from_slice(&encode::csr(0b001, 1, 0, 0x340).to_le_bytes());
+        core.csrs.set(MTVEC, 0x40);
+        core.csrs.set_mpp(Privilege::Supervisor);
+        core.csrs.set(MEPC, 0x10);
+        core.execute();
+        assert_eq!((core.pc, core.privilege, core.csrs.mpp()), (0x10, Privilege::Supervisor, Privilege::User));
+        core.execute();
+        assert_eq!((core.pc, core.privilege, core.csrs.mpp(), cause(&core)),
+                   (0x40, Privilege::Machine, Privilege::Supervisor, 2));
+
+        // sret from S to U at 0x20, where ecall is a U-mode call
+        let mut core = machine(0, &[SRET], &[]);
+        core.memory[0x20..0x24].copy_from_slice(&ECALL.to_le_bytes());
+        core.privilege = Privilege::Supervisor;
+        core.csrs.set_status(status::SPIE, true);
+        core.csrs.set(SEPC, 0x20);
+        core.execute();
+        assert_eq!((core.pc, core.privilege, core.csrs.status(status::SIE), core.csrs.spp()),
+                   (0x20, Privilege::User, true, Privilege::User));
+        core.execute();
+        assert_eq!((core.pc, core.csrs.get(MEPC), core.csrs.mpp(), cause(&core)), (0, 0x20, Privilege::User, 8));
+
+        // sret is illegal in U-mode and, with TSR, in S-mode
+        for (privilege, tsr) in [(Privilege::User, false), (Privilege::Supervisor, true)] {
+            let mut core = machine(0, &[SRET], &[]);
+            core.privilege = privilege;
+            core.csrs.set_status(status::TSR, tsr);
+            core.csrs.set(SEPC, 0x20);
+            core.csrs.set(MTVEC, 0x40);
+            core.execute();
+            assert_eq!((core.pc, cause(&core)), (0x40, 2));
+        }
+    }
+
+    #[test]
+    fn sfence_vma_flushes_the_page_caches() {
+        // sfence.vma a0, zero flushes the page of a0 only
+        let mut core = machine(0, &[SFENCE_VMA | (10 << 15), SFENCE_VMA], &[(10, 0x1004)]);
+        core.load_page.fill(0x1000, 0x3000);
+        core.store_page.fill(0x2000, 0x3000);
+        core.execute();
+        assert!(!core.load_page.hit(0x1000, 4) && core.store_page.hit(0x2000, 4));
+        core.execute();
+        assert!(!core.store_page.hit(0x2000, 4));
+
+        // illegal in U-mode and, with TVM, in S-mode
+        for (privilege, tvm, legal) in [(Privilege::User, false, false), (Privilege::Supervisor, false, true),
+                                        (Privilege::Supervisor, true, false), (Privilege::Machine, true, true)] {
+            let mut core = machine(0, &[SFENCE_VMA], &[]);
+            core.privilege = privilege;
+            core.csrs.set_status(status::TVM, tvm);
+            core.csrs.set(MTVEC, 0x40);
+            core.execute();
+            assert_eq!(core.pc, if legal {4} else {0x40}, "{:?} {}", privilege, tvm);
+        }
+    }
+
+    #[test]
+    fn illegal_instructions_trap_with_their_bits() {
+        for word in [0x0000_0000, 0xFFFF_FFFF, encode::r(0b000_0010, 0b000, 1, 2, 3) | 0x4000_0000] {
+            let mut core = machine(4, &[word], &[]);
+            core.csrs.set(MTVEC, 0x20);
+            core.execute();
+            assert_eq!((core.pc, core.csrs.get(MEPC), core.csrs.get(MTVAL)), (0x20, 4, word));
+            assert_eq!(cause(&core), 2);
+        }
+    }
+
+    #[test]
+    fn unknown_csrs_are_illegal() {
+        let csrrw = encode::csr(0b001, 1, 2, 0x7FF);
+        let core = step(csrrw, &[(1, 5)]);
+        assert_eq!((core.pc, core.csrs.get(MTVAL), core.regs[1]), (0, csrrw, 5));
+        assert_eq!(cause(&core), 2);
+    }
+}
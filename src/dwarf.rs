@@ -0,0 +1,397 @@
+//! DWARF `.debug_line` reader (versions 2 to 5), enough to map guest
+//! addresses back to source lines
+
+use std::fs;
+
+use elf::endian::AnyEndian;
+use elf::ElfBytes;
+
+/// One address of the line number program
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Row {
+    pub address: u32,
+    /// Index into `LineTable::files`
+    pub file: usize,
+    pub line: u32,
+    /// A recommended breakpoint location, the start of a statement
+    pub is_stmt: bool,
+    /// First address past a sequence, not an instruction
+    pub end_sequence: bool,
+}
+
+#[derive(Default)]
+pub struct LineTable {
+    pub files: Vec<String>,
+    /// Rows of every sequence in program order
+    pub rows: Vec<Row>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let bytes = self.data.get(self.offset..self.offset + n).ok_or("truncated .debug_line")?;
+        self.offset += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// 4 or 8 byte section offset
+    fn offset(&mut self, size: usize) -> Result<u64, String> {
+        if size == 8 {self.u64()} else {self.u32().map(u64::from)}
+    }
+
+    fn uleb(&mut self) -> Result<u64, String> {
+        let (mut value, mut shift) = (0u64, 0);
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7F) as u64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Result<i64, String> {
+        let (mut value, mut shift) = (0i64, 0);
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7F) as i64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Ok(value);
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<&'a str, String> {
+        let rest = &self.data[self.offset.min(self.data.len())..];
+        let len = rest.iter().position(|&b| b == 0).ok_or("unterminated string in .debug_line")?;
+        self.offset += len + 1;
+        std::str::from_utf8(&rest[..len]).map_err(|_| "non-UTF-8 file name".to_string())
+    }
+}
+
+/// NUL-terminated string at `offset` of a string section
+fn string_at(section: &[u8], offset: u64) -> Result<String, String> {
+    let mut reader = Reader {data: section, offset: offset as usize};
+    reader.string().map(str::to_string)
+}
+
+// DW_LNCT_* and DW_FORM_* used by version 5 entry formats
+const LNCT_PATH: u64 = 1;
+const LNCT_DIRECTORY_INDEX: u64 = 2;
+const FORM_BLOCK: u64 = 0x09;
+const FORM_DATA1: u64 = 0x0B;
+const FORM_DATA2: u64 = 0x05;
+const FORM_DATA4: u64 = 0x06;
+const FORM_DATA8: u64 = 0x07;
+const FORM_DATA16: u64 = 0x1E;
+const FORM_STRING: u64 = 0x08;
+const FORM_STRP: u64 = 0x0E;
+const FORM_LINE_STRP: u64 = 0x1F;
+const FORM_UDATA: u64 = 0x0F;
+
+/// Sections a version 5 header points into
+struct Strings<'a> {
+    debug_str: &'a [u8],
+    debug_line_str: &'a [u8],
+}
+
+enum Value {
+    Number(u64),
+    Text(String),
+}
+
+fn form(reader: &mut Reader, form: u64, offset_size: usize, strings: &Strings) -> Result<Value, String> {
+    Ok(match form {
+        FORM_STRING => Value::Text(reader.string()?.to_string()),
+        FORM_STRP => Value::Text(string_at(strings.debug_str, reader.offset(offset_size)?)?),
+        FORM_LINE_STRP => Value::Text(string_at(strings.debug_line_str, reader.offset(offset_size)?)?),
+        FORM_UDATA => Value::Number(reader.uleb()?),
+        FORM_DATA1 => Value::Number(reader.u8()? as u64),
+        FORM_DATA2 => Value::Number(reader.u16()? as u64),
+        FORM_DATA4 => Value::Number(reader.u32()? as u64),
+        FORM_DATA8 => Value::Number(reader.u64()?),
+        FORM_DATA16 => {
+            reader.bytes(16)?;
+            Value::Number(0)
+        }
+        FORM_BLOCK => {
+            let len = reader.uleb()? as usize;
+            reader.bytes(len)?;
+            Value::Number(0)
+        }
+        _ => return Err(format!("unsupported form 0x{:x} in .debug_line", form)),
+    })
+}
+
+/// Version 5 directory or file name table, (path, directory index) entries
+fn entries(reader: &mut Reader, offset_size: usize, strings: &Strings) -> Result<Vec<(String, usize)>, String> {
+    let mut formats = Vec::new();
+    for _ in 0..reader.u8()? {
+        formats.push((reader.uleb()?, reader.uleb()?));
+    }
+    let mut entries = Vec::new();
+    for _ in 0..reader.uleb()? {
+        let (mut path, mut directory) = (String::new(), 0);
+        for &(content, code) in &formats {
+            match (content, form(reader, code, offset_size, strings)?) {
+                (LNCT_PATH, Value::Text(text)) => path = text,
+                (LNCT_DIRECTORY_INDEX, Value::Number(index)) => directory = index as usize,
+                _ => {}
+            }
+        }
+        entries.push((path, directory));
+    }
+    Ok(entries)
+}
+
+fn join(directory: &str, name: &str) -> String {
+    if name.starts_with('/') || directory.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", directory.trim_end_matches('/'), name)
+    }
+}
+
+/// Appends the rows of the unit at the reader's offset, file indices are
+/// mapped into `table.files`
+fn unit(reader: &mut Reader, strings: &Strings, table: &mut LineTable) -> Result<(), String> {
+    let (length, offset_size) = match reader.u32()? {
+        0xFFFF_FFFF => (reader.u64()? as usize, 8),
+        length => (length as usize, 4),
+    };
+    let end = reader.offset + length;
+    let version = reader.u16()?;
+    if !(2..=5).contains(&version) {
+        return Err(format!("unsupported .debug_line version {}", version));
+    }
+    if version >= 5 {
+        // address and segment selector sizes
+        reader.bytes(2)?;
+    }
+    let header_length = reader.offset(offset_size)? as usize;
+    let program = reader.offset + header_length;
+    let min_length = reader.u8()? as u64;
+    if version >= 4 {
+        // maximum operations per instruction, 1 for non-VLIW
+        reader.u8()?;
+    }
+    let default_is_stmt = reader.u8()? != 0;
+    let line_base = reader.u8()? as i8 as i64;
+    let line_range = reader.u8()?.max(1);
+    let opcode_base = reader.u8()?;
+    let lengths = reader.bytes(opcode_base.saturating_sub(1) as usize)?.to_vec();
+
+    // unit file index to LineTable::files
+    let mut files = Vec::new();
+    if version >= 5 {
+        let directories = entries(reader, offset_size, strings)?;
+        for (name, directory) in entries(reader, offset_size, strings)? {
+            let directory = directories.get(directory).map_or("", |d| d.0.as_str());
+            files.push(join(directory, &name));
+        }
+    } else {
+        let mut directories = vec![String::new()];
+        loop {
+            let directory = reader.string()?;
+            if directory.is_empty() {
+                break;
+            }
+            directories.push(directory.to_string());
+        }
+        // file 0 doesn't exist before version 5
+        files.push(String::new());
+        loop {
+            let name = reader.string()?;
+            if name.is_empty() {
+                break;
+            }
+            let directory = reader.uleb()? as usize;
+            // modification time and size
+            reader.uleb()?;
+            reader.uleb()?;
+            files.push(join(directories.get(directory).map_or("", |d| d.as_str()), name));
+        }
+    }
+    let base = table.files.len();
+    table.files.extend(files);
+
+    reader.offset = program;
+    let reset = Row {address: 0, file: base + if version >= 5 {0} else {1}, line: 1, is_stmt: default_is_stmt,
+                     end_sequence: false};
+    let mut row = reset;
+    while reader.offset < end {
+        let opcode = reader.u8()?;
+        if opcode >= opcode_base {
+            let adjusted = (opcode - opcode_base) as u64;
+            row.address = row.address.wrapping_add((adjusted / line_range as u64 * min_length) as u32);
+            row.line = row.line.wrapping_add((line_base + (adjusted % line_range as u64) as i64) as u32);
+            table.rows.push(row);
+            continue;
+        }
+        match opcode {
+            0 => {
+                let len = reader.uleb()? as usize;
+                let next = reader.offset + len;
+                match reader.u8()? {
+                    // end_sequence
+                    1 => {
+                        table.rows.push(Row {end_sequence: true, ..row});
+                        row = reset;
+                    }
+                    // set_address
+                    2 => row.address = match len - 1 {
+                        8 => reader.u64()? as u32,
+                        _ => reader.u32()?,
+                    },
+                    // define_file (before version 5)
+                    3 => {
+                        let name = reader.string()?.to_string();
+                        table.files.push(name);
+                    }
+                    _ => {}
+                }
+                reader.offset = next;
+            }
+            // copy
+            1 => table.rows.push(row),
+            // advance_pc
+            2 => row.address = row.address.wrapping_add((reader.uleb()? * min_length) as u32),
+            // advance_line
+            3 => row.line = row.line.wrapping_add(reader.sleb()? as u32),
+            // set_file
+            4 => row.file = base + reader.uleb()? as usize,
+            // set_column
+            5 => {
+                reader.uleb()?;
+            }
+            // negate_stmt
+            6 => row.is_stmt = !row.is_stmt,
+            // const_add_pc
+            8 => {
+                let advance = (255 - opcode_base) as u64 / line_range as u64 * min_length;
+                row.address = row.address.wrapping_add(advance as u32);
+            }
+            // fixed_advance_pc
+            9 => row.address = row.address.wrapping_add(reader.u16()? as u32),
+            // set_basic_block, set_prologue_end, set_epilogue_begin take
+            // nothing, set_isa and unknown opcodes take ULEB operands
+            _ => {
+                for _ in 0..lengths[opcode as usize - 1] {
+                    reader.uleb()?;
+                }
+            }
+        }
+    }
+    reader.offset = end;
+    Ok(())
+}
+
+/// Parses a little-endian `.debug_line` with the string sections its
+/// version 5 units refer to
+pub fn parse(debug_line: &[u8], debug_str: &[u8], debug_line_str: &[u8]) -> Result<LineTable, String> {
+    let strings = Strings {debug_str, debug_line_str};
+    let mut table = LineTable::default();
+    let mut reader = Reader {data: debug_line, offset: 0};
+    while reader.offset < debug_line.len() {
+        unit(&mut reader, &strings, &mut table)?;
+    }
+    Ok(table)
+}
+
+/// Line table of the ELF at `path`, empty without debug info
+pub fn line_table(path: &str) -> Result<LineTable, String> {
+    let err = |e: &dyn std::fmt::Display| format!("{}: {}", path, e);
+    let data = fs::read(path).map_err(|e| err(&e))?;
+    let elf = ElfBytes::<AnyEndian>::minimal_parse(&data).map_err(|e| err(&e))?;
+    let section = |name| -> Result<&[u8], String> {
+        match elf.section_header_by_name(name).map_err(|e| err(&e))? {
+            Some(header) => Ok(elf.section_data(&header).map_err(|e| err(&e))?.0),
+            None => Ok(&[]),
+        }
+    };
+    parse(section(".debug_line")?, section(".debug_str")?, section(".debug_line_str")?).map_err(|e| err(&e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_4_program() {
+        let mut header = vec![
+            4, 0,
+            // header_length, patched below
+            0, 0, 0, 0,
+            // min_inst_length, max_ops, default_is_stmt, line_base, line_range, opcode_base
+            4, 1, 1, 0xFB, 14, 13,
+            0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1,
+        ];
+        header.extend(b"src\0\0");
+        header.extend(b"main.c\0\x01\0\0\0");
+        let header_length = (header.len() - 6) as u32;
+        header[2..6].copy_from_slice(&header_length.to_le_bytes());
+        let program = [
+            // set_address 0x100
+            0, 5, 2, 0x00, 0x01, 0x00, 0x00,
+            // advance_line 9, copy
+            3, 9, 1,
+            // special: address += 4, line += 1
+            13 + (1 - -5) + 14,
+            // advance_pc 2 (8 bytes), negate_stmt, copy
+            2, 2, 6, 1,
+            // end_sequence
+            0, 1, 1,
+        ];
+        let mut section = ((header.len() + program.len()) as u32).to_le_bytes().to_vec();
+        section.extend(&header);
+        section.extend(program.map(|b| b as u8));
+
+        let table = parse(&section, &[], &[]).unwrap();
+        assert_eq!(table.files, ["", "src/main.c"]);
+        let rows: Vec<(u32, u32, bool, bool)> = table.rows
+            .iter()
+            .map(|r| (r.address, r.line, r.is_stmt, r.end_sequence))
+            .collect();
+        assert_eq!(rows, [(0x100, 10, true, false), (0x104, 11, true, false), (0x10C, 11, false, false),
+                          (0x10C, 11, false, true)]);
+        assert!(table.rows.iter().all(|r| r.file == 1));
+    }
+
+    #[test]
+    fn leb128() {
+        let mut reader = Reader {data: &[0xE5, 0x8E, 0x26, 0x7F, 0x80, 0x7F], offset: 0};
+        assert_eq!(reader.uleb(), Ok(624485));
+        assert_eq!(reader.sleb(), Ok(-1));
+        assert_eq!(reader.sleb(), Ok(-128));
+        assert!(reader.u8().is_err());
+    }
+}
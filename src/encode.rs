@@ -0,0 +1,129 @@
+//! Instruction encoders for generated and hand-written test programs
+
+// the full set is used by the unit tests
+#![cfg_attr(not(test), allow(dead_code))]
+
+use crate::Instruction;
+
+pub const LOAD: u32 = 0b000_0011;
+pub const MISC_MEM: u32 = 0b000_1111;
+pub const OP_IMM: u32 = 0b001_0011;
+pub const AUIPC: u32 = 0b001_0111;
+pub const STORE: u32 = 0b010_0011;
+pub const OP: u32 = 0b011_0011;
+pub const LUI: u32 = 0b011_0111;
+pub const BRANCH: u32 = 0b110_0011;
+pub const JALR: u32 = 0b110_0111;
+pub const JAL: u32 = 0b110_1111;
+pub const SYSTEM: u32 = 0b111_0011;
+
+pub const ECALL: u32 = 0x0000_0073;
+pub const EBREAK: u32 = 0x0010_0073;
+pub const MRET: u32 = 0x3020_0073;
+pub const SRET: u32 = 0x1020_0073;
+/// sfence.vma x0, x0
+pub const SFENCE_VMA: u32 = 0x1200_0073;
+pub const WFI: u32 = 0x1050_0073;
+
+pub fn r(funct7: u32, funct3: u32, rd: u32, rs1: u32, rs2: u32) -> u32 {
+    (funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | OP
+}
+
+pub fn i(opcode: u32, funct3: u32, rd: u32, rs1: u32, imm: i32) -> u32 {
+    ((imm as u32 & 0xFFF) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+pub fn s(funct3: u32, rs1: u32, rs2: u32, imm: i32) -> u32 {
+    let imm = imm as u32;
+    ((imm >> 5 & 0x7F) << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | ((imm & 0x1F) << 7) | STORE
+}
+
+pub fn b(funct3: u32, rs1: u32, rs2: u32, imm: i32) -> u32 {
+    let imm = imm as u32;
+    ((imm >> 12 & 1) << 31) | ((imm >> 5 & 0x3F) << 25) | (rs2 << 20) | (rs1 << 15) |
+        (funct3 << 12) | ((imm >> 1 & 0xF) << 8) | ((imm >> 11 & 1) << 7) | BRANCH
+}
+
+/// `imm` is the upper 20 bits already in place
+pub fn u(opcode: u32, rd: u32, imm: u32) -> u32 {
+    (imm & 0xFFFF_F000) | (rd << 7) | opcode
+}
+
+pub fn j(rd: u32, imm: i32) -> u32 {
+    let imm = imm as u32;
+    ((imm >> 20 & 1) << 31) | ((imm >> 1 & 0x3FF) << 21) | ((imm >> 11 & 1) << 20) |
+        ((imm >> 12 & 0xFF) << 12) | (rd << 7) | JAL
+}
+
+/// CSR instruction, `source` is rs1 or the 5-bit immediate
+pub fn csr(funct3: u32, rd: u32, source: u32, csr: u16) -> u32 {
+    ((csr as u32) << 20) | (source << 15) | (funct3 << 12) | (rd << 7) | SYSTEM
+}
+
+/// Word that decodes back to `instruction`, fences with every ordering bit set
+pub fn instruction(instruction: Instruction) -> u32 {
+    use Instruction::*;
+    let reg = |index: usize| index as u32;
+    match instruction {
+        Lui(a) => u(LUI, reg(a.rd), a.imm as u32),
+        Auipc(a) => u(AUIPC, reg(a.rd), a.imm as u32),
+        Jal(a) => j(reg(a.rd), a.imm),
+        Jalr(a) => i(JALR, 0b000, reg(a.rd), reg(a.rs1), a.imm),
+        Beq(a) => b(0b000, reg(a.rs1), reg(a.rs2), a.imm),
+        Bne(a) => b(0b001, reg(a.rs1), reg(a.rs2), a.imm),
+        Blt(a) => b(0b100, reg(a.rs1), reg(a.rs2), a.imm),
+        Bge(a) => b(0b101, reg(a.rs1), reg(a.rs2), a.imm),
+        Bltu(a) => b(0b110, reg(a.rs1), reg(a.rs2), a.imm),
+        Bgeu(a) => b(0b111, reg(a.rs1), reg(a.rs2), a.imm),
+        Lb(a) => i(LOAD, 0b000, reg(a.rd), reg(a.rs1), a.imm),
+        Lh(a) => i(LOAD, 0b001, reg(a.rd), reg(a.rs1), a.imm),
+        Lw(a) => i(LOAD, 0b010, reg(a.rd), reg(a.rs1), a.imm),
+        Lbu(a) => i(LOAD, 0b100, reg(a.rd), reg(a.rs1), a.imm),
+        Lhu(a) => i(LOAD, 0b101, reg(a.rd), reg(a.rs1), a.imm),
+        Sb(a) => s(0b000, reg(a.rs1), reg(a.rs2), a.imm),
+        Sh(a) => s(0b001, reg(a.rs1), reg(a.rs2), a.imm),
+        Sw(a) => s(0b010, reg(a.rs1), reg(a.rs2), a.imm),
+        Addi(a) => i(OP_IMM, 0b000, reg(a.rd), reg(a.rs1), a.imm),
+        Slti(a) => i(OP_IMM, 0b010, reg(a.rd), reg(a.rs1), a.imm),
+        Sltiu(a) => i(OP_IMM, 0b011, reg(a.rd), reg(a.rs1), a.imm),
+        Xori(a) => i(OP_IMM, 0b100, reg(a.rd), reg(a.rs1), a.imm),
+        Ori(a) => i(OP_IMM, 0b110, reg(a.rd), reg(a.rs1), a.imm),
+        Andi(a) => i(OP_IMM, 0b111, reg(a.rd), reg(a.rs1), a.imm),
+        // the immediate keeps the funct7 bits
+        Slli(a) => i(OP_IMM, 0b001, reg(a.rd), reg(a.rs1), a.imm),
+        Srli(a) => i(OP_IMM, 0b101, reg(a.rd), reg(a.rs1), a.imm),
+        Srai(a) => i(OP_IMM, 0b101, reg(a.rd), reg(a.rs1), a.imm),
+        Add(a) => r(0, 0b000, reg(a.rd), reg(a.rs1), reg(a.rs2)),
+        Sub(a) => r(0b010_0000, 0b000, reg(a.rd), reg(a.rs1), reg(a.rs2)),
+        Sll(a) => r(0, 0b001, reg(a.rd), reg(a.rs1), reg(a.rs2)),
+        Slt(a) => r(0, 0b010, reg(a.rd), reg(a.rs1), reg(a.rs2)),
+        Sltu(a) => r(0, 0b011, reg(a.rd), reg(a.rs1), reg(a.rs2)),
+        Xor(a) => r(0, 0b100, reg(a.rd), reg(a.rs1), reg(a.rs2)),
+        Srl(a) => r(0, 0b101, reg(a.rd), reg(a.rs1), reg(a.rs2)),
+        Sra(a) => r(0b010_0000, 0b101, reg(a.rd), reg(a.rs1), reg(a.rs2)),
+        Or(a) => r(0, 0b110, reg(a.rd), reg(a.rs1), reg(a.rs2)),
+        And(a) => r(0, 0b111, reg(a.rd), reg(a.rs1), reg(a.rs2)),
+        Fence => 0x0FF0_0000 | MISC_MEM,
+        FenceI => (0b001 << 12) | MISC_MEM,
+        FenceTso => 0x8330_0000 | MISC_MEM,
+        Pause => 0x0100_0000 | MISC_MEM,
+        Ecall => ECALL,
+        Ebreak => EBREAK,
+        Mret => MRET,
+        Sret => SRET,
+        Wfi => WFI,
+        SfenceVma(a) => (0b000_1001 << 25) | (reg(a.rs2) << 20) | (reg(a.rs1) << 15) | (reg(a.rd) << 7) | SYSTEM,
+        Csrrw(a) => csr(0b001, reg(a.rd), reg(a.rs1), a.csr),
+        Csrrs(a) => csr(0b010, reg(a.rd), reg(a.rs1), a.csr),
+        Csrrc(a) => csr(0b011, reg(a.rd), reg(a.rs1), a.csr),
+        Csrrwi(a) => csr(0b101, reg(a.rd), reg(a.rs1), a.csr),
+        Csrrsi(a) => csr(0b110, reg(a.rd), reg(a.rs1), a.csr),
+        Csrrci(a) => csr(0b111, reg(a.rd), reg(a.rs1), a.csr),
+    }
+}
+
+/// lui + addi loading `value` into `rd`
+pub fn li(rd: u32, value: u32) -> [u32; 2] {
+    let high = value.wrapping_add(0x800) & 0xFFFF_F000;
+    [u(LUI, rd, high), i(OP_IMM, 0b000, rd, rd, value.wrapping_sub(high) as i32)]
+}
@@ -0,0 +1,80 @@
+use crate::memory::PageCache;
+use crate::trap::Exception;
+use crate::Cause;
+
+/// Instruction alignment, 4 bytes until there is a C extension
+pub const IALIGN: u32 = 4;
+
+/// Reads instructions as 16-bit parcels: the first says whether a second
+/// follows (low bits 0b11) and each is checked on its own, so an instruction
+/// running off the end of RAM faults with mtval at the parcel that isn't
+/// there. The prefetch buffer is the page of the last checked parcel,
+/// fetches inside it skip the checks.
+pub(crate) struct Fetch {
+    buffer: PageCache,
+}
+
+impl Fetch {
+    pub fn new() -> Self {
+        Self {buffer: PageCache::new()}
+    }
+
+    /// The instruction at `pc`: a 32-bit word, or a 16-bit parcel in the low
+    /// half for a compressed encoding (which the decoder rejects without C)
+    pub fn fetch(&mut self, memory: &[u8], pc: u32) -> Result<u32, Exception> {
+        if !pc.is_multiple_of(IALIGN) {
+            return Err(Exception::new(Cause::InstructionAddressMisaligned, pc));
+        }
+        let word = if self.buffer.hit(pc, 4) {
+            let start = pc as usize;
+            u32::from_le_bytes(memory[start..start + 4].try_into().unwrap())
+        } else {
+            let low = self.parcel(memory, pc)? as u32;
+            if low & 0b11 != 0b11 {
+                return Ok(low);
+            }
+            low | (self.parcel(memory, pc.wrapping_add(2))? as u32) << 16
+        };
+        Ok(if word & 0b11 == 0b11 {word} else {word & 0xFFFF})
+    }
+
+    fn parcel(&mut self, memory: &[u8], address: u32) -> Result<u16, Exception> {
+        let start = address as usize;
+        let bytes = memory.get(start..start + 2).ok_or(Exception::fetch_access(address))?;
+        self.buffer.fill(address, memory.len());
+        Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// Drops the buffered page if it holds `address`, or whatever it holds
+    /// for None
+    pub fn flush(&mut self, address: Option<u32>) {
+        self.buffer.flush(address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::csr_file::{MCAUSE, MEPC, MTVAL, MTVEC};
+    use super::*;
+    use crate::test_utils::machine;
+
+    #[test]
+    fn parcels_fault_on_their_own() {
+        let mut fetch = Fetch::new();
+        // addi's low parcel is the last one in memory
+        let memory = [0x13, 0, 0, 0, 0x13, 0];
+        assert_eq!(fetch.fetch(&memory, 0), Ok(0x13));
+        assert_eq!(fetch.fetch(&memory, 4), Err(Exception::fetch_access(6)));
+        assert_eq!(fetch.fetch(&memory, 8), Err(Exception::fetch_access(8)));
+        assert_eq!(fetch.fetch(&memory, 2), Err(Exception::new(Cause::InstructionAddressMisaligned, 2)));
+        // a compressed parcel is all that is read
+        assert_eq!(fetch.fetch(&[0x01, 0, 0xFF, 0xFF], 0), Ok(1));
+
+        // fetching past the end of memory traps instead of panicking
+        let mut core = machine(0x2000, &[], &[]);
+        core.csrs.set(MTVEC, 0x40);
+        core.execute();
+        assert_eq!((core.pc, core.csrs.get(MEPC), core.csrs.get(MTVAL)), (0x40, 0x2000, 0x2000));
+        assert_eq!(core.csrs.get(MCAUSE), 1);
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::fs;
+
+use crate::loader::{function_at, Function, Image};
+use crate::{CoreState, Instruction, Observer};
+
+// deeper call chains are folded into their outermost frames
+const MAX_DEPTH: usize = 512;
+
+// function index for addresses outside every symbol
+const UNKNOWN: usize = usize::MAX;
+
+/// What the previous instruction did to the call stack
+#[derive(Clone, Copy)]
+enum Pending {
+    None,
+    Call,
+    Return,
+}
+
+/// Attributes every executed instruction to its guest call stack, built from
+/// the ELF function symbols and tracked on calls (JAL/JALR linking ra or t0)
+/// and returns (JALR x0 through ra or t0). Writes collapsed stacks, one
+/// `outer;inner count` line per stack, for inferno or flamegraph.pl when
+/// dropped.
+pub struct Flamegraph {
+    path: String,
+    functions: Vec<Function>,
+    stack: Vec<usize>,
+    counts: HashMap<Vec<usize>, u64>,
+    pending: Pending,
+}
+
+impl Flamegraph {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            functions: Vec::new(),
+            stack: Vec::new(),
+            counts: HashMap::new(),
+            pending: Pending::None,
+        }
+    }
+
+    fn name(&self, function: usize) -> &str {
+        self.functions.get(function).map_or("[unknown]", |f| f.name.as_str())
+    }
+
+    pub fn collapsed(&self) -> String {
+        let mut lines: Vec<String> = self.counts
+            .iter()
+            .map(|(stack, count)| {
+                let frames: Vec<&str> = stack.iter().map(|&f| self.name(f)).collect();
+                format!("{} {}\n", frames.join(";"), count)
+            })
+            .collect();
+        lines.sort();
+        lines.concat()
+    }
+}
+
+impl Observer for Flamegraph {
+    fn loaded(&mut self, image: &Image) {
+        self.functions = image.functions.clone();
+    }
+
+    fn step(&mut self, core: &CoreState) {
+        let function = function_at(&self.functions, core.pc).unwrap_or(UNKNOWN);
+        match self.pending {
+            Pending::Call if self.stack.len() < MAX_DEPTH => self.stack.push(function),
+            Pending::Return if self.stack.len() > 1 => {
+                self.stack.pop();
+            }
+            _ => {}
+        }
+        // jumps without a link (tail calls, traps) replace the current frame
+        match self.stack.last_mut() {
+            Some(top) => *top = function,
+            None => self.stack.push(function),
+        }
+        match self.counts.get_mut(&self.stack) {
+            Some(count) => *count += 1,
+            None => {
+                self.counts.insert(self.stack.clone(), 1);
+            }
+        }
+
+        let word = core.memory.get(core.pc as usize..core.pc as usize + 4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()));
+        let link = |rd: usize| rd == 1 || rd == 5;
+        self.pending = match word.map(|word| CoreState::decode_with(word, core.lenient)) {
+            Some(Ok(Instruction::Jal(args))) if link(args.rd) => Pending::Call,
+            Some(Ok(Instruction::Jalr(args))) if link(args.rd) => Pending::Call,
+            Some(Ok(Instruction::Jalr(args))) if args.rd == 0 && link(args.rs1) => Pending::Return,
+            _ => Pending::None,
+        };
+    }
+}
+
+impl Drop for Flamegraph {
+    fn drop(&mut self) {
+        if let Err(e) = fs::write(&self.path, self.collapsed()) {
+            eprintln!("{}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{self, JALR, OP_IMM};
+    use crate::test_utils::machine;
+
+    fn function(start: u32, size: u32, name: &str) -> Function {
+        Function {start, size, name: name.to_string()}
+    }
+
+    #[test]
+    fn stacks_follow_calls_and_returns() {
+        // main: jal ra, leaf; addi; addi
+        // leaf (0x10): addi; ret
+        let mut program = [encode::i(OP_IMM, 0b000, 0, 0, 0); 6];
+        program[0] = encode::j(1, 0x10);
+        program[5] = encode::i(JALR, 0b000, 0, 1, 0);
+        let mut core = machine(0, &program, &[]);
+        let mut flamegraph = Flamegraph::new("/dev/null");
+        flamegraph.functions = vec![function(0, 0x10, "main"), function(0x10, 8, "leaf")];
+        for _ in 0..5 {
+            flamegraph.step(&core);
+            core.execute();
+        }
+        assert_eq!(flamegraph.collapsed(), "main 3\nmain;leaf 2\n");
+    }
+
+    #[test]
+    fn unknown_addresses_and_gaps() {
+        let functions = [function(0x10, 8, "a"), function(0x20, 4, "b")];
+        assert_eq!(function_at(&functions, 0x0C), None);
+        assert_eq!(function_at(&functions, 0x14), Some(0));
+        assert_eq!(function_at(&functions, 0x18), None);
+        assert_eq!(function_at(&functions, 0x20), Some(1));
+        assert_eq!(function_at(&functions, 0x24), None);
+    }
+}
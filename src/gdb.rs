@@ -0,0 +1,496 @@
+use std::net::{TcpListener, TcpStream};
+
+use gdbstub::common::Signal;
+use gdbstub::conn::{Connection, ConnectionExt};
+use gdbstub::stub::run_blocking::{BlockingEventLoop, Event, WaitForStopReasonError};
+use gdbstub::stub::{GdbStub, SingleThreadStopReason};
+use gdbstub::target::ext::base::single_register_access::{SingleRegisterAccess, SingleRegisterAccessOps};
+use gdbstub::target::ext::base::reverse_exec::{ReplayLogPosition, ReverseCont, ReverseContOps, ReverseStep,
+                                               ReverseStepOps};
+use gdbstub::target::ext::base::singlethread::{SingleThreadBase, SingleThreadResume, SingleThreadResumeOps,
+                                               SingleThreadSingleStep, SingleThreadSingleStepOps};
+use gdbstub::target::ext::base::BaseOps;
+use gdbstub::target::ext::breakpoints::{Breakpoints, BreakpointsOps, HwBreakpoint, HwBreakpointOps, SwBreakpoint,
+                                        SwBreakpointOps};
+use gdbstub::target::ext::memory_map::{MemoryMap, MemoryMapOps};
+use gdbstub::target::ext::target_description_xml_override::{TargetDescriptionXmlOverride,
+                                                             TargetDescriptionXmlOverrideOps};
+use gdbstub::target::{Target, TargetError, TargetResult};
+use gdbstub_arch::riscv::reg::id::RiscvRegId;
+use gdbstub_arch::riscv::reg::RiscvCoreRegs;
+use gdbstub_arch::riscv::Riscv32;
+
+use crate::history::DEFAULT_DEPTH;
+use crate::json::Value;
+use crate::rpc::{unhex, Error, Session, SLICE};
+use crate::Config;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+<architecture>riscv:rv32</architecture>
+<feature name="org.gnu.gdb.riscv.cpu">
+<reg name="zero" bitsize="32" type="int" regnum="0"/>
+<reg name="ra" bitsize="32" type="code_ptr"/>
+<reg name="sp" bitsize="32" type="data_ptr"/>
+<reg name="gp" bitsize="32" type="data_ptr"/>
+<reg name="tp" bitsize="32" type="data_ptr"/>
+<reg name="t0" bitsize="32" type="int"/>
+<reg name="t1" bitsize="32" type="int"/>
+<reg name="t2" bitsize="32" type="int"/>
+<reg name="fp" bitsize="32" type="data_ptr"/>
+<reg name="s1" bitsize="32" type="int"/>
+<reg name="a0" bitsize="32" type="int"/>
+<reg name="a1" bitsize="32" type="int"/>
+<reg name="a2" bitsize="32" type="int"/>
+<reg name="a3" bitsize="32" type="int"/>
+<reg name="a4" bitsize="32" type="int"/>
+<reg name="a5" bitsize="32" type="int"/>
+<reg name="a6" bitsize="32" type="int"/>
+<reg name="a7" bitsize="32" type="int"/>
+<reg name="s2" bitsize="32" type="int"/>
+<reg name="s3" bitsize="32" type="int"/>
+<reg name="s4" bitsize="32" type="int"/>
+<reg name="s5" bitsize="32" type="int"/>
+<reg name="s6" bitsize="32" type="int"/>
+<reg name="s7" bitsize="32" type="int"/>
+<reg name="s8" bitsize="32" type="int"/>
+<reg name="s9" bitsize="32" type="int"/>
+<reg name="s10" bitsize="32" type="int"/>
+<reg name="s11" bitsize="32" type="int"/>
+<reg name="t3" bitsize="32" type="int"/>
+<reg name="t4" bitsize="32" type="int"/>
+<reg name="t5" bitsize="32" type="int"/>
+<reg name="t6" bitsize="32" type="int"/>
+<reg name="pc" bitsize="32" type="code_ptr"/>
+</feature>
+</target>
+"#;
+
+/// `length` bytes of `object` from `offset` into `buf`, the qXfer window
+fn window(object: &str, offset: u64, length: usize, buf: &mut [u8]) -> usize {
+    let start = (offset as usize).min(object.len());
+    let end = start.saturating_add(length.min(buf.len())).min(object.len());
+    buf[..end - start].copy_from_slice(&object.as_bytes()[start..end]);
+    end - start
+}
+
+/// How GDB last resumed the guest
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Resume {
+    Continue,
+    Step,
+    ReverseStep,
+    ReverseContinue,
+}
+
+/// A `Session` as the target of gdbstub
+struct Stub {
+    session: Session,
+    resume: Resume,
+}
+
+impl Stub {
+    fn new(session: Session) -> Self {
+        Self {session, resume: Resume::Continue}
+    }
+
+    fn call(&mut self, method: &str, params: &[(&str, Value)]) -> Result<Value, Error> {
+        self.session.call(method, &Value::object(params))
+    }
+
+    fn memory_map(&self) -> String {
+        format!("<?xml version=\"1.0\"?>\n<!DOCTYPE memory-map PUBLIC \"+//IDN gnu.org//DTD GDB Memory Map V1.0//EN\" \
+                 \"http://sourceware.org/gdb/gdb-memory-map.dtd\">\n<memory-map>\n\
+                 <memory type=\"ram\" start=\"0x0\" length=\"0x{:x}\"/>\n</memory-map>\n", self.session.memory_size())
+    }
+
+    /// x0 to x31 by number or "pc"
+    fn set_register(&mut self, register: Value, value: u32) -> TargetResult<(), Self> {
+        self.call("set_register", &[("register", register), ("value", value.into())])
+            .map(|_| ())
+            .map_err(|_| TargetError::NonFatal)
+    }
+
+    fn breakpoint(&mut self, insert: bool, address: u32) -> TargetResult<bool, Self> {
+        let method = if insert {"set_breakpoint"} else {"delete_breakpoint"};
+        Ok(self.call(method, &[("address", address.into())]).is_ok())
+    }
+
+    /// Runs the guest as it was resumed until it stops, or until GDB sends
+    /// something while it runs on
+    fn wait(&mut self, conn: &mut TcpStream) -> Result<Event<SingleThreadStopReason<u32>>, String> {
+        let stop = match self.resume {
+            Resume::Step => {
+                let _ = self.call("step", &[]);
+                SingleThreadStopReason::DoneStep
+            }
+            Resume::ReverseStep | Resume::ReverseContinue => {
+                let method = if self.resume == Resume::ReverseStep {"reverse_step"} else {"reverse_continue"};
+                let status = self.call(method, &[]).map_err(|Error(_, message)| message)?;
+                match status.get("reason").and_then(Value::as_str) {
+                    Some("history start") => SingleThreadStopReason::ReplayLog {tid: None, pos: ReplayLogPosition::Begin},
+                    Some("breakpoint") => SingleThreadStopReason::SwBreak(()),
+                    _ => SingleThreadStopReason::DoneStep,
+                }
+            }
+            Resume::Continue => loop {
+                // Ctrl-C is the byte GDB sends while the guest runs
+                if conn.peek().map_err(|e| e.to_string())?.is_some() {
+                    return Ok(Event::IncomingData(ConnectionExt::read(conn).map_err(|e| e.to_string())?));
+                }
+                if !self.session.running() || self.session.run(SLICE).is_some() {
+                    break SingleThreadStopReason::SwBreak(());
+                }
+            }
+        };
+        Ok(Event::TargetStopped(match self.session.exit_code() {
+            Some(code) => SingleThreadStopReason::Exited(code as u8),
+            None => stop,
+        }))
+    }
+}
+
+impl Target for Stub {
+    type Arch = Riscv32;
+    type Error = String;
+
+    fn base_ops(&mut self) -> BaseOps<'_, Self::Arch, Self::Error> {
+        BaseOps::SingleThread(self)
+    }
+
+    fn support_breakpoints(&mut self) -> Option<BreakpointsOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn support_memory_map(&mut self) -> Option<MemoryMapOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn support_target_description_xml_override(&mut self) -> Option<TargetDescriptionXmlOverrideOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl SingleThreadBase for Stub {
+    fn read_registers(&mut self, regs: &mut RiscvCoreRegs<u32>) -> TargetResult<(), Self> {
+        let registers = self.call("registers", &[]).map_err(|_| TargetError::NonFatal)?;
+        if let Some(Value::Array(x)) = registers.get("x") {
+            for (reg, value) in regs.x.iter_mut().zip(x) {
+                *reg = value.as_u32().unwrap_or_default();
+            }
+        }
+        regs.pc = registers.get("pc").and_then(Value::as_u32).unwrap_or_default();
+        Ok(())
+    }
+
+    fn write_registers(&mut self, regs: &RiscvCoreRegs<u32>) -> TargetResult<(), Self> {
+        let registers = (1..32).map(|i| (Value::Number(i as f64), regs.x[i])).chain([("pc".into(), regs.pc)]);
+        for (register, value) in registers {
+            self.set_register(register, value)?;
+        }
+        Ok(())
+    }
+
+    fn support_single_register_access(&mut self) -> Option<SingleRegisterAccessOps<'_, (), Self>> {
+        Some(self)
+    }
+
+    /// Reads stop at the end of memory
+    fn read_addrs(&mut self, start_addr: u32, data: &mut [u8]) -> TargetResult<usize, Self> {
+        let length = data.len().min(self.session.memory_size().saturating_sub(start_addr as usize));
+        if length == 0 {
+            return Err(TargetError::Errno(0x14));
+        }
+        let memory = self.call("read_memory", &[("address", start_addr.into()), ("length", (length as u32).into())])
+            .map_err(|_| TargetError::Errno(0x14))?;
+        let bytes = unhex(memory.get("data").and_then(Value::as_str).unwrap_or_default())
+            .map_err(|_| TargetError::Errno(0x14))?;
+        data[..bytes.len()].copy_from_slice(&bytes);
+        Ok(bytes.len())
+    }
+
+    fn write_addrs(&mut self, start_addr: u32, data: &[u8]) -> TargetResult<(), Self> {
+        let hex: String = data.iter().map(|byte| format!("{:02x}", byte)).collect();
+        self.call("write_memory", &[("address", start_addr.into()), ("data", hex.as_str().into())])
+            .map(|_| ())
+            .map_err(|_| TargetError::Errno(0x14))
+    }
+
+    fn support_resume(&mut self) -> Option<SingleThreadResumeOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+/// x0 to x31 and pc, there are no CSRs in the target description
+impl SingleRegisterAccess<()> for Stub {
+    fn read_register(&mut self, _tid: (), reg_id: RiscvRegId<u32>, buf: &mut [u8]) -> TargetResult<usize, Self> {
+        let mut regs = RiscvCoreRegs::default();
+        self.read_registers(&mut regs)?;
+        let value = match reg_id {
+            RiscvRegId::Gpr(i) => regs.x[i as usize],
+            RiscvRegId::Pc => regs.pc,
+            _ => return Err(TargetError::NonFatal),
+        };
+        buf[..4].copy_from_slice(&value.to_le_bytes());
+        Ok(4)
+    }
+
+    fn write_register(&mut self, _tid: (), reg_id: RiscvRegId<u32>, val: &[u8]) -> TargetResult<(), Self> {
+        let value = u32::from_le_bytes(val.try_into().map_err(|_| TargetError::NonFatal)?);
+        match reg_id {
+            RiscvRegId::Gpr(i) => self.set_register(Value::Number(i as f64), value),
+            RiscvRegId::Pc => self.set_register("pc".into(), value),
+            _ => Err(TargetError::NonFatal),
+        }
+    }
+}
+
+impl SingleThreadResume for Stub {
+    /// Signals are ignored, there's no OS to deliver them to
+    fn resume(&mut self, _signal: Option<Signal>) -> Result<(), Self::Error> {
+        self.resume = Resume::Continue;
+        // an exited guest stays stopped
+        let _ = self.call("continue", &[]);
+        Ok(())
+    }
+
+    fn support_single_step(&mut self) -> Option<SingleThreadSingleStepOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn support_reverse_step(&mut self) -> Option<ReverseStepOps<'_, (), Self>> {
+        Some(self)
+    }
+
+    fn support_reverse_cont(&mut self) -> Option<ReverseContOps<'_, (), Self>> {
+        Some(self)
+    }
+}
+
+impl SingleThreadSingleStep for Stub {
+    fn step(&mut self, _signal: Option<Signal>) -> Result<(), Self::Error> {
+        self.resume = Resume::Step;
+        Ok(())
+    }
+}
+
+/// Runs backwards through the recorded history, stopping at a breakpoint or
+/// where the history begins
+impl ReverseStep<()> for Stub {
+    fn reverse_step(&mut self, _tid: ()) -> Result<(), Self::Error> {
+        self.resume = Resume::ReverseStep;
+        Ok(())
+    }
+}
+
+impl ReverseCont<()> for Stub {
+    fn reverse_cont(&mut self) -> Result<(), Self::Error> {
+        self.resume = Resume::ReverseContinue;
+        Ok(())
+    }
+}
+
+/// Software and hardware breakpoints are the same to an emulator,
+/// watchpoints aren't supported
+impl Breakpoints for Stub {
+    fn support_sw_breakpoint(&mut self) -> Option<SwBreakpointOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn support_hw_breakpoint(&mut self) -> Option<HwBreakpointOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl SwBreakpoint for Stub {
+    fn add_sw_breakpoint(&mut self, addr: u32, _kind: usize) -> TargetResult<bool, Self> {
+        self.breakpoint(true, addr)
+    }
+
+    fn remove_sw_breakpoint(&mut self, addr: u32, _kind: usize) -> TargetResult<bool, Self> {
+        self.breakpoint(false, addr)
+    }
+}
+
+impl HwBreakpoint for Stub {
+    fn add_hw_breakpoint(&mut self, addr: u32, _kind: usize) -> TargetResult<bool, Self> {
+        self.breakpoint(true, addr)
+    }
+
+    fn remove_hw_breakpoint(&mut self, addr: u32, _kind: usize) -> TargetResult<bool, Self> {
+        self.breakpoint(false, addr)
+    }
+}
+
+impl MemoryMap for Stub {
+    fn memory_map_xml(&self, offset: u64, length: usize, buf: &mut [u8]) -> TargetResult<usize, Self> {
+        Ok(window(&self.memory_map(), offset, length, buf))
+    }
+}
+
+impl TargetDescriptionXmlOverride for Stub {
+    fn target_description_xml(&self, annex: &[u8], offset: u64, length: usize, buf: &mut [u8])
+        -> TargetResult<usize, Self> {
+        match annex {
+            b"target.xml" => Ok(window(TARGET_XML, offset, length, buf)),
+            _ => Err(TargetError::NonFatal),
+        }
+    }
+}
+
+/// gdbstub's loop, the guest runs in `wait_for_stop_reason` between polls of
+/// the connection
+enum EventLoop {}
+
+impl BlockingEventLoop for EventLoop {
+    type Target = Stub;
+    type Connection = TcpStream;
+    type StopReason = SingleThreadStopReason<u32>;
+
+    #[allow(clippy::type_complexity)]
+    fn wait_for_stop_reason(stub: &mut Stub, conn: &mut TcpStream)
+        -> Result<Event<Self::StopReason>, WaitForStopReasonError<String, <TcpStream as Connection>::Error>> {
+        stub.wait(conn).map_err(WaitForStopReasonError::Target)
+    }
+
+    /// Ctrl-C from GDB: stops a running guest
+    fn on_interrupt(stub: &mut Stub) -> Result<Option<Self::StopReason>, String> {
+        let _ = stub.call("pause", &[]);
+        Ok(Some(SingleThreadStopReason::Signal(Signal::SIGINT)))
+    }
+}
+
+/// Serves GDB on `stream` until it detaches, kills the guest or goes away,
+/// returns the guest exit code, 0 if it didn't exit
+fn debug(mut stub: Stub, stream: TcpStream) -> Result<i32, String> {
+    match GdbStub::new(stream).run_blocking::<EventLoop>(&mut stub) {
+        Ok(_) => {}
+        Err(e) if e.is_connection_error() => {}
+        Err(e) => return Err(e.to_string()),
+    }
+    Ok(stub.session.exit_code().unwrap_or(0))
+}
+
+/// Loads a bare-metal program halted at its entry and serves one GDB at
+/// `address` over the remote serial protocol (`target remote address`), with
+/// the target description and memory map, breakpoints, stepping, continuing
+/// and Ctrl-C, and reverse stepping and continuing through the last steps
+/// recorded. Returns the guest exit code, 0 if GDB detached first.
+pub fn serve(address: &str, args: &[String], config: Config) -> Result<i32, String> {
+    let mut session = Session::new(args, config)?;
+    session.record(DEFAULT_DEPTH);
+    let listener = TcpListener::bind(address).map_err(|e| format!("{}: {}", address, e))?;
+    eprintln!("waiting for gdb on {}", listener.local_addr().map_err(|e| e.to_string())?);
+    let (stream, peer) = listener.accept().map_err(|e| e.to_string())?;
+    eprintln!("gdb connected from {}", peer);
+    debug(Stub::new(session), stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use super::{debug, Stub};
+    use crate::rpc::Session;
+    use crate::encode::{self, OP_IMM};
+
+    /// `$data#checksum`
+    fn frame(data: &str) -> Vec<u8> {
+        let sum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        format!("${}#{:02x}", data, sum).into_bytes()
+    }
+
+    /// Plays GDB against `stub`: sends each packet without acks ("\x03" as the
+    /// bare byte) and returns the replies, a packet followed by "\x03" and
+    /// "k" get none. Returns the exit code of the stub too.
+    fn converse(stub: Stub, packets: &'static [&'static str]) -> (Vec<String>, i32) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let gdb = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            let reply = |stream: &mut TcpStream| {
+                let mut text = Vec::new();
+                let mut byte = [0];
+                while !text.ends_with(b"#") {
+                    stream.read_exact(&mut byte).unwrap();
+                    if !(text.is_empty() && byte[0] != b'$') {
+                        text.push(byte[0]);
+                    }
+                }
+                stream.read_exact(&mut [0; 2]).unwrap();
+                // `x*n` is x and n - 29 more of it
+                let mut decoded = Vec::new();
+                let mut data = text[1..text.len() - 1].iter();
+                while let Some(&byte) = data.next() {
+                    match byte {
+                        b'*' => decoded.extend(vec![*decoded.last().unwrap(); *data.next().unwrap() as usize - 29]),
+                        _ => decoded.push(byte),
+                    }
+                }
+                String::from_utf8(decoded).unwrap()
+            };
+            stream.write_all(&frame("QStartNoAckMode")).unwrap();
+            let mut replies = vec![reply(&mut stream)];
+            for (i, packet) in packets.iter().enumerate() {
+                if *packet == "\x03" {
+                    stream.write_all(b"\x03").unwrap();
+                } else {
+                    stream.write_all(&frame(packet)).unwrap();
+                }
+                if *packet != "k" && packets.get(i + 1) != Some(&"\x03") {
+                    replies.push(reply(&mut stream));
+                }
+            }
+            replies
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let code = debug(stub, stream).unwrap();
+        (gdb.join().unwrap(), code)
+    }
+
+    #[test]
+    fn packets_drive_the_session() {
+        // loop: addi x1, x1, 1; addi x2, x2, 2; jal x0, loop
+        let stub = Stub::new(Session::inline(&[encode::i(OP_IMM, 0b000, 1, 1, 1), encode::i(OP_IMM, 0b000, 2, 2, 2),
+                                               encode::j(0, -8)]));
+        let (replies, code) = converse(stub, &["qSupported:multiprocess+;xmlRegisters=i386",
+            "qXfer:features:read:target.xml:0,40", "qXfer:features:read:target.xml:0,4000",
+            "qXfer:memory-map:read::0,1000", "?", "vCont;s:1", "s", "p1", "p20", "P20=00000000", "Z0,8,4",
+            "Z2,100,4", "c", "g", "m0,4", "m1000,4", "M100,2:abcd", "m100,2", "z0,8,4", "vCont;c", "\x03", "k"]);
+        let replies: Vec<&str> = replies.iter().map(String::as_str).collect();
+        assert_eq!(replies[0], "OK");
+        assert!(replies[1].contains("qXfer:features:read+") && replies[1].contains("ReverseStep+"));
+        assert!(replies[2].starts_with("m<?xml") && replies[2].len() == 0x41);
+        assert!(replies[3].ends_with("</target>\n") && replies[4].contains("length=\"0x1000\""));
+        assert_eq!(&replies[5..10], ["T05thread:p01.01;", "S05", "S05", "01000000", "08000000"]);
+        // watchpoints aren't supported
+        assert_eq!(&replies[10..13], ["OK", "OK", ""]);
+        // continued from 0 to the breakpoint at 8
+        assert_eq!(replies[13], "T05thread:p01.01;swbreak:;");
+        assert_eq!(replies[14].len(), 33 * 8);
+        assert_eq!((&replies[14][8..24], &replies[14][32 * 8..]), ("0200000004000000", "08000000"));
+        assert_eq!(&replies[15..20], ["93801000", "E14", "OK", "abcd", "OK"]);
+        // Ctrl-C while running, then the kill isn't answered
+        assert_eq!(replies[20], "S02");
+        assert_eq!((replies.len(), code), (21, 0));
+    }
+
+    #[test]
+    fn reverse_step_and_continue() {
+        // addi x1, x1, 1; sw x1, 0x100(x0); jal x0, 0
+        let mut session = Session::inline(&[encode::i(OP_IMM, 0b000, 1, 1, 1), encode::s(0b010, 0, 1, 0x100),
+                                            encode::j(0, -8)]);
+        session.record(16);
+        let (replies, _) = converse(Stub::new(session), &["Z0,4,4", "s", "s", "s", "s", "s", "s", "s", "p1",
+            "m100,4", "bs", "p20", "bc", "p20", "p1", "m100,4", "z0,4,4", "bc", "p20", "m100,4", "D"]);
+        assert!(replies[..9].iter().all(|reply| reply == "OK" || reply == "S05"));
+        // x1 is 3 and about to be stored in the third iteration
+        assert_eq!(&replies[9..13], ["03000000", "02000000", "S05", "00000000"]);
+        // back to the store of the second iteration
+        assert_eq!(&replies[13..17], ["T05thread:01;swbreak:;", "04000000", "02000000", "01000000"]);
+        assert_eq!(&replies[17..], ["OK", "T05replaylog:begin;", "00000000", "00000000", "OK"]);
+    }
+}
@@ -0,0 +1,92 @@
+//! Golden commit-log traces in `tests/golden`, `UPDATE_GOLDEN=1 cargo test`
+//! rewrites them after an intended behavior change
+
+use std::fs;
+
+use crate::csr_file::MTVEC;
+use crate::encode::{self, ECALL, EBREAK, LOAD, MRET, OP_IMM};
+use crate::test_utils::{commit, machine};
+
+fn check(name: &str, program: &[u32], steps: usize, setup: impl FnOnce(&mut crate::CoreState)) {
+    let mut core = machine(0, program, &[]);
+    setup(&mut core);
+    let trace: String = (0..steps).map(|_| commit(&mut core) + "\n").collect();
+
+    let path = format!("{}/tests/golden/{}.log", env!("CARGO_MANIFEST_DIR"), name);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::write(&path, &trace).unwrap();
+        return;
+    }
+    let golden = fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: {}", path, e));
+    for (i, (expected, actual)) in golden.lines().zip(trace.lines()).enumerate() {
+        assert_eq!(actual, expected, "{} line {}", name, i + 1);
+    }
+    assert_eq!(trace.lines().count(), golden.lines().count(), "{} length", name);
+}
+
+#[test]
+fn arithmetic() {
+    let mut program = Vec::new();
+    program.extend(encode::li(1, 0x8000_0000));
+    program.extend(encode::li(2, 0xFFFF_FFFF));
+    for (funct7, funct3) in [(0, 0), (0x20, 0), (0, 1), (0, 2), (0, 3), (0, 4), (0, 5), (0x20, 5), (0, 6), (0, 7)] {
+        program.push(encode::r(funct7, funct3, 3, 1, 2));
+    }
+    for (funct3, imm) in [(0, -1), (2, -1), (3, -1), (4, 0x555), (6, -2048), (7, 0xF0), (1, 3), (5, 3), (5, 0x400 | 3)] {
+        program.push(encode::i(OP_IMM, funct3, 4, 1, imm));
+    }
+    program.push(encode::u(encode::AUIPC, 5, 0xFFFF_F000));
+    check("arithmetic", &program, program.len(), |_| {});
+}
+
+#[test]
+fn memory() {
+    let [lui, addi] = encode::li(1, 0x200);
+    let [lui2, addi2] = encode::li(2, 0x8081_8283);
+    let program = [
+        lui, addi, lui2, addi2,
+        encode::s(0b010, 1, 2, 0),
+        encode::s(0b001, 1, 2, 6),
+        encode::s(0b000, 1, 2, -1),
+        encode::i(LOAD, 0b000, 3, 1, 0),
+        encode::i(LOAD, 0b001, 4, 1, 2),
+        encode::i(LOAD, 0b010, 5, 1, 4),
+        encode::i(LOAD, 0b100, 6, 1, -1),
+        encode::i(LOAD, 0b101, 7, 1, 6),
+    ];
+    check("memory", &program, program.len(), |_| {});
+}
+
+#[test]
+fn control_flow() {
+    // x1 = 3; loop: x2 += x1; x1 -= 1; bne x1, x0, loop; jal x5, +8; (skipped);
+    // jalr x6, 12(x5); (skipped); x7 = 2
+    let program = [
+        encode::i(OP_IMM, 0b000, 1, 0, 3),
+        encode::r(0, 0b000, 2, 2, 1),
+        encode::i(OP_IMM, 0b000, 1, 1, -1),
+        encode::b(0b001, 1, 0, -8),
+        encode::j(5, 8),
+        encode::i(OP_IMM, 0b000, 7, 0, 1),
+        encode::i(encode::JALR, 0b000, 6, 5, 12),
+        encode::i(OP_IMM, 0b000, 7, 0, 1),
+        encode::i(OP_IMM, 0b000, 7, 0, 2),
+    ];
+    check("control_flow", &program, 13, |_| {});
+}
+
+#[test]
+fn traps() {
+    // ecall, ebreak and an illegal word, each returning through a handler
+    // that moves mepc past the trapping instruction
+    let mut program = vec![ECALL, EBREAK, 0xFFFF_FFFF, encode::i(OP_IMM, 0b000, 10, 0, 1)];
+    program.resize(16, 0);
+    program.extend([
+        // x1 = mepc; x1 += 4; mepc = x1; mret
+        encode::csr(0b001, 1, 0, 0x341),
+        encode::i(OP_IMM, 0b000, 1, 1, 4),
+        encode::csr(0b001, 0, 1, 0x341),
+        MRET,
+    ]);
+    check("traps", &program, 16, |core| core.csrs.set(MTVEC, 64));
+}
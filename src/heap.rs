@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+
+use crate::linux::{align_up, EFAULT, EINVAL, ENOMEM, PAGE_SIZE};
+
+const MREMAP_MAYMOVE: u32 = 1;
+
+/// The program break and anonymous mappings of a user-mode guest, in the
+/// flat memory between the loaded image and the stack. The break grows up
+/// from the image, mappings are placed top-down below the stack in the
+/// highest gap that fits, and unmapped ranges are reused, so malloc
+/// implementations that map and unmap (musl's mallocng, large allocations
+/// in newlib or glibc) run for as long as their live data fits. New pages
+/// read zero like fresh kernel pages.
+pub(crate) struct Heap {
+    brk_start: u32,
+    brk: u32,
+    /// End of the mapping area, the stack starts there
+    top: u32,
+    /// Live mappings, start to page-aligned length
+    mappings: BTreeMap<u32, u32>,
+}
+
+impl Heap {
+    pub(crate) fn new(brk: u32, top: u32) -> Self {
+        Self {brk_start: brk, brk, top, mappings: BTreeMap::new()}
+    }
+
+    /// Lowest address a mapping may start at
+    fn floor(&self) -> u32 {
+        // the break stays below the page-aligned top
+        align_up(self.brk, PAGE_SIZE).unwrap_or(self.top)
+    }
+
+    /// Moves the break to `address` if it stays above the image and below
+    /// the mappings, returns the break
+    pub(crate) fn brk(&mut self, address: u32, memory: &mut [u8]) -> u32 {
+        let ceiling = self.mappings.keys().next().copied().unwrap_or(self.top);
+        if (self.brk_start..=ceiling).contains(&address) {
+            // pages given back by an earlier shrink come back zeroed
+            let (old, new) = (self.floor() as usize, align_up(address, PAGE_SIZE).unwrap_or(self.top) as usize);
+            if new > old {
+                memory[old..new].fill(0);
+            }
+            self.brk = address;
+        }
+        self.brk
+    }
+
+    /// Whether `len` bytes at `address` are inside the area and unmapped
+    fn is_free(&self, address: u32, len: u32) -> bool {
+        let Some(end) = address.checked_add(len) else {
+            return false;
+        };
+        address >= self.floor() && end <= self.top
+            && self.mappings.range(..end).next_back().is_none_or(|(&start, &size)| start + size <= address)
+    }
+
+    /// Start of the highest gap of `len` bytes
+    fn find(&self, len: u32) -> Option<u32> {
+        let mut end = self.top;
+        for (&start, &size) in self.mappings.iter().rev() {
+            if end - (start + size) >= len {
+                return Some(end - len);
+            }
+            end = start;
+        }
+        end.checked_sub(len).filter(|&start| start >= self.floor())
+    }
+
+    /// Removes `[address, address + len)` from the mappings, splitting the
+    /// ones it cuts
+    fn unmap(&mut self, address: u32, len: u32) {
+        let end = address + len;
+        let cut: Vec<(u32, u32)> = self.mappings.range(..end)
+            .filter(|&(&start, &size)| start + size > address)
+            .map(|(&start, &size)| (start, size))
+            .collect();
+        for (start, size) in cut {
+            self.mappings.remove(&start);
+            if start < address {
+                self.mappings.insert(start, address - start);
+            }
+            if start + size > end {
+                self.mappings.insert(end, start + size - end);
+            }
+        }
+    }
+
+    /// An anonymous mapping: at `hint` if it is free (or replacing what is
+    /// there if `fixed`), else in the highest gap
+    pub(crate) fn mmap(&mut self, hint: u32, len: u32, fixed: bool, memory: &mut [u8]) -> Result<u32, i32> {
+        if len == 0 || (fixed && !hint.is_multiple_of(PAGE_SIZE)) {
+            return Err(EINVAL);
+        }
+        let len = len.checked_add(PAGE_SIZE - 1).ok_or(ENOMEM)? & !(PAGE_SIZE - 1);
+        let address = if fixed {
+            if hint < self.floor() || hint.checked_add(len).is_none_or(|end| end > self.top) {
+                return Err(ENOMEM);
+            }
+            self.unmap(hint, len);
+            hint
+        } else if hint.is_multiple_of(PAGE_SIZE) && hint != 0 && self.is_free(hint, len) {
+            hint
+        } else {
+            self.find(len).ok_or(ENOMEM)?
+        };
+        self.mappings.insert(address, len);
+        memory[address as usize..(address + len) as usize].fill(0);
+        Ok(address)
+    }
+
+    pub(crate) fn munmap(&mut self, address: u32, len: u32) -> Result<u32, i32> {
+        if !address.is_multiple_of(PAGE_SIZE) || len == 0 {
+            return Err(EINVAL);
+        }
+        let len = align_up(len, PAGE_SIZE).ok_or(EINVAL)?;
+        if address.checked_add(len).is_none() {
+            return Err(EINVAL);
+        }
+        self.unmap(address, len);
+        Ok(0)
+    }
+
+    /// Shrinks or grows the mapping at `address`, in place if the pages
+    /// after it are free, else moving it with MREMAP_MAYMOVE
+    pub(crate) fn mremap(&mut self, address: u32, old_len: u32, new_len: u32, flags: u32, memory: &mut [u8])
+                         -> Result<u32, i32> {
+        if !address.is_multiple_of(PAGE_SIZE) || new_len == 0 {
+            return Err(EINVAL);
+        }
+        let (Some(old_len), Some(new_len)) = (align_up(old_len, PAGE_SIZE), align_up(new_len, PAGE_SIZE)) else {
+            return Err(EINVAL);
+        };
+        let size = match self.mappings.get(&address) {
+            Some(&size) if size >= old_len => size,
+            _ => return Err(EFAULT),
+        };
+        if new_len <= old_len {
+            self.unmap(address + new_len, old_len - new_len);
+            return Ok(address);
+        }
+        let start = address + old_len;
+        if size == old_len && self.is_free(start, new_len - old_len) {
+            memory[start as usize..(address + new_len) as usize].fill(0);
+            self.mappings.insert(address, new_len);
+            return Ok(address);
+        }
+        if flags & MREMAP_MAYMOVE == 0 {
+            return Err(ENOMEM);
+        }
+        let moved = self.find(new_len).ok_or(ENOMEM)?;
+        memory.copy_within(address as usize..start as usize, moved as usize);
+        memory[(moved + old_len) as usize..(moved + new_len) as usize].fill(0);
+        self.unmap(address, old_len);
+        self.mappings.insert(moved, new_len);
+        Ok(moved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: u32 = PAGE_SIZE;
+
+    #[test]
+    fn mappings_reuse_freed_pages() {
+        let mut memory = vec![0xAA; 16 * PAGE as usize];
+        let mut heap = Heap::new(PAGE + 10, 12 * PAGE);
+        let a = heap.mmap(0, 2 * PAGE, false, &mut memory).unwrap();
+        let b = heap.mmap(0, 1, false, &mut memory).unwrap();
+        assert_eq!((a, b), (10 * PAGE, 9 * PAGE));
+        assert!(memory[b as usize..a as usize + 2 * PAGE as usize].iter().all(|&byte| byte == 0));
+        // the hole left by a is reused, the break stops at the mappings
+        heap.munmap(a, 2 * PAGE).unwrap();
+        memory[a as usize] = 1;
+        assert_eq!(heap.mmap(0, PAGE, false, &mut memory), Ok(11 * PAGE));
+        assert_eq!(heap.brk(9 * PAGE + 1, &mut memory), PAGE + 10);
+        assert_eq!(heap.brk(3 * PAGE, &mut memory), 3 * PAGE);
+        assert_eq!(heap.brk(0, &mut memory), 3 * PAGE);
+        assert_eq!(heap.mmap(0, 16 * PAGE, false, &mut memory), Err(ENOMEM));
+
+        // b grows in place into the free page above it, then moves
+        assert_eq!(heap.mremap(b, PAGE, 2 * PAGE, 0, &mut memory), Ok(b));
+        memory[b as usize] = 7;
+        assert_eq!(heap.mremap(b, 2 * PAGE, 3 * PAGE, 0, &mut memory), Err(ENOMEM));
+        let moved = heap.mremap(b, 2 * PAGE, 3 * PAGE, MREMAP_MAYMOVE, &mut memory).unwrap();
+        assert_eq!((moved, memory[moved as usize]), (6 * PAGE, 7));
+        assert_eq!(heap.mremap(b, PAGE, 2 * PAGE, MREMAP_MAYMOVE, &mut memory), Err(EFAULT));
+
+        // MAP_FIXED replaces the middle of a mapping
+        assert_eq!(heap.mmap(7 * PAGE, PAGE, true, &mut memory), Ok(7 * PAGE));
+        assert_eq!(heap.mappings.iter().map(|(&start, &size)| (start / PAGE, size / PAGE)).collect::<Vec<_>>(),
+                   [(6, 1), (7, 1), (8, 1), (11, 1)]);
+        assert_eq!(heap.mmap(PAGE, PAGE, true, &mut memory), Err(ENOMEM));
+    }
+
+    #[test]
+    fn lengths_past_the_address_space_are_invalid() {
+        let mut memory = vec![0; 16 * PAGE as usize];
+        let mut heap = Heap::new(PAGE, 12 * PAGE);
+        let a = heap.mmap(0, PAGE, false, &mut memory).unwrap();
+        assert_eq!(heap.mremap(a, u32::MAX, 2 * PAGE, 0, &mut memory), Err(EINVAL));
+        assert_eq!(heap.mremap(a, PAGE, u32::MAX, 0, &mut memory), Err(EINVAL));
+        assert_eq!(heap.munmap(a, u32::MAX - 1), Err(EINVAL));
+        assert_eq!(align_up(u32::MAX - 2, PAGE), None);
+    }
+}
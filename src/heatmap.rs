@@ -0,0 +1,130 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+use crate::encode::{LOAD, STORE};
+use crate::loader::Image;
+use crate::{CoreState, Observer};
+
+/// Bytes per heatmap row
+pub const BUCKET: u32 = 64;
+const BAR: u64 = 40;
+
+/// Counts loads and stores per `BUCKET`-byte range. The report names the
+/// closest symbol at or below each range and flags ranges past the loaded
+/// image (heap, stack or devices); it is written to `path` when dropped, as
+/// JSON if the path ends in `.json`.
+pub struct Heatmap {
+    path: String,
+    // bucket address to (loads, stores)
+    buckets: BTreeMap<u32, (u64, u64)>,
+    symbols: Vec<(u32, String)>,
+    image_end: Option<u32>,
+    // opcode of the previous instruction, its access shows in last_access
+    last_opcode: u32,
+}
+
+impl Heatmap {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            buckets: BTreeMap::new(),
+            symbols: Vec::new(),
+            image_end: None,
+            last_opcode: 0,
+        }
+    }
+
+    fn symbol(&self, address: u32) -> Option<&str> {
+        let i = self.symbols.partition_point(|(value, _)| *value <= address).checked_sub(1)?;
+        Some(&self.symbols[i].1)
+    }
+
+    fn note(&self, address: u32) -> String {
+        match (self.image_end, self.symbol(address)) {
+            (Some(end), _) if address >= end => "outside image".to_string(),
+            (_, Some(symbol)) => symbol.to_string(),
+            _ => String::new(),
+        }
+    }
+
+    pub fn report(&self) -> String {
+        let loads: u64 = self.buckets.values().map(|b| b.0).sum();
+        let stores: u64 = self.buckets.values().map(|b| b.1).sum();
+        let max = self.buckets.values().map(|b| b.0 + b.1).max().unwrap_or(1);
+        let mut report = format!("{} byte buckets, {} loads, {} stores\n", BUCKET, loads, stores);
+        for (&address, &(loads, stores)) in &self.buckets {
+            let bar = "#".repeat(((loads + stores) * BAR).div_ceil(max) as usize);
+            report += &format!("0x{:08x} {:>10} {:>10} {:<40} {}\n", address, loads, stores, bar, self.note(address));
+        }
+        report
+    }
+
+    pub fn json(&self) -> String {
+        let rows: Vec<String> = self.buckets
+            .iter()
+            .map(|(address, (loads, stores))| {
+                format!("{{\"address\": {}, \"loads\": {}, \"stores\": {}, \"note\": \"{}\"}}",
+                        address, loads, stores, self.note(*address))
+            })
+            .collect();
+        format!("{{\"bucket\": {}, \"ranges\": [{}]}}\n", BUCKET, rows.join(", "))
+    }
+}
+
+impl Observer for Heatmap {
+    fn loaded(&mut self, image: &Image) {
+        self.symbols = image.symbols.iter().map(|(name, &value)| (value, name.clone())).collect();
+        self.symbols.sort();
+        self.image_end = Some(image.end);
+    }
+
+    fn step(&mut self, core: &CoreState) {
+        if let Some((address, _)) = core.last_access {
+            let bucket = self.buckets.entry(address & !(BUCKET - 1)).or_insert((0, 0));
+            match self.last_opcode {
+                LOAD => bucket.0 += 1,
+                STORE => bucket.1 += 1,
+                _ => {}
+            }
+        }
+        self.last_opcode = core.memory.get(core.pc as usize..core.pc as usize + 4)
+            .map_or(0, |bytes| u32::from_le_bytes(bytes.try_into().unwrap()) & 0x7F);
+    }
+}
+
+impl Drop for Heatmap {
+    fn drop(&mut self) {
+        let report = if self.path.ends_with(".json") {self.json()} else {self.report()};
+        if let Err(e) = fs::write(&self.path, report) {
+            eprintln!("{}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode;
+    use crate::test_utils::machine;
+
+    #[test]
+    fn counts_accesses_per_bucket() {
+        // sw x1, 0x100(x0); lw x2, 0x104(x0); lb x3, 0x140(x0)
+        let program = [encode::s(0b010, 0, 1, 0x100), encode::i(LOAD, 0b010, 2, 0, 0x104),
+                       encode::i(LOAD, 0b000, 3, 0, 0x140)];
+        let mut core = machine(0, &program, &[]);
+        let mut heatmap = Heatmap::new("/dev/null");
+        heatmap.symbols = vec![(0x100, "buffer".to_string())];
+        heatmap.image_end = Some(0x140);
+        for _ in 0..3 {
+            heatmap.step(&core);
+            core.execute();
+        }
+        heatmap.step(&core);
+        assert_eq!(heatmap.buckets[&0x100], (1, 1));
+        assert_eq!(heatmap.buckets[&0x140], (1, 0));
+        let report = heatmap.report();
+        assert!(report.starts_with("64 byte buckets, 2 loads, 1 stores\n"), "{}", report);
+        assert!(report.contains(" buffer\n") && report.contains(" outside image\n"));
+    }
+}
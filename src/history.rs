@@ -0,0 +1,131 @@
+use std::collections::VecDeque;
+
+use crate::csr_file::CsrFile;
+use crate::encode::SYSTEM;
+use crate::hpm::Counters;
+use crate::registers::Registers;
+use crate::{CoreState, Instruction, Privilege};
+
+/// Steps kept when no limit is given
+pub(crate) const DEFAULT_DEPTH: usize = 1 << 18;
+
+/// Core state before one step and the memory bytes the step's store
+/// overwrote. The counter configuration is only kept for CSR instructions,
+/// the only ones changing it.
+struct Entry {
+    pc: u32,
+    regs: Registers,
+    cycles: u64,
+    totals: [u64; 5],
+    counters: Option<Box<Counters>>,
+    privilege: Privilege,
+    csrs: CsrFile,
+    mip: u32,
+    retired: u64,
+    store: Option<(u32, Vec<u8>)>,
+}
+
+/// Undo log for reverse execution: one entry per single step, the oldest
+/// dropped past `depth`. Console output and other device effects aren't
+/// undone, stepping forward again after going back re-executes.
+pub(crate) struct History {
+    entries: VecDeque<Entry>,
+    depth: usize,
+}
+
+impl History {
+    pub(crate) fn new(depth: usize) -> Self {
+        Self {entries: VecDeque::new(), depth: depth.max(1)}
+    }
+
+    /// Steps that can be undone
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Saves the state of `core` before it executes the instruction at pc
+    pub(crate) fn record(&mut self, core: &CoreState, retired: u64) {
+        let word = core.memory.get(core.pc as usize..core.pc as usize + 4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()));
+        let store = word.and_then(|word| CoreState::decode_with(word, true).ok())
+            .and_then(|instruction| {
+                let (a, size) = match instruction {
+                    Instruction::Sw(a) => (a, 4),
+                    Instruction::Sh(a) => (a, 2),
+                    Instruction::Sb(a) => (a, 1),
+                    _ => return None,
+                };
+                let address = core.regs[a.rs1].wrapping_add(a.imm as u32);
+                let bytes = core.memory.get(address as usize..address as usize + size)?;
+                Some((address, bytes.to_vec()))
+            });
+        if self.entries.len() == self.depth {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(Entry {
+            pc: core.pc,
+            regs: core.regs,
+            cycles: core.cycles,
+            totals: core.counters.totals(),
+            counters: word.filter(|word| word & 0x7F == SYSTEM).map(|_| Box::new(core.counters.clone())),
+            privilege: core.privilege,
+            csrs: core.csrs.clone(),
+            mip: core.mip,
+            retired,
+            store,
+        });
+    }
+
+    /// Undoes the last recorded step, returns the instructions retired before
+    /// it or None at the start of the history
+    pub(crate) fn undo(&mut self, core: &mut CoreState) -> Option<u64> {
+        let entry = self.entries.pop_back()?;
+        core.pc = entry.pc;
+        core.regs = entry.regs;
+        core.cycles = entry.cycles;
+        if let Some(counters) = entry.counters {
+            core.counters = *counters;
+        }
+        core.counters.set_totals(entry.totals);
+        core.privilege = entry.privilege;
+        core.csrs = entry.csrs;
+        core.mip = entry.mip;
+        if let Some((address, bytes)) = entry.store {
+            let start = address as usize;
+            core.memory[start..start + bytes.len()].copy_from_slice(&bytes);
+            core.decode_cache.invalidate(address, bytes.len() as u32);
+            core.block_cache.invalidate(address, bytes.len() as u32);
+        }
+        Some(entry.retired)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csr_file::MSCRATCH;
+    use crate::encode::{self, OP_IMM};
+    use crate::test_utils::machine;
+
+    #[test]
+    fn steps_are_undone() {
+        // addi a0, zero, 7; sw a0, 0x100(zero); csrw mscratch, a0
+        let program = [encode::i(OP_IMM, 0b000, 10, 0, 7), encode::s(0b010, 0, 10, 0x100),
+                       encode::csr(0b001, 0, 10, 0x340)];
+        let mut core = machine(0, &program, &[]);
+        core.memory[0x100] = 0xAA;
+        let mut history = History::new(2);
+        for retired in 0..3 {
+            history.record(&core, retired);
+            core.execute();
+        }
+        assert_eq!((core.pc, core.regs[10], core.memory[0x100], core.csrs.get(MSCRATCH)), (12, 7, 7, 7));
+        assert_eq!(history.undo(&mut core), Some(2));
+        assert_eq!((core.pc, core.csrs.get(MSCRATCH)), (8, 0));
+        assert_eq!(history.undo(&mut core), Some(1));
+        assert_eq!((core.pc, core.regs[10], core.memory[0x100]), (4, 7, 0xAA));
+        // the first step fell out of the history
+        assert_eq!(history.undo(&mut core), None);
+        assert_eq!(history.len(), 0);
+    }
+}
@@ -0,0 +1,68 @@
+//! Host calls through plain `ecall`, for test programs too small to set up
+//! HTIF, semihosting or a UART: a7 selects the call like the Linux syscall
+//! numbers, a0 and a1 carry the arguments
+
+use crate::encode::ECALL;
+use crate::semihosting::Semihosting;
+use crate::CoreState;
+
+/// Writes a1 bytes from a0 to stdout, a0 = bytes written or -1
+const WRITE: u32 = 64;
+/// Exits with code a0
+const EXIT: u32 = 93;
+
+/// Whether pc points at an `ecall` with a host call number in a7, other
+/// ecalls trap as usual
+pub(crate) fn is_call(core: &CoreState) -> bool {
+    let start = core.pc as usize;
+    core.memory.get(start..start + 4) == Some(&ECALL.to_le_bytes()[..]) && matches!(core.regs[17], WRITE | EXIT)
+}
+
+/// Services the call at pc, returns the exit code if the guest exited
+pub(crate) fn call(core: &mut CoreState, console: &mut Semihosting) -> Option<i32> {
+    let (a0, a1) = (core.regs[10], core.regs[11]);
+    match core.regs[17] {
+        EXIT => return Some(a0 as i32),
+        _ => {
+            let data = core.memory.get(a0 as usize..a0 as usize + a1 as usize);
+            let written = match data.map(|data| console.stdout(data)) {
+                Some(Ok(())) => a1,
+                _ => u32::MAX,
+            };
+            core.regs.write(10, written);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{self, OP_IMM};
+    use crate::test_utils::machine;
+
+    #[test]
+    fn write_and_exit() {
+        let mut core = machine(0, &[ECALL, encode::i(OP_IMM, 0b000, 0, 0, 0)], &[(10, 0x100), (11, 3), (17, WRITE)]);
+        core.memory[0x100..0x103].copy_from_slice(b"hi\n");
+        let mut console = Semihosting::new(String::new());
+        console.quiet = true;
+        console.console = Some(Vec::new());
+        assert!(is_call(&core));
+        assert_eq!(call(&mut core, &mut console), None);
+        assert_eq!((core.regs[10], console.console.as_deref()), (3, Some(&b"hi\n"[..])));
+
+        core.regs.write(11, 0x10000);
+        call(&mut core, &mut console);
+        assert_eq!(core.regs[10], u32::MAX);
+        core.regs.write(17, EXIT);
+        core.regs.write(10, 7);
+        assert_eq!(call(&mut core, &mut console), Some(7));
+        // not a host call number, or not an ecall
+        core.regs.write(17, 1);
+        assert!(!is_call(&core));
+        core.regs.write(17, EXIT);
+        core.pc = 4;
+        assert!(!is_call(&core));
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::fs;
+
+use crate::disasm::pseudo;
+use crate::encode::{BRANCH, JAL, JALR, SYSTEM};
+use crate::loader::{function_at, Function, Image};
+use crate::{CoreState, Observer};
+
+/// Blocks listed when the spec gives no count
+pub const DEFAULT_TOP: usize = 10;
+
+#[derive(Default)]
+struct Block {
+    executions: u64,
+    instructions: u64,
+    // words of the longest run seen from this start
+    code: Vec<u32>,
+}
+
+/// Dynamic basic blocks, runs of instructions entered by a jump or taken
+/// branch and left by the next control transfer. The report lists the `top`
+/// blocks that retired the most instructions, with their symbols and
+/// disassembly, and is written to `path` when dropped.
+pub struct Hotspots {
+    path: String,
+    top: usize,
+    functions: Vec<Function>,
+    blocks: HashMap<u32, Block>,
+    // start and words of the block being executed
+    start: u32,
+    code: Vec<u32>,
+    // pc of the last instruction and whether it ends a block
+    last: Option<(u32, bool)>,
+}
+
+impl Hotspots {
+    /// `spec` is `file` or `file:N` to list N blocks
+    pub fn new(spec: &str) -> Self {
+        let (path, top) = match spec.rsplit_once(':').map(|(path, n)| (path, n.parse())) {
+            Some((path, Ok(top))) => (path, top),
+            _ => (spec, DEFAULT_TOP),
+        };
+        Self {
+            path: path.to_string(),
+            top,
+            functions: Vec::new(),
+            blocks: HashMap::new(),
+            start: 0,
+            code: Vec::new(),
+            last: None,
+        }
+    }
+
+    fn close(&mut self) {
+        if self.code.is_empty() {
+            return;
+        }
+        let block = self.blocks.entry(self.start).or_default();
+        block.executions += 1;
+        block.instructions += self.code.len() as u64;
+        if self.code.len() > block.code.len() {
+            block.code = self.code.clone();
+        }
+        self.code.clear();
+    }
+
+    fn symbol(&self, address: u32) -> String {
+        match function_at(&self.functions, address) {
+            Some(i) if address == self.functions[i].start => self.functions[i].name.clone(),
+            Some(i) => format!("{}+0x{:x}", self.functions[i].name, address - self.functions[i].start),
+            None => "?".to_string(),
+        }
+    }
+
+    pub fn report(&mut self) -> String {
+        self.close();
+        let total: u64 = self.blocks.values().map(|b| b.instructions).sum();
+        let mut blocks: Vec<(&u32, &Block)> = self.blocks.iter().collect();
+        blocks.sort_by(|a, b| b.1.instructions.cmp(&a.1.instructions).then(a.0.cmp(b.0)));
+        let mut report = format!("{} blocks, {} instructions\n", blocks.len(), total);
+        for (&start, block) in blocks.into_iter().take(self.top) {
+            report += &format!("\n0x{:08x} {}: {} executions, {} instructions ({:.1}%)\n", start, self.symbol(start),
+                               block.executions, block.instructions,
+                               100.0 * block.instructions as f64 / total.max(1) as f64);
+            for (i, &word) in block.code.iter().enumerate() {
+                let pc = start + 4 * i as u32;
+                report += &format!("  0x{:08x}  {:08x}  {}\n", pc, word, pseudo(word, pc, &self.functions));
+            }
+        }
+        report
+    }
+}
+
+impl Observer for Hotspots {
+    fn loaded(&mut self, image: &Image) {
+        self.functions = image.functions.clone();
+    }
+
+    fn step(&mut self, core: &CoreState) {
+        let Some(bytes) = core.memory.get(core.pc as usize..core.pc as usize + 4) else {
+            return;
+        };
+        let word = u32::from_le_bytes(bytes.try_into().unwrap());
+        match self.last {
+            Some((pc, ends)) if !ends && core.pc == pc.wrapping_add(4) => {}
+            _ => {
+                self.close();
+                self.start = core.pc;
+            }
+        }
+        self.code.push(word);
+        let ends = matches!(word & 0x7F, BRANCH | JAL | JALR | SYSTEM);
+        self.last = Some((core.pc, ends));
+    }
+}
+
+impl Drop for Hotspots {
+    fn drop(&mut self) {
+        let report = self.report();
+        if let Err(e) = fs::write(&self.path, report) {
+            eprintln!("{}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{self, OP_IMM};
+    use crate::test_utils::machine;
+
+    #[test]
+    fn loop_body_is_the_hottest_block() {
+        // addi x1, x0, 3; loop: addi x1, x1, -1; bne x1, x0, loop; ecall
+        let program = [encode::i(OP_IMM, 0b000, 1, 0, 3), encode::i(OP_IMM, 0b000, 1, 1, -1),
+                       encode::b(0b001, 1, 0, -4), encode::ECALL];
+        let mut core = machine(0, &program, &[]);
+        let mut hotspots = Hotspots::new("/dev/null:1");
+        hotspots.functions = vec![Function {start: 0, size: 16, name: "main".to_string()}];
+        for _ in 0..8 {
+            hotspots.step(&core);
+            core.execute();
+        }
+        let report = hotspots.report();
+        assert!(report.starts_with("3 blocks, 8 instructions\n\n\
+                                    0x00000004 main+0x4: 2 executions, 4 instructions (50.0%)\n\
+                                    \x20 0x00000004  fff08093  addi ra, ra, -1\n"), "{}", report);
+        // top 1
+        assert!(!report.contains("0x00000000 main"));
+        assert_eq!(Hotspots::new("/dev/null").top, DEFAULT_TOP);
+    }
+}
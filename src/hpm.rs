@@ -0,0 +1,203 @@
+use crate::encode::{BRANCH, LOAD, STORE};
+
+/// mhpmevent3 through mhpmevent31
+pub const COUNTERS: usize = 29;
+
+/// Events selectable with mhpmevent, the value written is the event number.
+/// Mispredictions and cache misses come from the timing model and stay at
+/// zero without `--predictor` or the caches.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Event {
+    None = 0,
+    Loads = 1,
+    Stores = 2,
+    Branches = 3,
+    TakenBranches = 4,
+    Mispredictions = 5,
+    IcacheMisses = 6,
+    DcacheMisses = 7,
+}
+
+impl Event {
+    /// WARL: unsupported event numbers count nothing
+    pub fn from_value(value: u32) -> Self {
+        match value {
+            1 => Self::Loads,
+            2 => Self::Stores,
+            3 => Self::Branches,
+            4 => Self::TakenBranches,
+            5 => Self::Mispredictions,
+            6 => Self::IcacheMisses,
+            7 => Self::DcacheMisses,
+            _ => Self::None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Counter {
+    event: Event,
+    // counter value when it was last written, its event selected or it was
+    // inhibited or restarted, and the event total at that point
+    value: u64,
+    start: u64,
+    // inhibited by mcountinhibit, stays at `value`
+    frozen: bool,
+}
+
+impl Counter {
+    fn count(&self, total: u64) -> u64 {
+        if self.frozen {
+            return self.value;
+        }
+        self.value.wrapping_add(total.wrapping_sub(self.start))
+    }
+
+    fn freeze(&mut self, frozen: bool, total: u64) {
+        if frozen != self.frozen {
+            *self = Counter {event: self.event, value: self.count(total), start: total, frozen};
+        }
+    }
+}
+
+/// mcycle, minstret, mhpmcounter3..31 and their events. Counters are
+/// computed from running event totals when read, so only the core-side
+/// events (loads, stores, branches) cost anything per instruction, and only
+/// while selected.
+#[derive(Clone)]
+pub(crate) struct Counters {
+    counters: [Counter; COUNTERS],
+    cycle: Counter,
+    instret: Counter,
+    /// Instructions retired, trapping ones don't count
+    pub retired: u64,
+    loads: u64,
+    stores: u64,
+    branches: u64,
+    taken: u64,
+    /// Some counter selects a core-side event, or `count_all`
+    pub active: bool,
+    all: bool,
+}
+
+impl Counters {
+    pub fn new() -> Self {
+        Self {
+            counters: [Counter {event: Event::None, value: 0, start: 0, frozen: false}; COUNTERS],
+            cycle: Counter {event: Event::None, value: 0, start: 0, frozen: false},
+            instret: Counter {event: Event::None, value: 0, start: 0, frozen: false},
+            retired: 0,
+            loads: 0,
+            stores: 0,
+            branches: 0,
+            taken: 0,
+            active: false,
+            all: false,
+        }
+    }
+
+    /// Counts the core-side events of an executed instruction
+    pub fn retire(&mut self, instruction: u32, taken: bool) {
+        match instruction & 0x7F {
+            LOAD => self.loads += 1,
+            STORE => self.stores += 1,
+            BRANCH => {
+                self.branches += 1;
+                self.taken += taken as u64;
+            }
+            _ => {}
+        }
+    }
+
+    /// Total of a core-side event, None for events the timing model counts
+    pub fn total(&self, event: Event) -> Option<u64> {
+        match event {
+            Event::None => Some(0),
+            Event::Loads => Some(self.loads),
+            Event::Stores => Some(self.stores),
+            Event::Branches => Some(self.branches),
+            Event::TakenBranches => Some(self.taken),
+            _ => None,
+        }
+    }
+
+    /// Loads, stores, branches, taken branches and retired instructions so
+    /// far
+    pub(crate) fn totals(&self) -> [u64; 5] {
+        [self.loads, self.stores, self.branches, self.taken, self.retired]
+    }
+
+    pub(crate) fn set_totals(&mut self, totals: [u64; 5]) {
+        [self.loads, self.stores, self.branches, self.taken, self.retired] = totals;
+    }
+
+    /// mcycle, given the elapsed cycles
+    pub fn cycle(&self, cycles: u64) -> u64 {
+        self.cycle.count(cycles)
+    }
+
+    pub fn write_cycle(&mut self, value: u64, cycles: u64) {
+        self.cycle = Counter {event: Event::None, value, start: cycles, frozen: self.cycle.frozen};
+    }
+
+    pub fn instret(&self) -> u64 {
+        self.instret.count(self.retired)
+    }
+
+    /// Writes minstret from a CSR instruction, which doesn't count itself:
+    /// the next instruction reads `value`
+    pub fn write_instret(&mut self, value: u64) {
+        self.instret = Counter {event: Event::None, value, start: self.retired + 1, frozen: self.instret.frozen};
+    }
+
+    /// mcountinhibit: bit 0 mcycle, bit 2 minstret, bits 3..31 the hpm
+    /// counters. time isn't a counter of the core, bit 1 is zero.
+    pub fn inhibited(&self) -> u32 {
+        let hpm = self.counters.iter().enumerate().fold(0, |mask, (i, c)| mask | (c.frozen as u32) << (i + 3));
+        hpm | self.cycle.frozen as u32 | (self.instret.frozen as u32) << 2
+    }
+
+    /// Writes mcountinhibit from a CSR instruction, given the elapsed cycles
+    /// and the event totals of the hpm counters. Counters freeze at their
+    /// current value and restart from it. minstret doesn't count the writing
+    /// instruction either way, mcycle counts the cycles of the one restarting
+    /// it.
+    pub fn inhibit(&mut self, mask: u32, cycles: u64, totals: [u64; COUNTERS]) {
+        self.cycle.freeze(mask & 1 != 0, cycles);
+        let frozen = mask & 0b100 != 0;
+        self.instret.freeze(frozen, self.retired + !frozen as u64);
+        for (i, counter) in self.counters.iter_mut().enumerate() {
+            counter.freeze((mask >> (i + 3)) & 1 != 0, totals[i]);
+        }
+    }
+
+    pub fn event(&self, index: usize) -> Event {
+        self.counters[index].event
+    }
+
+    /// Value of counter `index`, given the current total of its event
+    pub fn value(&self, index: usize, total: u64) -> u64 {
+        self.counters[index].count(total)
+    }
+
+    pub fn write(&mut self, index: usize, value: u64, total: u64) {
+        let counter = &mut self.counters[index];
+        counter.value = value;
+        counter.start = total;
+    }
+
+    /// Counts the core-side events whatever the counters select, for
+    /// reports on the whole run
+    pub fn count_all(&mut self) {
+        self.all = true;
+        self.active = true;
+    }
+
+    /// Selects `event` for counter `index`; `value` and `total` are the
+    /// counter's current value and the new event's total
+    pub fn select(&mut self, index: usize, event: Event, value: u64, total: u64) {
+        self.counters[index] = Counter {event, value, start: total, frozen: self.counters[index].frozen};
+        self.active = self.all || self.counters.iter().any(|c| matches!(
+            c.event, Event::Loads | Event::Stores | Event::Branches | Event::TakenBranches));
+    }
+}
@@ -0,0 +1,126 @@
+use std::io::{self, Write};
+
+use crate::linux::{Process, Syscall};
+use crate::{CoreState, Device};
+
+const DEVICE_SYSCALL: u64 = 0;
+const DEVICE_CONSOLE: u64 = 1;
+const CONSOLE_PUTCHAR: u64 = 1;
+
+/// Berkeley host-target interface as used by riscv-pk and the riscv-tests
+/// benchmarks: the guest writes `device << 56 | cmd << 48 | payload` to the
+/// 64-bit `tohost` word and the host acknowledges through `fromhost`.
+///
+/// Device 0 either exits (`payload & 1`, code in the upper bits) or points at
+/// a `magic_mem` packet `[syscall, a0, a1, a2, ...]` of 64-bit words whose
+/// first word receives the result. Device 1 command 1 prints a character.
+pub struct Htif {
+    tohost: u32,
+    fromhost: Option<u32>,
+    process: Process,
+}
+
+impl Htif {
+    pub fn new(tohost: u32, fromhost: Option<u32>) -> Self {
+        Self {
+            tohost,
+            fromhost,
+            // the proxy kernel manages brk/mmap itself
+            process: Process::new(0, 0),
+        }
+    }
+
+    /// The tohost and fromhost words
+    pub fn devices(&self) -> Vec<Device> {
+        let mut devices = vec![("htif.tohost", self.tohost, 8)];
+        devices.extend(self.fromhost.map(|fromhost| ("htif.fromhost", fromhost, 8)));
+        devices
+    }
+
+    /// Keeps a copy of the console and proxied stdout output
+    pub fn capture_console(&mut self) {
+        self.process.console = Some(Vec::new());
+    }
+
+    /// Drops console and proxied stdout output instead of printing it
+    pub fn quiet(&mut self) {
+        self.process.quiet = true;
+    }
+
+    /// Output kept since the last call
+    pub fn take_console(&mut self) -> Vec<u8> {
+        self.process.console.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    fn read_u64(core: &CoreState, address: u32) -> Option<u64> {
+        let start = address as usize;
+        core.memory.get(start..start + 8).map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn write_u64(core: &mut CoreState, address: u32, value: u64) {
+        let start = address as usize;
+        if let Some(target) = core.memory.get_mut(start..start + 8) {
+            target.copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    /// Called after every step, services a command once the guest touched the
+    /// high half of tohost (RV32 writes the low word first). Returns the exit
+    /// code if the guest exited.
+    pub fn step(&mut self, core: &mut CoreState) -> Option<i32> {
+        let high = self.tohost + 4;
+        match core.last_access {
+            Some((address, size)) if address < high + 4 && address + size > high => {}
+            _ => return None,
+        }
+        let value = Self::read_u64(core, self.tohost).filter(|&v| v != 0)?;
+        Self::write_u64(core, self.tohost, 0);
+
+        let device = value >> 56;
+        let cmd = (value >> 48) & 0xFF;
+        let payload = value & 0xFFFF_FFFF_FFFF;
+        match (device, cmd) {
+            (DEVICE_SYSCALL, 0) if payload & 1 != 0 => return Some((payload >> 1) as i32),
+            (DEVICE_SYSCALL, 0) => {
+                let magic = payload as u32;
+                let packet: Vec<u32> = (0..5)
+                    .map(|i| Self::read_u64(core, magic.wrapping_add(8 * i)).unwrap_or(0) as u32)
+                    .collect();
+                match self.process.call(core, packet[0], [packet[1], packet[2], packet[3], packet[4]]) {
+                    Syscall::Return(result) => Self::write_u64(core, magic, result as i32 as i64 as u64),
+                    Syscall::Exit(code) => return Some(code),
+                }
+            }
+            (DEVICE_CONSOLE, CONSOLE_PUTCHAR) => {
+                if !self.process.quiet {
+                    let mut stdout = io::stdout();
+                    let _ = stdout.write_all(&[payload as u8]).and_then(|_| stdout.flush());
+                }
+                if let Some(console) = self.process.console.as_mut() {
+                    console.push(payload as u8);
+                }
+            }
+            _ => eprintln!("unsupported htif command 0x{:016x} at 0x{:08x}", value, core.pc),
+        }
+        if let Some(fromhost) = self.fromhost {
+            Self::write_u64(core, fromhost, (device << 56) | (cmd << 48) | 1);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets_at_the_top_of_the_address_space_are_ignored() {
+        let mut core = CoreState::new(4096);
+        let mut htif = Htif::new(0x100, Some(0x108));
+        Htif::write_u64(&mut core, 0x100, 0xFFFF_FFF8);
+        core.last_access = Some((0x104, 4));
+        assert_eq!(htif.step(&mut core), None);
+        assert_eq!(Htif::read_u64(&core, 0x100), Some(0));
+        assert_eq!(Htif::read_u64(&core, 0x108), Some(1));
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::VecDeque;
+use std::fs;
+
+/// What the script does next
+enum Directive {
+    /// Waits until the console output contains the text
+    Output(Vec<u8>),
+    /// Waits until this many more instructions have retired
+    Instructions(u64),
+    /// Makes the text readable by the guest
+    Send(Vec<u8>),
+}
+
+/// Scripted console input for `run`: the guest's console reads (semihosting
+/// READ and READC on stdin, the SBI getchar) take bytes the script has sent
+/// instead of the host's stdin. The script runs its lines in order, a
+/// `wait` holding back the lines after it:
+///
+/// ```text
+/// # comment
+/// wait "login: "
+/// send "root\n"
+/// wait 100000
+/// send "reboot\n"
+/// ```
+///
+/// `wait "text"` waits for the console output to contain the text (output
+/// printed since the previous such wait matched counts), `wait N` for N
+/// more retired instructions. Strings take `\n`, `\r`, `\t`, `\\`, `\"` and
+/// `\xHH` escapes. Reads return nothing while no input is pending, so the
+/// run is the same on every host.
+pub struct InputScript {
+    directives: VecDeque<Directive>,
+    /// Console output not yet matched by an output wait
+    seen: Vec<u8>,
+    /// Retired count the current instruction wait started at
+    since: Option<u64>,
+    retired: u64,
+    pending: VecDeque<u8>,
+}
+
+impl InputScript {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Self::parse(&source).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    fn parse(source: &str) -> Result<Self, String> {
+        let mut directives = VecDeque::new();
+        for (number, line) in source.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let directive = match line.split_once(' ') {
+                Some(("send", text)) => unquote(text.trim()).map(Directive::Send),
+                Some(("wait", text)) if text.trim().starts_with('"') => unquote(text.trim()).map(Directive::Output),
+                Some(("wait", count)) => count.trim().parse().map(Directive::Instructions)
+                    .map_err(|_| format!("bad instruction count `{}`", count.trim())),
+                _ => Err("expected `send \"text\"`, `wait \"text\"` or `wait instructions`".to_string()),
+            };
+            directives.push_back(directive.map_err(|e| format!("line {}: {}", number + 1, e))?);
+        }
+        let mut script = Self {directives, seen: Vec::new(), since: None, retired: 0, pending: VecDeque::new()};
+        script.advance();
+        Ok(script)
+    }
+
+    /// Runs the lines up to the next wait that isn't over yet
+    fn advance(&mut self) {
+        while let Some(directive) = self.directives.front() {
+            match directive {
+                Directive::Send(text) => self.pending.extend(text),
+                Directive::Output(text) => match self.seen.windows(text.len()).position(|window| window == text) {
+                    Some(at) => {
+                        self.seen.drain(..at + text.len());
+                    }
+                    None => return,
+                }
+                Directive::Instructions(count) => {
+                    let since = *self.since.get_or_insert(self.retired);
+                    if self.retired - since < *count {
+                        return;
+                    }
+                    self.since = None;
+                }
+            }
+            self.directives.pop_front();
+        }
+    }
+
+    /// Called after every step with the instructions retired so far
+    pub(crate) fn step(&mut self, retired: u64) {
+        self.retired = retired;
+        if matches!(self.directives.front(), Some(Directive::Instructions(_))) {
+            self.advance();
+        }
+    }
+
+    /// Console output of the guest
+    pub(crate) fn output(&mut self, data: &[u8]) {
+        if self.directives.iter().any(|directive| matches!(directive, Directive::Output(_))) {
+            self.seen.extend_from_slice(data);
+            self.advance();
+        }
+    }
+
+    /// Takes up to `buffer.len()` pending bytes, returns how many
+    pub(crate) fn read(&mut self, buffer: &mut [u8]) -> usize {
+        let n = buffer.len().min(self.pending.len());
+        for (byte, input) in buffer.iter_mut().zip(self.pending.drain(..n)) {
+            *byte = input;
+        }
+        n
+    }
+
+    pub(crate) fn getchar(&mut self) -> Option<u8> {
+        self.pending.pop_front()
+    }
+}
+
+/// The bytes of a quoted string with escapes
+fn unquote(text: &str) -> Result<Vec<u8>, String> {
+    let inner = text.strip_prefix('"').and_then(|text| text.strip_suffix('"'))
+        .ok_or(format!("expected a quoted string, got `{}`", text))?;
+    let mut bytes = Vec::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut utf8 = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+            continue;
+        }
+        bytes.push(match chars.next() {
+            Some('n') => b'\n',
+            Some('r') => b'\r',
+            Some('t') => b'\t',
+            Some('\\') => b'\\',
+            Some('"') => b'"',
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                u8::from_str_radix(&hex, 16).map_err(|_| format!("bad escape `\\x{}`", hex))?
+            }
+            other => return Err(format!("bad escape `\\{}`", other.map(String::from).unwrap_or_default())),
+        });
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_hold_back_input() {
+        let mut script = InputScript::parse("# boot\nsend \"x\"\nwait \"login: \"\nsend \"root\\n\"\nwait 10\n\
+                                             send \"\\x04\"\n").unwrap();
+        let mut buffer = [0; 8];
+        assert_eq!((script.getchar(), script.getchar()), (Some(b'x'), None));
+        script.output(b"log");
+        script.step(5);
+        assert_eq!(script.read(&mut buffer), 0);
+        script.output(b"in: ");
+        assert_eq!(script.read(&mut buffer), 5);
+        assert_eq!(&buffer[..5], b"root\n");
+        // counted from when the wait began
+        script.step(14);
+        assert_eq!(script.getchar(), None);
+        script.step(15);
+        assert_eq!(script.getchar(), Some(4));
+
+        assert!(InputScript::parse("send root").err().unwrap().starts_with("line 1: expected a quoted string"));
+        assert!(InputScript::parse("wait soon").is_err());
+        assert!(InputScript::parse("type \"x\"").is_err());
+    }
+}
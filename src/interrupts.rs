@@ -0,0 +1,129 @@
+use crate::difftest::Rng;
+use crate::CoreState;
+
+/// mip/mie bits of the supervisor software, timer and external interrupts
+pub const SSI: u32 = 1 << 1;
+pub const STI: u32 = 1 << 5;
+pub const SEI: u32 = 1 << 9;
+/// mip/mie bits of the machine software, timer and external interrupts
+pub const MSI: u32 = 1 << 3;
+pub const MTI: u32 = 1 << 7;
+pub const MEI: u32 = 1 << 11;
+
+const DEFAULT_MEAN: u32 = 1000;
+
+/// Interrupt-timing stress: raises MTI or MEI after seeded random intervals
+/// (uniform up to twice the mean, in instructions) so handlers run at every
+/// point of the guest code. The schedule is printed to stderr when dropped;
+/// the same seed and program give the same schedule.
+pub struct Injector {
+    seed: u32,
+    mean: u32,
+    rng: Rng,
+    steps: u64,
+    next: u64,
+    /// (instruction, interrupt bit, pc) of every injection
+    pub schedule: Vec<(u64, u32, u32)>,
+}
+
+impl Injector {
+    pub fn new(seed: u32, mean: u32) -> Self {
+        let mut injector = Self {seed, mean: mean.max(1), rng: Rng(seed.max(1)), steps: 0, next: 0, schedule: Vec::new()};
+        injector.next = injector.interval();
+        injector
+    }
+
+    /// `seed[:mean interval]`, e.g. `7:500`
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (seed, mean) = spec.split_once(':').unwrap_or((spec, ""));
+        let seed = seed.parse().map_err(|_| format!("bad seed `{}`", seed))?;
+        let mean = match mean {
+            "" => DEFAULT_MEAN,
+            mean => mean.parse().map_err(|_| format!("bad interval `{}`", mean))?,
+        };
+        Ok(Self::new(seed, mean))
+    }
+
+    fn interval(&mut self) -> u64 {
+        1 + self.rng.below(2 * self.mean) as u64
+    }
+
+    /// Called before each instruction, raises the next interrupt when due
+    pub fn step(&mut self, core: &mut CoreState) {
+        self.steps += 1;
+        if self.steps < self.next {
+            return;
+        }
+        let bit = if self.rng.below(2) == 0 {MTI} else {MEI};
+        core.mip |= bit;
+        self.schedule.push((self.steps, bit, core.pc));
+        self.next = self.steps + self.interval();
+    }
+
+    pub fn report(&self) -> String {
+        let mut report = format!("interrupts: seed {}, mean interval {}, {} injected\n",
+                                 self.seed, self.mean, self.schedule.len());
+        for &(step, bit, pc) in &self.schedule {
+            let name = if bit == MTI {"timer"} else {"external"};
+            report += &format!("  instruction {:>10}: {:<8} at pc 0x{:08x}\n", step, name, pc);
+        }
+        report
+    }
+}
+
+impl Drop for Injector {
+    fn drop(&mut self) {
+        eprint!("{}", self.report());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::csr_file::{MCAUSE, MEPC};
+    use super::*;
+    use crate::encode::{self, MRET, OP_IMM};
+    use crate::test_utils::machine;
+
+    #[test]
+    fn interrupts_are_taken_when_enabled() {
+        // mtvec = 0x40 vectored; the handler at 0x40 + 4 * 7 counts in x5
+        let mut program = vec![encode::i(OP_IMM, 0b000, 0, 0, 0); 32];
+        program[0] = encode::csr(0b001, 0, 1, 0x305);
+        program[1] = encode::csr(0b001, 0, 2, 0x304);
+        program[2] = encode::csr(0b001, 0, 3, 0x300);
+        program[0x10 + 7] = encode::i(OP_IMM, 0b000, 5, 5, 1);
+        program[0x10 + 8] = MRET;
+        let mut core = machine(0, &program, &[(1, 0x41), (2, MTI), (3, 1 << 3)]);
+        // pending but disabled in mstatus until the third instruction
+        core.mip = MTI | MEI;
+        for _ in 0..3 {
+            assert_eq!(core.dispatch(crate::Engine::Step), 1);
+        }
+        assert_eq!(core.dispatch(crate::Engine::Step), 0);
+        assert_eq!((core.pc, core.csrs.get(MEPC), core.regs[5]), (0x5C, 12, 0));
+        // MEI isn't enabled in mie and stays pending
+        assert_eq!(core.mip, MEI);
+        assert_eq!(core.csrs.get(MCAUSE), 0x8000_0007);
+        core.dispatch(crate::Engine::Step);
+        core.dispatch(crate::Engine::Step);
+        assert_eq!((core.pc, core.regs[5]), (12, 1));
+    }
+
+    #[test]
+    fn schedule_is_reproducible() {
+        let run = |seed| {
+            let mut injector = Injector::parse(&format!("{}:20", seed)).unwrap();
+            let mut core = CoreState::new(64);
+            for _ in 0..1000 {
+                injector.step(&mut core);
+                core.mip = 0;
+            }
+            std::mem::take(&mut injector.schedule)
+        };
+        let schedule = run(7);
+        assert!((20..80).contains(&schedule.len()), "{}", schedule.len());
+        assert_eq!(schedule, run(7));
+        assert_ne!(schedule, run(8));
+        assert!(Injector::parse("x").is_err());
+    }
+}
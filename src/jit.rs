@@ -0,0 +1,561 @@
+use std::collections::{HashMap, HashSet};
+use std::mem::{offset_of, ManuallyDrop};
+
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{types, AbiParam, InstBuilder, MemFlagsData, SigRef, Value};
+use cranelift_codegen::Context;
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Module};
+
+use crate::block_cache::overlaps;
+use crate::memory::{Access, Size};
+use crate::{CoreState, Instruction};
+
+/// Dispatches of a block through the block interpreter before it is compiled
+const HOT: u32 = 16;
+
+const PAGE_SHIFT: u32 = 12;
+
+// results of the load and store helpers
+const LOAD_FAULT: u64 = 1 << 32;
+const STORED: u32 = 0;
+const STORE_FAULT: u32 = 1;
+// a store hit cached code or the watched range
+const STORE_INTERRUPTED: u32 = 2;
+
+// access argument of the load helper: the size in bytes, sign-extended with
+// SIGNED
+const SIGNED: u32 = 1 << 3;
+
+/// A compiled block: runs the core's instructions from pc, leaves pc after
+/// the last one that retired and returns how many did. It stops before an
+/// instruction that traps so that the interpreter raises the exception.
+type Code = extern "C" fn(&mut CoreState) -> u32;
+
+struct Translation {
+    code: Code,
+    // bytes of guest code it was compiled from
+    len: u32,
+    // bit i set when instruction i is a load or store
+    accesses: u64,
+}
+
+/// Cranelift translations of hot basic blocks, keyed by start pc like the
+/// block cache and dropped with it. The code of dropped translations is
+/// only freed with the whole `Jit`, as a compiled block may be the one
+/// whose store dropped it.
+pub struct Jit {
+    module: ManuallyDrop<JITModule>,
+    context: Context,
+    builder: FunctionBuilderContext,
+    translations: HashMap<u32, Translation>,
+    // pages holding at least one translation
+    code_pages: HashSet<u32>,
+    // dispatches of the blocks not compiled yet
+    heat: HashMap<u32, u32>,
+}
+
+impl Jit {
+    pub fn new() -> Self {
+        let builder = JITBuilder::with_flags(&[("opt_level", "speed")], default_libcall_names())
+            .expect("Cranelift doesn't support the host");
+        let module = JITModule::new(builder);
+        Self {
+            context: module.make_context(),
+            module: ManuallyDrop::new(module),
+            builder: FunctionBuilderContext::new(),
+            translations: HashMap::new(),
+            code_pages: HashSet::new(),
+            heat: HashMap::new(),
+        }
+    }
+
+    /// Drops the translations overlapping `size` bytes at `address`, returns
+    /// whether there were any
+    pub fn invalidate(&mut self, address: u32, size: u32) -> bool {
+        if size == 0 {
+            return false;
+        }
+        let (first, last) = (address >> PAGE_SHIFT, address.saturating_add(size - 1) >> PAGE_SHIFT);
+        if !(first..=last).any(|page| self.code_pages.contains(&page)) {
+            return false;
+        }
+        let before = self.translations.len();
+        // a dropped block cools down again, code rewriting itself in a loop
+        // would be compiled on every trip otherwise
+        self.translations.retain(|&pc, translation| {
+            let keep = !overlaps(address, size, pc, translation.len);
+            if !keep {
+                self.heat.remove(&pc);
+            }
+            keep
+        });
+        self.translations.len() != before
+    }
+
+    /// Counts a dispatch of the block at `pc`, true once it is hot
+    fn heat(&mut self, pc: u32) -> bool {
+        let heat = self.heat.entry(pc).or_insert(0);
+        *heat += 1;
+        *heat >= HOT
+    }
+
+    /// Compiles the block of `instructions` at `pc`
+    fn compile(&mut self, pc: u32, instructions: &[Instruction]) {
+        let config = self.module.target_config();
+        let pointer = config.pointer_type();
+        let mut signature = self.module.make_signature();
+        signature.params.push(AbiParam::new(pointer));
+        signature.returns.push(AbiParam::new(types::I32));
+        let mut load = self.module.make_signature();
+        load.params.extend([AbiParam::new(pointer), AbiParam::new(types::I32), AbiParam::new(types::I32)]);
+        load.returns.push(AbiParam::new(types::I64));
+        let mut store = self.module.make_signature();
+        store.params.extend([pointer, types::I32, types::I32, types::I32].map(AbiParam::new));
+        store.returns.push(AbiParam::new(types::I32));
+
+        let id = self.module.declare_anonymous_function(&signature).expect("JIT function declaration");
+        self.context.func.signature = signature;
+        let accesses = {
+            let mut builder = FunctionBuilder::new(&mut self.context.func, &mut self.builder);
+            let entry = builder.create_block();
+            builder.append_block_params_for_function_params(entry);
+            builder.switch_to_block(entry);
+            let core = builder.block_params(entry)[0];
+            let regs = [(); 32].map(|_| builder.declare_var(types::I32));
+            let (load, store) = (builder.import_signature(load), builder.import_signature(store));
+            let mut translator = Translator {builder, pointer, core, regs, loaded: 0, dirty: 0, load, store, accesses: 0};
+            translator.block(pc, instructions);
+            translator.builder.seal_all_blocks();
+            translator.builder.finalize(config);
+            translator.accesses
+        };
+        self.module.define_function(id, &mut self.context).expect("JIT translation");
+        self.module.clear_context(&mut self.context);
+        self.module.finalize_definitions().expect("JIT relocation");
+        // SAFETY: the function was built with the signature of `Code`
+        let code = unsafe { std::mem::transmute::<*const u8, Code>(self.module.get_finalized_function(id)) };
+        let len = 4 * instructions.len() as u32;
+        self.code_pages.insert(pc >> PAGE_SHIFT);
+        self.code_pages.insert(pc.wrapping_add(len - 1) >> PAGE_SHIFT);
+        self.translations.insert(pc, Translation {code, len, accesses});
+    }
+}
+
+impl Drop for Jit {
+    fn drop(&mut self) {
+        // SAFETY: compiled blocks only run inside `execute_compiled`, which
+        // borrows the core and so its `Jit`
+        unsafe { ManuallyDrop::take(&mut self.module).free_memory() };
+    }
+}
+
+/// Builds the function of one block. The guest registers live in variables,
+/// read from the core on first use and written back at every exit.
+struct Translator<'a> {
+    builder: FunctionBuilder<'a>,
+    pointer: types::Type,
+    core: Value,
+    regs: [Variable; 32],
+    // bit r set once x<r> has a value, or was written
+    loaded: u32,
+    dirty: u32,
+    load: SigRef,
+    store: SigRef,
+    accesses: u64,
+}
+
+impl Translator<'_> {
+    fn constant(&mut self, value: u32) -> Value {
+        self.builder.ins().iconst(types::I32, value as i64)
+    }
+
+    fn reg_offset(reg: usize) -> i32 {
+        (offset_of!(CoreState, regs) + 4 * reg) as i32
+    }
+
+    fn read(&mut self, reg: usize) -> Value {
+        if reg == 0 {
+            return self.constant(0);
+        }
+        if self.loaded & 1 << reg == 0 {
+            let value = self.builder.ins().load(types::I32, MemFlagsData::trusted(), self.core, Self::reg_offset(reg));
+            self.builder.def_var(self.regs[reg], value);
+            self.loaded |= 1 << reg;
+        }
+        self.builder.use_var(self.regs[reg])
+    }
+
+    fn write(&mut self, reg: usize, value: Value) {
+        if reg != 0 {
+            self.builder.def_var(self.regs[reg], value);
+            self.loaded |= 1 << reg;
+            self.dirty |= 1 << reg;
+        }
+    }
+
+    /// Writes the registers back, sets pc and returns `retired`
+    fn exit(&mut self, pc: Value, retired: usize) {
+        for reg in (1..32).filter(|reg| self.dirty & 1 << reg != 0) {
+            let value = self.builder.use_var(self.regs[reg]);
+            self.builder.ins().store(MemFlagsData::trusted(), value, self.core, Self::reg_offset(reg));
+        }
+        self.builder.ins().store(MemFlagsData::trusted(), pc, self.core, offset_of!(CoreState, pc) as i32);
+        let retired = self.constant(retired as u32);
+        self.builder.ins().return_(&[retired]);
+    }
+
+    /// `exit` when `condition` is nonzero, going on otherwise
+    fn exit_if(&mut self, condition: Value, pc: u32, retired: usize) {
+        let (exit, next) = (self.builder.create_block(), self.builder.create_block());
+        self.builder.ins().brif(condition, exit, &[], next, &[]);
+        self.builder.set_cold_block(exit);
+        self.builder.switch_to_block(exit);
+        let pc = self.constant(pc);
+        self.exit(pc, retired);
+        self.builder.switch_to_block(next);
+    }
+
+    fn helper(&mut self, signature: SigRef, function: *const (), args: &[Value]) -> Value {
+        let function = self.builder.ins().iconst(self.pointer, function as i64);
+        let args: Vec<Value> = [self.core].into_iter().chain(args.iter().copied()).collect();
+        let call = self.builder.ins().call_indirect(signature, function, &args);
+        self.builder.inst_results(call)[0]
+    }
+
+    fn block(&mut self, start: u32, instructions: &[Instruction]) {
+        for (i, instruction) in instructions.iter().enumerate() {
+            let pc = start.wrapping_add(4 * i as u32);
+            if !self.instruction(i, pc, instruction) {
+                return;
+            }
+        }
+        let end = self.constant(start.wrapping_add(4 * instructions.len() as u32));
+        self.exit(end, instructions.len());
+    }
+
+    /// Translates instruction `i` at `pc`, false if it ended the function
+    fn instruction(&mut self, i: usize, pc: u32, instruction: &Instruction) -> bool {
+        use Instruction::*;
+        let (rd, value) = match *instruction {
+            Lui(args) => (args.rd, self.constant(args.imm as u32)),
+            Auipc(args) => (args.rd, self.constant(pc.wrapping_add(args.imm as u32))),
+            Jal(args) => {
+                let target = pc.wrapping_add(args.imm as u32);
+                self.jump(i, pc, args.rd, None, target);
+                return false;
+            }
+            Jalr(args) => {
+                let base = self.read(args.rs1);
+                let target = self.builder.ins().iadd_imm_s(base, args.imm as i64);
+                let target = self.builder.ins().band_imm_s(target, !1);
+                self.jump(i, pc, args.rd, Some(target), 0);
+                return false;
+            }
+            Beq(args) => return self.branch(i, pc, IntCC::Equal, args.rs1, args.rs2, args.imm),
+            Bne(args) => return self.branch(i, pc, IntCC::NotEqual, args.rs1, args.rs2, args.imm),
+            Blt(args) => return self.branch(i, pc, IntCC::SignedLessThan, args.rs1, args.rs2, args.imm),
+            Bge(args) => return self.branch(i, pc, IntCC::SignedGreaterThanOrEqual, args.rs1, args.rs2, args.imm),
+            Bltu(args) => return self.branch(i, pc, IntCC::UnsignedLessThan, args.rs1, args.rs2, args.imm),
+            Bgeu(args) => return self.branch(i, pc, IntCC::UnsignedGreaterThanOrEqual, args.rs1, args.rs2, args.imm),
+            Lb(args) => (args.rd, self.load_value(i, pc, args.rs1, args.imm, Size::Byte as u32 | SIGNED)),
+            Lh(args) => (args.rd, self.load_value(i, pc, args.rs1, args.imm, Size::Half as u32 | SIGNED)),
+            Lw(args) => (args.rd, self.load_value(i, pc, args.rs1, args.imm, Size::Word as u32)),
+            Lbu(args) => (args.rd, self.load_value(i, pc, args.rs1, args.imm, Size::Byte as u32)),
+            Lhu(args) => (args.rd, self.load_value(i, pc, args.rs1, args.imm, Size::Half as u32)),
+            Sb(args) => return self.store_value(i, pc, args.rs1, args.rs2, args.imm, Size::Byte),
+            Sh(args) => return self.store_value(i, pc, args.rs1, args.rs2, args.imm, Size::Half),
+            Sw(args) => return self.store_value(i, pc, args.rs1, args.rs2, args.imm, Size::Word),
+            Addi(args) => {
+                let rs1 = self.read(args.rs1);
+                (args.rd, self.builder.ins().iadd_imm_s(rs1, args.imm as i64))
+            }
+            Slti(args) => {
+                let rs1 = self.read(args.rs1);
+                (args.rd, self.compare_imm(IntCC::SignedLessThan, rs1, args.imm))
+            }
+            Sltiu(args) => {
+                let rs1 = self.read(args.rs1);
+                (args.rd, self.compare_imm(IntCC::UnsignedLessThan, rs1, args.imm))
+            }
+            Xori(args) => {
+                let rs1 = self.read(args.rs1);
+                (args.rd, self.builder.ins().bxor_imm_s(rs1, args.imm as i64))
+            }
+            Ori(args) => {
+                let rs1 = self.read(args.rs1);
+                (args.rd, self.builder.ins().bor_imm_s(rs1, args.imm as i64))
+            }
+            Andi(args) => {
+                let rs1 = self.read(args.rs1);
+                (args.rd, self.builder.ins().band_imm_s(rs1, args.imm as i64))
+            }
+            Slli(args) => {
+                let rs1 = self.read(args.rs1);
+                (args.rd, self.builder.ins().ishl_imm_u(rs1, (args.shamt & 0x1F) as i64))
+            }
+            Srli(args) => {
+                let rs1 = self.read(args.rs1);
+                (args.rd, self.builder.ins().ushr_imm_u(rs1, (args.shamt & 0x1F) as i64))
+            }
+            Srai(args) => {
+                let rs1 = self.read(args.rs1);
+                (args.rd, self.builder.ins().sshr_imm_u(rs1, (args.shamt & 0x1F) as i64))
+            }
+            Slt(args) | Sltu(args) => {
+                let (rs1, rs2) = (self.read(args.rs1), self.read(args.rs2));
+                let condition = match instruction {
+                    Slt(_) => IntCC::SignedLessThan,
+                    _ => IntCC::UnsignedLessThan,
+                };
+                (args.rd, self.compare(condition, rs1, rs2))
+            }
+            Add(args) | Sub(args) | Sll(args) | Xor(args) | Srl(args) | Sra(args) | Or(args) | And(args) => {
+                let (rs1, rs2) = (self.read(args.rs1), self.read(args.rs2));
+                // Cranelift shifts take rs2 modulo 32 too
+                let ins = self.builder.ins();
+                let value = match instruction {
+                    Add(_) => ins.iadd(rs1, rs2),
+                    Sub(_) => ins.isub(rs1, rs2),
+                    Sll(_) => ins.ishl(rs1, rs2),
+                    Xor(_) => ins.bxor(rs1, rs2),
+                    Srl(_) => ins.ushr(rs1, rs2),
+                    Sra(_) => ins.sshr(rs1, rs2),
+                    Or(_) => ins.bor(rs1, rs2),
+                    _ => ins.band(rs1, rs2),
+                };
+                (args.rd, value)
+            }
+            Fence | FenceTso | Pause => return true,
+            // SYSTEM instructions end blocks, the interpreter runs them
+            _ => {
+                let pc = self.constant(pc);
+                self.exit(pc, i);
+                return false;
+            }
+        };
+        self.write(rd, value);
+        true
+    }
+
+    fn compare(&mut self, condition: IntCC, a: Value, b: Value) -> Value {
+        let flag = self.builder.ins().icmp(condition, a, b);
+        self.builder.ins().uextend(types::I32, flag)
+    }
+
+    fn compare_imm(&mut self, condition: IntCC, a: Value, imm: i32) -> Value {
+        let flag = self.builder.ins().icmp_imm_s(condition, a, imm as i64);
+        self.builder.ins().uextend(types::I32, flag)
+    }
+
+    /// JAL with a constant `target`, or JALR with `dynamic`: a misaligned
+    /// target exits before the jump
+    fn jump(&mut self, i: usize, pc: u32, rd: usize, dynamic: Option<Value>, target: u32) {
+        let target = match dynamic {
+            Some(target) => {
+                let misaligned = self.builder.ins().band_imm_u(target, 0b11);
+                self.exit_if(misaligned, pc, i);
+                target
+            }
+            None if target & 0b11 != 0 => {
+                let pc = self.constant(pc);
+                self.exit(pc, i);
+                return;
+            }
+            None => self.constant(target),
+        };
+        let link = self.constant(pc.wrapping_add(4));
+        self.write(rd, link);
+        self.exit(target, i + 1);
+    }
+
+    fn branch(&mut self, i: usize, pc: u32, condition: IntCC, rs1: usize, rs2: usize, imm: i32) -> bool {
+        let (rs1, rs2) = (self.read(rs1), self.read(rs2));
+        let taken = self.builder.ins().icmp(condition, rs1, rs2);
+        let (target, next) = (pc.wrapping_add(imm as u32), pc.wrapping_add(4));
+        let pc = match target & 0b11 {
+            0 => {
+                let (target, next) = (self.constant(target), self.constant(next));
+                self.builder.ins().select(taken, target, next)
+            }
+            _ => {
+                self.exit_if(taken, pc, i);
+                self.constant(next)
+            }
+        };
+        self.exit(pc, i + 1);
+        false
+    }
+
+    fn load_value(&mut self, i: usize, pc: u32, rs1: usize, imm: i32, access: u32) -> Value {
+        self.accesses |= 1 << i;
+        let base = self.read(rs1);
+        let address = self.builder.ins().iadd_imm_s(base, imm as i64);
+        let access = self.constant(access);
+        let result = self.helper(self.load, load as *const (), &[address, access]);
+        let fault = self.builder.ins().ushr_imm_u(result, 32);
+        self.exit_if(fault, pc, i);
+        self.builder.ins().ireduce(types::I32, result)
+    }
+
+    fn store_value(&mut self, i: usize, pc: u32, rs1: usize, rs2: usize, imm: i32, size: Size) -> bool {
+        self.accesses |= 1 << i;
+        let (base, value) = (self.read(rs1), self.read(rs2));
+        let address = self.builder.ins().iadd_imm_s(base, imm as i64);
+        let size = self.constant(size as u32);
+        let result = self.helper(self.store, store as *const (), &[address, size, value]);
+        let fault = self.builder.ins().icmp_imm_u(IntCC::Equal, result, STORE_FAULT as i64);
+        self.exit_if(fault, pc, i);
+        let interrupted = self.builder.ins().icmp_imm_u(IntCC::Equal, result, STORE_INTERRUPTED as i64);
+        self.exit_if(interrupted, pc.wrapping_add(4), i + 1);
+        true
+    }
+}
+
+fn size(bytes: u32) -> Size {
+    match bytes {
+        1 => Size::Byte,
+        2 => Size::Half,
+        _ => Size::Word,
+    }
+}
+
+extern "C" fn load(core: &mut CoreState, address: u32, access: u32) -> u64 {
+    core.load(address, size(access & !SIGNED), access & SIGNED != 0).map_or(LOAD_FAULT, u64::from)
+}
+
+extern "C" fn store(core: &mut CoreState, address: u32, size_bytes: u32, value: u32) -> u32 {
+    match core.store(address, size(size_bytes), value) {
+        Err(_) => STORE_FAULT,
+        Ok(()) if core.block_cache.interrupted => STORE_INTERRUPTED,
+        Ok(()) => STORED,
+    }
+}
+
+impl CoreState {
+    /// Runs the compiled block at pc, compiling the block cache's once it is
+    /// hot, and the block interpreter otherwise. Returns the retired
+    /// instructions, 0 to leave pc to `execute`. Only plain machines are
+    /// compiled: anything per instruction (timing, event counters, retire
+    /// logs) or per access (translation, PMP) goes to the interpreter.
+    pub(crate) fn execute_compiled(&mut self) -> usize {
+        let privileges = [self.privilege, self.data_privilege()];
+        if self.translation(Access::Fetch).is_some() || self.translation(Access::Load).is_some()
+            || privileges.iter().any(|&privilege| self.pmp.applies(privilege))
+            || self.timing.is_some() || self.counters.active || self.retire_log.is_some() {
+            return self.execute_block();
+        }
+        let start = self.pc;
+        let jit = self.jit.get_or_insert_with(Jit::new);
+        let Some(&Translation {code, accesses, ..}) = jit.translations.get(&start) else {
+            let retired = self.execute_block();
+            if retired != 0 && self.jit.as_mut().is_some_and(|jit| jit.heat(start)) {
+                self.compile_block(start);
+            }
+            return retired;
+        };
+        self.block_cache.interrupted = false;
+        let retired = code(self) as usize;
+        for i in 0..retired {
+            self.pc_history.push(start.wrapping_add(4 * i as u32));
+        }
+        if retired != 0 && accesses & 1 << (retired - 1) == 0 {
+            self.last_access = None;
+        }
+        self.counters.retired += retired as u64;
+        self.cycles += retired as u64;
+        retired
+    }
+
+    /// Compiles the block cached at `start`, unless a store dropped it
+    fn compile_block(&mut self, start: u32) {
+        let Some(block) = self.block_cache.get(start) else {
+            return;
+        };
+        let instructions: Result<Vec<Instruction>, _> = (0..block.len() as u32)
+            .map(|i| Self::decode_with(self.fetch_at(start.wrapping_add(4 * i)), self.lenient))
+            .collect();
+        if let (Ok(instructions), Some(jit)) = (instructions, self.jit.as_mut()) {
+            jit.compile(start, &instructions);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csr_file::{MCAUSE, MEPC, MTVAL, MTVEC};
+    use crate::encode::{self, LOAD, OP_IMM};
+    use crate::test_utils::machine;
+    use crate::Engine;
+
+    /// Runs `program` until pc reaches `end` on the step and JIT engines
+    /// and checks that they agree, returns the JIT's core
+    fn agree(program: &[u32], regs: &[(usize, u32)], end: u32) -> CoreState {
+        let cores = [Engine::Step, Engine::Jit].map(|engine| {
+            let mut core = machine(0, program, regs);
+            core.csrs.set(MTVEC, 0x200);
+            for (i, byte) in core.memory[0x400..0x600].iter_mut().enumerate() {
+                *byte = (i * 37) as u8;
+            }
+            while core.pc != end {
+                core.dispatch(engine);
+            }
+            core
+        });
+        let [step, jit] = &cores;
+        assert_eq!((jit.regs, jit.cycles, jit.counters.retired, jit.last_access),
+                   (step.regs, step.cycles, step.counters.retired, step.last_access));
+        assert_eq!([MEPC, MCAUSE, MTVAL].map(|csr| jit.csrs.get(csr)), [MEPC, MCAUSE, MTVAL].map(|csr| step.csrs.get(csr)));
+        assert!(jit.memory == step.memory);
+        assert_eq!(jit.pc_history.pcs(), step.pc_history.pcs());
+        let [_, jit] = cores;
+        jit
+    }
+
+    #[test]
+    fn compiled_loops_match_the_interpreter() {
+        // lw t0, 0(a0); slli t1, t0, 3; sra t2, t1, a1; sltu t3, t2, t0;
+        // add a2, a2, t2; sb t3, 1(a0); lbu t4, 2(a0); slti s1, t4, -5;
+        // addi a0, a0, 4; addi a3, a3, -1; bne a3, zero, loop
+        let program = [encode::i(LOAD, 0b010, 5, 10, 0), encode::i(OP_IMM, 0b001, 6, 5, 3),
+                       encode::r(0x20, 0b101, 7, 6, 11), encode::r(0, 0b011, 28, 7, 5),
+                       encode::r(0, 0b000, 12, 12, 7), encode::s(0b000, 10, 28, 1),
+                       encode::i(LOAD, 0b100, 29, 10, 2), encode::i(OP_IMM, 0b010, 9, 29, -5),
+                       encode::i(OP_IMM, 0b000, 10, 10, 4), encode::i(OP_IMM, 0b000, 13, 13, -1),
+                       encode::b(0b001, 13, 0, -40)];
+        let core = agree(&program, &[(10, 0x400), (11, 35), (13, 100)], 44);
+        assert!(core.jit.as_ref().unwrap().translations.contains_key(&0));
+    }
+
+    #[test]
+    fn traps_leave_compiled_code_for_the_interpreter() {
+        // the loop loads past the end of memory once it is compiled:
+        // lw t0, 0(a0); add a2, a2, t0; addi a0, a0, 4; jal zero, loop
+        let program = [encode::i(LOAD, 0b010, 5, 10, 0), encode::r(0, 0b000, 12, 12, 5),
+                       encode::i(OP_IMM, 0b000, 10, 10, 4), encode::j(0, -12)];
+        let core = agree(&program, &[(10, 0x0F00)], 0x200);
+        assert_eq!(core.csrs.get(MTVAL), 0x1000);
+        // a misaligned jalr target: addi t0, t0, -1; bne t0, zero, loop;
+        // jalr zero, 2(zero)
+        let program = [encode::i(OP_IMM, 0b000, 5, 5, -1), encode::b(0b001, 5, 0, -4),
+                       encode::i(encode::JALR, 0b000, 0, 0, 2)];
+        agree(&program, &[(5, 50)], 0x200);
+    }
+
+    #[test]
+    fn stores_into_a_compiled_block_end_it() {
+        // the last trips overwrite the first instruction with t4:
+        // addi a5, a5, 2; sltu t5, a3, a6; slli t5, t5, 10;
+        // xori t5, t5, 0x400; sw t4, 0(t5); addi a3, a3, -1; bne a3, zero, loop
+        let program = [encode::i(OP_IMM, 0b000, 15, 15, 2), encode::r(0, 0b011, 30, 13, 16),
+                       encode::i(OP_IMM, 0b001, 30, 30, 10), encode::i(OP_IMM, 0b100, 30, 30, 0x400),
+                       encode::s(0b010, 30, 29, 0), encode::i(OP_IMM, 0b000, 13, 13, -1),
+                       encode::b(0b001, 13, 0, -24)];
+        let rewritten = encode::i(OP_IMM, 0b000, 15, 15, 100);
+        let core = agree(&program, &[(13, 40), (16, 10), (29, rewritten)], 28);
+        // the trip with a3 at 9 stores, the 8 after it add 100
+        assert_eq!(core.regs[15], 32 * 2 + 8 * 100);
+    }
+}
@@ -0,0 +1,300 @@
+//! Minimal JSON values for the control protocols and result files: parsing,
+//! member access and compact serialization
+
+use std::fmt::{self, Display, Formatter};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    /// Members in document order
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Integral numbers in u32 range
+    pub fn as_u32(&self) -> Option<u32> {
+        match *self {
+            Value::Number(n) if n.fract() == 0.0 && (0.0..=u32::MAX as f64).contains(&n) => Some(n as u32),
+            _ => None,
+        }
+    }
+
+    pub fn object(members: &[(&str, Value)]) -> Value {
+        Value::Object(members.iter().map(|(k, v)| (k.to_string(), v.clone())).collect())
+    }
+}
+
+impl From<u32> for Value {
+    fn from(n: u32) -> Self {
+        Value::Number(n as f64)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(n: u64) -> Self {
+        Value::Number(n as f64)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.to_string())
+    }
+}
+
+fn write_string(f: &mut Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => f.write_str("null"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::String(s) => write_string(f, s),
+            Value::Array(items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_str("]")
+            }
+            Value::Object(members) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    offset: usize,
+}
+
+impl Parser<'_> {
+    fn error<T>(&self, what: &str) -> Result<T, String> {
+        Err(format!("{} at offset {}", what, self.offset))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.text.get(self.offset).is_some_and(|c| c.is_ascii_whitespace()) {
+            self.offset += 1;
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Value) -> Result<Value, String> {
+        if self.text[self.offset..].starts_with(word.as_bytes()) {
+            self.offset += word.len();
+            Ok(value)
+        } else {
+            self.error("unexpected token")
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        match self.text.get(self.offset) {
+            Some(b'n') => self.literal("null", Value::Null),
+            Some(b't') => self.literal("true", Value::Bool(true)),
+            Some(b'f') => self.literal("false", Value::Bool(false)),
+            Some(b'"') => self.string().map(Value::String),
+            Some(b'[') => {
+                self.offset += 1;
+                let mut items = Vec::new();
+                self.skip_whitespace();
+                if self.text.get(self.offset) == Some(&b']') {
+                    self.offset += 1;
+                    return Ok(Value::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.text.get(self.offset) {
+                        Some(b',') => self.offset += 1,
+                        Some(b']') => {
+                            self.offset += 1;
+                            return Ok(Value::Array(items));
+                        }
+                        _ => return self.error("expected , or ]"),
+                    }
+                }
+            }
+            Some(b'{') => {
+                self.offset += 1;
+                let mut members = Vec::new();
+                self.skip_whitespace();
+                if self.text.get(self.offset) == Some(&b'}') {
+                    self.offset += 1;
+                    return Ok(Value::Object(members));
+                }
+                loop {
+                    self.skip_whitespace();
+                    if self.text.get(self.offset) != Some(&b'"') {
+                        return self.error("expected a member name");
+                    }
+                    let key = self.string()?;
+                    self.skip_whitespace();
+                    if self.text.get(self.offset) != Some(&b':') {
+                        return self.error("expected :");
+                    }
+                    self.offset += 1;
+                    members.push((key, self.value()?));
+                    self.skip_whitespace();
+                    match self.text.get(self.offset) {
+                        Some(b',') => self.offset += 1,
+                        Some(b'}') => {
+                            self.offset += 1;
+                            return Ok(Value::Object(members));
+                        }
+                        _ => return self.error("expected , or }"),
+                    }
+                }
+            }
+            Some(b'-' | b'0'..=b'9') => {
+                let start = self.offset;
+                while self.text.get(self.offset).is_some_and(|c| b"+-.eE0123456789".contains(c)) {
+                    self.offset += 1;
+                }
+                let number = std::str::from_utf8(&self.text[start..self.offset]).unwrap();
+                number.parse().map(Value::Number).or_else(|_| self.error("bad number"))
+            }
+            _ => self.error("unexpected token"),
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self.text.get(self.offset..self.offset + 4)
+            .and_then(|d| std::str::from_utf8(d).ok())
+            .and_then(|d| u32::from_str_radix(d, 16).ok());
+        match digits {
+            Some(n) => {
+                self.offset += 4;
+                Ok(n)
+            }
+            None => self.error("bad \\u escape"),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        // opening quote
+        self.offset += 1;
+        let mut bytes = Vec::new();
+        loop {
+            match self.text.get(self.offset) {
+                None => return self.error("unterminated string"),
+                Some(b'"') => {
+                    self.offset += 1;
+                    return String::from_utf8(bytes).or_else(|_| self.error("bad UTF-8"));
+                }
+                Some(b'\\') => {
+                    self.offset += 1;
+                    let escaped = match self.text.get(self.offset) {
+                        Some(b'n') => '\n',
+                        Some(b't') => '\t',
+                        Some(b'r') => '\r',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(&c @ (b'"' | b'\\' | b'/')) => c as char,
+                        Some(b'u') => {
+                            self.offset += 1;
+                            let mut code = self.hex4()?;
+                            // surrogate pair
+                            if (0xD800..0xDC00).contains(&code) && self.text[self.offset..].starts_with(b"\\u") {
+                                self.offset += 2;
+                                code = 0x10000 + ((code - 0xD800) << 10) + (self.hex4()?.saturating_sub(0xDC00) & 0x3FF);
+                            }
+                            self.offset -= 1;
+                            char::from_u32(code).unwrap_or('\u{FFFD}')
+                        }
+                        _ => return self.error("bad escape"),
+                    };
+                    self.offset += 1;
+                    bytes.extend(escaped.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                Some(&c) => {
+                    bytes.push(c);
+                    self.offset += 1;
+                }
+            }
+        }
+    }
+}
+
+pub fn parse(text: &str) -> Result<Value, String> {
+    let mut parser = Parser {text: text.as_bytes(), offset: 0};
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.offset != text.len() {
+        return parser.error("trailing characters");
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let text = r#" {"id": 7, "params": {"list": [1, -2.5, true, null], "s": "a\"b\\né"}, "e": []} "#;
+        let value = parse(text).unwrap();
+        assert_eq!(value.get("id").and_then(Value::as_u32), Some(7));
+        let params = value.get("params").unwrap();
+        assert_eq!(params.get("s").and_then(Value::as_str), Some("a\"b\\né"));
+        assert_eq!(value.to_string(), r#"{"id":7,"params":{"list":[1,-2.5,true,null],"s":"a\"b\\né"},"e":[]}"#);
+        assert_eq!(parse(r#""😀""#).unwrap(), Value::String("😀".to_string()));
+        for bad in ["", "{", "[1,]", "{\"a\" 1}", "tru", "1 2", "\"abc"] {
+            assert!(parse(bad).is_err(), "{}", bad);
+        }
+        assert_eq!(Value::Number(-1.0).as_u32(), None);
+        assert_eq!(Value::Number(4294967295.0).as_u32(), Some(u32::MAX));
+    }
+}
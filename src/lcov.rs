@@ -0,0 +1,93 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+use crate::dwarf::{line_table, LineTable};
+use crate::loader::Image;
+use crate::{CoreState, Observer};
+
+/// Source line coverage: counts executed addresses and maps the statement
+/// rows of the DWARF line table back to lines. A line's count is the most
+/// any of its statements executed. Writes an lcov tracefile (for genhtml)
+/// when dropped.
+pub struct Lcov {
+    path: String,
+    table: LineTable,
+    counts: HashMap<u32, u64>,
+}
+
+impl Lcov {
+    pub fn new(path: &str) -> Self {
+        Self {path: path.to_string(), table: LineTable::default(), counts: HashMap::new()}
+    }
+
+    /// file to line to count, every statement line of the program included
+    fn lines(&self) -> BTreeMap<&str, BTreeMap<u32, u64>> {
+        let mut files: BTreeMap<&str, BTreeMap<u32, u64>> = BTreeMap::new();
+        for row in self.table.rows.iter().filter(|r| r.is_stmt && !r.end_sequence && r.line != 0) {
+            let Some(file) = self.table.files.get(row.file).filter(|f| !f.is_empty()) else {
+                continue;
+            };
+            let count = self.counts.get(&row.address).copied().unwrap_or(0);
+            let line = files.entry(file).or_default().entry(row.line).or_insert(0);
+            *line = (*line).max(count);
+        }
+        files
+    }
+
+    pub fn tracefile(&self) -> String {
+        let mut tracefile = String::from("TN:\n");
+        for (file, lines) in self.lines() {
+            tracefile += &format!("SF:{}\n", file);
+            for (line, count) in &lines {
+                tracefile += &format!("DA:{},{}\n", line, count);
+            }
+            let hit = lines.values().filter(|&&count| count > 0).count();
+            tracefile += &format!("LF:{}\nLH:{}\nend_of_record\n", lines.len(), hit);
+        }
+        tracefile
+    }
+}
+
+impl Observer for Lcov {
+    fn loaded(&mut self, image: &Image) {
+        match line_table(&image.path) {
+            Ok(table) if table.rows.is_empty() => eprintln!("{}: no DWARF line info, coverage is empty", image.path),
+            Ok(table) => self.table = table,
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+
+    fn step(&mut self, core: &CoreState) {
+        *self.counts.entry(core.pc).or_insert(0) += 1;
+    }
+}
+
+impl Drop for Lcov {
+    fn drop(&mut self) {
+        if let Err(e) = fs::write(&self.path, self.tracefile()) {
+            eprintln!("{}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dwarf::Row;
+
+    #[test]
+    fn statement_lines_take_the_highest_count() {
+        let row = |address, file, line, is_stmt| Row {address, file, line, is_stmt, end_sequence: false};
+        let mut lcov = Lcov::new("/dev/null");
+        lcov.table = LineTable {
+            files: vec![String::new(), "a.c".to_string(), "b.c".to_string()],
+            rows: vec![row(0x10, 1, 3, true), row(0x14, 1, 4, true), row(0x18, 1, 3, true),
+                       row(0x1C, 1, 3, false), row(0x20, 2, 7, true),
+                       Row {end_sequence: true, ..row(0x24, 2, 7, true)}],
+        };
+        lcov.counts = HashMap::from([(0x10, 1), (0x18, 5), (0x1C, 9), (0x24, 2)]);
+        assert_eq!(lcov.tracefile(), "TN:\n\
+                                      SF:a.c\nDA:3,5\nDA:4,0\nLF:2\nLH:1\nend_of_record\n\
+                                      SF:b.c\nDA:7,0\nLF:1\nLH:0\nend_of_record\n");
+    }
+}
@@ -0,0 +1,1827 @@
+//! RV32IM machine-mode emulator
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::Receiver;
+
+pub mod act;
+pub mod annotate;
+mod assertions;
+mod block_cache;
+pub mod bootrom;
+pub mod cache;
+pub mod campaign;
+pub mod cfi;
+pub mod compare;
+pub mod coredump;
+pub mod cosim;
+pub mod coverage;
+pub mod dashboard;
+mod csr_file;
+#[cfg(test)]
+mod csr_tests;
+mod decode_cache;
+pub mod difftest;
+pub mod disasm;
+mod dispatch;
+pub mod dwarf;
+mod encode;
+pub mod flamegraph;
+pub mod gdb;
+#[cfg(test)]
+mod golden;
+mod heap;
+pub mod heatmap;
+mod fetch;
+mod history;
+mod hostcall;
+pub mod hotspots;
+pub mod hpm;
+mod htif;
+pub mod input;
+pub mod interrupts;
+#[cfg(feature = "jit")]
+mod jit;
+mod json;
+pub mod lcov;
+pub mod linux;
+pub mod loader;
+pub mod lockstep;
+pub mod memcheck;
+mod memory;
+mod mmu;
+pub mod mmio;
+mod monitor;
+pub mod pc_history;
+mod pmp;
+pub mod predictor;
+pub mod profile;
+pub mod progress;
+pub mod registers;
+pub mod retire;
+pub mod rpc;
+pub mod script;
+mod sbi;
+mod semihosting;
+pub mod shard;
+mod signals;
+pub mod snapshot;
+pub mod stats;
+pub mod symbolic;
+pub mod taint;
+#[cfg(test)]
+mod test_utils;
+pub mod timing;
+pub mod torture;
+pub mod trace;
+mod trap;
+mod triage;
+pub mod vcd;
+pub mod watch;
+
+use assertions::Assertions;
+use block_cache::{BlockCache, MAX_BLOCK_LEN};
+use bootrom::BootRom;
+use csr_file::{status, CsrFile, MCOUNTEREN, MEPC, MIDELEG, MIE, SATP, SCOUNTEREN};
+use decode_cache::DecodeCache;
+use dispatch::{Flow, Kind, MicroOp};
+use fetch::{Fetch, IALIGN};
+use hpm::{Counters, Event};
+use htif::Htif;
+use input::InputScript;
+use interrupts::{Injector, SEI, SSI, STI};
+use memory::{Access, PageCache, Size};
+use pc_history::PcHistory;
+use pmp::Pmp;
+use progress::Progress;
+use registers::Registers;
+use retire::{Batching, Retired, RetireLog};
+use sbi::Sbi;
+use script::Script;
+use semihosting::Semihosting;
+use timing::TimingModel;
+use trace::Tracer;
+use trap::Exception;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ArgsRType {
+    rs1: usize,
+    rs2: usize,
+    rd: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ArgsIType {
+    rs1: usize,
+    rd: usize,
+    imm: i32,
+    shamt: u8,
+    csr: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ArgsSBType {
+    rs1: usize,
+    rs2: usize,
+    imm: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ArgsUJType {
+    rd: usize,
+    imm: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    Lui     (ArgsUJType),
+    Auipc   (ArgsUJType),
+    Jal     (ArgsUJType),
+    Jalr    (ArgsIType),
+    Beq     (ArgsSBType),
+    Bne     (ArgsSBType),
+    Blt     (ArgsSBType),
+    Bge     (ArgsSBType),
+    Bltu    (ArgsSBType),
+    Bgeu    (ArgsSBType),
+    Lb      (ArgsIType),
+    Lh      (ArgsIType),
+    Lw      (ArgsIType),
+    Lbu     (ArgsIType),
+    Lhu     (ArgsIType),
+    Sb      (ArgsSBType),
+    Sh      (ArgsSBType),
+    Sw      (ArgsSBType),
+    Addi    (ArgsIType),
+    Slti    (ArgsIType),
+    Sltiu   (ArgsIType),
+    Xori    (ArgsIType),
+    Ori     (ArgsIType),
+    Andi    (ArgsIType),
+    Slli    (ArgsIType),
+    Srli    (ArgsIType),
+    Srai    (ArgsIType),
+    Add     (ArgsRType),
+    Sub     (ArgsRType),
+    Sll     (ArgsRType),
+    Slt     (ArgsRType),
+    Sltu    (ArgsRType),
+    Xor     (ArgsRType),
+    Srl     (ArgsRType),
+    Sra     (ArgsRType),
+    Or      (ArgsRType),
+    And     (ArgsRType),
+    Fence, // args
+    FenceI,
+    FenceTso,
+    Pause,
+    Ecall,
+    Ebreak,
+    Mret,
+    Sret,
+    Wfi,
+    SfenceVma(ArgsRType),
+    Csrrw   (ArgsIType),
+    Csrrs   (ArgsIType),
+    Csrrc   (ArgsIType),
+    Csrrwi  (ArgsIType),
+    Csrrsi  (ArgsIType),
+    Csrrci  (ArgsIType),
+}
+
+#[derive(Debug)]
+pub struct IllegalInstruction;
+
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+enum Csr {
+    MIsa,
+    MVendorId,
+    MArchId,
+    MImpId,
+    MHartId,
+    MStatus,
+    MStatusH,
+    MIe,
+    MTvec,
+    MScratch,
+    MEpc,
+    MCause,
+    MTVal,
+    MIp,
+    MConfigPtr,
+    MSecCfg,
+    MSecCfgH,
+    // 0 to 15, entries 4n to 4n + 3
+    PmpCfg(u8),
+    // 0 to 63
+    PmpAddr(u8),
+    MEDeleg,
+    MIDeleg,
+    MCounterEn,
+    TSelect,
+    TData1,
+    SStatus,
+    SIe,
+    STvec,
+    SCounterEn,
+    SScratch,
+    SEpc,
+    SCause,
+    STVal,
+    SIp,
+    Satp,
+    STimeCmp,
+    STimeCmpH,
+    MCycle,
+    MCycleH,
+    MInstret,
+    MInstretH,
+    // 3 to 31
+    MHpmCounter(u8),
+    MHpmCounterH(u8),
+    MHpmEvent(u8),
+    MCountInhibit,
+    // the read-only views of the M-mode counters; time is cycles
+    Cycle,
+    CycleH,
+    Time,
+    TimeH,
+    Instret,
+    InstretH,
+    HpmCounter(u8),
+    HpmCounterH(u8),
+}
+
+/// Privilege modes, encoded as in mstatus.MPP and CSR addresses[9:8]
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug)]
+enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl Privilege {
+    fn from_bits(bits: u32) -> Option<Self> {
+        match bits {
+            0 => Some(Self::User),
+            1 => Some(Self::Supervisor),
+            3 => Some(Self::Machine),
+            _ => None,
+        }
+    }
+}
+
+/// mcause values: every standard exception code of the privileged and
+/// hypervisor specs, and the interrupts with their mip bit at the code
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Cause {
+    InstructionAddressMisaligned,
+    InstructionAccessFault,
+    IllegalInstruction,
+    Breakpoint,
+    LoadAddressMisaligned,
+    LoadAccessFault,
+    StoreAmoAddressMisaligned,
+    StoreAmoAccessFault,
+    Ucall,
+    Scall,
+    VirtualScall,
+    Mcall,
+    InstructionPageFault,
+    LoadPageFault,
+    StoreAmoPageFault,
+    SoftwareCheck,
+    HardwareError,
+    InstructionGuestPageFault,
+    LoadGuestPageFault,
+    VirtualInstruction,
+    StoreAmoGuestPageFault,
+    SupervisorSoftwareInterrupt,
+    MachineSoftwareInterrupt,
+    SupervisorTimerInterrupt,
+    MachineTimerInterrupt,
+    SupervisorExternalInterrupt,
+    MachineExternalInterrupt,
+    CounterOverflowInterrupt,
+}
+
+/// Interrupts in decreasing priority
+const INTERRUPTS: [Cause; 7] = [
+    Cause::MachineExternalInterrupt, Cause::MachineSoftwareInterrupt, Cause::MachineTimerInterrupt,
+    Cause::SupervisorExternalInterrupt, Cause::SupervisorSoftwareInterrupt, Cause::SupervisorTimerInterrupt,
+    Cause::CounterOverflowInterrupt,
+];
+
+impl Cause {
+    fn from_value(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(Self::InstructionAddressMisaligned),
+            1 => Some(Self::InstructionAccessFault),
+            2 => Some(Self::IllegalInstruction),
+            3 => Some(Self::Breakpoint),
+            4 => Some(Self::LoadAddressMisaligned),
+            5 => Some(Self::LoadAccessFault),
+            6 => Some(Self::StoreAmoAddressMisaligned),
+            7 => Some(Self::StoreAmoAccessFault),
+            8 => Some(Self::Ucall),
+            9 => Some(Self::Scall),
+            10 => Some(Self::VirtualScall),
+            11 => Some(Self::Mcall),
+            12 => Some(Self::InstructionPageFault),
+            13 => Some(Self::LoadPageFault),
+            15 => Some(Self::StoreAmoPageFault),
+            18 => Some(Self::SoftwareCheck),
+            19 => Some(Self::HardwareError),
+            20 => Some(Self::InstructionGuestPageFault),
+            21 => Some(Self::LoadGuestPageFault),
+            22 => Some(Self::VirtualInstruction),
+            23 => Some(Self::StoreAmoGuestPageFault),
+            0x8000_0001 => Some(Self::SupervisorSoftwareInterrupt),
+            0x8000_0003 => Some(Self::MachineSoftwareInterrupt),
+            0x8000_0005 => Some(Self::SupervisorTimerInterrupt),
+            0x8000_0007 => Some(Self::MachineTimerInterrupt),
+            0x8000_0009 => Some(Self::SupervisorExternalInterrupt),
+            0x8000_000B => Some(Self::MachineExternalInterrupt),
+            0x8000_000D => Some(Self::CounterOverflowInterrupt),
+            _ => None,
+        }
+    }
+
+    /// Rank when several causes are pending at once, 0 first: interrupts
+    /// (taken between instructions) in the order MEI, MSI, MTI, SEI, SSI,
+    /// STI, LCOFI, then the synchronous exceptions in the order of the
+    /// privileged spec's priority table, with misaligned loads and stores
+    /// detected before translation
+    fn priority(self) -> u8 {
+        if let Some(rank) = INTERRUPTS.iter().position(|&cause| cause == self) {
+            return rank as u8;
+        }
+        match self {
+            Self::InstructionPageFault | Self::InstructionGuestPageFault => 10,
+            Self::InstructionAccessFault => 11,
+            Self::IllegalInstruction | Self::VirtualInstruction | Self::InstructionAddressMisaligned
+            | Self::Ucall | Self::Scall | Self::VirtualScall | Self::Mcall | Self::Breakpoint
+            | Self::SoftwareCheck => 12,
+            Self::LoadAddressMisaligned | Self::StoreAmoAddressMisaligned => 13,
+            Self::LoadPageFault | Self::StoreAmoPageFault | Self::LoadGuestPageFault
+            | Self::StoreAmoGuestPageFault => 14,
+            Self::LoadAccessFault | Self::StoreAmoAccessFault | Self::HardwareError => 15,
+            _ => unreachable!("interrupts are ranked above"),
+        }
+    }
+
+    /// The cause taken out of several pending ones
+    fn highest(pending: impl IntoIterator<Item = Self>) -> Option<Self> {
+        pending.into_iter().min_by_key(|cause| cause.priority())
+    }
+
+    /// mip/mie bit of an interrupt, medeleg bit of an exception
+    fn bit(self) -> u32 {
+        1 << (CoreState::get_cause_value(&self) & 0x1F)
+    }
+}
+
+impl Csr {
+    fn get_csr(address: u16) -> Option<Self> {
+        match address {
+            0xF11 => Some(Self::MVendorId),
+            0xF12 => Some(Self::MArchId),
+            0xF13 => Some(Self::MImpId),
+            0xF14 => Some(Self::MHartId),
+            0xF15 => Some(Self::MConfigPtr),
+            0x300 => Some(Self::MStatus),
+            0x310 => Some(Self::MStatusH),
+            0x301 => Some(Self::MIsa),
+            0x304 => Some(Self::MIe),
+            0x305 => Some(Self::MTvec),
+            0x340 => Some(Self::MScratch),
+            0x341 => Some(Self::MEpc),
+            0x342 => Some(Self::MCause),
+            0x343 => Some(Self::MTVal),
+            0x344 => Some(Self::MIp),
+            0x747 => Some(Self::MSecCfg),
+            0x757 => Some(Self::MSecCfgH),
+            0x3A0..=0x3AF => Some(Self::PmpCfg((address - 0x3A0) as u8)),
+            0x3B0..=0x3EF => Some(Self::PmpAddr((address - 0x3B0) as u8)),
+            0x302 => Some(Self::MEDeleg),
+            0x303 => Some(Self::MIDeleg),
+            0x306 => Some(Self::MCounterEn),
+            0x7A0 => Some(Self::TSelect),
+            0x7A1 => Some(Self::TData1),
+            0x100 => Some(Self::SStatus),
+            0x104 => Some(Self::SIe),
+            0x105 => Some(Self::STvec),
+            0x106 => Some(Self::SCounterEn),
+            0x140 => Some(Self::SScratch),
+            0x141 => Some(Self::SEpc),
+            0x142 => Some(Self::SCause),
+            0x143 => Some(Self::STVal),
+            0x144 => Some(Self::SIp),
+            0x180 => Some(Self::Satp),
+            0x14D => Some(Self::STimeCmp),
+            0x15D => Some(Self::STimeCmpH),
+            0xB00 => Some(Self::MCycle),
+            0xB02 => Some(Self::MInstret),
+            0xB80 => Some(Self::MCycleH),
+            0xB82 => Some(Self::MInstretH),
+            0xB03..=0xB1F => Some(Self::MHpmCounter((address - 0xB00) as u8)),
+            0xB83..=0xB9F => Some(Self::MHpmCounterH((address - 0xB80) as u8)),
+            0x323..=0x33F => Some(Self::MHpmEvent((address - 0x320) as u8)),
+            0x320 => Some(Self::MCountInhibit),
+            0xC00 => Some(Self::Cycle),
+            0xC01 => Some(Self::Time),
+            0xC02 => Some(Self::Instret),
+            0xC80 => Some(Self::CycleH),
+            0xC81 => Some(Self::TimeH),
+            0xC82 => Some(Self::InstretH),
+            0xC03..=0xC1F => Some(Self::HpmCounter((address - 0xC00) as u8)),
+            0xC83..=0xC9F => Some(Self::HpmCounterH((address - 0xC80) as u8)),
+            _ => None
+        }
+    }
+
+    /// The inverse of `get_csr`
+    fn address(&self) -> u16 {
+        match self {
+            Self::MVendorId => 0xF11,
+            Self::MArchId => 0xF12,
+            Self::MImpId => 0xF13,
+            Self::MHartId => 0xF14,
+            Self::MConfigPtr => 0xF15,
+            Self::MStatus => 0x300,
+            Self::MStatusH => 0x310,
+            Self::MIsa => 0x301,
+            Self::MIe => 0x304,
+            Self::MTvec => 0x305,
+            Self::MScratch => 0x340,
+            Self::MEpc => 0x341,
+            Self::MCause => 0x342,
+            Self::MTVal => 0x343,
+            Self::MIp => 0x344,
+            Self::MSecCfg => 0x747,
+            Self::MSecCfgH => 0x757,
+            Self::PmpCfg(n) => 0x3A0 + *n as u16,
+            Self::PmpAddr(i) => 0x3B0 + *i as u16,
+            Self::MEDeleg => 0x302,
+            Self::MIDeleg => 0x303,
+            Self::MCounterEn => 0x306,
+            Self::TSelect => 0x7A0,
+            Self::TData1 => 0x7A1,
+            Self::SStatus => 0x100,
+            Self::SIe => 0x104,
+            Self::STvec => 0x105,
+            Self::SCounterEn => 0x106,
+            Self::SScratch => 0x140,
+            Self::SEpc => 0x141,
+            Self::SCause => 0x142,
+            Self::STVal => 0x143,
+            Self::SIp => 0x144,
+            Self::Satp => 0x180,
+            Self::STimeCmp => 0x14D,
+            Self::STimeCmpH => 0x15D,
+            Self::MCycle => 0xB00,
+            Self::MInstret => 0xB02,
+            Self::MCycleH => 0xB80,
+            Self::MInstretH => 0xB82,
+            Self::MHpmCounter(n) => 0xB00 + *n as u16,
+            Self::MHpmCounterH(n) => 0xB80 + *n as u16,
+            Self::MHpmEvent(n) => 0x320 + *n as u16,
+            Self::MCountInhibit => 0x320,
+            Self::Cycle => 0xC00,
+            Self::Time => 0xC01,
+            Self::Instret => 0xC02,
+            Self::CycleH => 0xC80,
+            Self::TimeH => 0xC81,
+            Self::InstretH => 0xC82,
+            Self::HpmCounter(n) => 0xC00 + *n as u16,
+            Self::HpmCounterH(n) => 0xC80 + *n as u16,
+        }
+    }
+}
+
+const RUN_MEMORY_SIZE: usize = 16 << 20;
+
+/// How the run loops advance the core
+#[derive(Clone, Copy, PartialEq)]
+pub enum Engine {
+    /// Decode cache, one instruction per dispatch
+    Step,
+    /// Basic-block cache, one block per dispatch
+    Block,
+    /// Hot blocks compiled to host code with Cranelift, the others and
+    /// whatever traps or touches CSRs interpreted as with `Block`
+    #[cfg(feature = "jit")]
+    Jit,
+}
+
+/// The machine's mvendorid, marchid and mimpid. All zero by default, which
+/// the spec reads as not implemented: rs-v has no registered architecture
+/// ID yet, and one would become the default marchid here.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Identity {
+    pub vendor: u32,
+    pub arch: u32,
+    pub implementation: u32,
+}
+
+impl Identity {
+    /// `name=value,...` with names `mvendorid`, `marchid` and `mimpid`,
+    /// values decimal or 0x hex, e.g. `mvendorid=0x489,mimpid=2`; the
+    /// others stay zero
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut identity = Self::default();
+        for setting in spec.split(',').filter(|s| !s.is_empty()) {
+            let (name, value) = setting.split_once('=').ok_or(format!("expected name=value, got `{}`", setting))?;
+            let value = match value.strip_prefix("0x") {
+                Some(hex) => u32::from_str_radix(hex, 16),
+                None => value.parse(),
+            }.map_err(|_| format!("bad value `{}`", value))?;
+            *match name {
+                "mvendorid" => &mut identity.vendor,
+                "marchid" => &mut identity.arch,
+                "mimpid" => &mut identity.implementation,
+                _ => return Err(format!("unknown identity CSR `{}`", name)),
+            } = value;
+        }
+        Ok(identity)
+    }
+}
+
+/// Settings shared by the run modes
+pub struct Config {
+    pub script: Option<Script>,
+    /// Guest memory size, mode default if None
+    pub memory_size: Option<usize>,
+    pub engine: Engine,
+    pub trace: Option<Tracer>,
+    /// Coverage, statistics and other per-instruction tools, need the `Step`
+    /// engine
+    pub observers: Vec<Box<dyn Observer>>,
+    /// Decode with reserved fields ignored, see `CoreState::decode_with`
+    pub lenient: bool,
+    /// Per-instruction latencies for `CoreState::cycles`, 1 cycle each if None
+    pub timing: Option<Box<dyn TimingModel>>,
+    /// Random interrupt injection for bare-metal runs, needs the `Step`
+    /// engine
+    pub interrupts: Option<Injector>,
+    /// ELF core file written when a bare-metal run aborts
+    pub core_dump: Option<String>,
+    /// Service `hostcall` ecalls in bare-metal runs
+    pub host_ecalls: bool,
+    /// Start bare-metal runs in a boot ROM instead of at the ELF entry
+    pub boot_rom: Option<BootRom>,
+    /// Service SBI calls from S-mode in bare-metal runs
+    pub sbi: bool,
+    /// mhartid of the emulated hart in bare-metal runs and riscv-tests
+    pub hart_id: u32,
+    /// Progress lines during `run` and `user`
+    pub progress: Option<Progress>,
+    /// Read monitor commands from stdin when Ctrl-C stops `run` or `user`
+    pub monitor: bool,
+    /// Console input of `run`
+    pub input: Option<InputScript>,
+    /// mvendorid, marchid and mimpid of `run` and the riscv-tests
+    pub identity: Identity,
+    /// PMP entries of `run` and the riscv-tests; None for none in `run` and
+    /// 16 in the riscv-tests, as on spike
+    pub pmp_entries: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            script: None,
+            memory_size: None,
+            engine: Engine::Block,
+            trace: None,
+            observers: Vec::new(),
+            lenient: false,
+            timing: None,
+            interrupts: None,
+            core_dump: None,
+            host_ecalls: false,
+            boot_rom: None,
+            sbi: false,
+            hart_id: 0,
+            progress: None,
+            monitor: false,
+            input: None,
+            identity: Identity::default(),
+            pmp_entries: None,
+        }
+    }
+}
+
+/// Memory-mapped register block of a run mode: name, base address and size
+pub type Device = (&'static str, u32, u32);
+
+/// Analysis tool called before every instruction. Reports are written when
+/// the observer is dropped at the end of the run.
+pub trait Observer {
+    /// Called once the program is loaded, before the first step
+    fn loaded(&mut self, _image: &loader::Image) {}
+
+    /// Called after `loaded` with the devices of the run mode
+    fn devices(&mut self, _devices: &[Device]) {}
+
+    fn step(&mut self, core: &CoreState);
+}
+
+/// A CSR implemented outside the core, e.g. a custom CSR of a device model,
+/// registered with `CoreState::hook_csr`. CSR instructions call `read` only
+/// when they read the CSR, so a read may have side effects.
+pub trait CsrHook {
+    fn read(&mut self) -> u32;
+
+    fn write(&mut self, value: u32);
+}
+
+pub struct CoreState {
+    pub pc: u32,
+    pub regs: Registers,
+    pub memory: Vec<u8>,
+    /// Ignore reserved encoding fields instead of raising illegal instruction
+    pub lenient: bool,
+    /// Elapsed cycles, one per instruction unless `timing` is set
+    pub cycles: u64,
+    pub timing: Option<Box<dyn TimingModel>>,
+    counters: Counters,
+    privilege: Privilege,
+    // mstatus, mtvec, mepc and the other CSRs that are plain state
+    csrs: CsrFile,
+    pmp: Pmp,
+    /// mip, platforms raise MSI/MTI/MEI here; taking an interrupt clears its
+    /// bit
+    pub mip: u32,
+    /// Sstc deadline in `cycles`, the clock of the SBI timer; STIP is
+    /// raised once it passes
+    stimecmp: u64,
+    // (address, size) of the last load/store
+    last_access: Option<(u32, u32)>,
+    /// An exception was raised by the first instruction of the trap handler,
+    /// which would raise it again forever
+    pub(crate) double_fault: bool,
+    /// First instruction executed that the emulator only stubs
+    pub unimplemented: Option<&'static str>,
+    /// Recently executed pcs for failure reports
+    pub pc_history: PcHistory,
+    /// mhartid, part of the machine configuration like `lenient`; the
+    /// emulator runs one hart
+    pub hart_id: u32,
+    /// mvendorid, marchid and mimpid, configuration like `hart_id`
+    pub identity: Identity,
+    // CSRs outside the core by address, they shadow the core's own
+    csr_hooks: HashMap<u16, Box<dyn CsrHook>>,
+    retire_log: Option<RetireLog>,
+    fetch_unit: Fetch,
+    load_page: PageCache,
+    store_page: PageCache,
+    decode_cache: DecodeCache,
+    block_cache: BlockCache,
+    // created by the first dispatch of `Engine::Jit`
+    #[cfg(feature = "jit")]
+    jit: Option<jit::Jit>,
+}
+
+impl Display for CoreState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "pc: 0x{:08x}", self.pc)?;
+        // for (i, reg) in self.regs.iter().enumerate() {
+        //     let new_line = {if i % 4 == 3 {'\n'} else {' '}};
+        //     write!(f, "{:>5}: 0x{:08x}{}", Self::reg_name(i), reg, new_line)?;
+        // }
+        // for m in self.memory {
+        //     write!(f, "{:02x} ", m)?;
+        // }
+        Ok(())
+    }
+}
+
+impl CoreState {
+    pub fn new(memory_size: usize) -> Self {
+        Self {
+            pc: 0x0000_0000,
+            regs: Registers::default(),
+            memory: vec![0; memory_size],
+            lenient: false,
+            cycles: 0,
+            timing: None,
+            counters: Counters::new(),
+            privilege: Privilege::Machine,
+            csrs: CsrFile::new(),
+            pmp: Pmp::new(0),
+            mip: 0,
+            stimecmp: u64::MAX,
+            last_access: None,
+            double_fault: false,
+            unimplemented: None,
+            pc_history: PcHistory::new(),
+            hart_id: 0,
+            identity: Identity::default(),
+            csr_hooks: HashMap::new(),
+            retire_log: None,
+            fetch_unit: Fetch::new(),
+            load_page: PageCache::new(),
+            store_page: PageCache::new(),
+            decode_cache: DecodeCache::new(),
+            block_cache: BlockCache::new(),
+            #[cfg(feature = "jit")]
+            jit: None,
+        }
+    }
+
+    pub fn reg_name(index: usize) -> String {
+        match index {
+            0 => "zero".to_string(),
+            1 => "ra".to_string(),
+            2 => "sp".to_string(),
+            3 => "gp".to_string(),
+            4 => "tp".to_string(),
+            5..=7 => format!("t{}", index - 5),
+            8..=9 => format!("s{}", index - 8),
+            10..=17 => format!("a{}", index - 10),
+            18..=27 => format!("s{}", index - 16),
+            28..=31 => format!("t{}", index - 25),
+            _ => unimplemented!(),
+        }
+    }
+
+    /// Power-on state: registers, CSRs, counters, the decode and page caches
+    /// and all of memory (device registers included) as `new` leaves them,
+    /// keeping the memory size, `lenient`, the PMP entries and the timing model
+    pub fn hard_reset(&mut self) {
+        let fresh = Self::new(self.memory.len());
+        *self = Self {lenient: self.lenient, hart_id: self.hart_id, identity: self.identity,
+                      timing: self.timing.take(), csr_hooks: std::mem::take(&mut self.csr_hooks),
+                      retire_log: self.retire_log.take(), pmp: Pmp::new(self.pmp.entries()), ..fresh};
+    }
+
+    pub fn reset(&mut self) {
+        self.pc = 0;
+        self.privilege = Privilege::Machine;
+        self.csrs.set_mpp(Privilege::Machine);
+        self.csrs.set_status(status::MIE, false);
+        self.csrs.set_status(status::MPIE, false);
+        self.flush_code();
+    }
+
+    /// Implements `entries` PMP entries, 0, 16 or 64, all off; 0 unless set,
+    /// which leaves every access unchecked
+    pub fn set_pmp_entries(&mut self, entries: usize) {
+        self.pmp = Pmp::new(entries);
+    }
+
+    /// Implements the CSR at `address` with `hook`; the privilege level and
+    /// read-only bits of the address still apply
+    pub fn hook_csr(&mut self, address: u16, hook: Box<dyn CsrHook>) {
+        self.csr_hooks.insert(address, hook);
+    }
+
+    /// Sends the instructions retired from now on to the returned receiver
+    /// in batches, for instrumentation on another thread that would slow
+    /// the core down as an `Observer`; works with either engine
+    pub fn log_retires(&mut self, batching: Batching) -> Receiver<Vec<Retired>> {
+        let (log, receiver) = RetireLog::new(batching);
+        self.retire_log = Some(log);
+        receiver
+    }
+
+    fn has_csr(&self, address: u16) -> bool {
+        self.csr_hooks.contains_key(&address) || Csr::get_csr(address).is_some()
+    }
+
+    /// Whether the privilege mode may access the CSR at `address`: csr[9:8]
+    /// is the lowest mode with access, mcounteren and scounteren enable the
+    /// user counters below M- and S-mode and mstatus.TVM takes satp from
+    /// S-mode
+    fn csr_permitted(&self, address: u16) -> bool {
+        if (address >> 8) & 0b11 > self.privilege as u16 {
+            return false;
+        }
+        match (address, self.privilege) {
+            (0xC00..=0xC1F | 0xC80..=0xC9F, privilege) => {
+                let bit = 1 << (address & 0x1F);
+                let machine = privilege == Privilege::Machine || self.csrs.get(MCOUNTEREN) & bit != 0;
+                machine && (privilege != Privilege::User || self.csrs.get(SCOUNTEREN) & bit != 0)
+            }
+            (SATP, Privilege::Supervisor) => !self.csrs.status(status::TVM),
+            _ => true,
+        }
+    }
+
+    /// A CSR instruction's read of a CSR `has_csr` accepted
+    fn read_csr(&mut self, address: u16) -> u32 {
+        match self.csr_hooks.get_mut(&address) {
+            Some(hook) => hook.read(),
+            None => self.get_csr_value(&Csr::get_csr(address).unwrap()),
+        }
+    }
+
+    /// A CSR instruction's write of a CSR `has_csr` accepted
+    fn write_csr(&mut self, address: u16, value: u32) {
+        match self.csr_hooks.get_mut(&address) {
+            Some(hook) => hook.write(value),
+            None => self.set_csr_value(&Csr::get_csr(address).unwrap(), value),
+        }
+    }
+
+    fn get_csr_value(&self, csr: &Csr) -> u32 {
+        match csr {
+            Csr::MHartId => self.hart_id,
+            Csr::MVendorId => self.identity.vendor,
+            Csr::MArchId => self.identity.arch,
+            Csr::MImpId => self.identity.implementation,
+            Csr::MIp => self.mip,
+            Csr::SIp => self.mip & self.csrs.get(MIDELEG),
+            Csr::SIe => self.csrs.get(MIE) & self.csrs.get(MIDELEG),
+            Csr::MCycle | Csr::MCycleH | Csr::MInstret | Csr::MInstretH | Csr::MHpmCounter(_) |
+            Csr::MHpmCounterH(_) | Csr::STimeCmp | Csr::STimeCmpH | Csr::Cycle | Csr::CycleH |
+            Csr::Time | Csr::TimeH | Csr::Instret | Csr::InstretH | Csr::HpmCounter(_) |
+            Csr::HpmCounterH(_) => {
+                let (value, high) = self.wide(csr).unwrap();
+                if high {(value >> 32) as u32} else {value as u32}
+            }
+            Csr::MHpmEvent(n) => self.counters.event(*n as usize - 3) as u32,
+            Csr::MCountInhibit => self.counters.inhibited(),
+            Csr::MSecCfg => self.pmp.mseccfg(),
+            Csr::PmpCfg(n) => self.pmp.cfg(*n),
+            Csr::PmpAddr(i) => self.pmp.addr(*i),
+            csr => self.csrs.get(csr.address()),
+        }
+    }
+
+    fn event_total(&self, event: Event) -> u64 {
+        self.counters.total(event)
+            .unwrap_or_else(|| self.timing.as_ref().map_or(0, |timing| timing.count(event)))
+    }
+
+    fn hpm_counter(&self, n: u8) -> u64 {
+        let index = n as usize - 3;
+        self.counters.value(index, self.event_total(self.counters.event(index)))
+    }
+
+    /// 64-bit CSRs, which RV32 accesses in halves: the whole value and
+    /// whether `csr` is its high half
+    fn wide(&self, csr: &Csr) -> Option<(u64, bool)> {
+        match csr {
+            Csr::MCycle | Csr::MCycleH => Some((self.counters.cycle(self.cycles), matches!(csr, Csr::MCycleH))),
+            Csr::MInstret | Csr::MInstretH => Some((self.counters.instret(), matches!(csr, Csr::MInstretH))),
+            Csr::STimeCmp | Csr::STimeCmpH => Some((self.stimecmp, matches!(csr, Csr::STimeCmpH))),
+            Csr::MHpmCounter(n) | Csr::MHpmCounterH(n) => {
+                Some((self.hpm_counter(*n), matches!(csr, Csr::MHpmCounterH(_))))
+            }
+            Csr::Cycle | Csr::CycleH => Some((self.counters.cycle(self.cycles), matches!(csr, Csr::CycleH))),
+            Csr::Time | Csr::TimeH => Some((self.cycles, matches!(csr, Csr::TimeH))),
+            Csr::Instret | Csr::InstretH => Some((self.counters.instret(), matches!(csr, Csr::InstretH))),
+            Csr::HpmCounter(n) | Csr::HpmCounterH(n) => {
+                Some((self.hpm_counter(*n), matches!(csr, Csr::HpmCounterH(_))))
+            }
+            _ => None,
+        }
+    }
+
+    /// Writes the whole value of a 64-bit CSR
+    fn set_wide(&mut self, csr: &Csr, value: u64) {
+        match csr {
+            Csr::MCycle | Csr::MCycleH => self.counters.write_cycle(value, self.cycles),
+            Csr::MInstret | Csr::MInstretH => self.counters.write_instret(value),
+            // a deadline in the future takes back the interrupt
+            Csr::STimeCmp | Csr::STimeCmpH => {
+                self.stimecmp = value;
+                self.mip &= !STI;
+                self.check_stimecmp();
+            }
+            Csr::MHpmCounter(n) | Csr::MHpmCounterH(n) => {
+                let index = *n as usize - 3;
+                let total = self.event_total(self.counters.event(index));
+                self.counters.write(index, value, total);
+            }
+            // read-only, only `lenient` gets a write here
+            Csr::Cycle | Csr::CycleH | Csr::Time | Csr::TimeH | Csr::Instret | Csr::InstretH |
+            Csr::HpmCounter(_) | Csr::HpmCounterH(_) => {}
+            _ => unreachable!("{:?} is a 32-bit CSR", csr),
+        }
+    }
+
+    fn set_csr_value(&mut self, csr: &Csr, value: u32) {
+        // a write to one half of a 64-bit CSR keeps the other
+        if let Some((old, high)) = self.wide(csr) {
+            let value = if high {
+                (old & 0xFFFF_FFFF) | (value as u64) << 32
+            } else {
+                (old & !0xFFFF_FFFF) | value as u64
+            };
+            return self.set_wide(csr, value);
+        }
+        match csr {
+            Csr::MHpmEvent(n) => {
+                let event = Event::from_value(value);
+                let old = self.hpm_counter(*n);
+                self.counters.select(*n as usize - 3, event, old, self.event_total(event));
+            }
+            Csr::MCountInhibit => {
+                let totals = std::array::from_fn(|i| self.event_total(self.counters.event(i)));
+                self.counters.inhibit(value, self.cycles, totals);
+            }
+            Csr::MSecCfg => self.pmp.set_mseccfg(value),
+            Csr::PmpCfg(n) => self.pmp.set_cfg(*n, value),
+            Csr::PmpAddr(i) => self.pmp.set_addr(*i, value),
+            // the platform sets the other mip bits
+            Csr::MIp => self.mip = (self.mip & !(SSI | SEI)) | (value & (SSI | SEI)),
+            Csr::SIp => {
+                let writable = SSI & self.csrs.get(MIDELEG);
+                self.mip = (self.mip & !writable) | (value & writable);
+            }
+            Csr::SIe => {
+                let delegated = self.csrs.get(MIDELEG);
+                self.csrs.write(MIE, (self.csrs.get(MIE) & !delegated) | (value & delegated));
+            }
+            // mhartid and the CSRs the file keeps fixed are read-only and
+            // rejected by the decoder
+            csr => {
+                self.csrs.write(csr.address(), value);
+            }
+        }
+    }
+
+    fn get_cause_value(cause: &Cause) -> u32 {
+        match cause {
+            Cause::InstructionAddressMisaligned => 0,
+            Cause::InstructionAccessFault => 1,
+            Cause::IllegalInstruction => 2,
+            Cause::Breakpoint => 3,
+            Cause::LoadAddressMisaligned => 4,
+            Cause::LoadAccessFault => 5,
+            Cause::StoreAmoAddressMisaligned => 6,
+            Cause::StoreAmoAccessFault => 7,
+            Cause::Ucall => 8,
+            Cause::Scall => 9,
+            Cause::VirtualScall => 10,
+            Cause::Mcall => 11,
+            Cause::InstructionPageFault => 12,
+            Cause::LoadPageFault => 13,
+            Cause::StoreAmoPageFault => 15,
+            Cause::SoftwareCheck => 18,
+            Cause::HardwareError => 19,
+            Cause::InstructionGuestPageFault => 20,
+            Cause::LoadGuestPageFault => 21,
+            Cause::VirtualInstruction => 22,
+            Cause::StoreAmoGuestPageFault => 23,
+            Cause::SupervisorSoftwareInterrupt => 0x8000_0001,
+            Cause::MachineSoftwareInterrupt => 0x8000_0003,
+            Cause::SupervisorTimerInterrupt => 0x8000_0005,
+            Cause::MachineTimerInterrupt => 0x8000_0007,
+            Cause::SupervisorExternalInterrupt => 0x8000_0009,
+            Cause::MachineExternalInterrupt => 0x8000_000B,
+            Cause::CounterOverflowInterrupt => 0x8000_000D,
+        }
+    }
+
+    /// Strict decode, reserved fields must hold their specified values
+    pub fn decode(instruction: u32) -> Result<Instruction, IllegalInstruction> {
+        Self::decode_with(instruction, false)
+    }
+
+    /// Decodes with reserved fields checked, or ignored if `lenient` (like
+    /// permissive cores do): rs1/rd of ECALL/EBREAK/MRET/SRET/WFI, shamt\[5\]
+    /// of the immediate shifts and writes to read-only CSRs. The unused FENCE
+    /// and FENCE.I fields and unknown FENCE fm values are ignored in both
+    /// modes, as the spec requires.
+    pub fn decode_with(instruction: u32, lenient: bool) -> Result<Instruction, IllegalInstruction> {
+        let opcode = instruction & 0b111_1111;
+        let funct3 = (instruction >> 12) & 0b111;
+        let funct7 = (instruction >> 25) & 0b111_1111;
+
+        let rs1: usize = ((instruction >> 15) & 0b1_1111).try_into().unwrap();
+        let rs2: usize = ((instruction >> 20) & 0b1_1111).try_into().unwrap();
+        let rd: usize = ((instruction >> 7) & 0b1_1111).try_into().unwrap();
+        let shamt = rs2 as u8;
+        let csr: u16 = ((instruction >> 20) & 0xFFF).try_into().unwrap();
+        // rs1 and rd of MISC-MEM and the privileged SYSTEM instructions
+        let unused = rs1 != 0 || rd != 0;
+
+        let imm_i = ((instruction & 0xFFF00000) as i32) >> 20;
+
+        let imm_s = {
+            let imm_11_5 = (instruction & 0xFE000000) as i32;
+            let imm_4_0 = ((instruction >> 7) & 0x1F) as i32;
+            (imm_11_5 >> 20) | imm_4_0
+        };
+
+        let imm_b = {
+            let imm_12 = (((instruction & 0x80000000) as i32) >> 19) as u32;
+            let imm_11 = (instruction & 0x00000080) << 4;
+            let imm_10_5 = (instruction >> 20) & 0x7E0;
+            let imm_4_1 = (instruction >> 7) & 0x1E;
+            (imm_12 | imm_11 | imm_10_5 | imm_4_1) as i32
+        };
+
+        let imm_u = (instruction & 0xFFFFF000) as i32;
+
+        let imm_j = {
+            let imm_20 = (((instruction & 0x80000000) as i32) >> 11) as u32;
+            let imm_19_12 = instruction & 0x000FF000;
+            let imm_11 = (instruction & 0x00100000) >> 9;
+            let imm_10_1 = (instruction & 0x7FE00000) >> 20;
+            (imm_20 | imm_19_12 | imm_11 | imm_10_1) as i32
+        };
+
+        let args_r = ArgsRType{rs1, rs2, rd};
+        let args_i = ArgsIType{rs1, rd, imm: imm_i, shamt, csr};
+        let args_s = ArgsSBType{rs1, rs2, imm: imm_s};
+        let args_b = ArgsSBType{rs1, rs2, imm: imm_b};
+        let args_u = ArgsUJType{rd, imm: imm_u};
+        let args_j = ArgsUJType{rd, imm: imm_j};
+
+        match opcode {
+            0b011_0111 => Ok(Instruction::Lui(args_u)),
+            0b001_0111 => Ok(Instruction::Auipc(args_u)),
+            0b110_1111 => Ok(Instruction::Jal(args_j)),
+            0b110_0111 => match funct3 {
+                0 => Ok(Instruction::Jalr(args_i)),
+                _ => Err(IllegalInstruction),
+            }
+            0b110_0011 => match funct3 {
+                0b000 => Ok(Instruction::Beq(args_b)),
+                0b001 => Ok(Instruction::Bne(args_b)),
+                0b100 => Ok(Instruction::Blt(args_b)),
+                0b101 => Ok(Instruction::Bge(args_b)),
+                0b110 => Ok(Instruction::Bltu(args_b)),
+                0b111 => Ok(Instruction::Bgeu(args_b)),
+                _ => Err(IllegalInstruction),
+            }
+            0b000_0011 => match funct3 {
+                0b000 => Ok(Instruction::Lb(args_i)),
+                0b001 => Ok(Instruction::Lh(args_i)),
+                0b010 => Ok(Instruction::Lw(args_i)),
+                0b100 => Ok(Instruction::Lbu(args_i)),
+                0b101 => Ok(Instruction::Lhu(args_i)),
+                _ => Err(IllegalInstruction),
+            }
+            0b010_0011 => match funct3 {
+                0b000 => Ok(Instruction::Sb(args_s)),
+                0b001 => Ok(Instruction::Sh(args_s)),
+                0b010 => Ok(Instruction::Sw(args_s)),
+                _ => Err(IllegalInstruction),
+            }
+            0b001_0011 => match funct3 {
+                0b000 => Ok(Instruction::Addi(args_i)),
+                0b010 => Ok(Instruction::Slti(args_i)),
+                0b011 => Ok(Instruction::Sltiu(args_i)),
+                0b100 => Ok(Instruction::Xori(args_i)),
+                0b110 => Ok(Instruction::Ori(args_i)),
+                0b111 => Ok(Instruction::Andi(args_i)),
+                0b001 => match funct7 {
+                    0 => Ok(Instruction::Slli(args_i)),
+                    0b000_0001 if lenient => Ok(Instruction::Slli(args_i)),
+                    _ => Err(IllegalInstruction),
+                }
+                0b101 => match if lenient {funct7 & !1} else {funct7} {
+                    0 => Ok(Instruction::Srli(args_i)),
+                    0b010_0000 => Ok(Instruction::Srai(args_i)),
+                    _ => Err(IllegalInstruction),
+                }
+                _ => Err(IllegalInstruction),
+            }
+            0b011_0011 => match funct7 {
+                0 => match funct3 {
+                    0b000 => Ok(Instruction::Add(args_r)),
+                    0b001 => Ok(Instruction::Sll(args_r)),
+                    0b010 => Ok(Instruction::Slt(args_r)),
+                    0b011 => Ok(Instruction::Sltu(args_r)),
+                    0b100 => Ok(Instruction::Xor(args_r)),
+                    0b101 => Ok(Instruction::Srl(args_r)),
+                    0b110 => Ok(Instruction::Or(args_r)),
+                    0b111 => Ok(Instruction::And(args_r)),
+                    _ => Err(IllegalInstruction),
+                }
+                0b010_0000 => match funct3 {
+                    0b000 => Ok(Instruction::Sub(args_r)),
+                    0b101 => Ok(Instruction::Sra(args_r)),
+                    _ => Err(IllegalInstruction),
+                }
+                _ => Err(IllegalInstruction),
+            }
+            // rs1, rd, the FENCE.I immediate and other fm values are reserved
+            // for finer-grain fences, which are plain fences until then
+            0b000_1111 => match funct3 {
+                0b000 => match (instruction >> 28, (instruction >> 20) & 0xFF) {
+                    // fm, pred/succ
+                    (0b1000, 0b0011_0011) => Ok(Instruction::FenceTso),
+                    (0, 0b0001_0000) if !unused => Ok(Instruction::Pause),
+                    _ => Ok(Instruction::Fence),
+                }
+                0b001 => Ok(Instruction::FenceI),
+                _ => Err(IllegalInstruction),
+            }
+            0b111_0011 => match (funct7, rs2, funct3) {
+                // rs1 and rs2 select the address and the address space
+                (0b000_1001, _, 0) if rd == 0 || lenient => Ok(Instruction::SfenceVma(args_r)),
+                (_, _, 0) if unused && !lenient => Err(IllegalInstruction),
+                (0, 0, 0) => Ok(Instruction::Ecall),
+                (0, 1, 0) => Ok(Instruction::Ebreak),
+                (0b001_1000, 0b0_0010, 0) => Ok(Instruction::Mret),
+                (0b000_1000, 0b0_0010, 0) => Ok(Instruction::Sret),
+                (0b000_1000, 0b0_0101, 0) => Ok(Instruction::Wfi),
+                // csr[11:10] == 0b11 is read-only; CSRRS/CSRRC with rs1 = x0
+                // and CSRRSI/CSRRCI with uimm = 0 don't write
+                (_, _, 0b010 | 0b011 | 0b110 | 0b111) if rs1 == 0 => Self::decode_csr(funct3, args_i),
+                _ if csr >> 10 == 0b11 && !lenient => Err(IllegalInstruction),
+                (_, _, _) => Self::decode_csr(funct3, args_i),
+            }
+            _ => Err(IllegalInstruction),
+        }
+    }
+
+    fn decode_csr(funct3: u32, args: ArgsIType) -> Result<Instruction, IllegalInstruction> {
+        match funct3 {
+            0b001 => Ok(Instruction::Csrrw(args)),
+            0b010 => Ok(Instruction::Csrrs(args)),
+            0b011 => Ok(Instruction::Csrrc(args)),
+            0b101 => Ok(Instruction::Csrrwi(args)),
+            0b110 => Ok(Instruction::Csrrsi(args)),
+            0b111 => Ok(Instruction::Csrrci(args)),
+            _ => Err(IllegalInstruction),
+        }
+    }
+
+    /// The word at pc, see `fetch_at`
+    pub fn fetch(&self) -> u32 {
+        self.fetch_at(self.pc)
+    }
+
+    /// The word at `pc` for observers and the timing model, 0 outside
+    /// memory or if it doesn't translate; execution goes through the fetch
+    /// unit
+    fn fetch_at(&self, pc: u32) -> u32 {
+        let pc = match self.translation(Access::Fetch) {
+            Some(privilege) => match self.walk(pc, Access::Fetch, privilege) {
+                Ok((physical, _)) => physical,
+                Err(_) => return 0,
+            },
+            None => pc,
+        };
+        let address = pc as usize..pc as usize + 4;
+        self.memory.get(address).map_or(0, |bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// A load of `size` bytes at `address`, sign- or zero-extended
+    fn load(&mut self, address: u32, size: Size, signed: bool) -> Result<u32, Exception> {
+        let start = self.check_access(address, size, Access::Load)?;
+        let mut bytes = [0; 4];
+        bytes[..size.bytes()].copy_from_slice(&self.memory[start..start + size.bytes()]);
+        Ok(size.extend(u32::from_le_bytes(bytes), signed))
+    }
+
+    /// A store of the low `size` bytes of `value` at `address`, dropping
+    /// cached code it overwrites
+    fn store(&mut self, address: u32, size: Size, value: u32) -> Result<(), Exception> {
+        let start = self.check_access(address, size, Access::Store)?;
+        self.memory[start..start + size.bytes()].copy_from_slice(&value.to_le_bytes()[..size.bytes()]);
+        self.invalidate_code(start as u32, size as u32);
+        Ok(())
+    }
+
+    /// Drops the decoded instructions and blocks overlapping `len` bytes at
+    /// `address`, after a store or a write of the host (a debugger, a
+    /// syscall reading into guest memory)
+    pub(crate) fn invalidate_code(&mut self, address: u32, len: u32) {
+        self.decode_cache.invalidate(address, len);
+        self.block_cache.invalidate(address, len);
+        #[cfg(feature = "jit")]
+        if self.jit.as_mut().is_some_and(|jit| jit.invalidate(address, len)) {
+            self.block_cache.interrupted = true;
+        }
+    }
+
+    /// Drops all decoded instructions, blocks and compiled code
+    pub(crate) fn flush_code(&mut self) {
+        self.decode_cache.flush();
+        self.block_cache.flush();
+        // nothing compiled is running, its code can go
+        #[cfg(feature = "jit")]
+        {
+            self.jit = None;
+        }
+    }
+
+    /// The checks every load and store goes through, returns the offset in
+    /// memory. Accesses inside the last page that passed skip them, unless
+    /// the address is translated. Misaligned accesses are done in one piece,
+    /// except across two virtual pages, which raises the misaligned
+    /// exception, and unless PMP checks the privilege. A page fault, a PMP
+    /// denial or an access outside memory fails, with mtval at the address.
+    fn check_access(&mut self, address: u32, size: Size, access: Access) -> Result<usize, Exception> {
+        self.last_access = Some((address, size as u32));
+        let (translation, privilege) = (self.translation(access), self.data_privilege());
+        if translation.is_some() || self.pmp.applies(privilege) {
+            let last = address.wrapping_add(size as u32 - 1);
+            if translation.is_some() && (address ^ last) >> 12 != 0 {
+                return Err(Exception::new(access.misaligned(), address));
+            }
+            let start = match translation {
+                Some(privilege) => self.translate(address, access, privilege)?,
+                None => address,
+            };
+            if !self.pmp.allows(start, size as u32, access, privilege)
+                || self.memory.get(start as usize..start as usize + size.bytes()).is_none() {
+                return Err(Exception::new(access.fault(), address));
+            }
+            return Ok(start as usize);
+        }
+        let start = address as usize;
+        let page = match access {
+            Access::Load => &mut self.load_page,
+            Access::Store => &mut self.store_page,
+            Access::Fetch => unreachable!("fetches go through the fetch unit"),
+        };
+        if !page.hit(address, size.bytes()) {
+            if self.memory.get(start..start + size.bytes()).is_none() {
+                return Err(Exception::new(access.fault(), address));
+            }
+            page.fill(address, self.memory.len());
+        }
+        Ok(start)
+    }
+
+    /// The instruction at `pc`, decoded. The decode cache is keyed by
+    /// physical address, which stores invalidate.
+    fn decode_at(&mut self, pc: u32) -> Result<MicroOp, Exception> {
+        let physical = match self.translation(Access::Fetch) {
+            Some(_) if !pc.is_multiple_of(IALIGN) => {
+                return Err(Exception::new(Cause::InstructionAddressMisaligned, pc));
+            }
+            Some(privilege) => self.translate(pc, Access::Fetch, privilege)?,
+            None => pc,
+        };
+        if self.pmp.applies(self.privilege) && pc.is_multiple_of(IALIGN)
+            && !self.pmp.allows(physical, 4, Access::Fetch, self.privilege) {
+            return Err(Exception::fetch_access(pc));
+        }
+        if let Some(op) = self.decode_cache.get(physical) {
+            return Ok(op);
+        }
+        // a parcel outside memory faults at its virtual address
+        let word = self.fetch_unit.fetch(&self.memory, physical)
+            .map_err(|fault| Exception::new(fault.cause, pc.wrapping_add(fault.tval.wrapping_sub(physical))))?;
+        Self::decode_with(word, self.lenient)
+            .map(MicroOp::from)
+            .inspect(|&op| self.decode_cache.insert(physical, op))
+            .map_err(|IllegalInstruction| Exception::illegal(word))
+    }
+
+    pub fn execute(&mut self) {
+        match self.decode_at(self.pc) {
+            Ok(op) => self.execute_instruction(op),
+            Err(exception) => {
+                let pc = self.pc;
+                self.pc_history.push(pc);
+                self.last_access = None;
+                trap::take_exception(self, exception);
+                self.count_cycles(pc);
+            }
+        }
+    }
+
+    /// Runs the cached basic block at pc, returns the number of retired
+    /// instructions or 0 if pc starts with an instruction blocks leave to
+    /// `execute` (SYSTEM, FENCE.I, illegal) or fetches are translated or
+    /// checked by PMP
+    fn execute_block(&mut self) -> usize {
+        // blocks are keyed by pc and skip the fetch checks, translated or
+        // PMP-checked code is stepped
+        if self.translation(Access::Fetch).is_some() || self.pmp.applies(self.privilege) {
+            return 0;
+        }
+        let block = match self.block_cache.get(self.pc) {
+            Some(block) => block,
+            None => {
+                let block = self.build_block(self.pc);
+                if block.is_empty() {
+                    return 0;
+                }
+                self.block_cache.insert(self.pc, block)
+            }
+        };
+        self.block_cache.interrupted = false;
+        for (i, &op) in block.iter().enumerate() {
+            let next = self.pc.wrapping_add(4);
+            self.execute_instruction(op);
+            if self.pc != next || self.block_cache.interrupted {
+                return i + 1;
+            }
+        }
+        block.len()
+    }
+
+    /// Advances by one instruction or one basic block, returns the number of
+    /// retired instructions
+    pub fn dispatch(&mut self, engine: Engine) -> usize {
+        self.check_stimecmp();
+        if self.mip != 0 && trap::take_interrupt(self) {
+            return 0;
+        }
+        let retired = match engine {
+            Engine::Block => self.execute_block(),
+            #[cfg(feature = "jit")]
+            Engine::Jit => self.execute_compiled(),
+            Engine::Step => 0,
+        };
+        let retired = match retired {
+            0 => {
+                self.execute();
+                1
+            }
+            retired => retired,
+        };
+        if let Some(log) = self.retire_log.as_mut() {
+            log.dispatched();
+        }
+        retired
+    }
+
+    fn build_block(&mut self, start: u32) -> Vec<MicroOp> {
+        let mut block = Vec::new();
+        let mut pc = start;
+        while block.len() < MAX_BLOCK_LEN && (pc as usize + 4) <= self.memory.len() {
+            match self.decode_at(pc) {
+                Ok(op) if op.kind == Kind::Branch => {
+                    block.push(op);
+                    break;
+                }
+                Ok(op) if op.kind == Kind::System => break,
+                Ok(op) => block.push(op),
+                Err(_) => break,
+            }
+            pc = pc.wrapping_add(4);
+        }
+        block
+    }
+
+    fn execute_instruction(&mut self, op: MicroOp) {
+        let pc = self.pc;
+        self.pc_history.push(pc);
+        self.last_access = None;
+
+        match (op.handler)(self, &op) {
+            Flow::Trap(exception) => trap::take_exception(self, exception),
+            Flow::Next => {
+                self.pc = self.pc.wrapping_add(4);
+                self.retire(pc);
+            }
+            Flow::Jump => self.retire(pc),
+        }
+        self.count_cycles(pc);
+    }
+
+    /// Retires the ecall or ebreak at pc that the host serviced in its
+    /// place, counting its cycles like an executed one
+    pub(crate) fn retire_serviced(&mut self) {
+        let pc = self.pc;
+        self.pc_history.push(pc);
+        self.last_access = None;
+        self.pc = pc.wrapping_add(4);
+        self.retire(pc);
+        self.count_cycles(pc);
+        if let Some(log) = self.retire_log.as_mut() {
+            log.dispatched();
+        }
+    }
+
+    fn check_stimecmp(&mut self) {
+        if self.cycles >= self.stimecmp {
+            self.mip |= STI;
+        }
+    }
+
+    fn retire(&mut self, pc: u32) {
+        self.counters.retired += 1;
+        if let Some(mut log) = self.retire_log.take() {
+            log.push(Retired {pc, instruction: self.fetch_at(pc)});
+            self.retire_log = Some(log);
+        }
+    }
+
+    /// Adds the cycles of the instruction at `pc` that just executed
+    fn count_cycles(&mut self, pc: u32) {
+        if self.counters.active {
+            let taken = self.pc != pc.wrapping_add(4);
+            self.counters.retire(self.fetch_at(pc), taken);
+        }
+        let Some(mut timing) = self.timing.take() else {
+            self.cycles += 1;
+            return;
+        };
+        let instruction = self.fetch_at(pc);
+        self.cycles += timing.cycles(self, pc, instruction);
+        self.timing = Some(timing);
+    }
+}
+
+/// How a `run` or `user` run ended, so wrappers can tell a guest failure
+/// from an emulator failure. An emulator panic unwinds instead (exit code
+/// 101).
+#[derive(Clone, Debug, PartialEq)]
+pub enum RunOutcome {
+    /// The guest exited with a code: semihosting SYS_EXIT, the HTIF exit
+    /// command, an SBI system reset, `exit`/`exit_group` in user mode or
+    /// ecall 93 with `--host-ecalls`
+    Exited(i32),
+    /// The guest failed without exiting: it entered an assertion, abort or
+    /// panic routine, or double faulted
+    GuestFailure(String),
+    /// Ctrl-C stopped the run
+    Interrupted(String),
+    /// The run couldn't start or go on: the program didn't load or a script
+    /// stopped it
+    EmulatorFailure(String),
+}
+
+impl RunOutcome {
+    /// Process exit code of an `EmulatorFailure`
+    pub const EMULATOR_FAILURE: i32 = 125;
+    /// Process exit code of an `Interrupted` run, as for a shell's SIGINT
+    pub const INTERRUPTED: i32 = 130;
+
+    /// The guest's own code when it exited, 1 for a guest failure
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Exited(code) => *code,
+            Self::GuestFailure(_) => 1,
+            Self::Interrupted(_) => Self::INTERRUPTED,
+            Self::EmulatorFailure(_) => Self::EMULATOR_FAILURE,
+        }
+    }
+
+    fn from_result(result: Result<i32, RunOutcome>) -> Self {
+        result.map_or_else(|outcome| outcome, Self::Exited)
+    }
+}
+
+impl Display for RunOutcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Exited(code) => write!(f, "exit {}", code),
+            Self::GuestFailure(report) | Self::Interrupted(report) | Self::EmulatorFailure(report) => {
+                write!(f, "{}", report)
+            }
+        }
+    }
+}
+
+impl From<String> for RunOutcome {
+    fn from(message: String) -> Self {
+        Self::EmulatorFailure(message)
+    }
+}
+
+impl From<&str> for RunOutcome {
+    fn from(message: &str) -> Self {
+        Self::EmulatorFailure(message.to_string())
+    }
+}
+
+/// Runs a bare-metal ELF with semihosting and HTIF (if the ELF has a
+/// `tohost` symbol)
+pub fn run(args: &[String], config: Config) -> RunOutcome {
+    RunOutcome::from_result(run_machine(args, config).map(|(code, _, _)| code))
+}
+
+/// `run`, also returning the final machine state and the loaded image
+pub(crate) fn run_machine(args: &[String], config: Config) -> Result<(i32, CoreState, loader::Image), RunOutcome> {
+    let Config {mut script, memory_size, engine, mut trace, mut observers, lenient, timing, mut interrupts, core_dump,
+                host_ecalls, boot_rom, sbi, hart_id, mut progress, monitor, input,
+                identity, pmp_entries} = config;
+    let path = args.first().ok_or("missing program")?;
+
+    let (mut core_state, image) = load_bare_metal(path, memory_size)?;
+    core_state.hart_id = hart_id;
+    core_state.identity = identity;
+    core_state.set_pmp_entries(pmp_entries.unwrap_or(0));
+    if let Some(rom) = boot_rom {
+        rom.install(&mut core_state, image.entry, image.end)?;
+    }
+    core_state.lenient = lenient;
+    core_state.timing = timing;
+    for observer in observers.iter_mut() {
+        observer.loaded(&image);
+    }
+    if let Some(progress) = progress.as_mut() {
+        progress.loaded(&image);
+    }
+    let mut platform = Platform::new(args, &image, &mut core_state);
+    platform.host_ecalls = host_ecalls;
+    platform.sbi = sbi.then(Sbi::new);
+    if sbi {
+        Sbi::delegate(&mut core_state);
+    }
+    platform.semihosting.input = input;
+    for observer in observers.iter_mut() {
+        observer.devices(&platform.devices());
+    }
+    let assertions = Assertions::new(&image);
+    signals::catch_interrupt();
+
+    loop {
+        if signals::interrupted() {
+            if let Err(report) = monitor::interrupt(&core_state, &assertions, &image.symbols, platform.retired, monitor) {
+                if let Some(path) = &core_dump {
+                    coredump::write(path, &core_state);
+                }
+                return Err(report);
+            }
+        }
+        if trace::ENABLED {
+            if let Some(tracer) = trace.as_mut() {
+                tracer.step(&core_state);
+            }
+        }
+        for observer in observers.iter_mut() {
+            observer.step(&core_state);
+        }
+        if let Some(script) = script.as_mut() {
+            if !script.step(&mut core_state) {
+                return Err("stopped by script".into());
+            }
+        }
+        if let Some(injector) = interrupts.as_mut() {
+            injector.step(&mut core_state);
+        }
+        if let Some(progress) = progress.as_mut() {
+            progress.step(&core_state, platform.retired);
+        }
+        if let Some(report) = assertions.check(&core_state) {
+            if let Some(path) = &core_dump {
+                coredump::write(path, &core_state);
+            }
+            return Err(RunOutcome::GuestFailure(format!("{}\n{}", report, core_state.pc_history.report(&image.symbols))));
+        }
+        // emulator errors panic, the pc history is printed and the core
+        // dumped on the way out
+        let step = match panic::catch_unwind(AssertUnwindSafe(|| platform.step(&mut core_state, engine))) {
+            Ok(step) => step,
+            Err(payload) => {
+                eprintln!("{}", core_state.pc_history.report(&image.symbols));
+                if let Some(path) = &core_dump {
+                    coredump::write(path, &core_state);
+                }
+                panic::resume_unwind(payload);
+            }
+        };
+        if let Some(code) = step {
+            return Ok((code, core_state, image));
+        }
+        if core_state.double_fault {
+            if let Some(path) = &core_dump {
+                coredump::write(path, &core_state);
+            }
+            return Err(RunOutcome::GuestFailure(format!("double fault: mcause {} raised at the trap handler 0x{:08x}\n{}",
+                                                        core_state.get_csr_value(&Csr::MCause), core_state.csrs.get(MEPC),
+                                                        core_state.pc_history.report(&image.symbols))));
+        }
+    }
+}
+
+/// Machine for `run` with the program at `path` loaded and pc at its entry
+pub(crate) fn load_bare_metal(path: &str, memory_size: Option<usize>) -> Result<(CoreState, loader::Image), String> {
+    let mut core_state = CoreState::new(memory_size.unwrap_or(RUN_MEMORY_SIZE));
+    let image = loader::load_segments(&mut core_state, path)?;
+    core_state.pc = image.entry;
+    Ok((core_state, image))
+}
+
+/// Devices of a bare-metal run: semihosting and HTIF (if the ELF has a
+/// `tohost` symbol)
+pub(crate) struct Platform {
+    semihosting: Semihosting,
+    htif: Option<Htif>,
+    /// Instructions retired through `step`, semihosting calls included
+    pub(crate) retired: u64,
+    /// Service `hostcall` ecalls
+    pub(crate) host_ecalls: bool,
+    /// Service ecalls from S-mode as SBI calls
+    pub(crate) sbi: Option<Sbi>,
+}
+
+impl Platform {
+    /// `args` is the guest command line, the program first
+    pub(crate) fn new(args: &[String], image: &loader::Image, core: &mut CoreState) -> Self {
+        let htif = image.symbols.get("tohost")
+            .map(|&tohost| Htif::new(tohost, image.symbols.get("fromhost").copied()));
+        if let Some(&tohost) = image.symbols.get("tohost") {
+            core.block_cache.watch = Some((tohost, 8));
+        }
+        Self {semihosting: Semihosting::new(args.join(" ")), htif, retired: 0, host_ecalls: false, sbi: None}
+    }
+
+    pub(crate) fn devices(&self) -> Vec<Device> {
+        self.htif.as_ref().map(Htif::devices).unwrap_or_default()
+    }
+
+    /// Drops the guest's stdout output instead of printing it
+    pub(crate) fn quiet(&mut self) {
+        self.semihosting.quiet = true;
+        if let Some(htif) = self.htif.as_mut() {
+            htif.quiet();
+        }
+    }
+
+    /// Keeps a copy of the console output for `take_console`
+    pub(crate) fn capture_console(&mut self) {
+        self.semihosting.console = Some(Vec::new());
+        if let Some(htif) = self.htif.as_mut() {
+            htif.capture_console();
+        }
+    }
+
+    /// Console output since the last call
+    pub(crate) fn take_console(&mut self) -> Vec<u8> {
+        let mut output = self.semihosting.console.as_mut().map(std::mem::take).unwrap_or_default();
+        if let Some(htif) = self.htif.as_mut() {
+            output.append(&mut htif.take_console());
+        }
+        output
+    }
+
+    /// Services a semihosting or host call or dispatches, returns the exit
+    /// code once the guest exits
+    pub(crate) fn step(&mut self, core: &mut CoreState, engine: Engine) -> Option<i32> {
+        if self.host_ecalls && hostcall::is_call(core) {
+            if let Some(code) = hostcall::call(core, &mut self.semihosting) {
+                return Some(code);
+            }
+            core.retire_serviced();
+            self.retired += 1;
+        } else if self.sbi.is_some() && Sbi::is_call(core) {
+            if let Some(code) = self.sbi.as_mut().unwrap().call(core, &mut self.semihosting) {
+                return Some(code);
+            }
+            core.retire_serviced();
+            self.retired += 1;
+        } else if Semihosting::is_call(core) {
+            if let Some(code) = self.semihosting.call(core) {
+                return Some(code);
+            }
+            core.retire_serviced();
+            self.retired += 1;
+        } else {
+            self.retired += core.dispatch(engine) as u64;
+        }
+        if let Some(input) = self.semihosting.input.as_mut() {
+            input.step(self.retired);
+        }
+        if let Some(sbi) = self.sbi.as_mut() {
+            sbi.tick(core);
+        }
+        self.htif.as_mut().and_then(|htif| htif.step(core))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dispatch::MicroOp;
+    use crate::{encode, run, Config, CoreState, Instruction, RunOutcome};
+
+    /// xorshift32, deterministic so failures reproduce
+    fn words(count: usize) -> impl Iterator<Item = u32> {
+        let mut state = 0x2545_F491_u32;
+        (0..count).map(move |_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        })
+    }
+
+    #[test]
+    fn random_words_decode_without_panicking() {
+        for word in words(1 << 18) {
+            if let Ok(instruction) = CoreState::decode(word) {
+                let op = MicroOp::from(instruction);
+                assert!(op.rd() < 32 && op.rs1() < 32 && op.rs2() < 32, "0x{:08x}", word);
+            }
+        }
+    }
+
+    #[test]
+    fn encoding_a_decoded_instruction_round_trips() {
+        // the privileged and fence words seldom come up at random
+        let corners = [0x0000_0073, 0x0010_0073, 0x3020_0073, 0x1020_0073, 0x1050_0073, 0x1200_0073,
+                       0x0FF0_000F, 0x8330_000F, 0x0100_000F, 0x0000_100F];
+        for word in words(1 << 18).chain(corners) {
+            for lenient in [false, true] {
+                if let Ok(instruction) = CoreState::decode_with(word, lenient) {
+                    let encoded = encode::instruction(instruction);
+                    assert_eq!(CoreState::decode_with(encoded, lenient).ok(), Some(instruction),
+                               "0x{:08x} encoded as 0x{:08x}", word, encoded);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn compressed_quadrants_are_illegal() {
+        for word in words(1 << 14) {
+            for quadrant in 0..3 {
+                assert!(CoreState::decode(word & !0b11 | quadrant).is_err(), "0x{:08x}", word);
+            }
+        }
+    }
+
+    #[test]
+    fn shift_immediates_check_funct7() {
+        for funct7 in 0..128 {
+            let word = |funct3: u32| (funct7 << 25) | (31 << 20) | (funct3 << 12) | 0b001_0011;
+            assert_eq!(CoreState::decode(word(0b001)).is_ok(), funct7 == 0);
+            assert_eq!(CoreState::decode(word(0b101)).is_ok(), funct7 == 0 || funct7 == 0b010_0000);
+            // shamt[5] is ignored when lenient
+            assert_eq!(CoreState::decode_with(word(0b001), true).is_ok(), funct7 & !1 == 0);
+        }
+        match CoreState::decode(0x41F0_5013) {
+            Ok(Instruction::Srai(args)) => assert_eq!(args.shamt, 31),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn system_funct12_corners() {
+        for funct12 in 0..0x1000_u32 {
+            for (rs1, rd) in [(0, 0), (1, 0), (0, 1), (31, 31)] {
+                let word = (funct12 << 20) | (rs1 << 15) | (rd << 7) | 0b111_0011;
+                let known = matches!(funct12, 0x000 | 0x001 | 0x302 | 0x102 | 0x105);
+                // sfence.vma with any rs2
+                let sfence = funct12 >> 5 == 0b000_1001;
+                assert_eq!(CoreState::decode(word).is_ok(), (known && rs1 == 0 || sfence) && rd == 0, "0x{:08x}", word);
+                assert_eq!(CoreState::decode_with(word, true).is_ok(), known || sfence, "0x{:08x}", word);
+            }
+        }
+        // funct3 = 0b100 is reserved
+        assert!(CoreState::decode(0x3400_4073).is_err());
+    }
+
+    #[test]
+    fn csr_address_is_unsigned() {
+        for csr in [0x000, 0x7FF, 0x800, 0xF14, 0xFFF] {
+            for funct3 in [0b001, 0b010, 0b011, 0b101, 0b110, 0b111] {
+                let word = (csr << 20) | (funct3 << 12) | (1 << 7) | 0b111_0011;
+                let op = MicroOp::from(CoreState::decode_with(word, true).unwrap());
+                assert_eq!(op.csr() as u32, csr);
+            }
+        }
+    }
+
+    #[test]
+    fn fence_reserved_fields_are_ignored() {
+        let fence = |fm: u32, pred_succ: u32, rs1: u32, rd: u32| {
+            (fm << 28) | (pred_succ << 20) | (rs1 << 15) | (rd << 7) | 0b000_1111
+        };
+        assert!(matches!(CoreState::decode(fence(0, 0xFF, 0, 0)), Ok(Instruction::Fence)));
+        assert!(matches!(CoreState::decode(fence(0b1000, 0x33, 0, 0)), Ok(Instruction::FenceTso)));
+        assert!(matches!(CoreState::decode(fence(0b1000, 0x33, 3, 4)), Ok(Instruction::FenceTso)));
+        assert!(matches!(CoreState::decode(fence(0, 0x10, 0, 0)), Ok(Instruction::Pause)));
+        for lenient in [false, true] {
+            for word in [fence(0b1000, 0xFF, 0, 0), fence(0b0001, 0xFF, 0, 0), fence(0, 0xFF, 1, 0),
+                         fence(0, 0xFF, 0, 1), fence(0, 0x10, 1, 0)] {
+                assert!(matches!(CoreState::decode_with(word, lenient), Ok(Instruction::Fence)), "0x{:08x}", word);
+            }
+            for word in [0x0010_100F, 0x0000_908F] {
+                assert!(matches!(CoreState::decode_with(word, lenient), Ok(Instruction::FenceI)), "0x{:08x}", word);
+            }
+        }
+    }
+
+    #[test]
+    fn read_only_csr_writes_are_illegal() {
+        let csr = |funct3: u32, rs1: u32| (0xF14 << 20) | (rs1 << 15) | (funct3 << 12) | (1 << 7) | 0b111_0011;
+        for funct3 in [0b001, 0b101] {
+            assert!(CoreState::decode(csr(funct3, 0)).is_err());
+            assert!(CoreState::decode_with(csr(funct3, 0), true).is_ok());
+        }
+        for funct3 in [0b010, 0b011, 0b110, 0b111] {
+            assert!(CoreState::decode(csr(funct3, 0)).is_ok());
+            assert!(CoreState::decode(csr(funct3, 1)).is_err());
+        }
+    }
+
+    #[test]
+    fn outcomes_give_the_exit_codes() {
+        let outcome = run(&[], Config::default());
+        assert_eq!((outcome.to_string(), outcome.exit_code()), ("missing program".to_string(), 125));
+        assert_eq!(RunOutcome::from_result(Ok(3)).exit_code(), 3);
+        assert_eq!(RunOutcome::from_result(Err(RunOutcome::GuestFailure("abort called".to_string()))).exit_code(), 1);
+        assert_eq!(RunOutcome::Interrupted(String::new()).exit_code(), 130);
+    }
+
+    #[test]
+    fn serviced_calls_retire_and_take_cycles() {
+        let mut core = crate::test_utils::machine(0, &[encode::ECALL], &[(17, 64)]);
+        let mut platform = crate::Platform {semihosting: crate::semihosting::Semihosting::new(String::new()), htif: None,
+                                            retired: 0, host_ecalls: true, sbi: None};
+        platform.semihosting.quiet = true;
+        assert_eq!(platform.step(&mut core, crate::Engine::Block), None);
+        assert_eq!((core.pc, core.counters.retired, core.cycles, platform.retired), (4, 1, 1, 1));
+        assert_eq!(core.pc_history.pcs(), [0]);
+    }
+}
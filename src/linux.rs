@@ -0,0 +1,372 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+
+use crate::assertions::Assertions;
+use crate::heap::Heap;
+use crate::loader::load_segments;
+use crate::{monitor, signals, trace};
+use crate::csr_file::{MCAUSE, MEPC, MTVAL, MTVEC};
+use crate::{Cause, Config, CoreState, RunOutcome};
+
+const MEMORY_SIZE: usize = 64 << 20;
+const STACK_SIZE: u32 = 8 << 20;
+pub(crate) const PAGE_SIZE: u32 = 4096;
+
+const ECALL: u32 = 0x0000_0073;
+
+// rv32 asm-generic syscall numbers
+const SYS_IOCTL: u32 = 29;
+const SYS_OPENAT: u32 = 56;
+const SYS_CLOSE: u32 = 57;
+const SYS_READ: u32 = 63;
+const SYS_WRITE: u32 = 64;
+const SYS_WRITEV: u32 = 66;
+const SYS_EXIT: u32 = 93;
+const SYS_EXIT_GROUP: u32 = 94;
+const SYS_SET_TID_ADDRESS: u32 = 96;
+const SYS_BRK: u32 = 214;
+const SYS_MUNMAP: u32 = 215;
+const SYS_MREMAP: u32 = 216;
+const SYS_MMAP: u32 = 222;
+
+const ENOENT: i32 = 2;
+const EIO: i32 = 5;
+const EBADF: i32 = 9;
+pub(crate) const ENOMEM: i32 = 12;
+const EACCES: i32 = 13;
+pub(crate) const EFAULT: i32 = 14;
+const EEXIST: i32 = 17;
+pub(crate) const EINVAL: i32 = 22;
+const ENOTTY: i32 = 25;
+const ENOSYS: i32 = 38;
+
+const AT_FDCWD: i32 = -100;
+const O_ACCMODE: u32 = 0o3;
+const O_CREAT: u32 = 0o100;
+const O_TRUNC: u32 = 0o1000;
+const O_APPEND: u32 = 0o2000;
+const MAP_FIXED: u32 = 0x10;
+const MAP_ANONYMOUS: u32 = 0x20;
+
+const AT_NULL: u32 = 0;
+const AT_PHDR: u32 = 3;
+const AT_PHENT: u32 = 4;
+const AT_PHNUM: u32 = 5;
+const AT_PAGESZ: u32 = 6;
+const AT_ENTRY: u32 = 9;
+const AT_RANDOM: u32 = 25;
+
+/// None if the rounded value doesn't fit, for guest-supplied values
+pub(crate) fn align_up(value: u32, align: u32) -> Option<u32> {
+    Some(value.checked_add(align - 1)? & !(align - 1))
+}
+
+fn errno(e: &io::Error) -> i32 {
+    match e.kind() {
+        io::ErrorKind::NotFound => ENOENT,
+        io::ErrorKind::PermissionDenied => EACCES,
+        io::ErrorKind::AlreadyExists => EEXIST,
+        _ => EIO,
+    }
+}
+
+/// Outcome of a host syscall
+pub enum Syscall {
+    /// Value for a0, negative errno on failure
+    Return(u32),
+    Exit(i32),
+}
+
+/// Host side of a Linux user-mode guest: open files and memory layout
+pub struct Process {
+    files: HashMap<u32, File>,
+    next_fd: u32,
+    heap: Heap,
+    /// Copy of the stdout output, kept when Some
+    pub console: Option<Vec<u8>>,
+    /// Drop stdout output instead of printing it
+    pub quiet: bool,
+}
+
+impl Process {
+    pub fn new(brk: u32, mmap_bottom: u32) -> Self {
+        Self {
+            files: HashMap::new(),
+            next_fd: 3,
+            heap: Heap::new(brk, mmap_bottom),
+            console: None,
+            quiet: false,
+        }
+    }
+
+    fn guest_slice(core: &CoreState, address: u32, len: u32) -> Result<&[u8], i32> {
+        let start = address as usize;
+        core.memory.get(start..start.checked_add(len as usize).ok_or(EFAULT)?).ok_or(EFAULT)
+    }
+
+    /// Callers drop the cached code they overwrite
+    fn guest_slice_mut(core: &mut CoreState, address: u32, len: u32) -> Result<&mut [u8], i32> {
+        let start = address as usize;
+        core.memory.get_mut(start..start.checked_add(len as usize).ok_or(EFAULT)?).ok_or(EFAULT)
+    }
+
+    fn guest_str(core: &CoreState, address: u32) -> Result<String, i32> {
+        let tail = core.memory.get(address as usize..).ok_or(EFAULT)?;
+        let len = tail.iter().position(|&b| b == 0).ok_or(EFAULT)?;
+        Ok(String::from_utf8_lossy(&tail[..len]).into_owned())
+    }
+
+    fn write(&mut self, fd: u32, data: &[u8]) -> Result<u32, i32> {
+        let result = match fd {
+            1 => {
+                if let Some(console) = self.console.as_mut() {
+                    console.extend_from_slice(data);
+                }
+                match self.quiet {
+                    true => Ok(()),
+                    false => io::stdout().write_all(data),
+                }
+            }
+            2 => io::stderr().write_all(data),
+            _ => self.files.get_mut(&fd).ok_or(EBADF)?.write_all(data),
+        };
+        result.map(|_| data.len() as u32).map_err(|e| errno(&e))
+    }
+
+    fn read(&mut self, fd: u32, buffer: &mut [u8]) -> Result<u32, i32> {
+        let result = match fd {
+            0 => io::stdin().read(buffer),
+            _ => self.files.get_mut(&fd).ok_or(EBADF)?.read(buffer),
+        };
+        result.map(|n| n as u32).map_err(|e| errno(&e))
+    }
+
+    fn openat(&mut self, dirfd: i32, path: &str, flags: u32) -> Result<u32, i32> {
+        if dirfd != AT_FDCWD && !path.starts_with('/') {
+            return Err(EBADF);
+        }
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            0 => options.read(true),
+            1 => options.write(true),
+            _ => options.read(true).write(true),
+        };
+        options
+            .create(flags & O_CREAT != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .append(flags & O_APPEND != 0);
+        let file = options.open(path).map_err(|e| errno(&e))?;
+        let fd = self.next_fd;
+        self.next_fd += 1;
+        self.files.insert(fd, file);
+        Ok(fd)
+    }
+
+    /// Runs syscall `number` with arguments a0..a3 against the host
+    pub fn call(&mut self, core: &mut CoreState, number: u32, args: [u32; 4]) -> Syscall {
+        let [a0, a1, a2, a3] = args;
+
+        let result: Result<u32, i32> = match number {
+            SYS_EXIT | SYS_EXIT_GROUP => return Syscall::Exit(a0 as i32),
+            SYS_WRITE => Self::guest_slice(core, a1, a2)
+                .map(|data| data.to_vec())
+                .and_then(|data| self.write(a0, &data)),
+            SYS_WRITEV => (0..a2)
+                .try_fold(0_u32, |total, i| {
+                    let entry = i.checked_mul(8).and_then(|offset| a1.checked_add(offset)).ok_or(EFAULT)?;
+                    let iov = Self::guest_slice(core, entry, 8)?;
+                    let base = u32::from_le_bytes(iov[0..4].try_into().unwrap());
+                    let len = u32::from_le_bytes(iov[4..8].try_into().unwrap());
+                    let data = Self::guest_slice(core, base, len)?.to_vec();
+                    total.checked_add(self.write(a0, &data)?).ok_or(EINVAL)
+                }),
+            // the buffer must be guest memory before the host reads into it
+            SYS_READ => Self::guest_slice(core, a1, a2).map(|buffer| buffer.len()).and_then(|len| {
+                let mut buffer = vec![0; len];
+                let n = self.read(a0, &mut buffer)?;
+                Self::guest_slice_mut(core, a1, n)?.copy_from_slice(&buffer[..n as usize]);
+                core.invalidate_code(a1, n);
+                Ok(n)
+            }),
+            SYS_OPENAT => Self::guest_str(core, a1)
+                .and_then(|path| self.openat(a0 as i32, &path, a2)),
+            SYS_CLOSE => match a0 {
+                0..=2 => Ok(0),
+                fd => self.files.remove(&fd).map(|_| 0).ok_or(EBADF),
+            }
+            SYS_IOCTL => Err(ENOTTY),
+            SYS_SET_TID_ADDRESS => Ok(1),
+            SYS_BRK => Ok(self.heap.brk(a0, &mut core.memory)),
+            // the protection in a2 isn't enforced
+            SYS_MMAP => match a3 & MAP_ANONYMOUS {
+                0 => Err(ENOSYS),
+                _ => self.heap.mmap(a0, a1, a3 & MAP_FIXED != 0, &mut core.memory),
+            }
+            SYS_MUNMAP => self.heap.munmap(a0, a1),
+            SYS_MREMAP => self.heap.mremap(a0, a1, a2, a3, &mut core.memory),
+            _ => {
+                eprintln!("unimplemented syscall {} at 0x{:08x}", number, core.pc);
+                Err(ENOSYS)
+            }
+        };
+
+        Syscall::Return(match result {
+            Ok(value) => value,
+            Err(e) => (-e) as u32,
+        })
+    }
+
+    /// Handles the ecall at pc, returns the exit code if the guest exited
+    fn ecall(&mut self, core: &mut CoreState) -> Option<i32> {
+        let number = core.regs[17];
+        let args = [core.regs[10], core.regs[11], core.regs[12], core.regs[13]];
+        match self.call(core, number, args) {
+            Syscall::Return(value) => {
+                core.regs.write(10, value);
+                None
+            }
+            Syscall::Exit(code) => Some(code),
+        }
+    }
+}
+
+fn push_bytes(core: &mut CoreState, sp: &mut u32, bytes: &[u8]) -> u32 {
+    *sp -= bytes.len() as u32;
+    core.memory[*sp as usize..*sp as usize + bytes.len()].copy_from_slice(bytes);
+    *sp
+}
+
+/// Copies argv and auxv onto the top of the stack, returns the initial sp
+fn setup_stack(core: &mut CoreState, args: &[String], auxv: &[(u32, u32)]) -> u32 {
+    let mut sp = core.memory.len() as u32;
+
+    let random = push_bytes(core, &mut sp, &[0x5a; 16]);
+    let mut argv: Vec<u32> = args
+        .iter()
+        .map(|arg| push_bytes(core, &mut sp, &[arg.as_bytes(), &[0]].concat()))
+        .collect();
+    argv.push(0);
+
+    // argc, argv, envp, auxv
+    let mut words = vec![args.len() as u32];
+    words.extend(argv);
+    words.push(0);
+    for &(key, value) in auxv {
+        words.extend([key, value]);
+    }
+    words.extend([AT_RANDOM, random, AT_NULL, 0]);
+
+    sp = (sp - 4 * words.len() as u32) & !0xF;
+    for (i, word) in words.iter().enumerate() {
+        let address = sp as usize + 4 * i;
+        core.memory[address..address + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    sp
+}
+
+/// Runs a static RV32 Linux binary
+pub fn run(args: &[String], config: Config) -> RunOutcome {
+    RunOutcome::from_result(run_process(args, config))
+}
+
+fn run_process(args: &[String], config: Config) -> Result<i32, RunOutcome> {
+    // user mode has no M-mode handlers to interrupt
+    let Config {mut script, memory_size, engine, mut trace, mut observers, lenient, timing, mut progress, monitor, ..} = config;
+    let path = args.first().ok_or("missing program")?;
+
+    let memory_size = memory_size.unwrap_or(MEMORY_SIZE);
+    if memory_size < 2 * STACK_SIZE as usize {
+        return Err(format!("user mode needs at least {} bytes of memory", 2 * STACK_SIZE).into());
+    }
+    let mut core = CoreState::new(memory_size);
+    core.lenient = lenient;
+    core.timing = timing;
+    let image = load_segments(&mut core, path)?;
+    for observer in observers.iter_mut() {
+        observer.loaded(&image);
+    }
+    if let Some(progress) = progress.as_mut() {
+        progress.loaded(&image);
+    }
+
+    let auxv = [
+        (AT_PHDR, image.phdr),
+        (AT_PHENT, image.phentsize),
+        (AT_PHNUM, image.phnum),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_ENTRY, image.entry),
+    ];
+    let sp = setup_stack(&mut core, args, &auxv);
+    core.regs.write(2, sp);
+    core.pc = image.entry;
+    // there are no trap handlers: traps vector past the end of memory,
+    // where the run stops
+    let trap_vector = core.memory.len() as u32 & !0b11;
+    core.csrs.set(MTVEC, trap_vector);
+
+    let brk = align_up(image.end, PAGE_SIZE).ok_or("the image ends at the top of the address space")?;
+    let mut process = Process::new(brk, core.memory.len() as u32 - STACK_SIZE);
+    let assertions = Assertions::new(&image);
+    signals::catch_interrupt();
+
+    loop {
+        if signals::interrupted() {
+            monitor::interrupt(&core, &assertions, &image.symbols, core.counters.retired, monitor)?;
+        }
+        if trace::ENABLED {
+            if let Some(tracer) = trace.as_mut() {
+                tracer.step(&core);
+            }
+        }
+        for observer in observers.iter_mut() {
+            observer.step(&core);
+        }
+        if let Some(script) = script.as_mut() {
+            if !script.step(&mut core) {
+                return Err("stopped by script".into());
+            }
+        }
+        if let Some(progress) = progress.as_mut() {
+            progress.step(&core, core.counters.retired);
+        }
+        if let Some(report) = assertions.check(&core) {
+            return Err(RunOutcome::GuestFailure(format!("{}\n{}", report, core.pc_history.report(&image.symbols))));
+        }
+        if core.fetch() == ECALL {
+            if let Some(code) = process.ecall(&mut core) {
+                return Ok(code);
+            }
+            core.pc = core.pc.wrapping_add(4);
+        } else {
+            core.dispatch(engine);
+        }
+        if core.pc == trap_vector {
+            let cause = core.csrs.get(MCAUSE);
+            let cause = Cause::from_value(cause).map_or(format!("mcause {}", cause), |cause| format!("{:?}", cause));
+            return Err(RunOutcome::GuestFailure(format!("unhandled {} at 0x{:08x}, mtval 0x{:08x}\n{}",
+                                                        cause, core.csrs.get(MEPC), core.csrs.get(MTVAL),
+                                                        core.pc_history.report(&image.symbols))));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::difftest;
+
+    #[test]
+    fn unhandled_traps_end_the_run() {
+        // an illegal instruction at the entry
+        let path = std::env::temp_dir().join("rs-v-linux-illegal.elf");
+        std::fs::write(&path, difftest::elf(&[0], &[])).unwrap();
+        let outcome = run(&[path.to_string_lossy().into_owned()], Config::default());
+        match outcome {
+            RunOutcome::GuestFailure(report) => {
+                assert!(report.starts_with("unhandled IllegalInstruction at 0x00010000, mtval 0x00000000"), "{}", report);
+            }
+            outcome => panic!("{:?}", outcome),
+        }
+    }
+}
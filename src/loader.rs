@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+
+use elf::abi;
+use elf::endian::AnyEndian;
+use elf::file::{Class, FileHeader};
+use elf::symbol::Symbol;
+use elf::ElfStream;
+
+use crate::CoreState;
+
+/// Where position-independent (ET_DYN) ELFs are loaded, the usual link
+/// address of static executables
+pub const PIE_BASE: u32 = 0x10000;
+
+/// Layout of a program loaded from its PT_LOAD segments
+pub struct Image {
+    /// ELF file the image was loaded from
+    pub path: String,
+    /// Added to every address of the ELF, nonzero for ET_DYN
+    pub base: u32,
+    pub entry: u32,
+    /// First address past the highest segment
+    pub end: u32,
+    /// Address of the program headers in guest memory, 0 if not loaded
+    pub phdr: u32,
+    pub phentsize: u32,
+    pub phnum: u32,
+    /// Symbol table, name to value
+    pub symbols: HashMap<String, u32>,
+    /// STT_FUNC symbols sorted by address
+    pub functions: Vec<Function>,
+}
+
+#[derive(Clone)]
+pub struct Function {
+    pub start: u32,
+    /// From the symbol, or up to the next function if the symbol has none
+    pub size: u32,
+    pub name: String,
+}
+
+/// Index of the function containing `address` in address-sorted `functions`
+pub fn function_at(functions: &[Function], address: u32) -> Option<usize> {
+    let i = functions.partition_point(|f| f.start <= address).checked_sub(1)?;
+    (address - functions[i].start < functions[i].size).then_some(i)
+}
+
+/// Register width of the machine
+pub const XLEN: u32 = 32;
+
+/// Checks that the ELF is a little-endian RV32 executable or shared object, the errors say
+/// what the file is instead. Compressed and hard-float code only warns: the
+/// machine traps on those instructions, which may never run.
+pub fn check_header(ehdr: &FileHeader<AnyEndian>) -> Result<(), String> {
+    let xlen = match ehdr.class {
+        Class::ELF32 => 32,
+        Class::ELF64 => 64,
+    };
+    if ehdr.e_machine != abi::EM_RISCV {
+        return Err(format!("not a RISC-V ELF (e_machine {}), the machine is RV{}", ehdr.e_machine, XLEN));
+    }
+    if xlen != XLEN {
+        return Err(format!("this is an RV{} ELF but the machine is RV{}", xlen, XLEN));
+    }
+    if ehdr.endianness == AnyEndian::Big {
+        return Err(format!("this is a big-endian ELF but the machine is little-endian RV{}", XLEN));
+    }
+    match ehdr.e_type {
+        abi::ET_EXEC | abi::ET_DYN => {}
+        abi::ET_REL => return Err("this is a relocatable object, link it first".to_string()),
+        e_type => return Err(format!("not an executable (e_type {})", e_type)),
+    }
+    if ehdr.e_flags & abi::EF_RISCV_RVC != 0 {
+        eprintln!("warning: the ELF may use compressed instructions, the machine has no C extension");
+    }
+    if ehdr.e_flags & abi::EF_RISCV_FLOAT_ABI_MASK != abi::EF_RISCV_FLOAT_ABI_SOFT {
+        eprintln!("warning: the ELF uses a hard-float ABI, the machine has no F or D extension");
+    }
+    Ok(())
+}
+
+/// Value of `symbol` once the ELF is loaded at `base`, absolute and
+/// undefined symbols stay
+fn value(symbol: &Symbol, base: u32) -> u32 {
+    match symbol.st_shndx {
+        abi::SHN_ABS | abi::SHN_UNDEF => symbol.st_value as u32,
+        _ => base.wrapping_add(symbol.st_value as u32),
+    }
+}
+
+/// Applies the dynamic relocations of an ELF loaded at `base`: the SHF_ALLOC
+/// SHT_RELA sections, resolving symbols against the ELF's own .dynsym.
+/// Undefined weak symbols are 0, other undefined symbols are an error since
+/// nothing else is loaded to define them.
+fn relocate<S: Read + Seek>(core: &mut CoreState, elf: &mut ElfStream<AnyEndian, S>, base: u32)
+                            -> Result<(), String> {
+    let mut relas = Vec::new();
+    let sections: Vec<_> = elf.section_headers().iter()
+        .filter(|s| s.sh_type == abi::SHT_RELA && s.sh_flags & abi::SHF_ALLOC as u64 != 0)
+        .copied()
+        .collect();
+    for section in sections {
+        relas.extend(elf.section_data_as_relas(&section).map_err(|e| e.to_string())?);
+    }
+    let mut symbols = Vec::new();
+    if let Some((sym_tab, str_tab)) = elf.dynamic_symbol_table().map_err(|e| e.to_string())? {
+        for sym in sym_tab.iter() {
+            let name = str_tab.get(sym.st_name as usize).unwrap_or("?").to_string();
+            let defined = !sym.is_undefined() || sym.st_bind() == abi::STB_WEAK;
+            symbols.push((name, defined.then(|| value(&sym, base))));
+        }
+    }
+    for rela in relas {
+        let address = base.wrapping_add(rela.r_offset as u32);
+        let addend = rela.r_addend as u32;
+        let symbol = || match symbols.get(rela.r_sym as usize) {
+            Some((_, Some(value))) => Ok(*value),
+            Some((name, None)) => Err(format!("undefined symbol {}", name)),
+            None => Err(format!("bad symbol index {} at 0x{:x}", rela.r_sym, address)),
+        };
+        let word = match rela.r_type {
+            abi::R_RISCV_NONE => continue,
+            abi::R_RISCV_RELATIVE => base.wrapping_add(addend),
+            abi::R_RISCV_32 => symbol()?.wrapping_add(addend),
+            abi::R_RISCV_JUMP_SLOT => symbol()?,
+            kind => return Err(format!("unsupported relocation type {} at 0x{:x}", kind, address)),
+        };
+        core.memory.get_mut(address as usize..address as usize + 4)
+            .ok_or(format!("relocation at 0x{:x} outside memory", address))?
+            .copy_from_slice(&word.to_le_bytes());
+    }
+    Ok(())
+}
+
+/// Copies every PT_LOAD segment to its virtual address and zeroes the bss
+/// part. Only the headers, the segment ranges and the symbol table are read,
+/// straight from the file into guest memory. ET_DYN ELFs are loaded at
+/// `PIE_BASE` and relocated.
+pub fn load_segments(core: &mut CoreState, path: &str) -> Result<Image, String> {
+    let err = |e: &dyn std::fmt::Display| format!("{}: {}", path, e);
+    let file = File::open(path).map_err(|e| err(&e))?;
+    let mut elf = ElfStream::<AnyEndian, _>::open_stream(&file).map_err(|e| err(&e))?;
+    check_header(&elf.ehdr).map_err(|e| err(&e))?;
+    let base = if elf.ehdr.e_type == abi::ET_DYN {PIE_BASE} else {0};
+
+    let mut image = Image {
+        path: path.to_string(),
+        base,
+        entry: base.wrapping_add(elf.ehdr.e_entry as u32),
+        end: 0,
+        phdr: 0,
+        phentsize: elf.ehdr.e_phentsize as u32,
+        phnum: elf.ehdr.e_phnum as u32,
+        symbols: HashMap::new(),
+        functions: Vec::new(),
+    };
+    if elf.segments().is_empty() {
+        return Err(err(&"no program headers"));
+    }
+    for segment in elf.segments().iter().filter(|s| s.p_type == abi::PT_LOAD) {
+        let address = base as usize + segment.p_vaddr as usize;
+        let memsz = segment.p_memsz as usize;
+        let filesz = segment.p_filesz as usize;
+        let target = core.memory
+            .get_mut(address..address + memsz)
+            .filter(|_| filesz <= memsz)
+            .ok_or(format!("{}: segment at 0x{:x} outside memory", path, address))?;
+        (&file).seek(SeekFrom::Start(segment.p_offset))
+            .and_then(|_| (&file).read_exact(&mut target[..filesz]))
+            .map_err(|_| err(&"truncated segment"))?;
+        target[filesz..].fill(0);
+        if (segment.p_offset..segment.p_offset + segment.p_filesz).contains(&elf.ehdr.e_phoff) {
+            image.phdr = (address as u64 + elf.ehdr.e_phoff - segment.p_offset) as u32;
+        }
+        image.end = image.end.max((address + memsz) as u32);
+    }
+    if elf.ehdr.e_type == abi::ET_DYN {
+        relocate(core, &mut elf, base).map_err(|e| err(&e))?;
+    }
+
+    // stripped shared objects still have their dynamic symbols
+    let has_symtab = elf.section_headers().iter().any(|s| s.sh_type == abi::SHT_SYMTAB);
+    let table = if has_symtab {elf.symbol_table()} else {elf.dynamic_symbol_table()};
+    if let Ok(Some((sym_tab, str_tab))) = table {
+        for sym in sym_tab.iter() {
+            if let Ok(name) = str_tab.get(sym.st_name as usize) {
+                if !name.is_empty() {
+                    image.symbols.insert(name.to_string(), value(&sym, base));
+                }
+                if sym.st_symtype() == abi::STT_FUNC && !name.is_empty() {
+                    image.functions.push(Function {
+                        start: value(&sym, base),
+                        size: sym.st_size as u32,
+                        name: name.to_string(),
+                    });
+                }
+            }
+        }
+    }
+    image.functions.sort_by_key(|f| f.start);
+    image.functions.dedup_by_key(|f| f.start);
+    for i in 0..image.functions.len() {
+        if image.functions[i].size == 0 {
+            let next = image.functions.get(i + 1).map_or(image.end, |f| f.start);
+            image.functions[i].size = next.saturating_sub(image.functions[i].start);
+        }
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use elf::ElfBytes;
+
+    use super::*;
+    use crate::difftest;
+    use crate::test_utils::machine;
+
+    /// Stripped ET_DYN ELF loaded whole at 0: relocated slots at 0x60,
+    /// .dynsym at 0x70, .dynstr at 0xB0, .rela.dyn at 0xC0
+    fn shared_object() -> Vec<u8> {
+        let mut elf = Vec::new();
+        elf.extend(b"\x7fELF\x01\x01\x01\0\0\0\0\0\0\0\0\0");
+        for half in [abi::ET_DYN, abi::EM_RISCV] {
+            elf.extend(half.to_le_bytes());
+        }
+        // version, entry, phoff, shoff, flags
+        for word in [1_u32, 0x60, 52, 0xE4, 0] {
+            elf.extend(word.to_le_bytes());
+        }
+        for half in [52_u16, 32, 1, 40, 5, 0] {
+            elf.extend(half.to_le_bytes());
+        }
+        for word in [abi::PT_LOAD, 0, 0, 0, 0xE4, 0xE4, 7, 0x1000] {
+            elf.extend(word.to_le_bytes());
+        }
+        elf.resize(0x70, 0);
+        // name, value, size, info, other, shndx
+        let symbols: [(u32, u32, u32, u8, u16); 4] = [
+            (0, 0, 0, 0, 0), (1, 0x70, 4, abi::STB_GLOBAL << 4 | abi::STT_OBJECT, 1),
+            (6, 0, 0, abi::STB_WEAK << 4, abi::SHN_UNDEF), (11, 0x60, 4, abi::STB_GLOBAL << 4 | abi::STT_FUNC, 1),
+        ];
+        for (name, value, size, info, shndx) in symbols {
+            for word in [name, value, size] {
+                elf.extend(word.to_le_bytes());
+            }
+            elf.extend([info, 0]);
+            elf.extend(shndx.to_le_bytes());
+        }
+        elf.extend(b"\0data\0weak\0main\0");
+        // offset, sym << 8 | type, addend
+        for (offset, info, addend) in [(0x60, abi::R_RISCV_RELATIVE, 0x70), (0x64, 1 << 8 | abi::R_RISCV_32, 4),
+                                       (0x68, 2 << 8 | abi::R_RISCV_JUMP_SLOT, 0)] {
+            for word in [offset, info, addend] {
+                elf.extend(word.to_le_bytes());
+            }
+        }
+        // name, type, flags, addr, offset, size, link, info, addralign, entsize
+        let sections: [[u32; 10]; 5] = [
+            [0; 10],
+            [0, abi::SHT_PROGBITS, abi::SHF_ALLOC, 0, 0, 0x70, 0, 0, 4, 0],
+            [0, abi::SHT_DYNSYM, abi::SHF_ALLOC, 0x70, 0x70, 0x40, 3, 1, 4, 16],
+            [0, abi::SHT_STRTAB, abi::SHF_ALLOC, 0xB0, 0xB0, 0x10, 0, 0, 1, 0],
+            [0, abi::SHT_RELA, abi::SHF_ALLOC, 0xC0, 0xC0, 0x24, 2, 0, 4, 12],
+        ];
+        for section in sections {
+            for word in section {
+                elf.extend(word.to_le_bytes());
+            }
+        }
+        elf
+    }
+
+    #[test]
+    fn foreign_elfs_are_refused() {
+        let file = difftest::elf(&[0x0000_0013], &[]);
+        let ehdr = ElfBytes::<AnyEndian>::minimal_parse(&file).unwrap().ehdr;
+        assert_eq!(check_header(&ehdr), Ok(()));
+        assert_eq!(check_header(&FileHeader {class: Class::ELF64, ..ehdr}),
+                   Err("this is an RV64 ELF but the machine is RV32".to_string()));
+        assert_eq!(check_header(&FileHeader {endianness: AnyEndian::Big, ..ehdr}),
+                   Err("this is a big-endian ELF but the machine is little-endian RV32".to_string()));
+        assert_eq!(check_header(&FileHeader {e_type: abi::ET_REL, ..ehdr}),
+                   Err("this is a relocatable object, link it first".to_string()));
+
+        // x86-64 in e_machine
+        let mut file = file;
+        file[18] = 62;
+        let path = std::env::temp_dir().join("rs-v-loader-x86.elf");
+        fs::write(&path, &file).unwrap();
+        let path = path.to_string_lossy().into_owned();
+        let mut core = machine(0, &[], &[]);
+        assert_eq!(load_segments(&mut core, &path).map(|_| ()),
+                   Err(format!("{}: not a RISC-V ELF (e_machine 62), the machine is RV32", path)));
+    }
+
+    #[test]
+    fn shared_objects_are_relocated() {
+        let path = std::env::temp_dir().join("rs-v-loader-pie.elf");
+        fs::write(&path, shared_object()).unwrap();
+        let mut core = machine(0, &[], &[]);
+        core.memory.resize(PIE_BASE as usize + 0x1000, 0);
+        let image = load_segments(&mut core, &path.to_string_lossy()).unwrap();
+        assert_eq!((image.base, image.entry, image.end), (PIE_BASE, PIE_BASE + 0x60, PIE_BASE + 0xE4));
+        assert_eq!((image.symbols["data"], image.symbols["weak"]), (PIE_BASE + 0x70, 0));
+        assert_eq!((image.functions[0].start, image.functions[0].name.as_str()), (PIE_BASE + 0x60, "main"));
+        let word = |address: u32| u32::from_le_bytes(core.memory[address as usize..address as usize + 4].try_into().unwrap());
+        // base + addend, data + 4 and the undefined weak symbol
+        assert_eq!((word(PIE_BASE + 0x60), word(PIE_BASE + 0x64), word(PIE_BASE + 0x68)),
+                   (PIE_BASE + 0x70, PIE_BASE + 0x74, 0));
+    }
+}
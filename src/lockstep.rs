@@ -0,0 +1,151 @@
+use crate::csr_file::{status, MCAUSE, MEPC, MIE, MSCRATCH, MTVAL, MTVEC};
+use crate::{load_bare_metal, Config, CoreState, Engine, Platform};
+
+/// Syncs between full memory comparisons
+const MEMORY_INTERVAL: u64 = 1 << 12;
+
+/// One side of the comparison
+struct Machine {
+    name: String,
+    engine: Engine,
+    core: CoreState,
+    platform: Platform,
+    exit: Option<i32>,
+}
+
+impl Machine {
+    /// `spec` is `step`, `block` or `jit`, `+lenient` to decode leniently
+    fn new(spec: &str, args: &[String], memory_size: Option<usize>) -> Result<Self, String> {
+        let (engine, lenient) = match spec.strip_suffix("+lenient") {
+            Some(engine) => (engine, true),
+            None => (spec, false),
+        };
+        let engine = match engine {
+            "step" => Engine::Step,
+            "block" => Engine::Block,
+            #[cfg(feature = "jit")]
+            "jit" => Engine::Jit,
+            _ => return Err(format!("bad configuration `{}`, expected step or block[+lenient]", spec)),
+        };
+        let path = args.first().ok_or("missing program")?;
+        let (mut core, image) = load_bare_metal(path, memory_size)?;
+        core.lenient = lenient;
+        let platform = Platform::new(args, &image, &mut core);
+        Ok(Self {name: spec.to_string(), engine, core, platform, exit: None})
+    }
+
+    fn step(&mut self) {
+        if self.exit.is_none() {
+            self.exit = self.platform.step(&mut self.core, self.engine);
+        }
+    }
+}
+
+/// Architectural state the two machines disagree on, memory if `memory`
+fn differences(a: &CoreState, b: &CoreState, memory: bool) -> Vec<String> {
+    let mut fields = vec![("pc".to_string(), a.pc, b.pc)];
+    fields.extend((1..32).map(|i| (CoreState::reg_name(i), a.regs[i], b.regs[i])));
+    fields.extend([
+        ("mstatus.MIE".to_string(), a.csrs.status(status::MIE) as u32, b.csrs.status(status::MIE) as u32),
+        ("mstatus.MPIE".to_string(), a.csrs.status(status::MPIE) as u32, b.csrs.status(status::MPIE) as u32),
+        ("mtvec".to_string(), a.csrs.get(MTVEC), b.csrs.get(MTVEC)),
+        ("mscratch".to_string(), a.csrs.get(MSCRATCH), b.csrs.get(MSCRATCH)),
+        ("mepc".to_string(), a.csrs.get(MEPC), b.csrs.get(MEPC)),
+        ("mcause".to_string(), a.csrs.get(MCAUSE), b.csrs.get(MCAUSE)),
+        ("mtval".to_string(), a.csrs.get(MTVAL), b.csrs.get(MTVAL)),
+        ("mie".to_string(), a.csrs.get(MIE), b.csrs.get(MIE)),
+        ("mip".to_string(), a.mip, b.mip),
+    ]);
+    let mut differences: Vec<String> = fields
+        .into_iter()
+        .filter(|(_, a, b)| a != b)
+        .map(|(name, a, b)| format!("{}: 0x{:08x} vs 0x{:08x}", name, a, b))
+        .collect();
+    if memory {
+        if let Some(address) = a.memory.iter().zip(&b.memory).position(|(a, b)| a != b) {
+            differences.push(format!("memory at 0x{:08x}: 0x{:02x} vs 0x{:02x}", address, a.memory[address],
+                                     b.memory[address]));
+        }
+    }
+    differences
+}
+
+/// Runs a bare-metal program on two configurations and compares their state
+/// whenever both have retired the same number of instructions, the first
+/// being the trusted one. `args` is `first,second program [args]`, e.g.
+/// `step,block prog.elf`. Returns the guest exit code, or 1 at the first
+/// divergence.
+pub fn run(args: &[String], config: Config) -> Result<i32, String> {
+    let Config {memory_size, ..} = config;
+    let (pair, args) = args.split_first().ok_or("missing configurations, e.g. step,block")?;
+    let (first, second) = pair.split_once(',').ok_or(format!("`{}` isn't two configurations", pair))?;
+    let mut a = Machine::new(first, args, memory_size)?;
+    let mut b = Machine::new(second, args, memory_size)?;
+    // the trusted side prints the guest output
+    b.platform.quiet();
+
+    let mut syncs: u64 = 0;
+    let mut compared = 0;
+    loop {
+        let (ahead, behind) = (a.platform.retired, b.platform.retired);
+        let behind = match (a.exit, b.exit) {
+            // one exited, the other ran on past it
+            (Some(_), None) if behind > ahead => break,
+            (None, Some(_)) if ahead > behind => break,
+            (None, Some(_)) => &mut a,
+            (None, None) if ahead <= behind => &mut a,
+            (_, None) => &mut b,
+            (Some(_), Some(_)) => break,
+        };
+        behind.step();
+        let retired = a.platform.retired;
+        let both_exited = a.exit.is_some() && b.exit.is_some();
+        if (retired == b.platform.retired && retired > compared) || both_exited {
+            compared = retired;
+            syncs += 1;
+            let differences = differences(&a.core, &b.core, both_exited || syncs.is_multiple_of(MEMORY_INTERVAL));
+            if !differences.is_empty() {
+                eprintln!("{} and {} diverge after {} instructions ({} at {} instructions):", a.name, b.name,
+                          retired, b.name, b.platform.retired);
+                for difference in differences {
+                    eprintln!("  {}", difference);
+                }
+                return Ok(1);
+            }
+        }
+    }
+    match (a.exit, b.exit) {
+        (Some(x), Some(y)) if x != y => {
+            eprintln!("{} exits with {}, {} with {}", a.name, x, b.name, y);
+            Ok(1)
+        }
+        (Some(code), Some(_)) => {
+            eprintln!("{} and {} agree over {} instructions", a.name, b.name, a.platform.retired);
+            Ok(code)
+        }
+        (exit, _) => {
+            let (exited, running) = if exit.is_some() {(&a, &b)} else {(&b, &a)};
+            eprintln!("{} exits after {} instructions, {} runs on", exited.name, exited.platform.retired,
+                      running.name);
+            Ok(1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{self, OP_IMM};
+    use crate::test_utils::machine;
+
+    #[test]
+    fn differences_name_the_fields() {
+        let program = [encode::i(OP_IMM, 0b000, 10, 0, 5)];
+        let a = machine(0, &program, &[]);
+        let mut b = machine(0, &program, &[(10, 5)]);
+        b.memory[0x200] = 1;
+        assert_eq!(differences(&a, &b, false), ["a0: 0x00000000 vs 0x00000005"]);
+        assert_eq!(differences(&a, &b, true)[1], "memory at 0x00000200: 0x00 vs 0x01");
+        assert!(differences(&a, &a, true).is_empty());
+    }
+}
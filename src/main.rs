@@ -1,698 +1,734 @@
+use std::any::Any;
+use std::collections::HashMap;
 use std::fs;
-use std::fmt::{Display, Formatter};
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use std::time::{Duration, SystemTime};
 
 use elf::abi;
 use elf::endian::AnyEndian;
 use elf::ElfBytes;
 
-#[derive(Debug)]
-struct ArgsRType {
-    rs1: usize,
-    rs2: usize,
-    rd: usize,
-}
+use rs_v::annotate::Annotate;
+use rs_v::bootrom::BootRom;
+use rs_v::cache::{Cache, Caches};
+use rs_v::cfi::Cfi;
+use rs_v::coverage::Coverage;
+use rs_v::disasm::disassemble;
+use rs_v::flamegraph::Flamegraph;
+use rs_v::heatmap::Heatmap;
+use rs_v::hotspots::Hotspots;
+use rs_v::input::InputScript;
+use rs_v::interrupts::Injector;
+use rs_v::lcov::Lcov;
+use rs_v::memcheck::Memcheck;
+use rs_v::mmio::MmioLog;
+use rs_v::predictor::Predictor;
+use rs_v::progress::Progress;
+use rs_v::script::Script;
+use rs_v::stats::Stats;
+use rs_v::taint::Taint;
+use rs_v::timing::{InOrder, TimingModel};
+use rs_v::trace::{self, AsmTrace, Tracer};
+use rs_v::vcd::Vcd;
+use rs_v::watch::Watch;
+use rs_v::{act, campaign, compare, cosim, dashboard, difftest, gdb, linux, loader, lockstep, profile, rpc, run, shard, snapshot, symbolic, torture, Config, CoreState, Engine, Identity, Observer, RunOutcome};
 
-#[derive(Debug)]
-struct ArgsIType {
-    rs1: usize,
-    rd: usize,
-    imm: i32,
-    shamt: u8,
-    csr: u16,
-}
+const MEMORY_SIZE: usize = 4096;
+// the v kernel (vm.c) hands out the MAX_TEST_PAGES = 63 pages after the
+// first 63 as user pages
+const V_MEMORY_SIZE: usize = 2 * 63 * 4096;
+// riscv-tests sections are linked at 0, the data pages follow the code
+const MAX_SECTION_END: usize = 16 << 20;
+// user-level, machine-mode and supervisor-mode riscv-tests
+const SUITES: [&str; 3] = ["rv32ui", "rv32mi", "rv32si"];
+/// Instructions a riscv-test may run before it counts as hung
+const TIMEOUT: u64 = 1 << 24;
+/// How often `test --watch` looks at the ELFs
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
 
-#[derive(Debug)]
-struct ArgsSBType {
-    rs1: usize,
-    rs2: usize,
-    imm: i32,
-}
+fn get_tests(path: &str, filter: &str) -> Vec<String> {
+    let dir = fs::read_dir(path).unwrap();
+    dir
+        .map(|entry| String::from(entry.unwrap().path().to_str().unwrap()))
+        .filter(|entry| entry.contains(filter) && !entry.ends_with("dump"))
+        .collect()
 
-#[derive(Debug)]
-struct ArgsUJType {
-    rd: usize,
-    imm: i32,
 }
 
-#[derive(Debug)]
-enum Instruction {
-    Lui     (ArgsUJType),
-    Auipc   (ArgsUJType),
-    Jal     (ArgsUJType),
-    Jalr    (ArgsIType),
-    Beq     (ArgsSBType),
-    Bne     (ArgsSBType),
-    Blt     (ArgsSBType),
-    Bge     (ArgsSBType),
-    Bltu    (ArgsSBType),
-    Bgeu    (ArgsSBType),
-    Lb      (ArgsIType),
-    Lh      (ArgsIType),
-    Lw      (ArgsIType),
-    Lbu     (ArgsIType),
-    Lhu     (ArgsIType),
-    Sb      (ArgsSBType),
-    Sh      (ArgsSBType),
-    Sw      (ArgsSBType),
-    Addi    (ArgsIType),
-    Slti    (ArgsIType),
-    Sltiu   (ArgsIType),
-    Xori    (ArgsIType),
-    Ori     (ArgsIType),
-    Andi    (ArgsIType),
-    Slli    (ArgsIType),
-    Srli    (ArgsIType),
-    Srai    (ArgsIType),
-    Add     (ArgsRType),
-    Sub     (ArgsRType),
-    Sll     (ArgsRType),
-    Slt     (ArgsRType),
-    Sltu    (ArgsRType),
-    Xor     (ArgsRType),
-    Srl     (ArgsRType),
-    Sra     (ArgsRType),
-    Or      (ArgsRType),
-    And     (ArgsRType),
-    Fence, // args
-    FenceTso,
-    Pause,
-    Ecall,
-    Ebreak,
-    Mret,
-    Wfi,
-    Csrrw   (ArgsIType),
-    Csrrs   (ArgsIType),
-    Csrrc   (ArgsIType),
-    Csrrwi  (ArgsIType),
-    Csrrsi  (ArgsIType),
-    Csrrci  (ArgsIType),
-}
 
-#[derive(Debug)]
-struct IllegalInstruction;
-
-#[derive(Debug)]
-enum Csr {
-    MIsa,
-    MVendorId,
-    MArchId,
-    MImpId,
-    MHartId,
-    MStatus,
-    MIe,
-    MTvec,
-    MScratch,
-    MEpc,
-    MCause,
-    MTVal,
-    MIp,
-    MConfigPtr,
+fn exit_with(result: Result<i32, String>) -> ! {
+    match result {
+        Ok(code) => std::process::exit(code),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
 }
 
-
-enum Cause {
-    InstructionAddressMisaligned,
-    InstructionAccessFault,
-    IllegalInstruction,
-    Breakpoint,
-    LoadAddressMisaligned,
-    LoadAccessFault,
-    StoreAmoAddressMisaligned,
-    StoreAmoAccessFault,
-    // Ucall,
-    // Scall,
-    Mcall,
-    SoftwareCheck,
-    HardwareError,
+/// Exits with the code of the outcome, saying why unless the guest exited
+fn exit_with_outcome(outcome: RunOutcome) -> ! {
+    if !matches!(outcome, RunOutcome::Exited(_)) {
+        eprintln!("{}", outcome);
+    }
+    std::process::exit(outcome.exit_code())
 }
 
-impl Csr {
-    fn get_csr(address: u16) -> Option<Self> {
-        match address {
-            0xF11 => Some(Self::MVendorId),
-            0xF12 => Some(Self::MArchId),
-            0xF13 => Some(Self::MImpId),
-            0xF14 => Some(Self::MHartId),
-            0xF15 => Some(Self::MConfigPtr),
-            0x300 => Some(Self::MStatus),
-            0x301 => Some(Self::MIsa),
-            0x304 => Some(Self::MIe),
-            0x305 => Some(Self::MTvec),
-            0x340 => Some(Self::MScratch),
-            0x341 => Some(Self::MEpc),
-            0x342 => Some(Self::MCause),
-            0x343 => Some(Self::MTVal),
-            0x344 => Some(Self::MIp),
-            _ => None
+/// Copies the allocatable sections (.text, .data, .rodata, .sdata, .tohost,
+/// ...) to their addresses and zeroes .bss, growing memory up to
+/// `MAX_SECTION_END` when a section lies past it
+fn load_sections(core_state: &mut CoreState, elf: &ElfBytes<AnyEndian>) -> Result<(), String> {
+    let sections = elf.section_headers().ok_or("no section headers")?;
+    for section in sections.iter().filter(|s| s.sh_flags & abi::SHF_ALLOC as u64 != 0 && s.sh_size != 0) {
+        let start = section.sh_addr as usize;
+        let end = start + section.sh_size as usize;
+        if end > MAX_SECTION_END {
+            return Err(format!("section at 0x{:x} past 0x{:x}", start, MAX_SECTION_END));
+        }
+        if end > core_state.memory.len() {
+            core_state.memory.resize(end, 0);
+        }
+        if section.sh_type == abi::SHT_NOBITS {
+            core_state.memory[start..end].fill(0);
+        } else {
+            let data = elf.section_data(&section).map_err(|e| e.to_string())?.0;
+            core_state.memory[start..start + data.len()].copy_from_slice(data);
         }
     }
+    Ok(())
 }
 
-const MEMORY_SIZE: usize = 4096;
-
-struct CoreState {
-    pc: u32,
-    regs: [u32; 32],
-    memory: [u8; MEMORY_SIZE],
-    // M-mode
-    mie: bool,
-    mpie: bool,
-    mtvec: u32,
-    mscratch: u32,
-    mepc: u32,
-    mcause: Cause,
-    mtval: u32,
+/// riscv-tests environment, the `p` or `v` in `rv32ui-p-add`
+#[derive(Clone, Copy, PartialEq)]
+enum Env {
+    /// Bare machine, M-mode with physical addresses
+    Physical,
+    /// The test runs in U-mode under a small kernel that pages it with Sv32
+    Virtual,
 }
 
-impl Display for CoreState {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "pc: 0x{:08x}", self.pc)?;
-        // for (i, reg) in self.regs.iter().enumerate() {
-        //     let new_line = {if i % 4 == 3 {'\n'} else {' '}};
-        //     write!(f, "{:>5}: 0x{:08x}{}", Self::reg_name(i), reg, new_line)?;
-        // }
-        // for m in self.memory {
-        //     write!(f, "{:02x} ", m)?;
-        // }
-        Ok(())
+impl Env {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "p" => Some(Self::Physical),
+            "v" => Some(Self::Virtual),
+            _ => None,
+        }
     }
-}
 
-impl CoreState {
-    fn reg_name(index: usize) -> String {
-        match index {
-            0 => "zero".to_string(),
-            1 => "ra".to_string(),
-            2 => "sp".to_string(),
-            3 => "gp".to_string(),
-            4 => "tp".to_string(),
-            5..=7 => format!("t{}", index - 5),
-            8..=9 => format!("s{}", index - 8),
-            10..=17 => format!("a{}", index - 10),
-            18..=27 => format!("s{}", index - 16),
-            28..=31 => format!("t{}", index - 25),
-            _ => unimplemented!(),
+    /// The environment in a test's file name, physical for names without
+    /// one
+    fn of(test: &str) -> Self {
+        let name = test.rsplit('/').next().unwrap();
+        match name.split('-').nth(1).and_then(Self::parse) {
+            Some(env) => env,
+            None => Self::Physical,
         }
     }
 
-    fn reset(&mut self) {
-        self.pc = 0;
-        self.mie = false;
-        self.mpie = false;
+    /// Memory a test starts with, sections past it grow it. Both
+    /// environments start at the ELF entry in M-mode; the `v` kernel
+    /// builds its page tables and enables paging itself, in memory past the
+    /// image.
+    fn memory_size(self) -> usize {
+        match self {
+            Self::Physical => MEMORY_SIZE,
+            Self::Virtual => V_MEMORY_SIZE,
+        }
     }
+}
+
+/// How a riscv-test ended
+enum Outcome {
+    Pass,
+    Fail,
+    /// Not runnable here: an unreadable file, a foreign ELF or sections that
+    /// don't fit
+    Skipped(String),
+    /// No `pass` or `fail` symbol to stop at
+    SymbolsMissing,
+    /// Didn't pass after running an instruction the emulator only stubs, so
+    /// the result says more about the emulator than about the guest
+    Unimplemented(&'static str),
+    /// Still running after `TIMEOUT` instructions
+    Timeout,
+    /// The emulator panicked, with the message and where
+    Panic(String),
+}
 
-    fn get_csr_value(&self, csr: &Csr) -> u32 {
-        match csr {
-            // RV32IM
-            Csr::MIsa => (1 << 30) | (1 << 8) | (1 << 12),
-            Csr::MVendorId => 0,
-            Csr::MArchId => 0,
-            Csr::MImpId => 0,
-            Csr::MHartId => 0,
-            Csr::MStatus => (3 << 11) |
-                            ((self.mie as u32) << 3) |
-                            ((self.mpie as u32) << 7),
-            Csr::MIe => 0,
-            Csr::MTvec => self.mtvec,
-            Csr::MScratch => self.mscratch,
-            Csr::MEpc => self.mepc,
-            Csr::MCause => Self::get_cause_value(&self.mcause),
-            Csr::MTVal => self.mtval,
-            Csr::MIp => 0,
-            Csr::MConfigPtr => 0,
+impl Outcome {
+    /// Line printed under the test name
+    fn report(&self) -> String {
+        match self {
+            Outcome::Pass => "🟢".to_string(),
+            Outcome::Fail => "🔴".to_string(),
+            Outcome::Skipped(reason) => format!("🟡 skipped: {}", reason),
+            Outcome::SymbolsMissing => "🟡 no pass/fail symbols".to_string(),
+            Outcome::Unimplemented(feature) => format!("🟠 unimplemented: {}", feature),
+            Outcome::Timeout => format!("🟣 timeout after {} instructions", TIMEOUT),
+            Outcome::Panic(message) => format!("💥 panic: {}", message),
         }
     }
 
-    fn set_csr_value(&mut self, csr: &Csr, value: u32) {
-        match csr {
-            Csr::MStatus => {
-                self.mie = (value >> 3) & 1 != 0;
-                self.mpie = (value >> 7) & 1 != 0;
-            }
-            Csr::MTvec => self.mtvec = value,
-            Csr::MScratch => self.mscratch = value,
-            Csr::MEpc => self.mepc = value,
-            // Csr::MCause => Self::get_cause_value(&self.mcause),
-            Csr::MTVal => self.mtval = value,
-            _ => {},
+    /// Exit code of the harness when this is its worst outcome: 1 for a
+    /// guest failure, `RunOutcome::EMULATOR_FAILURE` when the emulator is to
+    /// blame
+    fn exit_code(&self) -> i32 {
+        match self {
+            Outcome::Pass | Outcome::Skipped(_) | Outcome::SymbolsMissing => 0,
+            Outcome::Fail | Outcome::Timeout => 1,
+            Outcome::Unimplemented(_) | Outcome::Panic(_) => RunOutcome::EMULATOR_FAILURE,
         }
     }
 
-    fn get_cause_value(cause: &Cause) -> u32 {
-        match cause {
-            Cause::InstructionAddressMisaligned => 0,
-            Cause::InstructionAccessFault => 1,
-            Cause::IllegalInstruction => 2,
-            Cause::Breakpoint => 3,
-            Cause::LoadAddressMisaligned => 4,
-            Cause::LoadAccessFault => 5,
-            Cause::StoreAmoAddressMisaligned => 6,
-            Cause::StoreAmoAccessFault => 7,
-            Cause::Mcall => 11,
-            Cause::SoftwareCheck => 18,
-            Cause::HardwareError => 19,
+    /// Column of the summary
+    fn class(&self) -> usize {
+        match self {
+            Outcome::Pass => 0,
+            Outcome::Fail => 1,
+            Outcome::Unimplemented(_) => 2,
+            Outcome::Timeout => 3,
+            Outcome::Panic(_) => 4,
+            Outcome::SymbolsMissing => 5,
+            Outcome::Skipped(_) => 6,
         }
     }
+}
 
-    fn decode(instruction: u32) -> Result<Instruction, IllegalInstruction> {
-        let opcode = instruction & 0b111_1111;
-        let funct3 = (instruction >> 12) & 0b111;
-        let funct7 = (instruction >> 25) & 0b111_1111;
+const CLASSES: [&str; 7] = ["passed", "failed", "unimplemented", "timed out", "panicked", "without symbols", "skipped"];
 
-        let rs1: usize = ((instruction >> 15) & 0b1_1111).try_into().unwrap();
-        let rs2: usize = ((instruction >> 20) & 0b1_1111).try_into().unwrap();
-        let rd: usize = ((instruction >> 7) & 0b1_1111).try_into().unwrap();
-        let shamt = rs2 as u8;
-        let csr: u16 = ((instruction >> 20) & 0xFFF).try_into().unwrap();
+/// Message of a caught panic
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload.downcast_ref::<&str>().map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown payload".to_string())
+}
 
-        let imm_i = ((instruction & 0xFFF00000) as i32) >> 20;
+/// Loads and runs one riscv-test on a fresh machine, configured by
+/// `configure`
+fn run_test(test: &str, configure: &dyn Fn(&mut CoreState), script: &mut Option<Script>, trace: &mut Option<Tracer>,
+            observers: &mut [Box<dyn Observer>]) -> (Outcome, Option<String>) {
+    // nothing an earlier ELF set up or left in memory carries over
+    let mut core_state = CoreState::new(Env::of(test).memory_size());
+    configure(&mut core_state);
+
+    let file_contents = match fs::read(test) {
+        Ok(contents) => contents,
+        Err(e) => return (Outcome::Skipped(format!("{}: {}", test, e)), None),
+    };
+    let elf = match ElfBytes::<AnyEndian>::minimal_parse(&file_contents) {
+        Ok(elf) => elf,
+        Err(e) => return (Outcome::Skipped(format!("not an ELF: {}", e)), None),
+    };
+    if let Err(e) = loader::check_header(&elf.ehdr) {
+        return (Outcome::Skipped(e), None);
+    }
+    if let Err(e) = load_sections(&mut core_state, &elf) {
+        return (Outcome::Skipped(e), None);
+    }
 
-        let imm_s = {
-            let imm_11_5 = (instruction & 0xFE000000) as i32;
-            let imm_4_0 = ((instruction >> 7) & 0x1F) as i32;
-            (imm_11_5 >> 20) | imm_4_0
-        };
+    let mut pass_pc: u32 = 0;
+    let mut fail_pc: u32 = 0;
 
-        let imm_b = {
-            let imm_12 = (((instruction & 0x80000000) as i32) >> 19) as u32;
-            let imm_11 = (instruction & 0x00000080) << 4;
-            let imm_10_5 = (instruction >> 20) & 0x7E0;
-            let imm_4_1 = (instruction >> 7) & 0x1E;
-            (imm_12 | imm_11 | imm_10_5 | imm_4_1) as i32
+    let mut symbols = HashMap::new();
+    // a stripped ELF has no symbol table
+    let Ok(Some((sym_tab, str_tab))) = elf.symbol_table() else {
+        return (Outcome::SymbolsMissing, None);
+    };
+    for sym in sym_tab.iter() {
+        let Ok(name) = str_tab.get(sym.st_name as usize) else {
+            continue;
         };
+        symbols.insert(name.to_string(), sym.st_value as u32);
+        match name {
+            "pass" => pass_pc = sym.st_value as u32,
+            "fail" => fail_pc = sym.st_value as u32,
+            _ => {}
+        }
+    }
 
-        let imm_u = (instruction & 0xFFFFF000) as i32;
+    if (pass_pc == 0) || (fail_pc == 0) {
+        return (Outcome::SymbolsMissing, None);
+    }
 
-        let imm_j = {
-            let imm_20 = (((instruction & 0x80000000) as i32) >> 11) as u32;
-            let imm_19_12 = instruction & 0x000FF000;
-            let imm_11 = (instruction & 0x00100000) >> 9;
-            let imm_10_1 = (instruction & 0x7FE00000) >> 20;
-            (imm_20 | imm_19_12 | imm_11 | imm_10_1) as i32
-        };
+    // RVTEST_PASS and RVTEST_FAIL in a trap handler end the test through
+    // tohost, 1 for a pass and an odd test number otherwise
+    let tohost = symbols.get("tohost").map(|&address| address as usize);
 
-        let args_r = ArgsRType{rs1, rs2, rd};
-        let args_i = ArgsIType{rs1, rd, imm: imm_i, shamt, csr};
-        let args_s = ArgsSBType{rs1, rs2, imm: imm_s};
-        let args_b = ArgsSBType{rs1, rs2, imm: imm_b};
-        let args_u = ArgsUJType{rd, imm: imm_u};
-        let args_j = ArgsUJType{rd, imm: imm_j};
-
-        match opcode {
-            0b011_0111 => Ok(Instruction::Lui(args_u)),
-            0b001_0111 => Ok(Instruction::Auipc(args_u)),
-            0b110_1111 => Ok(Instruction::Jal(args_j)),
-            0b110_0111 => match funct3 {
-                0 => Ok(Instruction::Jalr(args_i)),
-                _ => Err(IllegalInstruction),
-            }
-            0b110_0011 => match funct3 {
-                0b000 => Ok(Instruction::Beq(args_b)),
-                0b001 => Ok(Instruction::Bne(args_b)),
-                0b100 => Ok(Instruction::Blt(args_b)),
-                0b101 => Ok(Instruction::Bge(args_b)),
-                0b110 => Ok(Instruction::Bltu(args_b)),
-                0b111 => Ok(Instruction::Bgeu(args_b)),
-                _ => Err(IllegalInstruction),
-            }
-            0b000_0011 => match funct3 {
-                0b000 => Ok(Instruction::Lb(args_i)),
-                0b001 => Ok(Instruction::Lh(args_i)),
-                0b010 => Ok(Instruction::Lw(args_i)),
-                0b100 => Ok(Instruction::Lbu(args_i)),
-                0b101 => Ok(Instruction::Lhu(args_i)),
-                _ => Err(IllegalInstruction),
-            }
-            0b010_0011 => match funct3 {
-                0b000 => Ok(Instruction::Sb(args_s)),
-                0b001 => Ok(Instruction::Sh(args_s)),
-                0b010 => Ok(Instruction::Sw(args_s)),
-                _ => Err(IllegalInstruction),
-            }
-            0b001_0011 => match funct3 {
-                0b000 => Ok(Instruction::Addi(args_i)),
-                0b010 => Ok(Instruction::Slti(args_i)),
-                0b011 => Ok(Instruction::Sltiu(args_i)),
-                0b100 => Ok(Instruction::Xori(args_i)),
-                0b110 => Ok(Instruction::Ori(args_i)),
-                0b111 => Ok(Instruction::Andi(args_i)),
-                0b001 => match funct7 {
-                    0 => Ok(Instruction::Slli(args_i)),
-                    _ => Err(IllegalInstruction),
-                }
-                0b101 => match funct7 {
-                    0 => Ok(Instruction::Srli(args_i)),
-                    0b010_0000 => Ok(Instruction::Srai(args_i)),
-                    _ => Err(IllegalInstruction),
-                }
-                _ => Err(IllegalInstruction),
-            }
-            0b011_0011 => match funct7 {
-                0 => match funct3 {
-                    0b000 => Ok(Instruction::Add(args_r)),
-                    0b001 => Ok(Instruction::Sll(args_r)),
-                    0b010 => Ok(Instruction::Slt(args_r)),
-                    0b011 => Ok(Instruction::Sltu(args_r)),
-                    0b100 => Ok(Instruction::Xor(args_r)),
-                    0b101 => Ok(Instruction::Srl(args_r)),
-                    0b110 => Ok(Instruction::Or(args_r)),
-                    0b111 => Ok(Instruction::And(args_r)),
-                    _ => Err(IllegalInstruction),
-                }
-                0b010_0000 => match funct3 {
-                    0b000 => Ok(Instruction::Sub(args_r)),
-                    0b101 => Ok(Instruction::Sra(args_r)),
-                    _ => Err(IllegalInstruction),
-                }
-                _ => Err(IllegalInstruction),
-            }
-            0b000_1111 => Ok(Instruction::Fence),
-            0b111_0011 => match (funct7, rs2, rs1, funct3, rd) {
-                (0, 0, 0, 0, 0) => Ok(Instruction::Ecall),
-                (0, 1, 0, 0, 0) => Ok(Instruction::Ebreak),
-                (0b001_1000, 0b0_0010, 0, 0, 0) => Ok(Instruction::Mret),
-                (0b000_1000, 0b0_0101, 0, 0, 0) => Ok(Instruction::Wfi),
-                (_, _, _, 0b001, _) => Ok(Instruction::Csrrw(args_i)),
-                (_, _, _, 0b010, _) => Ok(Instruction::Csrrs(args_i)),
-                (_, _, _, 0b011, _) => Ok(Instruction::Csrrc(args_i)),
-                (_, _, _, 0b101, _) => Ok(Instruction::Csrrwi(args_i)),
-                (_, _, _, 0b110, _) => Ok(Instruction::Csrrsi(args_i)),
-                (_, _, _, 0b111, _) => Ok(Instruction::Csrrci(args_i)),
-                _ => Err(IllegalInstruction),
-            }
-            _ => Err(IllegalInstruction),
-        }
+    core_state.reset();
+    if let Some(tracer) = trace.as_mut() {
+        tracer.note(test);
     }
 
-    /// TODO: Refactor branch load store sections
-    ///
-    /// TODO: Fix rs/rd races
-    ///
-    fn execute(&mut self) {
-        let address = (self.pc as usize)..=((self.pc + 3) as usize);
-        let instruction = u32::from_le_bytes(self.memory[address].try_into().expect("fetch error"));
-        let instruction = Self::decode(instruction);
-
-        if let Ok(instr) = instruction {
-
-            let jump_branch: bool = match &instr {
-                Instruction::Jal(_) |
-                Instruction::Jalr(_) |
-                Instruction::Beq(_) |
-                Instruction::Bne(_) |
-                Instruction::Blt(_) |
-                Instruction::Bge(_) |
-                Instruction::Bltu(_) |
-                Instruction::Bgeu(_) => true,
-                _ => false
-            };
-
-            let mut exception = false;
-
-            match instr {
-                Instruction::Lui(args) => {
-                    self.regs[args.rd] = args.imm as u32;
-                }
-                Instruction::Auipc(args) => {
-                    self.regs[args.rd] = args.imm as u32 + self.pc;
-                }
-                Instruction::Jal(args) => {
-                    self.regs[args.rd] = self.pc + 4;
-                    self.pc += args.imm as u32;
+    let outcome = 'run: {
+        for _ in 0..TIMEOUT {
+            if trace::ENABLED {
+                if let Some(tracer) = trace.as_mut() {
+                    tracer.step(&core_state);
                 }
-                Instruction::Jalr(args) => {
-                    let rs1 = self.regs[args.rs1];
-                    self.regs[args.rd] = self.pc + 4;
-                    self.pc = (rs1 + (args.imm as u32)) & 0xFFFF_FFFE;
-                }
-                Instruction::Beq(args) => {
-                    self.pc =
-                        if self.regs[args.rs1] == self.regs[args.rs2]
-                            {self.pc + (args.imm as u32)} else {self.pc + 4};
-                }
-                Instruction::Bne(args) => {
-                    self.pc =
-                        if self.regs[args.rs1] != self.regs[args.rs2]
-                            {self.pc + (args.imm as u32)} else {self.pc + 4};
-                }
-                Instruction::Blt(args) => {
-                    self.pc =
-                        if (self.regs[args.rs1] as i32) < (self.regs[args.rs2] as i32)
-                            {self.pc + (args.imm as u32)} else {self.pc + 4};
-                }
-                Instruction::Bge(args) => {
-                    self.pc =
-                        if (self.regs[args.rs1] as i32) >= (self.regs[args.rs2] as i32)
-                            {self.pc + (args.imm as u32)} else {self.pc + 4};
-                }
-                Instruction::Bltu(args) => {
-                    self.pc =
-                        if self.regs[args.rs1] < self.regs[args.rs2]
-                            {self.pc + (args.imm as u32)} else {self.pc + 4};
-                }
-                Instruction::Bgeu(args) => {
-                    self.pc =
-                        if self.regs[args.rs1] >= self.regs[args.rs2]
-                            {self.pc + (args.imm as u32)} else {self.pc + 4};
-                }
-                Instruction::Lb(args) => {
-                    let address = (self.regs[args.rs1] + args.imm as u32) as usize;
-                    self.regs[args.rd] = self.memory[address] as i32 as u32;
-                }
-                Instruction::Lh(args) => {
-                    let address = (self.regs[args.rs1] + args.imm as u32) as usize;
-                    let address = address..=address + 1;
-                    self.regs[args.rd] = u16::from_le_bytes(self.memory[address]
-                                                                .try_into()
-                                                                .expect("lh error")) as i32 as u32;
-                }
-                Instruction::Lw(args) => {
-                    let address = (self.regs[args.rs1] + args.imm as u32) as usize;
-                    let address = address..=address + 3;
-                    self.regs[args.rd] = u32::from_le_bytes(self.memory[address]
-                                                                .try_into()
-                                                                .expect("lw error"));
-                }
-                Instruction::Lbu(args) => {
-                    let address = (self.regs[args.rs1] + args.imm as u32) as usize;
-                    self.regs[args.rd] = self.memory[address] as u32;
-                }
-                Instruction::Lhu(args) => {
-                    let address = (self.regs[args.rs1] + args.imm as u32) as usize;
-                    let address = address..=address + 1;
-                    self.regs[args.rd] = u16::from_le_bytes(self.memory[address]
-                                                                .try_into()
-                                                                .expect("lhu error")) as u32;
-                }
-                Instruction::Sb(args) => {
-                    let address = (self.regs[args.rs1] + args.imm as u32) as usize;
-                    let bytes = self.regs[args.rs2].to_le_bytes();
-                    self.memory[address] = bytes[0];
-                }
-                Instruction::Sh(args) => {
-                    let address = (self.regs[args.rs1] + args.imm as u32) as usize;
-                    let bytes = self.regs[args.rs2].to_le_bytes();
-                    self.memory[address] = bytes[0];
-                    self.memory[address + 1] = bytes[1];
-                }
-                Instruction::Sw(args) => {
-                    let address = (self.regs[args.rs1] + args.imm as u32) as usize;
-                    let bytes = self.regs[args.rs2].to_le_bytes();
-                    self.memory[address] = bytes[0];
-                    self.memory[address + 1] = bytes[1];
-                    self.memory[address + 2] = bytes[2];
-                    self.memory[address + 3] = bytes[3];
+            }
+            for observer in observers.iter_mut() {
+                observer.step(&core_state);
+            }
+            if let Some(script) = script.as_mut() {
+                if !script.step(&mut core_state) {
+                    println!("stopped by script");
+                    break 'run Outcome::Fail;
                 }
-                Instruction::Addi(args) => {
+            }
+            let (pc, word) = (core_state.pc, core_state.fetch());
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| core_state.dispatch(Engine::Step))) {
+                break 'run Outcome::Panic(format!("{} (executing `{}` at 0x{:08x})", panic_message(&*payload),
+                                                  disassemble(word, pc), pc));
+            }
+            match core_state.pc {
+                p if p == pass_pc => break 'run Outcome::Pass,
+                f if f == fail_pc => break 'run Outcome::Fail,
+                _ => {}
+            }
+            match tohost.and_then(|address| core_state.memory.get(address..address + 4)) {
+                Some([0, 0, 0, 0]) | None => {}
+                Some(&[1, 0, 0, 0]) => break 'run Outcome::Pass,
+                Some(_) => break 'run Outcome::Fail,
+            }
+        }
+        Outcome::Timeout
+    };
+    let outcome = match (outcome, core_state.unimplemented) {
+        (outcome @ (Outcome::Pass | Outcome::Panic(_)), _) => outcome,
+        (_, Some(feature)) => Outcome::Unimplemented(feature),
+        (outcome, None) => outcome,
+    };
+    // how the guest got to the fail label, the timeout or the panic
+    let history = match outcome {
+        Outcome::Pass => None,
+        _ => Some(core_state.pc_history.report(&symbols)),
+    };
+    (outcome, history)
+}
 
-                }
-                Instruction::Slti(args) => {
+/// The test ELFs: the riscv-tests suites in `riscv-tests-elf/`, or every
+/// file in `dir`, of environment `env` if Some
+fn test_list(dir: Option<&str>, env: Option<Env>) -> Vec<String> {
+    let tests: Vec<String> = match dir {
+        Some(dir) => get_tests(dir, ""),
+        None => SUITES.iter().flat_map(|suite| get_tests("riscv-tests-elf", suite)).collect(),
+    };
+    tests.into_iter().filter(|test| env.is_none_or(|env| Env::of(test) == env)).collect()
+}
 
-                }
-                Instruction::Sltiu(args) => {
+/// When each test ELF was last modified
+fn modified(tests: &[String]) -> HashMap<String, SystemTime> {
+    tests.iter()
+        .filter_map(|test| Some((test.clone(), fs::metadata(test).and_then(|m| m.modified()).ok()?)))
+        .collect()
+}
 
-                }
-                Instruction::Xori(args) => {
+/// Counts of each class, e.g. `3 passed, 1 failed`
+fn summary(classes: impl Iterator<Item = usize>) -> String {
+    let mut counts = [0; CLASSES.len()];
+    for class in classes {
+        counts[class] += 1;
+    }
+    let summary: Vec<String> = CLASSES.iter().zip(counts)
+        .filter(|&(_, count)| count != 0)
+        .map(|(class, count)| format!("{} {}", count, class))
+        .collect();
+    summary.join(", ")
+}
 
-                }
-                Instruction::Ori(args) => {
+/// Runs the riscv-tests ELFs (or those in `dir`), pass/fail by reaching the
+/// `pass`/`fail` symbols, sums up how many ended which way and returns the
+/// worst exit code. With `watch` it then polls the ELFs and reruns each one
+/// that changes or appears, printing its report and the updated summary,
+/// until killed.
+fn test(config: Config, dir: Option<&str>, env: Option<Env>, watch: bool) -> i32 {
+    let Config {mut script, mut trace, mut observers, lenient, hart_id, identity, pmp_entries, ..} = config;
+    let configure = |core: &mut CoreState| {
+        core.lenient = lenient;
+        core.hart_id = hart_id;
+        core.identity = identity;
+        core.set_pmp_entries(pmp_entries.unwrap_or(16));
+    };
+    let mut run = |test: &str| {
+        println!("{}", test);
+        // a panic loading the ELF or running it ends only this test
+        let (outcome, history) = panic::catch_unwind(AssertUnwindSafe(|| {
+            run_test(test, &configure, &mut script, &mut trace, &mut observers)
+        })).unwrap_or_else(|payload| (Outcome::Panic(panic_message(&*payload)), None));
+        println!("{}", outcome.report());
+        if let Some(history) = history {
+            println!("{}", history);
+        }
+        outcome
+    };
 
-                }
-                Instruction::Andi(args) => {
+    let tests = test_list(dir, env);
+    let mut outcomes = Vec::new();
+    for test in &tests {
+        outcomes.push(run(test));
+    }
+    println!("{}", summary(outcomes.iter().map(Outcome::class)));
+    if !watch {
+        return outcomes.iter().map(Outcome::exit_code).max().unwrap_or(0);
+    }
 
-                }
-                Instruction::Slli(args) => {
+    let mut stamps = modified(&tests);
+    let mut classes: HashMap<String, usize> = tests.into_iter()
+        .zip(outcomes.iter().map(Outcome::class))
+        .collect();
+    loop {
+        thread::sleep(WATCH_INTERVAL);
+        let current = modified(&test_list(dir, env));
+        let mut changed: Vec<&String> = current.iter()
+            .filter(|&(test, stamp)| stamps.get(test) != Some(stamp))
+            .map(|(test, _)| test)
+            .collect();
+        let before = classes.len();
+        classes.retain(|test, _| current.contains_key(test));
+        if changed.is_empty() && classes.len() == before {
+            continue;
+        }
+        changed.sort();
+        for test in changed {
+            classes.insert(test.clone(), run(test).class());
+        }
+        println!("{}", summary(classes.values().copied()));
+        stamps = current;
+    }
+}
 
+/// Puts the branch predictor and the caches in front of the timing model
+fn timing_model(mut timing: Option<Box<dyn TimingModel>>, icache: Option<Cache>, dcache: Option<Cache>,
+                predictor: Option<String>) -> Option<Box<dyn TimingModel>> {
+    if let Some(spec) = predictor {
+        match Predictor::parse(timing.take(), &spec) {
+            Ok(predictor) => timing = Some(Box::new(predictor)),
+            Err(e) => {
+                eprintln!("--predictor: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if icache.is_some() || dcache.is_some() {
+        timing = Some(Box::new(Caches::new(timing, icache, dcache)));
+    }
+    timing
+}
+
+fn main() -> std::io::Result<()> {
+    let mut script: Option<Script> = None;
+    let mut memory_size = None;
+    let mut engine = Engine::Block;
+    let mut trace = None;
+    let mut observers: Vec<Box<dyn Observer>> = Vec::new();
+    let mut lenient = false;
+    let mut timing: Option<Box<dyn TimingModel>> = None;
+    let (mut icache, mut dcache, mut predictor) = (None, None, None);
+    let mut interrupts = None;
+    let mut core_dump = None;
+    let mut host_ecalls = false;
+    let mut boot_rom = None;
+    let mut sbi = false;
+    let mut hart_id = 0;
+    let mut progress = None;
+    let mut monitor = false;
+    let mut input = None;
+    let mut identity = Identity::default();
+    let mut pmp_entries = None;
+    let mut env = None;
+    let mut args = std::env::args().skip(1);
+    // the options, up to the mode
+    let mut mode = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--script" => {
+                let path = args.next().expect("--script needs a file");
+                match Script::load(&path) {
+                    Ok(s) => script = Some(s),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        std::process::exit(1);
+                    }
                 }
-                Instruction::Srli(args) => {
-                }
-                Instruction::Srai(args) => {
-                }
-                Instruction::Add(args) => {
-                    self.regs[args.rd] = self.regs[args.rs1] + self.regs[args.rs2];
-                }
-                Instruction::Sub(args) => {
-                    self.regs[args.rd] = self.regs[args.rs1] - self.regs[args.rs2];
-                }
-                Instruction::Sll(args) => {
-                    self.regs[args.rd] = self.regs[args.rs1] << (self.regs[args.rs2] & 0b1_1111);
+            }
+            "--trace" => {
+                let path = args.next().expect("--trace needs a file or -");
+                match Tracer::open(&path) {
+                    Ok(t) => trace = Some(t),
+                    Err(e) => {
+                        eprintln!("{}: {}", path, e);
+                        std::process::exit(1);
+                    }
                 }
-                Instruction::Slt(args) => {
-                    self.regs[args.rd] =
-                        if (self.regs[args.rs1] as i32) < (self.regs[args.rs2] as i32) {1} else {0};
+            }
+            "--trace-asm" => {
+                let path = args.next().expect("--trace-asm needs a file or -");
+                match AsmTrace::open(&path) {
+                    Ok(t) => observers.push(Box::new(t)),
+                    Err(e) => {
+                        eprintln!("{}: {}", path, e);
+                        std::process::exit(1);
+                    }
                 }
-                Instruction::Sltu(args) => {
-                    self.regs[args.rd] =
-                        if self.regs[args.rs1] < self.regs[args.rs2] {1} else {0};
+            }
+            "--annotate" => {
+                observers.push(Box::new(Annotate::new(&args.next().expect("--annotate needs a file"))));
+            }
+            "--cfi" => {
+                observers.push(Box::new(Cfi::new(&args.next().expect("--cfi needs a file"))));
+            }
+            "--coverage" => {
+                observers.push(Box::new(Coverage::new(&args.next().expect("--coverage needs a file"))));
+            }
+            "--flamegraph" => {
+                observers.push(Box::new(Flamegraph::new(&args.next().expect("--flamegraph needs a file"))));
+            }
+            "--heatmap" => {
+                observers.push(Box::new(Heatmap::new(&args.next().expect("--heatmap needs a file"))));
+            }
+            "--hotspots" => {
+                observers.push(Box::new(Hotspots::new(&args.next().expect("--hotspots needs a file"))));
+            }
+            "--lcov" => {
+                observers.push(Box::new(Lcov::new(&args.next().expect("--lcov needs a file"))));
+            }
+            "--memcheck" => {
+                observers.push(Box::new(Memcheck::new(&args.next().expect("--memcheck needs a file"))));
+            }
+            "--mmio" => {
+                let spec = args.next().expect("--mmio needs a file[:name=base+size,...]");
+                match MmioLog::open(&spec) {
+                    Ok(log) => observers.push(Box::new(log)),
+                    Err(e) => {
+                        eprintln!("--mmio: {}", e);
+                        std::process::exit(1);
+                    }
                 }
-                Instruction::Xor(args) => {
-                    self.regs[args.rd] = self.regs[args.rs1] ^ self.regs[args.rs2];
+            }
+            "--stats" => {
+                observers.push(Box::new(Stats::new(&args.next().expect("--stats needs a file"))));
+            }
+            "--taint" => {
+                let spec = args.next().expect("--taint needs file:source=...,sink=...");
+                match Taint::new(&spec) {
+                    Ok(taint) => observers.push(Box::new(taint)),
+                    Err(e) => {
+                        eprintln!("--taint: {}", e);
+                        std::process::exit(1);
+                    }
                 }
-                Instruction::Srl(args) => {
-                    self.regs[args.rd] = self.regs[args.rs1] >> (self.regs[args.rs2] & 0b1_1111);
+            }
+            "--vcd" => {
+                let spec = args.next().expect("--vcd needs a file[:signal,...]");
+                match Vcd::open(&spec) {
+                    Ok(vcd) => observers.push(Box::new(vcd)),
+                    Err(e) => {
+                        eprintln!("--vcd: {}", e);
+                        std::process::exit(1);
+                    }
                 }
-                Instruction::Sra(args) => {
-                    self.regs[args.rd] = ((self.regs[args.rs1] as i32) >> (self.regs[args.rs2] & 0b1_1111)) as u32;
+            }
+            "--watch-expr" => {
+                let expr = args.next().expect("--watch-expr needs an expression");
+                match Watch::parse(&expr) {
+                    Ok(watch) => observers.push(Box::new(watch)),
+                    Err(e) => {
+                        eprintln!("--watch-expr: {}", e);
+                        std::process::exit(1);
+                    }
                 }
-                Instruction::Or(args) => {
-                    self.regs[args.rd] = self.regs[args.rs1] | self.regs[args.rs2];
+            }
+            "--memory" => {
+                memory_size = Some(args.next()
+                    .and_then(|size| size.parse().ok())
+                    .expect("--memory needs a size in bytes"));
+            }
+            "--lenient" => lenient = true,
+            "--env" => {
+                env = match args.next().as_deref() {
+                    Some("all") => None,
+                    Some(name) if Env::parse(name).is_some() => Env::parse(name),
+                    _ => {
+                        eprintln!("--env needs `p`, `v` or `all`");
+                        std::process::exit(1);
+                    }
                 }
-                Instruction::And(args) => {
-                    self.regs[args.rd] = self.regs[args.rs1] & self.regs[args.rs2];
+            }
+            "--core-dump" => core_dump = Some(args.next().expect("--core-dump needs a file")),
+            "--host-ecalls" => host_ecalls = true,
+            "--sbi" => sbi = true,
+            "--monitor" => monitor = true,
+            "--input" => {
+                let path = args.next().expect("--input needs a file");
+                match InputScript::load(&path) {
+                    Ok(script) => input = Some(script),
+                    Err(e) => {
+                        eprintln!("--input: {}", e);
+                        std::process::exit(1);
+                    }
                 }
-                Instruction::Fence => {}
-                Instruction::FenceTso => todo!(),
-                Instruction::Pause => todo!(),
-                Instruction::Ecall => {
-                    exception = true;
-                    self.mepc = self.pc;
-                    self.mcause = Cause::Mcall;
+            }
+            "--hart" => {
+                hart_id = args.next()
+                    .and_then(|id| id.parse().ok())
+                    .expect("--hart needs a hart ID");
+            }
+            "--identity" => {
+                let spec = args.next().expect("--identity needs name=value,...");
+                match Identity::parse(&spec) {
+                    Ok(ids) => identity = ids,
+                    Err(e) => {
+                        eprintln!("--identity: {}", e);
+                        std::process::exit(1);
+                    }
                 }
-                Instruction::Ebreak => {
-                    exception = true;
-                    self.mepc = self.pc;
-                    self.mcause = Cause::Breakpoint;
+            }
+            "--pmp" => {
+                pmp_entries = args.next()
+                    .and_then(|entries| entries.parse().ok())
+                    .filter(|entries| matches!(entries, 0 | 16 | 64));
+                if pmp_entries.is_none() {
+                    eprintln!("--pmp needs 0, 16 or 64 entries");
+                    std::process::exit(1);
                 }
-                Instruction::Mret => todo!(),
-                Instruction::Wfi => todo!(),
-                Instruction::Csrrw(args) => {
-                    if let Some(csr) = Csr::get_csr(args.csr) {
-                        let rs1 = self.regs[args.rs1];
-                        self.regs[args.rd] = self.get_csr_value(&csr);
-                        self.set_csr_value(&csr, rs1);
-                    } else {
-                        exception = true;
-                        self.mepc = self.pc;
-                        self.mcause = Cause::IllegalInstruction;
+            }
+            "--progress" => {
+                let spec = args.next().expect("--progress needs seconds or `signal`");
+                match Progress::parse(&spec) {
+                    Ok(reporter) => progress = Some(reporter),
+                    Err(e) => {
+                        eprintln!("--progress: {}", e);
+                        std::process::exit(1);
                     }
                 }
-                Instruction::Csrrs(args) => {
-                    // println!("{:?}", Csr::get_csr(args.csr));
-                }
-                Instruction::Csrrc(args) => {
-                    // println!("{:?}", Csr::get_csr(args.csr));
+            }
+            "--boot-rom" => {
+                let spec = args.next().expect("--boot-rom needs address[:file.dtb]");
+                match BootRom::parse(&spec) {
+                    Ok(rom) => boot_rom = Some(rom),
+                    Err(e) => {
+                        eprintln!("--boot-rom: {}", e);
+                        std::process::exit(1);
+                    }
                 }
-                Instruction::Csrrwi(args) => {
-                    // println!("{:?}", Csr::get_csr(args.csr));
+            }
+            "--timing" => {
+                let spec = args.next().expect("--timing needs a model");
+                match InOrder::parse(&spec) {
+                    Ok(model) => timing = Some(Box::new(model)),
+                    Err(e) => {
+                        eprintln!("--timing: {}", e);
+                        std::process::exit(1);
+                    }
                 }
-                Instruction::Csrrsi(args) => {
-                    // println!("{:?}", Csr::get_csr(args.csr));
+            }
+            "--icache" | "--dcache" => {
+                let spec = args.next().unwrap_or_else(|| panic!("{} needs size:ways:line[:miss cycles]", arg));
+                match Cache::parse(&spec) {
+                    Ok(cache) if arg == "--icache" => icache = Some(cache),
+                    Ok(cache) => dcache = Some(cache),
+                    Err(e) => {
+                        eprintln!("{}: {}", arg, e);
+                        std::process::exit(1);
+                    }
                 }
-                Instruction::Csrrci(args) => {
-                    // println!("{:?}", Csr::get_csr(args.csr));
+            }
+            "--predictor" => predictor = Some(args.next().expect("--predictor needs a scheme")),
+            "--interrupts" => {
+                let spec = args.next().expect("--interrupts needs seed[:mean interval]");
+                match Injector::parse(&spec) {
+                    Ok(injector) => interrupts = Some(injector),
+                    Err(e) => {
+                        eprintln!("--interrupts: {}", e);
+                        std::process::exit(1);
+                    }
                 }
             }
-            match (jump_branch, exception) {
-                (_, true) => {
-                    self.pc = self.mtvec;
-                    println!("😱 it's a trap!");
-                    // remove!
-                    todo!();
+            "--engine" => {
+                engine = match args.next().as_deref() {
+                    Some("step") => Engine::Step,
+                    Some("block") => Engine::Block,
+                    #[cfg(feature = "jit")]
+                    Some("jit") => Engine::Jit,
+                    _ => {
+                        eprintln!("--engine needs `step`, `block` or, with the `jit` feature, `jit`");
+                        std::process::exit(1);
+                    }
                 }
-                (false, false) => self.pc += 4,
-                (_, _) => {},
             }
-            self.regs[0] = 0;
-        } else {
-            todo!()
+            _ if !arg.starts_with("--") => {
+                mode = Some(arg);
+                break;
+            }
+            _ => {
+                eprintln!("unknown argument `{}`", arg);
+                std::process::exit(1);
+            }
         }
     }
-}
-
-fn get_tests(path: &str, filter: &str) -> Vec<String> {
-    let dir = fs::read_dir(path).unwrap();
-    dir
-        .map(|entry| String::from(entry.unwrap().path().to_str().unwrap()))
-        .filter(|entry| entry.contains(filter) && !entry.ends_with("dump"))
-        .collect()
-
-}
-
 
-fn main() -> std::io::Result<()> {
-    let mut core_state = CoreState {
-        pc: 0x0000_0000,
-        regs: [0; 32],
-        memory: [0; MEMORY_SIZE],
-        mie: false,
-        mpie: false,
-        mtvec: 0,
-        mscratch: 0,
-        mepc: 0,
-        mcause: Cause::HardwareError,
-        mtval: 0,
+    let timing = timing_model(timing, icache, dcache, predictor);
+    // hooks, traces, observers and injected interrupts see every instruction
+    if script.is_some() || trace.is_some() || !observers.is_empty() || interrupts.is_some() {
+        engine = Engine::Step;
+    }
+    let config = Config {script, memory_size, engine, trace, observers, lenient, timing, interrupts, core_dump, host_ecalls,
+                         boot_rom, sbi, hart_id, progress, monitor, input, identity, pmp_entries};
+    let args: Vec<String> = args.collect();
+    let Some(mode) = mode else {
+        std::process::exit(test(Config {engine: Engine::Step, ..config}, None, env, false));
     };
-
-    let tests = get_tests("riscv-tests-elf", "rv32ui");
-
-    for test in tests {
-
-        let file_contents = fs::read(&test)
-                                        .expect("file read error");
-        let elf = ElfBytes::<AnyEndian>::minimal_parse(&file_contents)
-                                                .expect("elf parse error");
-        let sections = elf.section_headers().expect("elf parse error");
-
-        for section in sections {
-            if (abi::SHF_EXECINSTR as u64) & section.sh_flags != 0 {
-                let text = elf.section_data(&section).expect("elf parse error").0;
-                core_state.memory[..text.len()].copy_from_slice(text);
-            }
+    match mode.as_str() {
+        "user" => exit_with_outcome(linux::run(&args, config)),
+        "run" => exit_with_outcome(run(&args, config)),
+        "serve" => {
+            let (address, args) = args.split_first().expect("serve needs an address such as 127.0.0.1:5555");
+            exit_with(rpc::serve(address, args, config))
         }
-
-        let mut pass_pc: u32 = 0;
-        let mut fail_pc: u32 = 0;
-
-        let (sym_tab, str_tab) = elf.symbol_table().unwrap().unwrap();
-        for sym in sym_tab.iter() {
-            let name = str_tab.get(sym.st_name as usize).unwrap();
-            match name {
-                "pass" => pass_pc = sym.st_value as u32,
-                "fail" => fail_pc = sym.st_value as u32,
-                _ => {}
-            }
+        "dashboard" => {
+            let (address, args) = args.split_first().expect("dashboard needs an address such as 127.0.0.1:8080");
+            exit_with(dashboard::serve(address, args, config))
         }
-        println!("{}", test);
-        println!("pass: 0x{:x} fail: 0x{:x}", pass_pc, fail_pc);
-
-        if (pass_pc == 0) || (fail_pc == 0) {
-            println!("🟡");
-            continue;
+        "gdb" => {
+            let (address, args) = args.split_first().expect("gdb needs an address such as 127.0.0.1:1234");
+            exit_with(gdb::serve(address, args, config))
         }
-
-        core_state.reset();
-
-        loop {
-            println!("{}", core_state);
-            core_state.execute();
-            match core_state.pc {
-                p if p == pass_pc => {println!("🟢"); break;},
-                f if f == fail_pc => {println!("🔴"); break;},
-                _ => {}
+        "cosim" => {
+            let (address, args) = args.split_first().expect("cosim needs an address such as 127.0.0.1:6000");
+            exit_with(cosim::serve(address, args, config))
+        }
+        "lockstep" => exit_with(lockstep::run(&args, config)),
+        "compare" => exit_with(compare::run(&args, config)),
+        "campaign" => exit_with(campaign::run(&args, config)),
+        "symbolic" => exit_with(symbolic::run(&args, config)),
+        "difftest" => exit_with(difftest::run(&args)),
+        "act" => exit_with(act::run(&args, config)),
+        "torture" => exit_with(torture::run(&args, config.engine)),
+        "merge" => exit_with(shard::merge(&args)),
+        "snapshot" => exit_with(snapshot::run(&args, config)),
+        "diff-state" => exit_with(snapshot::diff_state(&args)),
+        "test" => {
+            let watch = args.iter().any(|arg| arg == "--watch");
+            let dir = args.iter().find(|arg| *arg != "--watch");
+            std::process::exit(test(Config {engine: Engine::Step, ..config}, dir.map(String::as_str), env, watch))
+        }
+        "bench" => exit_with(profile::run(&args, config).map(|report| {
+            eprintln!("instructions: {}, cycles: {} (CPI {:.2}, {:.3} s at {} Hz)", report.instructions,
+                      report.cycles, report.cycles as f64 / report.instructions as f64,
+                      report.cycles as f64 / profile::TIMER_HZ as f64, profile::TIMER_HZ);
+            eprintln!("host: {:.3} s, {:.1} MIPS", report.seconds,
+                      report.instructions as f64 / report.seconds / 1e6);
+            match report.score {
+                Some((workload, score)) => eprintln!("score: {} {}", workload, score),
+                None => eprintln!("score: not found"),
             }
+            report.exit_code
+        })),
+        _ => {
+            eprintln!("unknown argument `{}`", mode);
+            std::process::exit(1);
         }
     }
-
-
-    Ok(())
 }
@@ -0,0 +1,290 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs;
+
+use crate::loader::{function_at, Function, Image};
+use crate::{CoreState, Instruction, Observer};
+
+/// Bytes before and after each live block where guest code must not reach
+const REDZONE: u32 = 16;
+/// Freed blocks remembered for use-after-free checks, oldest forgotten first
+const QUARANTINE: usize = 1024;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Allocator {
+    Malloc,
+    Calloc,
+    Realloc,
+    Free,
+}
+
+/// Allocator entry points, newlib's reentrant `_r` variants take the
+/// reentrancy pointer first
+const ENTRY_POINTS: [(&str, Allocator, usize); 8] = [
+    ("malloc", Allocator::Malloc, 0), ("calloc", Allocator::Calloc, 0),
+    ("realloc", Allocator::Realloc, 0), ("free", Allocator::Free, 0),
+    ("_malloc_r", Allocator::Malloc, 1), ("_calloc_r", Allocator::Calloc, 1),
+    ("_realloc_r", Allocator::Realloc, 1), ("_free_r", Allocator::Free, 1),
+];
+
+#[derive(Clone, Copy)]
+struct Block {
+    size: u32,
+    // pc of the allocator call
+    site: u32,
+}
+
+/// Allocator call in progress, finished when it returns to `ret`
+struct Call {
+    allocator: Allocator,
+    args: [u32; 2],
+    site: u32,
+    ret: u32,
+}
+
+/// Heap checking for guests with a symbolized malloc/free (newlib or any
+/// libc in the ELF): intercepts the allocator calls, keeps live blocks with
+/// redzones on both sides and a quarantine of freed blocks, and checks every
+/// load and store outside the allocator. Heap overflows, use after free,
+/// double and invalid frees are printed with pc and a backtrace from a shadow
+/// call stack as they happen, and listed in the report written when dropped.
+pub struct Memcheck {
+    path: String,
+    functions: Vec<Function>,
+    entry_points: HashMap<u32, (Allocator, usize)>,
+    live: BTreeMap<u32, Block>,
+    /// (start, block, pc of the free)
+    freed: VecDeque<(u32, Block, u32)>,
+    call: Option<Call>,
+    calls: Vec<u32>,
+    errors: Vec<String>,
+}
+
+impl Memcheck {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            functions: Vec::new(),
+            entry_points: HashMap::new(),
+            live: BTreeMap::new(),
+            freed: VecDeque::new(),
+            call: None,
+            calls: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    fn symbol(&self, address: u32) -> String {
+        match function_at(&self.functions, address) {
+            Some(i) if address == self.functions[i].start => self.functions[i].name.clone(),
+            Some(i) => format!("{}+0x{:x}", self.functions[i].name, address - self.functions[i].start),
+            None => "?".to_string(),
+        }
+    }
+
+    fn error(&mut self, pc: u32, what: String) {
+        let mut error = format!("0x{:08x} {}: {}\n", pc, self.symbol(pc), what);
+        for &site in self.calls.iter().rev() {
+            error += &format!("  called from 0x{:08x} {}\n", site, self.symbol(site));
+        }
+        eprint!("memcheck: {}", error);
+        self.errors.push(error);
+    }
+
+    fn describe(&self, start: u32, block: Block) -> String {
+        format!("block 0x{:08x} ({} bytes, allocated at 0x{:08x} {})", start, block.size, block.site,
+                self.symbol(block.site))
+    }
+
+    fn allocated(&mut self, start: u32, size: u32, site: u32) {
+        if start == 0 {
+            return;
+        }
+        let end = start.wrapping_add(size.max(1));
+        self.freed.retain(|&(freed, block, _)| freed >= end || freed.wrapping_add(block.size.max(1)) <= start);
+        self.live.insert(start, Block {size, site});
+    }
+
+    fn free(&mut self, pc: u32, start: u32) {
+        if start == 0 {
+            return;
+        }
+        match self.live.remove(&start) {
+            Some(block) => {
+                if self.freed.len() == QUARANTINE {
+                    self.freed.pop_front();
+                }
+                self.freed.push_back((start, block, pc));
+            }
+            None => match self.freed.iter().find(|&&(freed, _, _)| freed == start) {
+                Some(&(_, block, freed_at)) => {
+                    let what = format!("double free of {}, freed at 0x{:08x} {}", self.describe(start, block),
+                                       freed_at, self.symbol(freed_at));
+                    self.error(pc, what);
+                }
+                None => self.error(pc, format!("free of 0x{:08x}, not an allocated block", start)),
+            },
+        }
+    }
+
+    /// Finishes the allocator call returning with `result` in a0
+    fn returned(&mut self, call: Call, result: u32) {
+        match call.allocator {
+            Allocator::Malloc => self.allocated(result, call.args[0], call.site),
+            Allocator::Calloc => self.allocated(result, call.args[0].wrapping_mul(call.args[1]), call.site),
+            Allocator::Realloc => {
+                self.free(call.site, call.args[0]);
+                self.allocated(result, call.args[1], call.site);
+            }
+            Allocator::Free => self.free(call.site, call.args[0]),
+        }
+    }
+
+    /// Checks a guest access of `size` bytes at `address`
+    fn access(&mut self, pc: u32, address: u32, size: u32, kind: &str) {
+        let end = address.wrapping_add(size);
+        let before = self.live.range(..=address).next_back().map(|(&start, &block)| (start, block));
+        if let Some((start, block)) = before {
+            let block_end = start.wrapping_add(block.size);
+            if end <= block_end {
+                return;
+            }
+            if address < block_end.wrapping_add(REDZONE) {
+                let what = format!("heap overflow, {} of {} bytes at 0x{:08x} is {} bytes past the end of {}", kind,
+                                   size, address, end - block_end, self.describe(start, block));
+                return self.error(pc, what);
+            }
+        }
+        if let Some(&(start, block, freed_at)) = self.freed.iter()
+            .find(|&&(start, block, _)| address < start.wrapping_add(block.size) && end > start) {
+            let what = format!("use after free, {} of {} bytes at 0x{:08x} in {}, freed at 0x{:08x} {}", kind, size,
+                               address, self.describe(start, block), freed_at, self.symbol(freed_at));
+            return self.error(pc, what);
+        }
+        if let Some((&start, &block)) = self.live.range(address..).next() {
+            if end > start.wrapping_sub(REDZONE) {
+                let what = format!("heap underflow, {} of {} bytes at 0x{:08x} is {} bytes before {}", kind, size,
+                                   address, start - address, self.describe(start, block));
+                self.error(pc, what);
+            }
+        }
+    }
+
+    pub fn report(&self) -> String {
+        let leaked: u32 = self.live.values().map(|block| block.size).sum();
+        let mut report = format!("{} errors, {} blocks ({} bytes) live at exit\n", self.errors.len(), self.live.len(),
+                                 leaked);
+        for error in &self.errors {
+            report += error;
+        }
+        report
+    }
+}
+
+impl Observer for Memcheck {
+    fn loaded(&mut self, image: &Image) {
+        self.functions = image.functions.clone();
+        for (name, allocator, first) in ENTRY_POINTS {
+            if let Some(&address) = image.symbols.get(name) {
+                self.entry_points.insert(address, (allocator, first));
+            }
+        }
+        if self.entry_points.is_empty() {
+            eprintln!("{}: no malloc/free symbols, only the shadow call stack is kept", image.path);
+        }
+    }
+
+    fn step(&mut self, core: &CoreState) {
+        let pc = core.pc;
+        if self.call.as_ref().is_some_and(|call| call.ret == pc) {
+            let call = self.call.take().unwrap();
+            self.returned(call, core.regs[10]);
+        }
+        if self.call.is_none() {
+            if let Some(&(allocator, first)) = self.entry_points.get(&pc) {
+                let site = core.regs[1].wrapping_sub(4);
+                self.call = Some(Call {allocator, args: [core.regs[10 + first], core.regs[11 + first]], site,
+                                       ret: core.regs[1]});
+            }
+        }
+        let Some(bytes) = core.memory.get(pc as usize..pc as usize + 4) else {
+            return;
+        };
+        let instruction = CoreState::decode_with(u32::from_le_bytes(bytes.try_into().unwrap()), true);
+        // the shadow call stack for backtraces, calls link ra
+        match instruction {
+            Ok(Instruction::Jal(a)) if a.rd == 1 => self.calls.push(pc),
+            Ok(Instruction::Jalr(a)) if a.rd == 1 => self.calls.push(pc),
+            Ok(Instruction::Jalr(a)) if a.rd == 0 && a.rs1 == 1 => {
+                self.calls.pop();
+            }
+            _ => {}
+        }
+        // the allocator's own header accesses are fine
+        if self.call.is_some() {
+            return;
+        }
+        let (address, size, kind) = match instruction {
+            Ok(Instruction::Lw(a)) => (core.regs[a.rs1].wrapping_add(a.imm as u32), 4, "load"),
+            Ok(Instruction::Lh(a) | Instruction::Lhu(a)) => (core.regs[a.rs1].wrapping_add(a.imm as u32), 2, "load"),
+            Ok(Instruction::Lb(a) | Instruction::Lbu(a)) => (core.regs[a.rs1].wrapping_add(a.imm as u32), 1, "load"),
+            Ok(Instruction::Sw(a)) => (core.regs[a.rs1].wrapping_add(a.imm as u32), 4, "store"),
+            Ok(Instruction::Sh(a)) => (core.regs[a.rs1].wrapping_add(a.imm as u32), 2, "store"),
+            Ok(Instruction::Sb(a)) => (core.regs[a.rs1].wrapping_add(a.imm as u32), 1, "store"),
+            _ => return,
+        };
+        self.access(pc, address, size, kind);
+    }
+}
+
+impl Drop for Memcheck {
+    fn drop(&mut self) {
+        if let Err(e) = fs::write(&self.path, self.report()) {
+            eprintln!("{}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{self, LOAD};
+    use crate::test_utils::machine;
+
+    #[test]
+    fn overflow_use_after_free_and_double_free() {
+        // 0x00: sw x0, 16(a0); 0x04: lbu x5, 3(a0)
+        let program = [encode::s(0b010, 10, 0, 16), encode::i(LOAD, 0b100, 5, 10, 3)];
+        let mut core = machine(0, &program, &[]);
+        let mut memcheck = Memcheck::new("/dev/null");
+        memcheck.functions = vec![Function {start: 0, size: 0x40, name: "main".to_string()},
+                                  Function {start: 0x100, size: 0x100, name: "malloc".to_string()}];
+        memcheck.entry_points = HashMap::from([(0x100, (Allocator::Malloc, 0)), (0x180, (Allocator::Free, 0))]);
+        let mut at = |memcheck: &mut Memcheck, pc, a0, ra| {
+            core.pc = pc;
+            core.regs.write(10, a0);
+            core.regs.write(1, ra);
+            memcheck.step(&core);
+        };
+        // malloc(16) from 0x20 returns 0x400
+        at(&mut memcheck, 0x100, 16, 0x24);
+        at(&mut memcheck, 0x24, 0x400, 0x24);
+        at(&mut memcheck, 0x4, 0x400, 0);
+        assert!(memcheck.errors.is_empty());
+        at(&mut memcheck, 0x0, 0x400, 0);
+        // free(0x400) twice
+        for _ in 0..2 {
+            at(&mut memcheck, 0x180, 0x400, 0x34);
+            at(&mut memcheck, 0x34, 0, 0x34);
+        }
+        at(&mut memcheck, 0x4, 0x400, 0);
+        assert_eq!(memcheck.errors, [
+            "0x00000000 main: heap overflow, store of 4 bytes at 0x00000410 is 4 bytes past the end of \
+             block 0x00000400 (16 bytes, allocated at 0x00000020 main+0x20)\n",
+            "0x00000030 main+0x30: double free of block 0x00000400 (16 bytes, allocated at 0x00000020 main+0x20), \
+             freed at 0x00000030 main+0x30\n",
+            "0x00000004 main+0x4: use after free, load of 1 bytes at 0x00000403 in block 0x00000400 (16 bytes, \
+             allocated at 0x00000020 main+0x20), freed at 0x00000030 main+0x30\n",
+        ]);
+        assert!(memcheck.report().starts_with("3 errors, 0 blocks (0 bytes) live at exit\n"));
+    }
+}
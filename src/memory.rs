@@ -0,0 +1,149 @@
+use crate::Cause;
+
+const PAGE_SHIFT: u32 = 12;
+const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
+
+/// Last guest page an access type went through the slow path for. Accesses
+/// that stay inside it skip the lookup; once memory is a bus the slow path is
+/// where MMIO and access faults get resolved.
+pub struct PageCache {
+    page: Option<u32>,
+}
+
+impl PageCache {
+    pub fn new() -> Self {
+        Self {page: None}
+    }
+
+    /// Whether `size` bytes at `address` lie inside the cached page
+    pub fn hit(&self, address: u32, size: usize) -> bool {
+        self.page == Some(address >> PAGE_SHIFT) &&
+            (address as usize & (PAGE_SIZE - 1)) + size <= PAGE_SIZE
+    }
+
+    /// Drops the cached page if it holds `address`, or whatever it holds for
+    /// None
+    pub fn flush(&mut self, address: Option<u32>) {
+        if address.is_none_or(|address| self.page == Some(address >> PAGE_SHIFT)) {
+            self.page = None;
+        }
+    }
+
+    /// Caches the page of `address` if it is all backed by `memory_len` bytes of RAM
+    pub fn fill(&mut self, address: u32, memory_len: usize) {
+        let page = address >> PAGE_SHIFT;
+        if ((page as usize) + 1) << PAGE_SHIFT <= memory_len {
+            self.page = Some(page);
+        }
+    }
+}
+
+/// Width of a load or store
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Size {
+    Byte = 1,
+    Half = 2,
+    Word = 4,
+}
+
+impl Size {
+    pub fn bytes(self) -> usize {
+        self as usize
+    }
+
+    /// The low `self` bytes of `value`, sign- or zero-extended
+    pub fn extend(self, value: u32, signed: bool) -> u32 {
+        let shift = 32 - 8 * self as u32;
+        match signed {
+            true => (((value << shift) as i32) >> shift) as u32,
+            false => (value << shift) >> shift,
+        }
+    }
+}
+
+/// Why memory is accessed, for the checks and their faults
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Access {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl Access {
+    /// The access fault a failed check raises
+    pub(crate) fn fault(self) -> Cause {
+        match self {
+            Access::Fetch => Cause::InstructionAccessFault,
+            Access::Load => Cause::LoadAccessFault,
+            Access::Store => Cause::StoreAmoAccessFault,
+        }
+    }
+
+    /// The page fault a failed translation raises
+    pub(crate) fn page_fault(self) -> Cause {
+        match self {
+            Access::Fetch => Cause::InstructionPageFault,
+            Access::Load => Cause::LoadPageFault,
+            Access::Store => Cause::StoreAmoPageFault,
+        }
+    }
+
+    /// The misaligned exception of an access split across pages
+    pub(crate) fn misaligned(self) -> Cause {
+        match self {
+            Access::Fetch => Cause::InstructionAddressMisaligned,
+            Access::Load => Cause::LoadAddressMisaligned,
+            Access::Store => Cause::StoreAmoAddressMisaligned,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode;
+    use crate::test_utils::machine;
+
+    #[test]
+    fn accesses_go_through_one_checked_path() {
+        assert_eq!((Size::Byte.extend(0x1280, true), Size::Half.extend(0x1_8000, false)), (0xFFFF_FF80, 0x8000));
+        assert_eq!(Size::Word.extend(0x8000_0000, true), 0x8000_0000);
+        // misaligned and across the first page, then in the cached page
+        let mut core = machine(0, &[], &[]);
+        core.memory.resize(2 * PAGE_SIZE, 0);
+        core.store(0xFFF, Size::Word, 0x1234_5678).unwrap();
+        assert_eq!((core.load(0xFFF, Size::Word, false), core.last_access), (Ok(0x1234_5678), Some((0xFFF, 4))));
+        assert_eq!((core.load(0x1000, Size::Half, true), core.last_access), (Ok(0x3456), Some((0x1000, 2))));
+    }
+
+    #[test]
+    fn accesses_outside_memory_trap_to_the_guest() {
+        // mtvec = 0x40, lw and sw past the end, the handler reads mcause and
+        // mtval, counts in x8 and reads mepc
+        let mut program = [0; 20];
+        program[..5].copy_from_slice(&[
+            encode::csr(0b001, 0, 1, 0x305),
+            encode::i(encode::LOAD, 0b010, 5, 2, 0),
+            encode::s(0b010, 2, 2, 4),
+            encode::i(encode::OP_IMM, 0b000, 0, 0, 0),
+            0,
+        ]);
+        program[16..].copy_from_slice(&[
+            encode::csr(0b010, 6, 0, 0x342),
+            encode::csr(0b010, 7, 0, 0x343),
+            encode::i(encode::OP_IMM, 0b000, 8, 8, 1),
+            encode::csr(0b010, 9, 0, 0x341),
+        ]);
+        let mut core = machine(0, &program, &[(1, 0x40), (2, 0x10_0000), (5, 0x55)]);
+        for _ in 0..6 {
+            core.execute();
+        }
+        assert_eq!(core.regs[5], 0x55);
+        assert_eq!((core.regs[6], core.regs[7], core.regs[9], core.pc), (5, 0x10_0000, 4, 0x50));
+        core.pc = 8;
+        for _ in 0..5 {
+            core.execute();
+        }
+        assert_eq!((core.regs[6], core.regs[7], core.regs[8], core.regs[9]), (7, 0x10_0004, 2, 8));
+    }
+}
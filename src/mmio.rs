@@ -0,0 +1,115 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::encode::{LOAD, STORE};
+use crate::script::parse_number;
+use crate::{CoreState, Device, Observer};
+
+/// Bus log of guest loads and stores to device registers, one CSV row per
+/// access with the cycle it retired in, hart, pc, direction, address, size,
+/// value and device name. The run mode names its devices; the spec may add
+/// more as `name=base+size`.
+pub struct MmioLog {
+    out: BufWriter<File>,
+    devices: Vec<(String, u32, u32)>,
+    // pc, word and cycles after the last instruction observed
+    last: Option<(u32, u32, u64)>,
+}
+
+impl MmioLog {
+    /// `spec` is `file` or `file:name=base+size,...`, e.g.
+    /// `bus.csv:clint=0x0200_0000+0x10000`
+    pub fn open(spec: &str) -> Result<Self, String> {
+        let (path, extra) = spec.split_once(':').unwrap_or((spec, ""));
+        let devices = extra
+            .split(',')
+            .filter(|device| !device.is_empty())
+            .map(|device| {
+                let (name, range) = device.split_once('=').ok_or(format!("bad device `{}`", device))?;
+                let (base, size) = range.split_once('+').ok_or(format!("bad device `{}`", device))?;
+                Ok((name.to_string(), parse_number(base)?, parse_number(size)?))
+            })
+            .collect::<Result<_, String>>()?;
+        let mut out = BufWriter::new(File::create(path).map_err(|e| format!("{}: {}", path, e))?);
+        writeln!(out, "cycle,hart,pc,access,address,size,value,device").map_err(|e| format!("{}: {}", path, e))?;
+        Ok(Self {out, devices, last: None})
+    }
+
+    fn device(&self, address: u32, size: u32) -> Option<&str> {
+        self.devices
+            .iter()
+            .find(|&&(_, base, length)| address < base.wrapping_add(length) && address.wrapping_add(size) > base)
+            .map(|(name, _, _)| name.as_str())
+    }
+
+    /// Row for the device access of the instruction that just retired
+    fn entry(&mut self, core: &CoreState) -> Option<String> {
+        let (pc, word, cycles) = self.last.replace((core.pc, core.fetch_at(core.pc), core.cycles))?;
+        // nothing retired, e.g. an interrupt was taken
+        if core.cycles == cycles {
+            return None;
+        }
+        let (address, size) = core.last_access?;
+        let device = self.device(address, size)?;
+        let mask = if size == 4 {u32::MAX} else {(1 << (8 * size)) - 1};
+        let (access, value) = match word & 0x7F {
+            LOAD => {
+                let rd = (word >> 7 & 0x1F) as usize;
+                let start = address as usize;
+                let value = match rd {
+                    0 => core.memory[start..start + size as usize].iter().rev().fold(0, |v, &b| v << 8 | b as u32),
+                    rd => core.regs[rd],
+                };
+                ("read", value & mask)
+            }
+            STORE => ("write", core.regs[(word >> 20 & 0x1F) as usize] & mask),
+            _ => return None,
+        };
+        Some(format!("{},0,0x{:08x},{},0x{:08x},{},0x{:x},{}", core.cycles, pc, access, address, size, value, device))
+    }
+}
+
+impl Observer for MmioLog {
+    fn devices(&mut self, devices: &[Device]) {
+        self.devices.extend(devices.iter().map(|&(name, base, size)| (name.to_string(), base, size)));
+    }
+
+    fn step(&mut self, core: &CoreState) {
+        if core.memory.get(core.pc as usize..core.pc as usize + 4).is_none() {
+            return;
+        }
+        if let Some(entry) = self.entry(core) {
+            let _ = writeln!(self.out, "{}", entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{self, OP_IMM};
+    use crate::test_utils::machine;
+
+    #[test]
+    fn logs_device_accesses_only() {
+        // sw x2, 0x700(x0); lb x3, 0x701(x0); sh x2, 0x600(x0); lw x0, 0x704(x0)
+        let program = [encode::s(0b010, 0, 2, 0x700), encode::i(LOAD, 0b000, 3, 0, 0x701),
+                       encode::s(0b001, 0, 2, 0x600), encode::i(LOAD, 0b010, 0, 0, 0x704),
+                       encode::i(OP_IMM, 0b000, 0, 0, 0)];
+        let mut core = machine(0, &program, &[(2, 0x1234_80FF)]);
+        core.memory[0x704] = 0x42;
+        let mut log = MmioLog::open("/dev/null:uart=0x700+8").unwrap();
+        log.devices(&[("timer", 0x600, 1)]);
+        let mut entries = Vec::new();
+        for _ in 0..5 {
+            entries.extend(log.entry(&core));
+            core.execute();
+        }
+        assert_eq!(entries, ["1,0,0x00000000,write,0x00000700,4,0x123480ff,uart",
+                             // sign extended in x3, the logged value has the access size
+                             "2,0,0x00000004,read,0x00000701,1,0x80,uart",
+                             "3,0,0x00000008,write,0x00000600,2,0x80ff,timer",
+                             "4,0,0x0000000c,read,0x00000704,4,0x42,uart"]);
+        assert!(MmioLog::open("/dev/null:uart").is_err());
+    }
+}
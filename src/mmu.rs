@@ -0,0 +1,170 @@
+use crate::csr_file::{status, MSTATUS, SATP};
+use crate::memory::Access;
+use crate::trap::Exception;
+use crate::{CoreState, Privilege};
+
+/// satp.MODE, Sv32 when set
+const SV32: u32 = 1 << 31;
+const PPN: u32 = (1 << 22) - 1;
+const PAGE_SHIFT: u32 = 12;
+
+/// Page table entry bits
+mod pte {
+    pub const V: u32 = 1 << 0;
+    pub const R: u32 = 1 << 1;
+    pub const W: u32 = 1 << 2;
+    pub const X: u32 = 1 << 3;
+    pub const U: u32 = 1 << 4;
+    pub const A: u32 = 1 << 6;
+    pub const D: u32 = 1 << 7;
+}
+
+// Sv32 translation of the accesses below M-mode. There is no TLB: every
+// access walks the tables, so SFENCE.VMA has nothing to drop and the page,
+// decode and block caches are skipped (or keyed by physical address) while
+// translation is on. A and D are set by the walk, as with Svadu.
+
+impl CoreState {
+    /// The privilege loads and stores are checked at: MPP in M-mode with
+    /// mstatus.MPRV set
+    pub(crate) fn data_privilege(&self) -> Privilege {
+        match self.privilege {
+            Privilege::Machine if self.csrs.status(status::MPRV) => self.csrs.mpp(),
+            privilege => privilege,
+        }
+    }
+
+    /// The privilege `access` is translated at, None if its addresses are
+    /// physical
+    pub(crate) fn translation(&self, access: Access) -> Option<Privilege> {
+        let privilege = match access {
+            Access::Fetch => self.privilege,
+            Access::Load | Access::Store => self.data_privilege(),
+        };
+        (privilege != Privilege::Machine && self.csrs.get(SATP) & SV32 != 0).then_some(privilege)
+    }
+
+    /// The physical address of `address`, walking the page tables at
+    /// `privilege` and setting A and D. A page fault carries the virtual
+    /// address, as does an access fault for a table or page outside memory or
+    /// that PMP denies.
+    pub(crate) fn translate(&mut self, address: u32, access: Access, privilege: Privilege) -> Result<u32, Exception> {
+        let (physical, update) = self.walk(address, access, privilege)?;
+        if let Some((entry, pte)) = update {
+            self.set_physical_word(entry, pte);
+        }
+        Ok(physical)
+    }
+
+    /// `translate` without writing, for observers: the physical address and
+    /// the entry to write back if its A or D has to be set
+    pub(crate) fn walk(&self, address: u32, access: Access, privilege: Privilege)
+        -> Result<(u32, Option<(u64, u32)>), Exception> {
+        let page_fault = Exception::new(access.page_fault(), address);
+        let access_fault = Exception::new(access.fault(), address);
+        let status = self.csrs.get(MSTATUS);
+        let mut table = (self.csrs.get(SATP) & PPN) as u64;
+        for level in [1, 0] {
+            let vpn = (address >> (PAGE_SHIFT + 10 * level)) & 0x3FF;
+            let entry = (table << PAGE_SHIFT) + 4 * vpn as u64;
+            let pte = self.physical_word(entry).ok_or(access_fault)?;
+            // the walk reads and updates the tables as S-mode
+            if !self.pmp.allows(entry as u32, 4, Access::Load, Privilege::Supervisor) {
+                return Err(access_fault);
+            }
+            if pte & pte::V == 0 || (pte & pte::R == 0 && pte & pte::W != 0) {
+                return Err(page_fault);
+            }
+            if pte & (pte::R | pte::X) == 0 {
+                table = (pte >> 10) as u64;
+                continue;
+            }
+            let permitted = match access {
+                Access::Fetch => pte & pte::X != 0,
+                Access::Load => pte & pte::R != 0 || (status & status::MXR != 0 && pte & pte::X != 0),
+                Access::Store => pte & pte::W != 0,
+            };
+            let user = match (privilege, pte & pte::U != 0) {
+                (Privilege::User, page) => page,
+                (_, true) => access != Access::Fetch && status & status::SUM != 0,
+                (_, false) => true,
+            };
+            let offset = (1u64 << (PAGE_SHIFT + 10 * level)) - 1;
+            let base = ((pte >> 10) as u64) << PAGE_SHIFT;
+            // a megapage is aligned to its size
+            if !permitted || !user || base & offset != 0 {
+                return Err(page_fault);
+            }
+            let flags = pte::A | if access == Access::Store {pte::D} else {0};
+            if pte & flags != flags && !self.pmp.allows(entry as u32, 4, Access::Store, Privilege::Supervisor) {
+                return Err(access_fault);
+            }
+            let physical = u32::try_from(base | (address as u64 & offset)).map_err(|_| access_fault)?;
+            return Ok((physical, (pte & flags != flags).then_some((entry, pte | flags))));
+        }
+        Err(page_fault)
+    }
+
+    fn physical_word(&self, address: u64) -> Option<u32> {
+        let start = usize::try_from(address).ok()?;
+        let bytes = self.memory.get(start..start.checked_add(4)?)?;
+        Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// A write of the walk to an entry `physical_word` read
+    fn set_physical_word(&mut self, address: u64, value: u32) {
+        let start = address as usize;
+        self.memory[start..start + 4].copy_from_slice(&value.to_le_bytes());
+        self.invalidate_code(address as u32, 4);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cause;
+    use crate::test_utils::machine;
+
+    fn write(core: &mut CoreState, address: u32, value: u32) {
+        core.set_physical_word(address as u64, value);
+    }
+
+    #[test]
+    fn sv32_walks_check_permissions_and_set_a_and_d() {
+        let mut core = machine(0, &[], &[]);
+        core.memory.resize(0x4000, 0);
+        // root at 0x1000: VA 0x0040_0000 -> table at 0x2000, VA 0 an
+        // unaligned megapage; the table maps 0x0040_0000 to a user page at
+        // 0x3000 and 0x0040_1000 to a read-only supervisor page
+        core.csrs.set(SATP, SV32 | 1);
+        write(&mut core, 0x1004, 2 << 10 | pte::V);
+        write(&mut core, 0x1000, 3 << 10 | pte::V | pte::R);
+        write(&mut core, 0x2000, 3 << 10 | pte::V | pte::R | pte::W | pte::U);
+        write(&mut core, 0x2004, 3 << 10 | pte::V | pte::R);
+        core.privilege = Privilege::User;
+        assert_eq!(core.translation(Access::Load), Some(Privilege::User));
+        assert_eq!(core.translate(0x0040_0123, Access::Load, Privilege::User), Ok(0x3123));
+        assert_eq!(core.physical_word(0x2000).unwrap() & (pte::A | pte::D), pte::A);
+        assert_eq!(core.translate(0x0040_0123, Access::Store, Privilege::User), Ok(0x3123));
+        assert_eq!(core.physical_word(0x2000).unwrap() & (pte::A | pte::D), pte::A | pte::D);
+        let fault = |cause, address| Err(Exception::new(cause, address));
+        assert_eq!(core.translate(0x0040_1000, Access::Load, Privilege::User),
+                   fault(Cause::LoadPageFault, 0x0040_1000));
+        assert_eq!(core.translate(0x0040_1000, Access::Store, Privilege::Supervisor),
+                   fault(Cause::StoreAmoPageFault, 0x0040_1000));
+        assert_eq!(core.translate(0x10, Access::Load, Privilege::Supervisor), fault(Cause::LoadPageFault, 0x10));
+        assert_eq!(core.translate(0x0080_0000, Access::Fetch, Privilege::User),
+                   fault(Cause::InstructionPageFault, 0x0080_0000));
+        // S-mode reaches user pages with SUM, for loads and stores only
+        assert!(core.translate(0x0040_0000, Access::Load, Privilege::Supervisor).is_err());
+        core.csrs.set_status(status::SUM, true);
+        assert_eq!(core.translate(0x0040_0000, Access::Load, Privilege::Supervisor), Ok(0x3000));
+        assert!(core.translate(0x0040_0000, Access::Fetch, Privilege::Supervisor).is_err());
+        // M-mode loads translate with MPRV, at MPP
+        core.privilege = Privilege::Machine;
+        assert_eq!(core.translation(Access::Load), None);
+        core.csrs.write(MSTATUS, status::MPRV | 1 << 11);
+        assert_eq!((core.translation(Access::Load), core.translation(Access::Fetch)),
+                   (Some(Privilege::Supervisor), None));
+    }
+}
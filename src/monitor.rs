@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+
+use crate::assertions::Assertions;
+use crate::{signals, watch, CoreState, RunOutcome};
+
+const HELP: &str = "c(ontinue), q(uit), p(rint) <expression>, bt, regs";
+
+/// What a run prints when Ctrl-C stops it: pc with a backtrace, the
+/// instruction and cycle counts and the last pcs
+pub(crate) fn report(core: &CoreState, assertions: &Assertions, symbols: &HashMap<String, u32>, retired: u64) -> String {
+    format!("interrupted\n{}\n{} instructions retired, {} cycles\n{}", assertions.location(core), retired,
+            core.cycles, core.pc_history.report(symbols))
+}
+
+/// Handles a Ctrl-C at an instruction boundary: Err with the report ends the
+/// run, with `monitor` the report is printed and the commands decide
+pub(crate) fn interrupt(core: &CoreState, assertions: &Assertions, symbols: &HashMap<String, u32>, retired: u64,
+                        monitor: bool) -> Result<(), RunOutcome> {
+    let report = report(core, assertions, symbols, retired);
+    if !monitor {
+        return Err(RunOutcome::Interrupted(report));
+    }
+    eprintln!("{}", report);
+    if !session(core, assertions, &mut std::io::stdin().lock(), &mut std::io::stderr()) {
+        return Err(RunOutcome::Interrupted("interrupted".to_string()));
+    }
+    signals::catch_interrupt();
+    Ok(())
+}
+
+/// Reads monitor commands from `input` after a Ctrl-C until one resumes the
+/// run (true) or ends it (false, also at the end of input). Expressions are
+/// the ones of `--watch-expr`.
+pub(crate) fn session(core: &CoreState, assertions: &Assertions, input: &mut impl BufRead,
+                      output: &mut impl Write) -> bool {
+    let mut line = String::new();
+    loop {
+        let _ = write!(output, "(rs-v) ");
+        let _ = output.flush();
+        line.clear();
+        if input.read_line(&mut line).unwrap_or(0) == 0 {
+            return false;
+        }
+        let (command, rest) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+        let reply = match command {
+            "c" | "continue" => return true,
+            "q" | "quit" => return false,
+            "p" | "print" => match watch::evaluate(rest, core) {
+                Ok(value) => format!("0x{:08x} ({})", value, value as i32),
+                Err(e) => e,
+            },
+            "bt" => assertions.location(core),
+            "regs" => registers(core),
+            "" => continue,
+            _ => HELP.to_string(),
+        };
+        let _ = writeln!(output, "{}", reply);
+    }
+}
+
+/// pc and the registers, four a line
+fn registers(core: &CoreState) -> String {
+    let mut text = format!("pc 0x{:08x}", core.pc);
+    for i in 0..32 {
+        let separator = if i % 4 == 0 {"\n"} else {"  "};
+        text += &format!("{}{:>4} 0x{:08x}", separator, CoreState::reg_name(i), core.regs[i]);
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loader::Image;
+    use crate::test_utils::machine;
+
+    #[test]
+    fn commands_until_continue_or_quit() {
+        let image = Image {path: String::new(), base: 0, entry: 0, end: 0, phdr: 0, phentsize: 0, phnum: 0,
+                           symbols: HashMap::new(), functions: Vec::new()};
+        let assertions = Assertions::new(&image);
+        let core = machine(0x10, &[], &[(10, 0xFFFF_FFFE), (1, 0x24)]);
+        let mut output = Vec::new();
+        assert!(session(&core, &assertions, &mut "p a0 + 1\nbt\nfoo\n\ncontinue\nq\n".as_bytes(), &mut output));
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "(rs-v) 0xffffffff (-1)\n(rs-v)   at 0x00000010 ?\n  called from 0x00000020 ?\n\
+                    (rs-v) c(ontinue), q(uit), p(rint) <expression>, bt, regs\n(rs-v) (rs-v) ");
+        assert!(!session(&core, &assertions, &mut "regs\n".as_bytes(), &mut Vec::new()));
+        assert!(report(&core, &assertions, &HashMap::new(), 7).starts_with(
+            "interrupted\n  at 0x00000010 ?\n  called from 0x00000020 ?\n7 instructions retired, 0 cycles\nlast 0 pcs"));
+    }
+}
@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+
+/// Instructions remembered
+pub const DEPTH: usize = 16;
+
+/// The pcs of the last `DEPTH` executed instructions, kept on every run so a
+/// failure report can show how the guest got there without a trace
+#[derive(Clone)]
+pub struct PcHistory {
+    pcs: [u32; DEPTH],
+    // instructions recorded, the next slot is `count % DEPTH`
+    count: u64,
+}
+
+impl PcHistory {
+    pub fn new() -> Self {
+        Self {pcs: [0; DEPTH], count: 0}
+    }
+
+    pub(crate) fn push(&mut self, pc: u32) {
+        self.pcs[(self.count % DEPTH as u64) as usize] = pc;
+        self.count += 1;
+    }
+
+    /// Oldest first
+    pub fn pcs(&self) -> Vec<u32> {
+        let start = self.count.saturating_sub(DEPTH as u64);
+        (start..self.count).map(|i| self.pcs[(i % DEPTH as u64) as usize]).collect()
+    }
+
+    /// The pcs one per line under a heading, each with the closest symbol
+    /// at or below it
+    pub fn report(&self, symbols: &HashMap<String, u32>) -> String {
+        let pcs = self.pcs();
+        let mut report = format!("last {} pcs, oldest first:", pcs.len());
+        for pc in pcs {
+            report.push_str(&format!("\n  0x{:08x}", pc));
+            let closest = symbols.iter()
+                .filter(|&(name, &value)| !name.is_empty() && value <= pc)
+                .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)));
+            match closest {
+                Some((name, &value)) if value == pc => report.push_str(&format!(" <{}>", name)),
+                Some((name, &value)) => report.push_str(&format!(" <{}+0x{:x}>", name, pc - value)),
+                None => {}
+            }
+        }
+        report
+    }
+}
+
+impl Default for PcHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::csr_file::MTVEC;
+    use super::*;
+    use crate::encode::{self, OP_IMM};
+    use crate::test_utils::machine;
+
+    #[test]
+    fn keeps_the_last_pcs() {
+        // a loop of addi and j, behind an unmapped fetch that traps to it
+        let mut core = machine(0x40, &[encode::i(OP_IMM, 0b000, 1, 1, 1), encode::j(0, -4)], &[]);
+        core.pc = 0x2000;
+        core.csrs.set(MTVEC, 0x40);
+        for _ in 0..DEPTH + 1 {
+            core.execute();
+        }
+        let pcs = core.pc_history.pcs();
+        assert_eq!(pcs.len(), DEPTH);
+        assert_eq!((pcs[0], pcs[1], pcs[DEPTH - 1]), (0x40, 0x44, 0x44));
+
+        let mut history = PcHistory::new();
+        history.push(0x7);
+        history.push(0x14);
+        history.push(0x20);
+        let symbols = HashMap::from([("start".to_string(), 0x10), ("loop".to_string(), 0x20),
+                                     ("also_loop".to_string(), 0x20), (String::new(), 0)]);
+        assert_eq!(history.report(&symbols),
+                   "last 3 pcs, oldest first:\n  0x00000007\n  0x00000014 <start+0x4>\n  0x00000020 <also_loop>");
+    }
+}
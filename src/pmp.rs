@@ -0,0 +1,280 @@
+use crate::memory::Access;
+use crate::Privilege;
+
+/// mseccfg fields of Smepmp
+pub(crate) mod seccfg {
+    /// Machine Mode Lockdown
+    pub(crate) const MML: u32 = 1 << 0;
+    /// Machine Mode Whitelist Policy
+    pub(crate) const MMWP: u32 = 1 << 1;
+    /// Rule Locking Bypass
+    pub(crate) const RLB: u32 = 1 << 2;
+}
+
+/// pmpcfg fields of an entry
+const R: u8 = 1 << 0;
+const W: u8 = 1 << 1;
+const X: u8 = 1 << 2;
+const A: u8 = 0b11 << 3;
+const L: u8 = 1 << 7;
+const TOR: u8 = 1 << 3;
+const NA4: u8 = 2 << 3;
+const NAPOT: u8 = 3 << 3;
+
+/// The most entries the spec allows
+const MAX_ENTRIES: usize = 64;
+
+/// Physical memory protection with Smepmp: the pmpcfg and pmpaddr CSRs of
+/// 0, 16 or 64 entries, the others read zero, and mseccfg. The grain is 4
+/// bytes. Each load, store, fetch and page table access it `applies` to is
+/// checked against the lowest-numbered entry matching any of its bytes,
+/// which has to cover all of them.
+#[derive(Clone, PartialEq, Debug)]
+pub(crate) struct Pmp {
+    entries: usize,
+    cfg: [u8; MAX_ENTRIES],
+    addr: [u32; MAX_ENTRIES],
+    mseccfg: u32,
+    // M-mode accesses are checked: a rule is locked or MML or MMWP is set
+    machine: bool,
+}
+
+impl Pmp {
+    pub(crate) fn new(entries: usize) -> Self {
+        assert!(matches!(entries, 0 | 16 | 64), "{} PMP entries, there can be 0, 16 or 64", entries);
+        Self {entries, cfg: [0; MAX_ENTRIES], addr: [0; MAX_ENTRIES], mseccfg: 0, machine: false}
+    }
+
+    pub(crate) fn entries(&self) -> usize {
+        self.entries
+    }
+
+    /// pmpcfg`n`, the configuration of entries 4n to 4n + 3
+    pub(crate) fn cfg(&self, n: u8) -> u32 {
+        let first = 4 * n as usize;
+        u32::from_le_bytes(self.cfg[first..first + 4].try_into().unwrap())
+    }
+
+    pub(crate) fn set_cfg(&mut self, n: u8, value: u32) {
+        for (i, byte) in (4 * n as usize..).zip(value.to_le_bytes()) {
+            if i < self.entries {
+                self.cfg[i] = self.legal_cfg(i, byte);
+            }
+        }
+        self.update();
+    }
+
+    pub(crate) fn addr(&self, i: u8) -> u32 {
+        self.addr[i as usize]
+    }
+
+    /// Writes of a locked entry's address, or of the address below a locked
+    /// TOR entry, are ignored
+    pub(crate) fn set_addr(&mut self, i: u8, value: u32) {
+        let i = i as usize;
+        let above = i + 1 < self.entries && self.locked(i + 1) && self.cfg[i + 1] & A == TOR;
+        if i < self.entries && !self.locked(i) && !above {
+            self.addr[i] = value;
+        }
+    }
+
+    pub(crate) fn mseccfg(&self) -> u32 {
+        self.mseccfg
+    }
+
+    /// MML and MMWP stay set until reset; RLB can't be set while a rule is
+    /// locked with it clear
+    pub(crate) fn set_mseccfg(&mut self, value: u32) {
+        let sticky = self.mseccfg & (seccfg::MML | seccfg::MMWP);
+        let locked = self.mseccfg & seccfg::RLB == 0 && self.cfg[..self.entries].iter().any(|cfg| cfg & L != 0);
+        let rlb = if locked {0} else {value & seccfg::RLB};
+        self.mseccfg = sticky | (value & (seccfg::MML | seccfg::MMWP)) | rlb;
+        self.update();
+    }
+
+    /// Opens entry 0 as an unlocked RWX rule over all addresses, as firmware
+    /// leaves it for the kernel
+    pub(crate) fn allow_all(&mut self) {
+        if self.entries != 0 {
+            self.set_addr(0, !0);
+            self.set_cfg(0, (NAPOT | R | W | X) as u32);
+        }
+    }
+
+    /// Whether accesses at `privilege` are checked at all, so the others
+    /// can take the fast paths
+    pub(crate) fn applies(&self, privilege: Privilege) -> bool {
+        self.entries != 0 && (privilege != Privilege::Machine || self.machine)
+    }
+
+    /// Whether `size` bytes at the physical `address` may be accessed at
+    /// `privilege`, anything without entries
+    pub(crate) fn allows(&self, address: u32, size: u32, access: Access, privilege: Privilege) -> bool {
+        if self.entries == 0 {
+            return true;
+        }
+        let (start, end) = (address as u64, address as u64 + size as u64);
+        for i in 0..self.entries {
+            let Some((low, high)) = self.range(i) else {
+                continue;
+            };
+            if start >= high || end <= low {
+                continue;
+            }
+            let bit = match access {
+                Access::Load => R,
+                Access::Store => W,
+                Access::Fetch => X,
+            };
+            return start >= low && end <= high && self.permissions(self.cfg[i], privilege) & bit != 0;
+        }
+        // no rule matches: S- and U-mode are denied, M-mode is unless MMWP,
+        // and may not execute with MML
+        match privilege {
+            Privilege::Machine => self.mseccfg & seccfg::MMWP == 0 &&
+                (self.mseccfg & seccfg::MML == 0 || access != Access::Fetch),
+            _ => false,
+        }
+    }
+
+    fn locked(&self, i: usize) -> bool {
+        self.cfg[i] & L != 0 && self.mseccfg & seccfg::RLB == 0
+    }
+
+    /// The byte written to a pmpcfg entry, legalized: a locked entry keeps
+    /// its value, as does one written with the reserved R = 0, W = 1
+    /// without MML, and with MML and no RLB a locked rule M-mode may
+    /// execute can't be added
+    fn legal_cfg(&self, i: usize, value: u8) -> u8 {
+        let value = value & (L | A | X | W | R);
+        let mml = self.mseccfg & seccfg::MML != 0;
+        let reserved = !mml && value & (R | W) == W;
+        let executable = mml && self.mseccfg & seccfg::RLB == 0 && value & L != 0 &&
+            self.permissions(value, Privilege::Machine) & X != 0;
+        if self.locked(i) || reserved || executable {self.cfg[i]} else {value}
+    }
+
+    /// The addresses an entry matches, None when it is off
+    fn range(&self, i: usize) -> Option<(u64, u64)> {
+        let addr = self.addr[i] as u64;
+        match self.cfg[i] & A {
+            TOR => Some((if i == 0 {0} else {(self.addr[i - 1] as u64) << 2}, addr << 2)),
+            NA4 => Some((addr << 2, (addr << 2) + 4)),
+            NAPOT => {
+                let size = 8u64 << self.addr[i].trailing_ones();
+                Some(((addr << 2) & !(size - 1), ((addr << 2) & !(size - 1)) + size))
+            }
+            _ => None,
+        }
+    }
+
+    /// R, W and X of a matching entry for `privilege`. Without MML locked
+    /// rules bind M-mode and the others only S- and U-mode; with MML
+    /// locked rules are M-mode's and the others S- and U-mode's, and
+    /// R = 0, W = 1 makes a shared region.
+    fn permissions(&self, cfg: u8, privilege: Privilege) -> u8 {
+        const WX: u8 = W | X;
+        const RWX: u8 = R | W | X;
+        let machine = privilege == Privilege::Machine;
+        let locked = cfg & L != 0;
+        let rwx = cfg & RWX;
+        if self.mseccfg & seccfg::MML == 0 {
+            return if machine && !locked {RWX} else {rwx};
+        }
+        match (locked, rwx) {
+            (false, W) => if machine {R | W} else {R},
+            (false, WX) => R | W,
+            (true, W) => X,
+            (true, WX) => if machine {R | X} else {X},
+            (true, RWX) => R,
+            (locked, rwx) => if locked == machine {rwx} else {0},
+        }
+    }
+
+    fn update(&mut self) {
+        self.machine = self.mseccfg & (seccfg::MML | seccfg::MMWP) != 0 ||
+            self.cfg[..self.entries].iter().any(|cfg| cfg & L != 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const U: Privilege = Privilege::User;
+    const M: Privilege = Privilege::Machine;
+
+    #[test]
+    fn entries_match_by_number_and_cover_the_access() {
+        let mut pmp = Pmp::new(16);
+        assert!(!pmp.applies(M) && pmp.applies(U));
+        assert!(pmp.allows(0, 4, Access::Store, M) && !pmp.allows(0, 4, Access::Load, U));
+        // 0: NA4 at 0x100 read-only, 1: TOR from there up to 0x1000, 2: NAPOT
+        // 0x1000 of 0x1000 bytes
+        pmp.set_addr(0, 0x100 >> 2);
+        pmp.set_addr(1, 0x1000 >> 2);
+        pmp.set_addr(2, (0x1000 >> 2) | 0x1FF);
+        pmp.set_cfg(0, u32::from_le_bytes([NA4 | R, TOR | R | W | X, NAPOT | R | X, 0]));
+        assert!(pmp.allows(0x100, 4, Access::Load, U) && !pmp.allows(0x100, 4, Access::Store, U));
+        assert!(pmp.allows(0x104, 4, Access::Store, U) && !pmp.allows(0, 4, Access::Store, U));
+        assert!(!pmp.allows(0xFFE, 4, Access::Load, U), "spans two entries");
+        assert!(pmp.allows(0x1FFC, 4, Access::Fetch, U) && !pmp.allows(0x1FFC, 4, Access::Store, U));
+        assert!(!pmp.allows(0x2000, 4, Access::Load, U));
+        // R = 0, W = 1 is reserved without MML
+        pmp.set_cfg(0, u32::from_le_bytes([NA4 | W, TOR | R | W | X, NAPOT | R | X, 0]));
+        assert_eq!(pmp.cfg(0) & 0xFF, (NA4 | R) as u32);
+    }
+
+    #[test]
+    fn locked_entries_bind_m_mode_until_rlb() {
+        let mut pmp = Pmp::new(16);
+        pmp.set_addr(1, 0x1000 >> 2);
+        pmp.set_cfg(0, u32::from_le_bytes([0, L | TOR | R, 0, 0]));
+        assert!(pmp.applies(M));
+        assert!(pmp.allows(0x800, 4, Access::Load, M) && !pmp.allows(0x800, 4, Access::Store, M));
+        assert!(pmp.allows(0x1000, 4, Access::Store, M), "no rule matches");
+        // neither the entry nor the address below it can change, nor RLB
+        pmp.set_cfg(0, 0);
+        pmp.set_addr(0, 0x40);
+        pmp.set_addr(1, 0);
+        pmp.set_mseccfg(seccfg::RLB);
+        assert_eq!((pmp.cfg(0), pmp.addr(0), pmp.addr(1), pmp.mseccfg()), (((L | TOR | R) as u32) << 8, 0, 0x400, 0));
+        // with RLB set first the rule can be removed
+        let mut pmp = Pmp::new(16);
+        pmp.set_mseccfg(seccfg::RLB);
+        pmp.set_cfg(0, (L | NA4) as u32);
+        pmp.set_cfg(0, 0);
+        assert_eq!(pmp.cfg(0), 0);
+        assert!(Pmp::new(0).allows(0, 4, Access::Load, U));
+    }
+
+    #[test]
+    fn mml_splits_the_rules_between_the_modes() {
+        let mut pmp = Pmp::new(16);
+        pmp.set_mseccfg(seccfg::MML);
+        pmp.set_mseccfg(0);
+        assert_eq!(pmp.mseccfg(), seccfg::MML);
+        // NAPOT entries of 8 bytes at 0, 8, 16, ...
+        let napot = |i: u8| (i as u32) << 1;
+        for i in 0..6 {
+            pmp.set_addr(i, napot(i));
+        }
+        pmp.set_cfg(0, u32::from_le_bytes([NAPOT | R | W, NAPOT | W, L | NAPOT | R, L | NAPOT | R | W | X]));
+        pmp.set_cfg(1, u32::from_le_bytes([L | NAPOT | X, NAPOT | W | X, 0, 0]));
+        // an unlocked rule is S/U-mode's, a locked one M-mode's
+        assert!(!pmp.allows(0, 4, Access::Load, M) && pmp.allows(0, 4, Access::Store, U));
+        assert!(pmp.allows(16, 4, Access::Load, M) && !pmp.allows(16, 4, Access::Load, U));
+        // shared regions: M writes and S/U reads, both read, both read and
+        // write
+        assert!(pmp.allows(8, 4, Access::Store, M) && pmp.allows(8, 4, Access::Load, U));
+        assert!(!pmp.allows(8, 4, Access::Store, U));
+        assert!(pmp.allows(24, 4, Access::Load, U) && !pmp.allows(24, 4, Access::Store, M));
+        assert!(pmp.allows(40, 4, Access::Store, U) && pmp.allows(40, 4, Access::Store, M));
+        // locked executable M-mode rules can't be added without RLB
+        assert_eq!(pmp.cfg(1) & 0xFF, 0);
+        // M-mode may not execute where no rule matches
+        assert!(pmp.allows(0x100, 4, Access::Store, M) && !pmp.allows(0x100, 4, Access::Fetch, M));
+        pmp.set_mseccfg(seccfg::MMWP);
+        assert!(!pmp.allows(0x100, 4, Access::Load, M));
+    }
+}
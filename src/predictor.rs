@@ -0,0 +1,193 @@
+use crate::encode::BRANCH;
+use crate::hpm::Event;
+use crate::timing::TimingModel;
+use crate::CoreState;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Scheme {
+    /// Backward taken, forward not taken
+    Static,
+    /// 2-bit counters indexed by pc
+    Bimodal,
+    /// 2-bit counters indexed by pc xor the global branch history
+    Gshare,
+}
+
+/// Conditional branch predictor in front of another timing model (one cycle
+/// per instruction if none), adding `penalty` cycles per misprediction. The
+/// misprediction rate is printed to stderr when the model is dropped at the
+/// end of the run.
+pub struct Predictor {
+    scheme: Scheme,
+    bits: u32,
+    counters: Vec<u8>,
+    history: u32,
+    pub branches: u64,
+    pub mispredictions: u64,
+    pub penalty: u64,
+    inner: Option<Box<dyn TimingModel>>,
+}
+
+impl Predictor {
+    /// `bits` is the log2 of the counter table size and the gshare history
+    /// length
+    pub fn new(inner: Option<Box<dyn TimingModel>>, scheme: Scheme, bits: u32) -> Self {
+        let entries = if scheme == Scheme::Static {0} else {1 << bits};
+        Self {
+            scheme,
+            bits,
+            // weakly not taken
+            counters: vec![1; entries],
+            history: 0,
+            branches: 0,
+            mispredictions: 0,
+            penalty: 0,
+            inner,
+        }
+    }
+
+    /// `static[:penalty]`, `bimodal:bits[:penalty]` or `gshare:bits[:penalty]`,
+    /// e.g. `gshare:12:3`
+    pub fn parse(inner: Option<Box<dyn TimingModel>>, spec: &str) -> Result<Self, String> {
+        let mut fields = spec.split(':');
+        let scheme = match fields.next() {
+            Some("static") => Scheme::Static,
+            Some("bimodal") => Scheme::Bimodal,
+            Some("gshare") => Scheme::Gshare,
+            _ => return Err(format!("unknown predictor `{}`", spec)),
+        };
+        let mut number = |name| {
+            fields.next().map(|field| field.parse::<u32>().map_err(|_| format!("bad {} `{}`", name, field)))
+        };
+        let bits = match scheme {
+            Scheme::Static => 0,
+            _ => number("table size")
+                .ok_or(format!("{} needs a table size in bits", spec))??,
+        };
+        if bits > 24 {
+            return Err(format!("{} bit tables are too large", bits));
+        }
+        let mut predictor = Self::new(inner, scheme, bits);
+        if let Some(penalty) = number("penalty") {
+            predictor.penalty = penalty? as u64;
+        }
+        Ok(predictor)
+    }
+
+    fn index(&self, pc: u32) -> usize {
+        let index = match self.scheme {
+            Scheme::Gshare => (pc >> 2) ^ self.history,
+            _ => pc >> 2,
+        };
+        (index & ((1 << self.bits) - 1)) as usize
+    }
+
+    /// Predicts the branch at `pc` to `target`, learns the outcome and returns
+    /// whether the prediction was right
+    pub fn predict(&mut self, pc: u32, target: u32, taken: bool) -> bool {
+        self.branches += 1;
+        let predicted = match self.scheme {
+            Scheme::Static => target < pc,
+            _ => {
+                let index = self.index(pc);
+                let predicted = self.counters[index] >= 2;
+                let counter = &mut self.counters[index];
+                *counter = if taken {(*counter + 1).min(3)} else {counter.saturating_sub(1)};
+                self.history = ((self.history << 1) | taken as u32) & ((1 << self.bits) - 1);
+                predicted
+            }
+        };
+        if predicted != taken {
+            self.mispredictions += 1;
+        }
+        predicted == taken
+    }
+
+    pub fn report(&self) -> String {
+        let name = match self.scheme {
+            Scheme::Static => "static".to_string(),
+            Scheme::Bimodal => format!("bimodal {} bit", self.bits),
+            Scheme::Gshare => format!("gshare {} bit", self.bits),
+        };
+        format!("{} predictor: {} branches, {} mispredicted ({:.2}%)\n", name, self.branches, self.mispredictions,
+                100.0 * self.mispredictions as f64 / self.branches.max(1) as f64)
+    }
+}
+
+fn branch_target(pc: u32, instruction: u32) -> u32 {
+    let imm = ((instruction & 0x8000_0000) as i32 >> 19) as u32
+        | ((instruction & 0x80) << 4)
+        | ((instruction >> 20) & 0x7E0)
+        | ((instruction >> 7) & 0x1E);
+    pc.wrapping_add(imm)
+}
+
+impl TimingModel for Predictor {
+    fn cycles(&mut self, core: &CoreState, pc: u32, instruction: u32) -> u64 {
+        let cycles = self.inner.as_mut().map_or(1, |inner| inner.cycles(core, pc, instruction));
+        if instruction & 0x7F != BRANCH {
+            return cycles;
+        }
+        let taken = core.pc != pc.wrapping_add(4);
+        match self.predict(pc, branch_target(pc, instruction), taken) {
+            true => cycles,
+            false => cycles + self.penalty,
+        }
+    }
+
+    fn count(&self, event: Event) -> u64 {
+        match event {
+            Event::Mispredictions => self.mispredictions,
+            _ => self.inner.as_ref().map_or(0, |inner| inner.count(event)),
+        }
+    }
+}
+
+impl Drop for Predictor {
+    fn drop(&mut self) {
+        eprint!("{}", self.report());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode;
+
+    #[test]
+    fn schemes_learn_patterns() {
+        assert_eq!(branch_target(0x100, encode::b(0b000, 0, 0, -8)), 0xF8);
+        assert_eq!(branch_target(0x100, encode::b(0b000, 0, 0, 0x7FE)), 0x8FE);
+
+        // a loop branch taken 7 times then falling through, twice
+        let outcomes: Vec<bool> = (0..16).map(|i| i % 8 != 7).collect();
+        let mispredicted = |spec| {
+            let mut predictor = Predictor::parse(None, spec).unwrap();
+            for &taken in &outcomes {
+                predictor.predict(0x40, 0x20, taken);
+            }
+            predictor.mispredictions
+        };
+        assert_eq!(mispredicted("static"), 2);
+        // warms up through weakly not taken, then misses each exit
+        assert_eq!(mispredicted("bimodal:4"), 3);
+        assert!(Predictor::parse(None, "gshare").is_err());
+        assert!(Predictor::parse(None, "tage:10").is_err());
+        assert_eq!(Predictor::parse(None, "gshare:10:5").unwrap().penalty, 5);
+    }
+
+    #[test]
+    fn gshare_tracks_alternation() {
+        let mut predictor = Predictor::new(None, Scheme::Gshare, 4);
+        for i in 0..100 {
+            predictor.predict(0x40, 0x80, i % 2 == 0);
+        }
+        // a bimodal counter can't follow taken/not-taken alternation
+        assert!(predictor.mispredictions < 10, "{}", predictor.mispredictions);
+        let mut bimodal = Predictor::new(None, Scheme::Bimodal, 4);
+        for i in 0..100 {
+            bimodal.predict(0x40, 0x80, i % 2 == 0);
+        }
+        assert!(bimodal.mispredictions >= 50);
+    }
+}
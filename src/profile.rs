@@ -0,0 +1,157 @@
+use std::io::{self, Write};
+use std::time::Instant;
+
+use crate::interrupts::MTI;
+use crate::loader::load_segments;
+use crate::trace;
+use crate::{Config, CoreState, Device};
+
+const MEMORY_SIZE: usize = 16 << 20;
+
+// devices live in the top 64 KiB of the default memory
+const DEVICE_BASE: u32 = 0x00FF_0000;
+const DEVICE_SIZE: u32 = 0x3000;
+const UART_THR: u32 = DEVICE_BASE;
+const UART_LSR: u32 = DEVICE_BASE + 5;
+const TIMER_MTIME: u32 = DEVICE_BASE + 0x1000;
+const TIMER_FREQUENCY: u32 = DEVICE_BASE + 0x1008;
+const TIMER_MTIMECMP: u32 = DEVICE_BASE + 0x1010;
+const EXIT: u32 = DEVICE_BASE + 0x2000;
+const DEVICES: [Device; 3] = [("uart", UART_THR, 8), ("timer", TIMER_MTIME, 24), ("exit", EXIT, 4)];
+
+// THR empty | transmitter idle
+const LSR_IDLE: u8 = 0x60;
+const EXIT_PASS: u32 = 0x5555;
+const EXIT_FAIL: u32 = 0x3333;
+
+/// Nominal clock of the timing model, mtime counts `CoreState::cycles` (one
+/// per retired instruction without a timing model)
+pub const TIMER_HZ: u32 = 100_000_000;
+
+/// Outcome of a benchmark run
+pub struct Report {
+    pub exit_code: i32,
+    pub instructions: u64,
+    /// Elapsed cycles, equal to the final mtime
+    pub cycles: u64,
+    pub seconds: f64,
+    /// Workload name and score parsed from the UART output
+    pub score: Option<(&'static str, f64)>,
+}
+
+fn number_after(line: &str, label: &str) -> Option<f64> {
+    let (_, rest) = line.split_once(label)?;
+    rest.trim_start_matches([' ', ':', '\t']).split_whitespace().next()?.parse().ok()
+}
+
+/// Finds the CoreMark or Dhrystone result in the console output
+fn score(output: &str) -> Option<(&'static str, f64)> {
+    for line in output.lines().rev() {
+        let found = number_after(line, "Iterations/Sec")
+            .map(|score| ("coremark", score))
+            .or_else(|| number_after(line, "Dhrystones per Second").map(|score| ("dhrystone", score)));
+        if found.is_some() {
+            return found;
+        }
+    }
+    None
+}
+
+fn read_u32(core: &CoreState, address: u32) -> u32 {
+    let start = address as usize;
+    u32::from_le_bytes(core.memory[start..start + 4].try_into().unwrap())
+}
+
+fn write_u32(core: &mut CoreState, address: u32, value: u32) {
+    let start = address as usize;
+    core.memory[start..start + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Runs a bare-metal CoreMark/Dhrystone build on the benchmark profile: a
+/// byte-wide UART transmit register, a 64-bit mtime counter next to its
+/// frequency and the mtimecmp raising the machine timer interrupt, and an
+/// exit register (`0x5555` passes, `code << 16 | 0x3333` fails).
+pub fn run(args: &[String], config: Config) -> Result<Report, String> {
+    let Config {mut script, memory_size, engine, mut trace, mut observers, lenient, timing, mut interrupts, ..} = config;
+    let path = args.first().ok_or("missing program")?;
+
+    let memory_size = memory_size.unwrap_or(MEMORY_SIZE);
+    if memory_size < (DEVICE_BASE + DEVICE_SIZE) as usize {
+        return Err(format!("the benchmark profile needs at least {} bytes of memory",
+                           DEVICE_BASE + DEVICE_SIZE));
+    }
+    let mut core = CoreState::new(memory_size);
+    core.lenient = lenient;
+    core.timing = timing;
+    let image = load_segments(&mut core, path)?;
+    for observer in observers.iter_mut() {
+        observer.loaded(&image);
+        observer.devices(&DEVICES);
+    }
+    if image.end > DEVICE_BASE {
+        return Err(format!("{}: overlaps the devices at 0x{:08x}", path, DEVICE_BASE));
+    }
+    core.pc = image.entry;
+    core.memory[UART_LSR as usize] = LSR_IDLE;
+    write_u32(&mut core, TIMER_FREQUENCY, TIMER_HZ);
+    write_u32(&mut core, TIMER_MTIMECMP, u32::MAX);
+    write_u32(&mut core, TIMER_MTIMECMP + 4, u32::MAX);
+    // device stores end the running block so they are serviced in order
+    core.block_cache.watch = Some((DEVICE_BASE, DEVICE_SIZE));
+
+    let mut output = Vec::new();
+    let mut instructions: u64 = 0;
+    let start = Instant::now();
+    let exit_code = loop {
+        let mtime = core.cycles;
+        write_u32(&mut core, TIMER_MTIME, mtime as u32);
+        write_u32(&mut core, TIMER_MTIME + 4, (mtime >> 32) as u32);
+        // the words are compared before every instruction, so the usual
+        // update (low to all ones, high, then low) never fires in between
+        let mtimecmp = read_u32(&core, TIMER_MTIMECMP) as u64 | (read_u32(&core, TIMER_MTIMECMP + 4) as u64) << 32;
+        if mtime >= mtimecmp {
+            core.mip |= MTI;
+        } else {
+            core.mip &= !MTI;
+        }
+        if trace::ENABLED {
+            if let Some(tracer) = trace.as_mut() {
+                tracer.step(&core);
+            }
+        }
+        for observer in observers.iter_mut() {
+            observer.step(&core);
+        }
+        if let Some(script) = script.as_mut() {
+            if !script.step(&mut core) {
+                return Err("stopped by script".to_string());
+            }
+        }
+        if let Some(injector) = interrupts.as_mut() {
+            injector.step(&mut core);
+        }
+        instructions += core.dispatch(engine) as u64;
+
+        let c = core.memory[UART_THR as usize];
+        if c != 0 {
+            core.memory[UART_THR as usize] = 0;
+            output.push(c);
+            let mut stdout = io::stdout();
+            let _ = stdout.write_all(&[c]).and_then(|_| stdout.flush());
+        }
+        match read_u32(&core, EXIT) {
+            0 => {}
+            EXIT_PASS => break 0,
+            value if value & 0xFFFF == EXIT_FAIL => break (value >> 16) as i32,
+            value => break value as i32,
+        }
+    };
+
+    Ok(Report {
+        exit_code,
+        instructions,
+        cycles: core.cycles,
+        seconds: start.elapsed().as_secs_f64(),
+        score: score(&String::from_utf8_lossy(&output)),
+    })
+}
@@ -0,0 +1,89 @@
+use std::time::{Duration, Instant};
+
+use crate::loader::{function_at, Function, Image};
+use crate::profile::TIMER_HZ;
+use crate::{signals, CoreState};
+
+/// Steps between looks at the clock
+const POLL_INTERVAL: u64 = 1 << 16;
+
+/// Periodic progress lines for long runs on stderr: instructions retired,
+/// guest time at the nominal clock, MIPS since the previous line and the
+/// function running. Printed every interval and whenever SIGUSR1 (or
+/// Ctrl+T) arrives.
+pub struct Progress {
+    /// None reports only on the signal
+    interval: Option<Duration>,
+    functions: Vec<Function>,
+    steps: u64,
+    last: Instant,
+    last_retired: u64,
+}
+
+impl Progress {
+    pub fn new(interval: Option<Duration>) -> Self {
+        signals::catch_progress();
+        Self {interval, functions: Vec::new(), steps: 0, last: Instant::now(), last_retired: 0}
+    }
+
+    /// Seconds between lines, e.g. `10` or `0.5`, or `signal`
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        if spec == "signal" {
+            return Ok(Self::new(None));
+        }
+        match spec.parse::<f64>() {
+            Ok(seconds) if seconds > 0.0 => Ok(Self::new(Some(Duration::from_secs_f64(seconds)))),
+            _ => Err(format!("bad interval `{}`, expected seconds or `signal`", spec)),
+        }
+    }
+
+    pub(crate) fn loaded(&mut self, image: &Image) {
+        self.functions = image.functions.clone();
+    }
+
+    /// Called before every step with the instructions retired so far
+    pub(crate) fn step(&mut self, core: &CoreState, retired: u64) {
+        self.steps += 1;
+        let due = self.steps.is_multiple_of(POLL_INTERVAL)
+            && self.interval.is_some_and(|interval| self.last.elapsed() >= interval);
+        if due || signals::progress_requested() {
+            eprintln!("{}", self.line(core, retired));
+        }
+    }
+
+    fn line(&mut self, core: &CoreState, retired: u64) -> String {
+        let seconds = self.last.elapsed().as_secs_f64();
+        let mips = (retired - self.last_retired) as f64 / seconds.max(1e-9) / 1e6;
+        self.last = Instant::now();
+        self.last_retired = retired;
+        format!("progress: {} instructions, {:.6} s guest time, {:.1} MIPS, pc 0x{:08x} {}",
+                retired, core.cycles as f64 / TIMER_HZ as f64, mips, core.pc, self.symbol(core.pc))
+    }
+
+    fn symbol(&self, address: u32) -> String {
+        match function_at(&self.functions, address) {
+            Some(i) if address == self.functions[i].start => format!("<{}>", self.functions[i].name),
+            Some(i) => format!("<{}+0x{:x}>", self.functions[i].name, address - self.functions[i].start),
+            None => "<?>".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_name_the_function() {
+        assert!(Progress::parse("0").is_err() && Progress::parse("often").is_err());
+        assert_eq!(Progress::parse("0.5").unwrap().interval, Some(Duration::from_millis(500)));
+        let mut progress = Progress::parse("signal").unwrap();
+        progress.functions = vec![Function {start: 0x100, size: 0x20, name: "main".to_string()}];
+        let mut core = CoreState::new(16);
+        core.pc = 0x108;
+        core.cycles = TIMER_HZ as u64 / 4;
+        let line = progress.line(&core, 1000);
+        assert!(line.starts_with("progress: 1000 instructions, 0.250000 s guest time, "), "{}", line);
+        assert!(line.ends_with(" MIPS, pc 0x00000108 <main+0x8>"), "{}", line);
+    }
+}
@@ -0,0 +1,49 @@
+use std::ops::Index;
+
+/// x0..x31. Writes go through `write`, which drops writes to x0, so x0 reads
+/// as zero at every point, not only between instructions.
+/// The JIT addresses the array inside the core directly.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[repr(transparent)]
+pub struct Registers([u32; 32]);
+
+impl Registers {
+    pub fn write(&mut self, index: usize, value: u32) {
+        debug_assert!(index < 32, "register index {} out of range", index);
+        if index != 0 {
+            self.0[index] = value;
+        }
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, u32> {
+        self.0.iter()
+    }
+}
+
+impl Index<usize> for Registers {
+    type Output = u32;
+
+    fn index(&self, index: usize) -> &u32 {
+        &self.0[index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Registers;
+
+    #[test]
+    fn x0_ignores_writes() {
+        let mut regs = Registers::default();
+        regs.write(0, 0xDEAD_BEEF);
+        regs.write(31, 7);
+        assert_eq!((regs[0], regs[31]), (0, 7));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "out of range")]
+    fn out_of_range_writes_assert() {
+        Registers::default().write(32, 1);
+    }
+}
@@ -0,0 +1,105 @@
+use std::mem;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+
+/// Batches in flight before the core waits for the receiver
+const CHANNEL_BOUND: usize = 64;
+
+/// An instruction that retired: trapping instructions don't, as for
+/// minstret
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Retired {
+    pub pc: u32,
+    pub instruction: u32,
+}
+
+/// When the core hands a batch of retired instructions over
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Batching {
+    /// After every dispatch: a basic block with the block engine, one
+    /// instruction with the step engine
+    Block,
+    /// Every n instructions
+    Every(usize),
+}
+
+/// Retire events of a core, collected into batches and sent through a
+/// bounded channel so the instrumentation runs on its own thread, one send
+/// per batch instead of a call per instruction. The last, partial batch is
+/// sent when the core is dropped, which also ends the channel.
+pub(crate) struct RetireLog {
+    sender: SyncSender<Vec<Retired>>,
+    batch: Vec<Retired>,
+    batching: Batching,
+}
+
+impl RetireLog {
+    pub(crate) fn new(batching: Batching) -> (Self, Receiver<Vec<Retired>>) {
+        let (sender, receiver) = mpsc::sync_channel(CHANNEL_BOUND);
+        let capacity = match batching {
+            Batching::Block => 64,
+            Batching::Every(n) => n,
+        };
+        (Self {sender, batch: Vec::with_capacity(capacity), batching}, receiver)
+    }
+
+    pub(crate) fn push(&mut self, retired: Retired) {
+        self.batch.push(retired);
+        if matches!(self.batching, Batching::Every(n) if self.batch.len() >= n) {
+            self.flush();
+        }
+    }
+
+    /// Called after every dispatch
+    pub(crate) fn dispatched(&mut self) {
+        if self.batching == Batching::Block {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.batch.is_empty() {
+            return;
+        }
+        let capacity = self.batch.capacity();
+        let batch = mem::replace(&mut self.batch, Vec::with_capacity(capacity));
+        // a receiver that hung up just stops getting events
+        let _ = self.sender.send(batch);
+    }
+}
+
+impl Drop for RetireLog {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{self, OP_IMM};
+    use crate::test_utils::machine;
+    use crate::Engine;
+
+    #[test]
+    fn batches_follow_the_setting() {
+        // addi x1, x1, 1 three times, then an illegal instruction
+        let program = [encode::i(OP_IMM, 0b000, 1, 1, 1); 3];
+        for (engine, batching, sizes) in [
+            (Engine::Block, Batching::Block, vec![3]),
+            (Engine::Step, Batching::Block, vec![1, 1, 1]),
+            (Engine::Block, Batching::Every(2), vec![2, 1]),
+        ] {
+            let mut core = machine(0, &program, &[]);
+            let receiver = core.log_retires(batching);
+            while core.pc < 12 {
+                core.dispatch(engine);
+            }
+            // the trap at 12 doesn't retire
+            core.dispatch(engine);
+            drop(core);
+            let batches: Vec<Vec<Retired>> = receiver.iter().collect();
+            assert_eq!(batches.iter().map(Vec::len).collect::<Vec<_>>(), sizes);
+            assert_eq!(batches.concat()[2], Retired {pc: 8, instruction: program[2]});
+        }
+    }
+}
@@ -0,0 +1,527 @@
+use std::collections::BTreeSet;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpListener;
+
+use crate::history::{History, DEFAULT_DEPTH};
+use crate::interrupts::{Injector, MEI, MSI, MTI};
+use crate::json::{self, Value};
+use crate::script::{parse_number, parse_reg};
+use crate::snapshot::Snapshot;
+use crate::trace::{self, Tracer};
+use crate::{load_bare_metal, Config, CoreState, Engine, Observer, Platform};
+
+/// Dispatches between polls of the connection while the guest runs
+pub(crate) const SLICE: u64 = 10_000;
+/// Largest `read_memory` length
+const MAX_READ: u32 = 1 << 16;
+
+const PARSE_ERROR: i32 = -32700;
+const INVALID_REQUEST: i32 = -32600;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+/// Requests that are well-formed but can't be served in the current state
+const SERVER_ERROR: i32 = -32000;
+
+/// JSON-RPC error code and message
+pub(crate) struct Error(pub(crate) i32, pub(crate) String);
+
+fn invalid(message: String) -> Error {
+    Error(INVALID_PARAMS, message)
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum State {
+    Paused,
+    Running,
+    Exited(i32),
+}
+
+/// A bare-metal machine driven by JSON-RPC requests, see `serve`
+pub(crate) struct Session {
+    core: CoreState,
+    platform: Platform,
+    engine: Engine,
+    trace: Option<Tracer>,
+    observers: Vec<Box<dyn Observer>>,
+    interrupts: Option<Injector>,
+    breakpoints: BTreeSet<u32>,
+    // undo log while recording for reverse execution
+    history: Option<History>,
+    state: State,
+    // the first dispatch after resuming doesn't stop at a breakpoint on pc
+    resumed: bool,
+    quit: bool,
+}
+
+/// Number parameter, a JSON number or a string such as `"0x8000_0000"`
+fn number(params: &Value, key: &str) -> Result<u32, Error> {
+    match params.get(key) {
+        Some(Value::String(text)) => parse_number(text).map_err(invalid),
+        Some(value) => value.as_u32().ok_or_else(|| invalid(format!("`{}` must be a 32-bit number", key))),
+        None => Err(invalid(format!("missing `{}`", key))),
+    }
+}
+
+fn string<'a>(params: &'a Value, key: &str) -> Result<&'a str, Error> {
+    params.get(key).and_then(Value::as_str).ok_or_else(|| invalid(format!("missing string `{}`", key)))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub(crate) fn unhex(text: &str) -> Result<Vec<u8>, Error> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return Err(invalid("`data` must be pairs of hex digits".to_string()));
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16))
+        .collect::<Result<_, _>>()
+        .map_err(|_| invalid("`data` must be pairs of hex digits".to_string()))
+}
+
+fn response(id: Value, result: Result<Value, Error>) -> String {
+    let outcome = match result {
+        Ok(result) => ("result", result),
+        Err(Error(code, message)) => ("error", Value::object(&[("code", Value::Number(code as f64)),
+                                                               ("message", message.as_str().into())])),
+    };
+    Value::object(&[("jsonrpc", "2.0".into()), ("id", id), outcome]).to_string()
+}
+
+impl Session {
+    pub(crate) fn new(args: &[String], config: Config) -> Result<Self, String> {
+        let Config {memory_size, engine, trace, mut observers, lenient, timing, interrupts, ..} = config;
+        let path = args.first().ok_or("missing program")?;
+        let (mut core, image) = load_bare_metal(path, memory_size)?;
+        core.lenient = lenient;
+        core.timing = timing;
+        for observer in observers.iter_mut() {
+            observer.loaded(&image);
+        }
+        let platform = Platform::new(args, &image, &mut core);
+        for observer in observers.iter_mut() {
+            observer.devices(&platform.devices());
+        }
+        Ok(Self {
+            core,
+            platform,
+            engine,
+            trace,
+            observers,
+            interrupts,
+            breakpoints: BTreeSet::new(),
+            history: None,
+            state: State::Paused,
+            resumed: false,
+            quit: false,
+        })
+    }
+
+    /// Session of an inline test program at address 0
+    #[cfg(test)]
+    pub(crate) fn inline(program: &[u32]) -> Self {
+        Self {
+            core: crate::test_utils::machine(0, program, &[]),
+            platform: Platform {semihosting: crate::semihosting::Semihosting::new(String::new()), htif: None, retired: 0,
+                                host_ecalls: false, sbi: None},
+            engine: Engine::Block,
+            trace: None,
+            observers: Vec::new(),
+            interrupts: None,
+            breakpoints: BTreeSet::new(),
+            history: None,
+            state: State::Paused,
+            resumed: false,
+            quit: false,
+        }
+    }
+
+    pub(crate) fn running(&self) -> bool {
+        self.state == State::Running
+    }
+
+    pub(crate) fn exit_code(&self) -> Option<i32> {
+        match self.state {
+            State::Exited(code) => Some(code),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn memory_size(&self) -> usize {
+        self.core.memory.len()
+    }
+
+    /// Starts recording the last `depth` steps for `reverse_step` and
+    /// `reverse_continue`, running single steps from then on
+    pub(crate) fn record(&mut self, depth: usize) {
+        self.history = Some(History::new(depth));
+    }
+
+    /// Starts keeping console output for `console`
+    pub(crate) fn capture_console(&mut self) {
+        self.platform.capture_console();
+    }
+
+    /// Console output since the last call
+    pub(crate) fn console(&mut self) -> Vec<u8> {
+        self.platform.take_console()
+    }
+
+    /// Runs the tools and one dispatch, returns true once the guest exited
+    fn advance(&mut self, engine: Engine) -> bool {
+        if trace::ENABLED {
+            if let Some(tracer) = self.trace.as_mut() {
+                tracer.step(&self.core);
+            }
+        }
+        for observer in self.observers.iter_mut() {
+            observer.step(&self.core);
+        }
+        if let Some(injector) = self.interrupts.as_mut() {
+            injector.step(&mut self.core);
+        }
+        self.resumed = false;
+        if let Some(history) = self.history.as_mut() {
+            history.record(&self.core, self.platform.retired);
+        }
+        match self.platform.step(&mut self.core, engine) {
+            Some(code) => {
+                self.state = State::Exited(code);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Runs up to `budget` dispatches, returns the reason if the guest
+    /// stopped at a breakpoint or exited
+    pub(crate) fn run(&mut self, budget: u64) -> Option<&'static str> {
+        // blocks would run past breakpoints and record several steps as one
+        let engine = if self.breakpoints.is_empty() && self.history.is_none() {self.engine} else {Engine::Step};
+        for _ in 0..budget {
+            if !self.resumed && self.breakpoints.contains(&self.core.pc) {
+                self.state = State::Paused;
+                return Some("breakpoint");
+            }
+            if self.advance(engine) {
+                return Some("exit");
+            }
+        }
+        None
+    }
+
+    /// Undoes one step, false at the start of the history
+    fn back(&mut self) -> Result<bool, Error> {
+        let history = self.history.as_mut().ok_or(Error(SERVER_ERROR, "not recording".to_string()))?;
+        match history.undo(&mut self.core) {
+            Some(retired) => {
+                self.platform.retired = retired;
+                self.state = State::Paused;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn status(&self) -> Value {
+        let state = match self.state {
+            State::Paused => "paused",
+            State::Running => "running",
+            State::Exited(_) => "exited",
+        };
+        let mut status = vec![("state", state.into()), ("pc", self.core.pc.into()), ("cycles", self.core.cycles.into())];
+        if let State::Exited(code) = self.state {
+            status.push(("exit_code", Value::Number(code as f64)));
+        }
+        if let Some(history) = &self.history {
+            status.push(("history", (history.len() as u32).into()));
+        }
+        Value::object(&status)
+    }
+
+    /// `stopped` notification sent when a run ends on its own
+    fn stopped(&self, reason: &str) -> String {
+        let mut params = self.status();
+        if let Value::Object(members) = &mut params {
+            members.insert(0, ("reason".to_string(), reason.into()));
+        }
+        Value::object(&[("jsonrpc", "2.0".into()), ("method", "stopped".into()), ("params", params)]).to_string()
+    }
+
+    fn live(&self) -> Result<(), Error> {
+        match self.state {
+            State::Exited(code) => Err(Error(SERVER_ERROR, format!("program exited with code {}", code))),
+            _ => Ok(()),
+        }
+    }
+
+    fn memory(&self, address: u32, length: u32) -> Result<std::ops::Range<usize>, Error> {
+        let range = address as usize..address as usize + length as usize;
+        match range.end <= self.core.memory.len() {
+            true => Ok(range),
+            false => Err(Error(SERVER_ERROR, format!("0x{:08x}+{} is outside guest memory", address, length))),
+        }
+    }
+
+    pub(crate) fn call(&mut self, method: &str, params: &Value) -> Result<Value, Error> {
+        match method {
+            "status" => {}
+            "pause" => {
+                if self.state == State::Running {
+                    self.state = State::Paused;
+                }
+            }
+            "resume" | "continue" => {
+                self.live()?;
+                self.state = State::Running;
+                self.resumed = true;
+            }
+            "step" => {
+                self.live()?;
+                let count = match params.get("count") {
+                    Some(_) => number(params, "count")?,
+                    None => 1,
+                };
+                self.state = State::Paused;
+                for _ in 0..count {
+                    if self.advance(Engine::Step) {
+                        break;
+                    }
+                }
+            }
+            "record" => {
+                let depth = match params.get("depth") {
+                    Some(_) => number(params, "depth")? as usize,
+                    None => DEFAULT_DEPTH,
+                };
+                self.record(depth);
+            }
+            "reverse_step" | "reverse_continue" => {
+                let count = match params.get("count") {
+                    Some(_) if method == "reverse_step" => number(params, "count")?,
+                    _ if method == "reverse_step" => 1,
+                    _ => u32::MAX,
+                };
+                let mut reason = None;
+                for _ in 0..count {
+                    if !self.back()? {
+                        reason = Some("history start");
+                        break;
+                    }
+                    if method == "reverse_continue" && self.breakpoints.contains(&self.core.pc) {
+                        reason = Some("breakpoint");
+                        break;
+                    }
+                }
+                let mut status = self.status();
+                if let (Some(reason), Value::Object(members)) = (reason, &mut status) {
+                    members.insert(0, ("reason".to_string(), reason.into()));
+                }
+                return Ok(status);
+            }
+            "registers" => {
+                let x = self.core.regs.iter().map(|&reg| reg.into()).collect();
+                return Ok(Value::object(&[("pc", self.core.pc.into()), ("x", Value::Array(x))]));
+            }
+            "set_register" => {
+                let value = number(params, "value")?;
+                match params.get("register") {
+                    Some(Value::String(name)) if name == "pc" => self.core.pc = value,
+                    Some(Value::String(name)) => self.core.regs.write(parse_reg(name).map_err(invalid)?, value),
+                    _ => match number(params, "register")? {
+                        index @ 0..=31 => self.core.regs.write(index as usize, value),
+                        index => return Err(invalid(format!("bad register {}", index))),
+                    },
+                }
+                return Ok(Value::Null);
+            }
+            "read_memory" => {
+                let (address, length) = (number(params, "address")?, number(params, "length")?);
+                if length > MAX_READ {
+                    return Err(invalid(format!("`length` is limited to {}", MAX_READ)));
+                }
+                let range = self.memory(address, length)?;
+                return Ok(Value::object(&[("address", address.into()), ("data", hex(&self.core.memory[range]).as_str().into())]));
+            }
+            "write_memory" => {
+                let address = number(params, "address")?;
+                let data = unhex(string(params, "data")?)?;
+                let range = self.memory(address, data.len() as u32)?;
+                self.core.memory[range].copy_from_slice(&data);
+                self.core.invalidate_code(address, data.len() as u32);
+                return Ok(Value::Null);
+            }
+            "set_breakpoint" | "delete_breakpoint" | "breakpoints" => {
+                if method != "breakpoints" {
+                    let address = number(params, "address")?;
+                    if method == "set_breakpoint" {
+                        self.breakpoints.insert(address);
+                    } else if !self.breakpoints.remove(&address) {
+                        return Err(invalid(format!("no breakpoint at 0x{:08x}", address)));
+                    }
+                }
+                return Ok(Value::Array(self.breakpoints.iter().map(|&address| address.into()).collect()));
+            }
+            "snapshot" => {
+                let path = string(params, "path")?;
+                let snapshot = Snapshot::capture(&self.core, &self.platform);
+                std::fs::write(path, snapshot.to_bytes())
+                    .map_err(|e| Error(SERVER_ERROR, format!("{}: {}", path, e)))?;
+                return Ok(Value::Null);
+            }
+            "interrupt" => {
+                self.core.mip |= match string(params, "line")? {
+                    "software" => MSI,
+                    "timer" => MTI,
+                    "external" => MEI,
+                    line => return Err(invalid(format!("bad interrupt line `{}`", line))),
+                };
+                return Ok(Value::Null);
+            }
+            "quit" => {
+                self.quit = true;
+                return Ok(Value::Null);
+            }
+            _ => return Err(Error(METHOD_NOT_FOUND, format!("unknown method `{}`", method))),
+        }
+        Ok(self.status())
+    }
+
+    /// Serves one request line, returns the response (None for notifications)
+    pub(crate) fn handle(&mut self, line: &str) -> Option<String> {
+        let request = match json::parse(line) {
+            Ok(request) => request,
+            Err(e) => return Some(response(Value::Null, Err(Error(PARSE_ERROR, e)))),
+        };
+        let id = request.get("id").cloned();
+        let result = match request.get("method").and_then(Value::as_str) {
+            Some(method) => self.call(method, request.get("params").unwrap_or(&Value::Null)),
+            None => Err(Error(INVALID_REQUEST, "missing `method`".to_string())),
+        };
+        id.map(|id| response(id, result))
+    }
+}
+
+/// Loads a bare-metal program paused at its entry and serves one client at
+/// `address` (e.g. `127.0.0.1:5555`) with newline-delimited JSON-RPC 2.0.
+/// Returns the guest exit code, 0 if the client quit or hung up first.
+pub fn serve(address: &str, args: &[String], config: Config) -> Result<i32, String> {
+    let mut session = Session::new(args, config)?;
+    let listener = TcpListener::bind(address).map_err(|e| format!("{}: {}", address, e))?;
+    eprintln!("listening on {}", listener.local_addr().map_err(|e| e.to_string())?);
+    let (mut stream, peer) = listener.accept().map_err(|e| e.to_string())?;
+    eprintln!("connection from {}", peer);
+    let mut pending = Vec::new();
+    let mut buffer = [0; 4096];
+    while !session.quit {
+        if session.state == State::Running {
+            if let Some(reason) = session.run(SLICE) {
+                writeln!(stream, "{}", session.stopped(reason)).map_err(|e| e.to_string())?;
+            }
+        }
+        // poll while running, wait for requests otherwise
+        stream.set_nonblocking(session.state == State::Running).map_err(|e| e.to_string())?;
+        let read = stream.read(&mut buffer);
+        stream.set_nonblocking(false).map_err(|e| e.to_string())?;
+        match read {
+            Ok(0) => break,
+            Ok(n) => pending.extend_from_slice(&buffer[..n]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e.to_string()),
+        }
+        while let Some(end) = pending.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(response) = session.handle(line.trim()) {
+                writeln!(stream, "{}", response).map_err(|e| e.to_string())?;
+            }
+            if session.quit {
+                break;
+            }
+        }
+    }
+    Ok(session.exit_code().unwrap_or(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{self, OP_IMM};
+
+    fn call(session: &mut Session, request: &str) -> String {
+        session.handle(request).unwrap()
+    }
+
+    #[test]
+    fn requests_drive_the_machine() {
+        // loop: addi x1, x1, 1; addi x2, x2, 2; jal x0, loop
+        let mut session = Session::inline(&[encode::i(OP_IMM, 0b000, 1, 1, 1), encode::i(OP_IMM, 0b000, 2, 2, 2),
+                                    encode::j(0, -8)]);
+        assert_eq!(call(&mut session, r#"{"jsonrpc":"2.0","id":1,"method":"step","params":{"count":4}}"#),
+                   r#"{"jsonrpc":"2.0","id":1,"result":{"state":"paused","pc":4,"cycles":4}}"#);
+        assert_eq!(call(&mut session, r#"{"id":2,"method":"set_breakpoint","params":{"address":"0x8"}}"#),
+                   r#"{"jsonrpc":"2.0","id":2,"result":[8]}"#);
+        call(&mut session, r#"{"id":3,"method":"resume"}"#);
+        assert_eq!(session.run(SLICE), Some("breakpoint"));
+        assert!(session.stopped("breakpoint").starts_with(r#"{"jsonrpc":"2.0","method":"stopped","params":{"reason":"breakpoint","state":"paused","pc":8"#));
+        // resuming runs past the breakpoint it stopped at
+        call(&mut session, r#"{"id":4,"method":"continue"}"#);
+        assert_eq!(session.run(SLICE), Some("breakpoint"));
+        assert_eq!(session.core.regs[2], 6);
+
+        call(&mut session, r#"{"id":5,"method":"set_register","params":{"register":"a0","value":7}}"#);
+        call(&mut session, r#"{"id":6,"method":"set_register","params":{"register":"pc","value":0}}"#);
+        let registers = json::parse(&call(&mut session, r#"{"id":7,"method":"registers"}"#)).unwrap();
+        let registers = registers.get("result").unwrap();
+        assert_eq!(registers.get("pc"), Some(&Value::Number(0.0)));
+        assert_eq!(registers.get("x"), Some(&Value::Array([0, 3, 6, 0, 0, 0, 0, 0, 0, 0, 7].iter().map(|&reg: &u32| reg.into())
+                                                             .chain(std::iter::repeat_n(Value::Number(0.0), 21)).collect())));
+
+        // patch the first instruction to addi x1, x1, 16
+        let patch = hex(&encode::i(OP_IMM, 0b000, 1, 1, 16).to_le_bytes());
+        call(&mut session, &format!(r#"{{"id":8,"method":"write_memory","params":{{"address":0,"data":"{}"}}}}"#, patch));
+        assert_eq!(call(&mut session, r#"{"id":9,"method":"read_memory","params":{"address":0,"length":4}}"#),
+                   format!(r#"{{"jsonrpc":"2.0","id":9,"result":{{"address":0,"data":"{}"}}}}"#, patch));
+        call(&mut session, r#"{"id":10,"method":"step"}"#);
+        assert_eq!(session.core.regs[1], 19);
+
+        call(&mut session, r#"{"id":11,"method":"interrupt","params":{"line":"timer"}}"#);
+        assert_eq!(session.core.mip, MTI);
+        for (request, code) in [
+            ("{", PARSE_ERROR),
+            (r#"{"id":12}"#, INVALID_REQUEST),
+            (r#"{"id":13,"method":"reboot"}"#, METHOD_NOT_FOUND),
+            (r#"{"id":14,"method":"read_memory","params":{"address":4096,"length":4}}"#, SERVER_ERROR),
+            (r#"{"id":15,"method":"write_memory","params":{"address":0,"data":"abc"}}"#, INVALID_PARAMS),
+            (r#"{"id":16,"method":"delete_breakpoint","params":{"address":4}}"#, INVALID_PARAMS),
+        ] {
+            assert!(call(&mut session, request).contains(&format!(r#""error":{{"code":{},"#, code)), "{}", request);
+        }
+        // notifications get no response
+        assert_eq!(session.handle(r#"{"method":"quit"}"#), None);
+        assert!(session.quit);
+    }
+
+    #[test]
+    fn writes_replace_cached_code() {
+        // four addi x1, x1, 1 and a loop back
+        let mut program = vec![encode::i(OP_IMM, 0b000, 1, 1, 1); 4];
+        program.push(encode::j(0, -16));
+        let mut session = Session::inline(&program);
+        call(&mut session, r#"{"id":1,"method":"step","params":{"count":5}}"#);
+        assert_eq!(session.core.regs[1], 4);
+        let patch: Vec<u8> = (0..4).flat_map(|_| encode::i(OP_IMM, 0b000, 1, 1, 2).to_le_bytes()).collect();
+        call(&mut session, &format!(r#"{{"id":2,"method":"write_memory","params":{{"address":0,"data":"{}"}}}}"#,
+                                    hex(&patch)));
+        // an empty write is valid and changes nothing
+        assert_eq!(call(&mut session, r#"{"id":3,"method":"write_memory","params":{"address":8,"data":""}}"#),
+                   r#"{"jsonrpc":"2.0","id":3,"result":null}"#);
+        call(&mut session, r#"{"id":4,"method":"step","params":{"count":4}}"#);
+        assert_eq!(session.core.regs[1], 12);
+    }
+}
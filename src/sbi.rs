@@ -0,0 +1,185 @@
+//! SBI for S-mode kernels without firmware: `ecall` from S-mode is serviced
+//! by the emulator like OpenSBI would, a7 selecting the extension and a6 the
+//! function. Returns a0 = error and a1 = value, the legacy calls only a0.
+
+use crate::csr_file::{MCOUNTEREN, MEDELEG, MIDELEG};
+use crate::encode::ECALL;
+use crate::interrupts::{SEI, SSI, STI};
+use crate::semihosting::Semihosting;
+use crate::{CoreState, Privilege};
+
+const LEGACY_SET_TIMER: u32 = 0x00;
+const LEGACY_PUTCHAR: u32 = 0x01;
+const LEGACY_GETCHAR: u32 = 0x02;
+const LEGACY_SHUTDOWN: u32 = 0x08;
+const BASE: u32 = 0x10;
+const TIME: u32 = 0x5449_4D45;
+const IPI: u32 = 0x0073_5049;
+const SRST: u32 = 0x5352_5354;
+const EXTENSIONS: [u32; 8] = [LEGACY_SET_TIMER, LEGACY_PUTCHAR, LEGACY_GETCHAR, LEGACY_SHUTDOWN, BASE, TIME, IPI,
+                              SRST];
+
+/// SBI 2.0
+const SPEC_VERSION: u32 = 2 << 24;
+/// Implementation ID, outside the range the spec assigns
+const IMPL_ID: u32 = 0x7273;
+const SUCCESS: u32 = 0;
+const NOT_SUPPORTED: u32 = -2i32 as u32;
+const INVALID_PARAM: u32 = -3i32 as u32;
+/// system_reset reason a guest reports failures with
+const SYSTEM_FAILURE: u32 = 1;
+/// The exceptions OpenSBI leaves to the kernel: misaligned fetches,
+/// breakpoints, U-mode ecalls and page faults
+const DELEGATED: u32 = 1 << 0 | 1 << 3 | 1 << 8 | 1 << 12 | 1 << 13 | 1 << 15;
+
+/// State of the SBI layer: the timer deadline, in `CoreState::cycles` like
+/// the benchmark mtime
+pub(crate) struct Sbi {
+    timer: Option<u64>,
+}
+
+impl Sbi {
+    pub(crate) fn new() -> Self {
+        Self {timer: None}
+    }
+
+    /// Sets the machine up as the firmware would before entering the
+    /// kernel: the S-mode interrupts and the usual exceptions delegated, the
+    /// counters enabled and all of memory open below M-mode
+    pub(crate) fn delegate(core: &mut CoreState) {
+        core.csrs.write(MIDELEG, SSI | STI | SEI);
+        core.csrs.write(MEDELEG, DELEGATED);
+        core.csrs.write(MCOUNTEREN, !0);
+        core.pmp.allow_all();
+    }
+
+    /// Whether pc points at an `ecall` from S-mode
+    pub(crate) fn is_call(core: &CoreState) -> bool {
+        core.privilege == Privilege::Supervisor && core.fetch() == ECALL
+    }
+
+    /// Raises the supervisor timer interrupt once the deadline passes
+    pub(crate) fn tick(&mut self, core: &mut CoreState) {
+        if self.timer.is_some_and(|timer| core.cycles >= timer) {
+            core.mip |= STI;
+            self.timer = None;
+        }
+    }
+
+    fn set_timer(&mut self, core: &mut CoreState, deadline: u64) {
+        core.mip &= !STI;
+        self.timer = Some(deadline);
+    }
+
+    /// Services the call at pc, returns the exit code if the guest shut the
+    /// system down
+    pub(crate) fn call(&mut self, core: &mut CoreState, console: &mut Semihosting) -> Option<i32> {
+        let (a0, a1) = (core.regs[10], core.regs[11]);
+        // a0, and a1 unless the call is a legacy one
+        let (error, value) = match (core.regs[17], core.regs[16]) {
+            (LEGACY_SET_TIMER, _) => {
+                self.set_timer(core, a0 as u64 | (a1 as u64) << 32);
+                (SUCCESS, None)
+            }
+            (LEGACY_PUTCHAR, _) => {
+                let _ = console.stdout(&[a0 as u8]);
+                (SUCCESS, None)
+            }
+            // -1 unless a console input script has sent something
+            (LEGACY_GETCHAR, _) => (console.getchar().map_or(u32::MAX, |c| c as u32), None),
+            (LEGACY_SHUTDOWN, _) => return Some(0),
+            (BASE, 0) => (SUCCESS, Some(SPEC_VERSION)),
+            (BASE, 1) => (SUCCESS, Some(IMPL_ID)),
+            (BASE, 2) => (SUCCESS, Some(1)),
+            (BASE, 3) => (SUCCESS, Some(EXTENSIONS.contains(&a0) as u32)),
+            (BASE, 4) => (SUCCESS, Some(core.identity.vendor)),
+            (BASE, 5) => (SUCCESS, Some(core.identity.arch)),
+            (BASE, 6) => (SUCCESS, Some(core.identity.implementation)),
+            (TIME, 0) => {
+                self.set_timer(core, a0 as u64 | (a1 as u64) << 32);
+                (SUCCESS, Some(0))
+            }
+            // the mask is relative to the base hart, -1 for all of them
+            (IPI, 0) => {
+                let bit = core.hart_id.wrapping_sub(a1);
+                if a1 == u32::MAX || (bit < 32 && a0 >> bit & 1 != 0) {
+                    core.mip |= SSI;
+                }
+                (SUCCESS, Some(0))
+            }
+            // shutdown, cold or warm reboot all end the run
+            (SRST, 0) if a0 <= 2 => return Some((a1 == SYSTEM_FAILURE) as i32),
+            (SRST, 0) => (INVALID_PARAM, Some(0)),
+            _ => (NOT_SUPPORTED, Some(0)),
+        };
+        core.regs.write(10, error);
+        if let Some(value) = value {
+            core.regs.write(11, value);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csr_file::{status, MIE, SCAUSE, STVEC};
+    use crate::test_utils::machine;
+    use crate::Engine;
+
+    fn set(core: &mut CoreState, regs: &[(usize, u32)]) {
+        for &(reg, value) in regs {
+            core.regs.write(reg, value);
+        }
+    }
+
+    #[test]
+    fn calls_from_s_mode() {
+        let mut core = machine(0, &[ECALL], &[(17, BASE), (16, 3), (10, SRST)]);
+        let mut console = Semihosting::new(String::new());
+        console.quiet = true;
+        console.console = Some(Vec::new());
+        let mut sbi = Sbi::new();
+        // M-mode ecalls trap as usual
+        assert!(!Sbi::is_call(&core));
+        core.privilege = Privilege::Supervisor;
+        assert!(Sbi::is_call(&core));
+        assert_eq!(sbi.call(&mut core, &mut console), None);
+        assert_eq!((core.regs[10], core.regs[11]), (SUCCESS, 1));
+
+        for (a7, a0) in [(LEGACY_PUTCHAR, b'k' as u32), (0x4442_434E, 0)] {
+            set(&mut core, &[(17, a7), (10, a0)]);
+            sbi.call(&mut core, &mut console);
+        }
+        assert_eq!((core.regs[10], console.console.as_deref()), (NOT_SUPPORTED, Some(&b"k"[..])));
+
+        // the timer fires at the deadline and set_timer clears it
+        set(&mut core, &[(17, TIME), (16, 0), (10, 10), (11, 0)]);
+        sbi.call(&mut core, &mut console);
+        core.cycles = 9;
+        sbi.tick(&mut core);
+        assert_eq!(core.mip & STI, 0);
+        core.cycles = 10;
+        sbi.tick(&mut core);
+        assert_eq!(core.mip & STI, STI);
+        sbi.call(&mut core, &mut console);
+        assert_eq!(core.mip & STI, 0);
+
+        set(&mut core, &[(17, SRST), (10, 0), (11, SYSTEM_FAILURE)]);
+        assert_eq!(sbi.call(&mut core, &mut console), Some(1));
+    }
+
+    #[test]
+    fn the_kernel_takes_its_interrupts() {
+        let mut core = machine(0, &[], &[]);
+        Sbi::delegate(&mut core);
+        core.csrs.set(STVEC, 0x40);
+        core.csrs.write(MIE, STI);
+        core.privilege = Privilege::Supervisor;
+        core.csrs.set_status(status::SIE, true);
+        core.mip = STI;
+        core.dispatch(Engine::Step);
+        assert_eq!((core.pc, core.privilege, core.csrs.get(SCAUSE)),
+                   (0x40, Privilege::Supervisor, 1 << 31 | 5));
+    }
+}
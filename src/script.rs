@@ -0,0 +1,337 @@
+use std::cell::RefCell;
+use std::fs;
+use std::rc::Rc;
+
+use rhai::{Dynamic, Engine, EvalAltResult, FnPtr, AST};
+
+use crate::CoreState;
+
+/// Hook script in Rhai. The top-level code registers the hooks and runs once
+/// at load:
+///
+/// ```text
+/// // comment
+/// on_pc(0x0000_0040, || { dump(0x1000, 4); regs(); });
+/// every(1000, || print("tick"));
+/// on_access(0x0ffc, || { set_reg("a0", 1); stop(); });
+/// on_pc(0x0000_0100, || { set_reg("a0", 0x80); skip(); });
+/// ```
+///
+/// Triggers are checked before every instruction, `on_access` fires on the
+/// step after a load/store touched the address. Hooks read and write the
+/// registers, pc and memory (`reg`, `set_reg`, `pc`, `set_pc`, `read_word`,
+/// `write_word`, `read_byte`, `write_byte`) and can register more hooks.
+/// `skip` steps over the instruction at pc without executing it, so with
+/// `set_reg` it patches out a hardware access: the last hook above makes the
+/// load at 0x100 read 0x80. Host code can patch instructions too,
+/// `Script::default()` has no hooks.
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    hooks: Vec<Hook>,
+    // hooks the script registered since the last step
+    registered: Rc<RefCell<Vec<Hook>>>,
+    machine: Rc<RefCell<Machine>>,
+    steps: u64,
+}
+
+struct Hook {
+    trigger: Trigger,
+    action: Action,
+}
+
+enum Trigger {
+    Pc(u32),
+    Every(u64),
+    Access(u32),
+}
+
+enum Action {
+    Call(FnPtr),
+    /// Host code replacing the instruction, see `Script::patch`
+    Patch(Box<dyn FnMut(&mut CoreState)>),
+}
+
+/// The core as hooks see it while they run: the registers, pc and the memory
+/// moved out of the core
+#[derive(Default)]
+struct Machine {
+    regs: [u32; 32],
+    pc: u32,
+    memory: Vec<u8>,
+    // stores of the hooks, the core drops the code cached there
+    written: Vec<(u32, u32)>,
+    steps: u64,
+    stop: bool,
+}
+
+impl Machine {
+    fn enter(&mut self, core: &mut CoreState, steps: u64) {
+        self.regs.iter_mut().zip(core.regs.iter()).for_each(|(reg, &value)| *reg = value);
+        self.pc = core.pc;
+        self.memory = std::mem::take(&mut core.memory);
+        self.steps = steps;
+        self.stop = false;
+    }
+
+    /// Hands the state back to the core, returns false if a hook stopped the
+    /// run
+    fn leave(&mut self, core: &mut CoreState) -> bool {
+        for (i, &value) in self.regs.iter().enumerate() {
+            core.regs.write(i, value);
+        }
+        core.pc = self.pc;
+        core.memory = std::mem::take(&mut self.memory);
+        for (address, len) in self.written.drain(..) {
+            core.invalidate_code(address, len);
+        }
+        !self.stop
+    }
+
+    fn bytes(&mut self, address: i64, len: usize) -> Result<&mut [u8], Box<EvalAltResult>> {
+        let start = address as u32 as usize;
+        self.memory.get_mut(start..start + len)
+            .ok_or_else(|| format!("0x{:08x} is out of memory", address as u32).into())
+    }
+}
+
+pub(crate) fn parse_number(text: &str) -> Result<u32, String> {
+    let text = text.replace('_', "");
+    let value = match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => text.parse::<u32>(),
+    };
+    value.map_err(|_| format!("bad number `{}`", text))
+}
+
+pub(crate) fn parse_reg(text: &str) -> Result<usize, String> {
+    if let Some(index) = text.strip_prefix('x') {
+        if let Ok(index @ 0..=31) = index.parse::<usize>() {
+            return Ok(index);
+        }
+    }
+    (0..32)
+        .find(|&i| CoreState::reg_name(i) == text)
+        .ok_or(format!("bad register `{}`", text))
+}
+
+fn reg(name: &str) -> Result<usize, Box<EvalAltResult>> {
+    parse_reg(name).map_err(Into::into)
+}
+
+impl Default for Script {
+    fn default() -> Self {
+        Self::parse("").unwrap()
+    }
+}
+
+impl Script {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Self::parse(&source).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    fn parse(source: &str) -> Result<Self, String> {
+        let registered = Rc::new(RefCell::new(Vec::new()));
+        let machine = Rc::new(RefCell::new(Machine::default()));
+        let engine = Self::engine(&registered, &machine);
+        let ast = engine.compile(source).map_err(|e| e.to_string())?;
+        engine.run_ast(&ast).map_err(|e| e.to_string())?;
+        let hooks = registered.take();
+        Ok(Self {engine, ast, hooks, registered, machine, steps: 0})
+    }
+
+    /// The engine with the hook and machine functions on `registered` and
+    /// `machine`
+    fn engine(registered: &Rc<RefCell<Vec<Hook>>>, machine: &Rc<RefCell<Machine>>) -> Engine {
+        let mut engine = Engine::new();
+        let hooks = registered.clone();
+        engine.register_fn("on_pc", move |address: i64, callback: FnPtr| {
+            hooks.borrow_mut().push(Hook {trigger: Trigger::Pc(address as u32), action: Action::Call(callback)});
+        });
+        let hooks = registered.clone();
+        engine.register_fn("every", move |n: i64, callback: FnPtr| -> Result<(), Box<EvalAltResult>> {
+            if n <= 0 {
+                return Err("`every` needs a positive count".into());
+            }
+            hooks.borrow_mut().push(Hook {trigger: Trigger::Every(n as u64), action: Action::Call(callback)});
+            Ok(())
+        });
+        let hooks = registered.clone();
+        engine.register_fn("on_access", move |address: i64, callback: FnPtr| {
+            hooks.borrow_mut().push(Hook {trigger: Trigger::Access(address as u32), action: Action::Call(callback)});
+        });
+
+        let m = machine.clone();
+        engine.register_fn("reg", move |name: &str| -> Result<i64, Box<EvalAltResult>> {
+            Ok(m.borrow().regs[reg(name)?] as i64)
+        });
+        let m = machine.clone();
+        engine.register_fn("set_reg", move |name: &str, value: i64| -> Result<(), Box<EvalAltResult>> {
+            let index = reg(name)?;
+            if index != 0 {
+                m.borrow_mut().regs[index] = value as u32;
+            }
+            Ok(())
+        });
+        let m = machine.clone();
+        engine.register_fn("pc", move || m.borrow().pc as i64);
+        let m = machine.clone();
+        engine.register_fn("set_pc", move |pc: i64| m.borrow_mut().pc = pc as u32);
+        let m = machine.clone();
+        engine.register_fn("skip", move || {
+            let mut machine = m.borrow_mut();
+            machine.pc = machine.pc.wrapping_add(4);
+        });
+        let m = machine.clone();
+        engine.register_fn("stop", move || m.borrow_mut().stop = true);
+        let m = machine.clone();
+        engine.register_fn("steps", move || m.borrow().steps as i64);
+
+        let m = machine.clone();
+        engine.register_fn("read_word", move |address: i64| -> Result<i64, Box<EvalAltResult>> {
+            let mut machine = m.borrow_mut();
+            Ok(u32::from_le_bytes(machine.bytes(address, 4)?.try_into().unwrap()) as i64)
+        });
+        let m = machine.clone();
+        engine.register_fn("read_byte", move |address: i64| -> Result<i64, Box<EvalAltResult>> {
+            Ok(m.borrow_mut().bytes(address, 1)?[0] as i64)
+        });
+        let m = machine.clone();
+        engine.register_fn("write_word", move |address: i64, value: i64| -> Result<(), Box<EvalAltResult>> {
+            let mut machine = m.borrow_mut();
+            machine.bytes(address, 4)?.copy_from_slice(&(value as u32).to_le_bytes());
+            machine.written.push((address as u32, 4));
+            Ok(())
+        });
+        let m = machine.clone();
+        engine.register_fn("write_byte", move |address: i64, value: i64| -> Result<(), Box<EvalAltResult>> {
+            let mut machine = m.borrow_mut();
+            machine.bytes(address, 1)?[0] = value as u8;
+            machine.written.push((address as u32, 1));
+            Ok(())
+        });
+
+        let m = machine.clone();
+        engine.register_fn("dump", move |address: i64, words: i64| {
+            let mut machine = m.borrow_mut();
+            for i in 0..words.max(0) {
+                let address = (address as u32).wrapping_add(4 * i as u32);
+                match machine.bytes(address as i64, 4) {
+                    Ok(bytes) => println!("0x{:08x}: 0x{:08x}", address, u32::from_le_bytes(bytes.try_into().unwrap())),
+                    Err(_) => println!("0x{:08x}: out of memory", address),
+                }
+            }
+        });
+        let m = machine.clone();
+        engine.register_fn("regs", move || {
+            for (i, reg) in m.borrow().regs.iter().enumerate() {
+                let new_line = {if i % 4 == 3 {'\n'} else {' '}};
+                print!("{:>5}: 0x{:08x}{}", CoreState::reg_name(i), reg, new_line);
+            }
+        });
+        engine
+    }
+
+    /// Runs `callback` instead of the instruction at `pc`, then goes on with
+    /// the next one unless the callback moved pc itself
+    pub fn patch(&mut self, pc: u32, callback: impl FnMut(&mut CoreState) + 'static) {
+        self.hooks.push(Hook {trigger: Trigger::Pc(pc), action: Action::Patch(Box::new(callback))});
+    }
+
+    /// Called before each instruction, returns false if a hook stopped the
+    /// run or failed
+    pub fn step(&mut self, core: &mut CoreState) -> bool {
+        let mut running = true;
+        let mut checked = None;
+        // a hook moving pc skipped the instruction there, the pc hooks of the
+        // one it moved to run before that executes
+        while running && checked != Some(core.pc) {
+            let first = checked.is_none();
+            let start = core.pc;
+            checked = Some(start);
+            for hook in &mut self.hooks {
+                let fire = match hook.trigger {
+                    Trigger::Pc(pc) => start == pc,
+                    Trigger::Every(n) => first && self.steps > 0 && self.steps.is_multiple_of(n),
+                    Trigger::Access(address) => first && core.last_access
+                        .is_some_and(|(start, size)| (start..start + size).contains(&address)),
+                };
+                if !fire {
+                    continue;
+                }
+                match &mut hook.action {
+                    Action::Call(callback) => {
+                        self.machine.borrow_mut().enter(core, self.steps);
+                        let result = callback.call::<Dynamic>(&self.engine, &self.ast, ());
+                        running &= self.machine.borrow_mut().leave(core);
+                        if let Err(e) = result {
+                            eprintln!("script: {}", e);
+                            running = false;
+                        }
+                    }
+                    Action::Patch(callback) => {
+                        let pc = core.pc;
+                        callback(core);
+                        if core.pc == pc {
+                            core.pc = pc.wrapping_add(4);
+                        }
+                    }
+                }
+            }
+            self.hooks.append(&mut self.registered.borrow_mut());
+        }
+        self.steps += 1;
+        running
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{self, LOAD, OP_IMM};
+    use crate::test_utils::{machine, word};
+
+    #[test]
+    fn patches_replace_instructions() {
+        // lw a0, 0(a1) from a missing device; addi a2, a2, 1; addi a2, a2, 1;
+        // addi a3, a3, 1
+        let program = [encode::i(LOAD, 0b010, 10, 11, 0), encode::i(OP_IMM, 0b000, 12, 12, 1),
+                       encode::i(OP_IMM, 0b000, 12, 12, 1), encode::i(OP_IMM, 0b000, 13, 13, 1)];
+        let mut core = machine(0, &program, &[(11, 0x4000_0000)]);
+        let mut script = Script::parse(r#"on_pc(0, || { set_reg("a0", 0x80); skip(); });"#).unwrap();
+        // a host stub jumping over the second addi, whose hooks run in the
+        // same step
+        script.patch(4, |core| {
+            core.regs.write(13, 7);
+            core.pc = 12;
+        });
+        assert!(script.step(&mut core));
+        assert_eq!((core.pc, core.regs[10], core.regs[13]), (12, 0x80, 7));
+        core.execute();
+        assert_eq!((core.pc, core.regs[12], core.regs[13]), (16, 0, 8));
+        assert!(Script::parse("on_pc(0, || skip()").is_err() && Script::parse("every(0, || stop());").is_err());
+    }
+
+    #[test]
+    fn hooks_see_memory_and_register_hooks() {
+        // addi a0, a0, 1 three times
+        let addi = encode::i(OP_IMM, 0b000, 10, 10, 1);
+        let mut core = machine(0, &[addi, addi, addi], &[]);
+        let mut script = Script::parse(r#"
+            let count = 0;
+            every(1, || {
+                count += 1;
+                write_word(0x10, read_word(0x10) + reg("a0"));
+                if count == 1 { on_pc(pc() + 4, || stop()); }
+            });"#).unwrap();
+        while script.step(&mut core) {
+            core.execute();
+        }
+        // stopped before the third addi by the hook the first call added
+        assert_eq!((core.pc, core.regs[10], word(&core, 0x10)), (8, 2, 1 + 2));
+        // an error stops the run too
+        let mut script = Script::parse("on_pc(8, || write_word(0xffff_fff0, 1));").unwrap();
+        assert!(!script.step(&mut core));
+    }
+}
@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::input::InputScript;
+use crate::CoreState;
+
+// slli x0, x0, 0x1f; ebreak; srai x0, x0, 7
+const ENTRY_NOP: u32 = 0x01f0_1013;
+const EBREAK: u32 = 0x0010_0073;
+const EXIT_NOP: u32 = 0x4070_5013;
+
+const SYS_OPEN: u32 = 0x01;
+const SYS_CLOSE: u32 = 0x02;
+const SYS_WRITEC: u32 = 0x03;
+const SYS_WRITE0: u32 = 0x04;
+const SYS_WRITE: u32 = 0x05;
+const SYS_READ: u32 = 0x06;
+const SYS_READC: u32 = 0x07;
+const SYS_ISERROR: u32 = 0x08;
+const SYS_ISTTY: u32 = 0x09;
+const SYS_SEEK: u32 = 0x0A;
+const SYS_FLEN: u32 = 0x0C;
+const SYS_REMOVE: u32 = 0x0E;
+const SYS_CLOCK: u32 = 0x10;
+const SYS_TIME: u32 = 0x11;
+const SYS_ERRNO: u32 = 0x13;
+const SYS_GET_CMDLINE: u32 = 0x15;
+const SYS_HEAPINFO: u32 = 0x16;
+const SYS_EXIT: u32 = 0x18;
+const SYS_EXIT_EXTENDED: u32 = 0x20;
+
+const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x20026;
+
+const STDIN: u32 = 1;
+const STDOUT: u32 = 2;
+const STDERR: u32 = 3;
+
+/// Host side of the RISC-V semihosting protocol (ARM semihosting calls
+/// behind the `slli; ebreak; srai` sequence, op in a0, argument in a1)
+pub struct Semihosting {
+    files: HashMap<u32, File>,
+    next_handle: u32,
+    errno: i32,
+    cmdline: String,
+    start: Instant,
+    /// Copy of the stdout output, kept when Some
+    pub console: Option<Vec<u8>>,
+    /// Drop stdout output instead of printing it
+    pub quiet: bool,
+    /// Console input instead of stdin, when Some
+    pub input: Option<InputScript>,
+}
+
+impl Semihosting {
+    pub fn new(cmdline: String) -> Self {
+        Self {
+            files: HashMap::new(),
+            next_handle: STDERR + 1,
+            errno: 0,
+            cmdline,
+            start: Instant::now(),
+            console: None,
+            quiet: false,
+            input: None,
+        }
+    }
+
+    fn word(core: &CoreState, address: u32) -> Option<u32> {
+        let start = address as usize;
+        core.memory.get(start..start + 4).map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    /// Whether pc points at the ebreak of a semihosting sequence
+    pub fn is_call(core: &CoreState) -> bool {
+        Self::word(core, core.pc) == Some(EBREAK) &&
+            Self::word(core, core.pc.wrapping_sub(4)) == Some(ENTRY_NOP) &&
+            Self::word(core, core.pc.wrapping_add(4)) == Some(EXIT_NOP)
+    }
+
+    fn bytes(core: &CoreState, address: u32, len: u32) -> Option<&[u8]> {
+        let start = address as usize;
+        core.memory.get(start..start + len as usize)
+    }
+
+    /// Reads `N` argument words from the parameter block
+    fn params<const N: usize>(core: &CoreState, block: u32) -> Option<[u32; N]> {
+        let mut params = [0; N];
+        for (i, param) in params.iter_mut().enumerate() {
+            *param = Self::word(core, block.wrapping_add(4 * i as u32))?;
+        }
+        Some(params)
+    }
+
+    fn failed(&mut self, e: io::Error) -> u32 {
+        self.errno = e.raw_os_error().unwrap_or(5);
+        u32::MAX
+    }
+
+    fn open(&mut self, path: &str, mode: u32) -> u32 {
+        if path == ":tt" {
+            return match mode {
+                0..=3 => STDIN,
+                4..=7 => STDOUT,
+                _ => STDERR,
+            };
+        }
+        // fopen modes r, rb, r+, r+b, w, wb, w+, w+b, a, ab, a+, a+b
+        let mut options = OpenOptions::new();
+        let update = mode & 2 != 0;
+        match mode >> 2 {
+            0 => options.read(true).write(update),
+            1 => options.write(true).read(update).create(true).truncate(true),
+            _ => options.append(true).read(update).create(true),
+        };
+        match options.open(path) {
+            Ok(file) => {
+                let handle = self.next_handle;
+                self.next_handle += 1;
+                self.files.insert(handle, file);
+                handle
+            }
+            Err(e) => self.failed(e),
+        }
+    }
+
+    /// Console output, also used by the other host interfaces
+    pub(crate) fn stdout(&mut self, data: &[u8]) -> io::Result<()> {
+        if let Some(console) = self.console.as_mut() {
+            console.extend_from_slice(data);
+        }
+        if let Some(input) = self.input.as_mut() {
+            input.output(data);
+        }
+        match self.quiet {
+            true => Ok(()),
+            false => io::stdout().write_all(data),
+        }
+    }
+
+    /// A byte of scripted console input, None without a script or while
+    /// it holds input back
+    pub(crate) fn getchar(&mut self) -> Option<u8> {
+        self.input.as_mut().and_then(InputScript::getchar)
+    }
+
+    fn write(&mut self, handle: u32, data: &[u8]) -> io::Result<()> {
+        match handle {
+            STDOUT => self.stdout(data),
+            STDERR => io::stderr().write_all(data),
+            _ => self.files.get_mut(&handle).ok_or(io::ErrorKind::InvalidInput)?.write_all(data),
+        }
+    }
+
+    fn read(&mut self, handle: u32, buffer: &mut [u8]) -> io::Result<usize> {
+        match handle {
+            STDIN => match self.input.as_mut() {
+                Some(input) => Ok(input.read(buffer)),
+                None => io::stdin().read(buffer),
+            }
+            _ => self.files.get_mut(&handle).ok_or(io::ErrorKind::InvalidInput)?.read(buffer),
+        }
+    }
+
+    /// Services the call at pc, returns the exit code if the guest exited
+    pub fn call(&mut self, core: &mut CoreState) -> Option<i32> {
+        let op = core.regs[10];
+        let arg = core.regs[11];
+
+        let result = match op {
+            SYS_OPEN => match Self::params::<3>(core, arg) {
+                Some([path, mode, len]) => match Self::bytes(core, path, len) {
+                    Some(path) => {
+                        let path = String::from_utf8_lossy(path).into_owned();
+                        self.open(&path, mode)
+                    }
+                    None => u32::MAX,
+                }
+                None => u32::MAX,
+            }
+            SYS_CLOSE => match Self::params::<1>(core, arg) {
+                Some([STDIN | STDOUT | STDERR]) => 0,
+                Some([handle]) if self.files.remove(&handle).is_some() => 0,
+                _ => u32::MAX,
+            }
+            SYS_WRITEC => {
+                let c = Self::bytes(core, arg, 1).map(|c| c.to_vec()).unwrap_or_default();
+                let _ = self.write(STDOUT, &c);
+                0
+            }
+            SYS_WRITE0 => {
+                let text = core.memory.get(arg as usize..).unwrap_or_default();
+                let len = text.iter().position(|&b| b == 0).unwrap_or(text.len());
+                let text = text[..len].to_vec();
+                let _ = self.write(STDOUT, &text);
+                0
+            }
+            // WRITE and READ return the number of bytes *not* transferred
+            SYS_WRITE => match Self::params::<3>(core, arg) {
+                Some([handle, buffer, len]) => match Self::bytes(core, buffer, len) {
+                    Some(data) => {
+                        let data = data.to_vec();
+                        match self.write(handle, &data) {
+                            Ok(()) => 0,
+                            Err(e) => {
+                                self.failed(e);
+                                len
+                            }
+                        }
+                    }
+                    None => len,
+                }
+                None => u32::MAX,
+            }
+            SYS_READ => match Self::params::<3>(core, arg) {
+                Some([handle, buffer, len]) => {
+                    let mut data = vec![0; len as usize];
+                    match self.read(handle, &mut data) {
+                        Ok(n) => {
+                            let start = buffer as usize;
+                            match core.memory.get_mut(start..start + n) {
+                                Some(target) => {
+                                    target.copy_from_slice(&data[..n]);
+                                    core.invalidate_code(buffer, n as u32);
+                                    len - n as u32
+                                }
+                                None => len,
+                            }
+                        }
+                        Err(e) => {
+                            self.failed(e);
+                            len
+                        }
+                    }
+                }
+                None => u32::MAX,
+            }
+            SYS_READC => match self.input.as_mut() {
+                Some(input) => input.getchar().map_or(u32::MAX, |c| c as u32),
+                None => {
+                    let mut c = [0];
+                    match io::stdin().read(&mut c) {
+                        Ok(1) => c[0] as u32,
+                        _ => u32::MAX,
+                    }
+                }
+            }
+            SYS_ISERROR => match Self::params::<1>(core, arg) {
+                Some([status]) => ((status as i32) < 0) as u32,
+                None => 0,
+            }
+            SYS_ISTTY => match Self::params::<1>(core, arg) {
+                Some([STDIN | STDOUT | STDERR]) => 1,
+                _ => 0,
+            }
+            SYS_SEEK => match Self::params::<2>(core, arg) {
+                Some([handle, position]) => match self.files.get_mut(&handle) {
+                    Some(file) => match file.seek(SeekFrom::Start(position as u64)) {
+                        Ok(_) => 0,
+                        Err(e) => self.failed(e),
+                    }
+                    None => u32::MAX,
+                }
+                None => u32::MAX,
+            }
+            SYS_FLEN => match Self::params::<1>(core, arg) {
+                Some([handle]) => match self.files.get(&handle).map(|f| f.metadata()) {
+                    Some(Ok(metadata)) => metadata.len() as u32,
+                    Some(Err(e)) => self.failed(e),
+                    None => u32::MAX,
+                }
+                None => u32::MAX,
+            }
+            SYS_REMOVE => match Self::params::<2>(core, arg) {
+                Some([path, len]) => match Self::bytes(core, path, len) {
+                    Some(path) => match fs::remove_file(String::from_utf8_lossy(path).as_ref()) {
+                        Ok(()) => 0,
+                        Err(e) => self.failed(e),
+                    }
+                    None => u32::MAX,
+                }
+                None => u32::MAX,
+            }
+            SYS_CLOCK => (self.start.elapsed().as_millis() / 10) as u32,
+            SYS_TIME => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|t| t.as_secs() as u32)
+                .unwrap_or(0),
+            SYS_ERRNO => self.errno as u32,
+            SYS_GET_CMDLINE => match Self::params::<2>(core, arg) {
+                Some([buffer, len]) if (self.cmdline.len() as u32) < len => {
+                    let start = buffer as usize;
+                    let cmdline = [self.cmdline.as_bytes(), &[0]].concat();
+                    match core.memory.get_mut(start..start + cmdline.len()) {
+                        Some(target) => {
+                            target.copy_from_slice(&cmdline);
+                            core.invalidate_code(buffer, cmdline.len() as u32);
+                            let len_address = arg as usize + 4;
+                            core.memory[len_address..len_address + 4]
+                                .copy_from_slice(&(self.cmdline.len() as u32).to_le_bytes());
+                            core.invalidate_code(arg.wrapping_add(4), 4);
+                            0
+                        }
+                        None => u32::MAX,
+                    }
+                }
+                _ => u32::MAX,
+            }
+            SYS_HEAPINFO => {
+                // zeroes tell the C runtime to use its linker-provided defaults
+                if let Some(block) = Self::word(core, arg) {
+                    let start = block as usize;
+                    if let Some(target) = core.memory.get_mut(start..start + 16) {
+                        target.fill(0);
+                        core.invalidate_code(block, 16);
+                    }
+                }
+                0
+            }
+            SYS_EXIT => return Some(if arg == ADP_STOPPED_APPLICATION_EXIT {0} else {1}),
+            SYS_EXIT_EXTENDED => return match Self::params::<2>(core, arg) {
+                Some([ADP_STOPPED_APPLICATION_EXIT, code]) => Some(code as i32),
+                _ => Some(1),
+            },
+            _ => {
+                eprintln!("unimplemented semihosting call 0x{:x} at 0x{:08x}", op, core.pc);
+                u32::MAX
+            }
+        };
+        core.regs.write(10, result);
+        None
+    }
+}
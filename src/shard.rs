@@ -0,0 +1,211 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+
+use crate::json::{self, Value};
+
+/// Slice `index` (1-based) of `count` of a suite, tests are dealt round-robin
+/// in their sorted order so every shard sees the same list
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) struct Shard {
+    index: usize,
+    count: usize,
+}
+
+impl Default for Shard {
+    fn default() -> Self {
+        Self {index: 1, count: 1}
+    }
+}
+
+impl Shard {
+    /// `3/8`
+    pub(crate) fn parse(text: &str) -> Result<Self, String> {
+        let (index, count) = text.split_once('/').ok_or(format!("bad shard `{}`, expected e.g. 3/8", text))?;
+        match (index.parse(), count.parse()) {
+            (Ok(index), Ok(count)) if (1..=count).contains(&index) => Ok(Self {index, count}),
+            _ => Err(format!("bad shard `{}`, expected i/n with 1 <= i <= n", text)),
+        }
+    }
+
+    /// Whether the `i`th test of the suite belongs to this shard
+    pub(crate) fn contains(&self, i: usize) -> bool {
+        i % self.count == self.index - 1
+    }
+}
+
+/// Result of one test: `error` is why it failed
+pub(crate) struct Outcome {
+    pub(crate) name: String,
+    pub(crate) extension: String,
+    pub(crate) error: Option<String>,
+}
+
+/// Writes the shard's outcomes as JSON for `merge`
+pub(crate) fn write_results(path: &str, suite: &str, shard: Shard, outcomes: &[Outcome]) -> Result<(), String> {
+    let tests = outcomes.iter().map(|outcome| {
+        let mut test = vec![("name", outcome.name.as_str().into()), ("extension", outcome.extension.as_str().into()),
+                            ("passed", outcome.error.is_none().into())];
+        if let Some(error) = &outcome.error {
+            test.push(("error", error.as_str().into()));
+        }
+        Value::object(&test)
+    }).collect();
+    let results = Value::object(&[("suite", suite.into()), ("shard", (shard.index as u32).into()),
+                                  ("shards", (shard.count as u32).into()), ("tests", Value::Array(tests))]);
+    fs::write(path, format!("{}\n", results)).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// Self-contained summary page: pass/fail per suite and extension, then the
+/// failures
+fn html(matrix: &BTreeMap<(String, String), (usize, usize)>, failures: &[(String, Outcome)],
+        missing: &[String]) -> String {
+    let mut page = "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>rs-v test summary</title>\n\
+                    <style>body{font-family:sans-serif}table{border-collapse:collapse}\
+                    td,th{border:1px solid #999;padding:2px 8px;text-align:right}td:first-child,\
+                    td:nth-child(2){text-align:left}.fail{background:#f4c7c3}.pass{background:#c8e6c9}</style>\n\
+                    </head><body>\n<h1>rs-v test summary</h1>\n".to_string();
+    for shard in missing {
+        page += &format!("<p class=\"fail\">missing shard {}</p>\n", escape(shard));
+    }
+    page += "<table>\n<tr><th>Suite</th><th>Extension</th><th>Passed</th><th>Failed</th><th>Total</th></tr>\n";
+    for ((suite, extension), (passed, failed)) in matrix {
+        let class = if *failed > 0 {"fail"} else {"pass"};
+        page += &format!("<tr class=\"{}\"><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n", class,
+                         escape(suite), escape(extension), passed, failed, passed + failed);
+    }
+    page += "</table>\n";
+    if !failures.is_empty() {
+        page += "<h2>Failures</h2>\n<ul>\n";
+        for (suite, outcome) in failures {
+            page += &format!("<li>{} <code>{}</code>: {}</li>\n", escape(suite), escape(&outcome.name),
+                             escape(outcome.error.as_deref().unwrap_or("")));
+        }
+        page += "</ul>\n";
+    }
+    page + "</body></html>\n"
+}
+
+fn parse_results(path: &str, text: &str) -> Result<(String, Shard, Vec<Outcome>), String> {
+    let err = |what: &str| format!("{}: {}", path, what);
+    let results = json::parse(text).map_err(|e| err(&e))?;
+    let suite = results.get("suite").and_then(Value::as_str).ok_or(err("missing `suite`"))?;
+    let number = |key| results.get(key).and_then(Value::as_u32).ok_or(err(&format!("missing `{}`", key)));
+    let shard = Shard::parse(&format!("{}/{}", number("shard")?, number("shards")?)).map_err(|e| err(&e))?;
+    let Some(Value::Array(tests)) = results.get("tests") else {
+        return Err(err("missing `tests`"));
+    };
+    let outcomes = tests.iter().map(|test| {
+        let text = |key| test.get(key).and_then(Value::as_str).map(str::to_string);
+        let passed = test.get("passed") == Some(&Value::Bool(true));
+        Ok(Outcome {
+            name: text("name").ok_or(err("test without `name`"))?,
+            extension: text("extension").unwrap_or_default(),
+            error: if passed {None} else {Some(text("error").unwrap_or_else(|| "failed".to_string()))},
+        })
+    }).collect::<Result<_, String>>()?;
+    Ok((suite.to_string(), shard, outcomes))
+}
+
+/// `rs-v merge [--html summary.html] results.json...`: combines the results
+/// `act` and `torture` wrote with `--results`, one file per shard, and prints
+/// the pass/fail matrix per suite and extension. Fails if a test failed or a
+/// shard of a suite is missing.
+pub fn merge(args: &[String]) -> Result<i32, String> {
+    let mut html_path = None;
+    let mut paths = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--html" => html_path = Some(args.next().ok_or("--html needs a file")?.clone()),
+            _ if arg.starts_with("--") => return Err(format!("unknown merge argument `{}`", arg)),
+            _ => paths.push(arg.clone()),
+        }
+    }
+    if paths.is_empty() {
+        return Err("no results files".to_string());
+    }
+
+    // suite to (shard count, shards seen)
+    let mut shards: BTreeMap<String, (usize, BTreeSet<usize>)> = BTreeMap::new();
+    let mut matrix: BTreeMap<(String, String), (usize, usize)> = BTreeMap::new();
+    let mut failures = Vec::new();
+    for path in &paths {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let (suite, shard, outcomes) = parse_results(path, &text)?;
+        let (count, seen) = shards.entry(suite.clone()).or_insert((shard.count, BTreeSet::new()));
+        if *count != shard.count {
+            return Err(format!("{}: {} has {} shards here, {} elsewhere", path, suite, shard.count, count));
+        }
+        if !seen.insert(shard.index) {
+            return Err(format!("{}: shard {}/{} of {} given twice", path, shard.index, shard.count, suite));
+        }
+        for outcome in outcomes {
+            let entry = matrix.entry((suite.clone(), outcome.extension.clone())).or_default();
+            match outcome.error {
+                None => entry.0 += 1,
+                Some(_) => {
+                    entry.1 += 1;
+                    failures.push((suite.clone(), outcome));
+                }
+            }
+        }
+    }
+    let missing: Vec<String> = shards.iter()
+        .flat_map(|(suite, (count, seen))| {
+            (1..=*count).filter(|i| !seen.contains(i)).map(move |i| format!("{}/{} of {}", i, count, suite))
+        })
+        .collect();
+
+    for shard in &missing {
+        println!("missing shard {}", shard);
+    }
+    for (suite, outcome) in &failures {
+        println!("{} {}: {}", suite, outcome.name, outcome.error.as_deref().unwrap_or(""));
+    }
+    println!("| Suite | Extension | Passed | Failed | Total |\n|-------|-----------|--------|--------|-------|");
+    for ((suite, extension), (passed, failed)) in &matrix {
+        println!("| {} | {} | {} | {} | {} |", suite, extension, passed, failed, passed + failed);
+    }
+    if let Some(path) = html_path {
+        fs::write(&path, html(&matrix, &failures, &missing)).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    Ok((!failures.is_empty() || !missing.is_empty()) as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shards_partition_and_merge() {
+        let shards: Vec<Shard> = (1..=3).map(|i| Shard::parse(&format!("{}/3", i)).unwrap()).collect();
+        for i in 0..10 {
+            assert_eq!(shards.iter().filter(|shard| shard.contains(i)).count(), 1);
+        }
+        assert!(Shard::parse("0/3").is_err() && Shard::parse("4/3").is_err() && Shard::parse("3").is_err());
+
+        let dir = std::env::temp_dir();
+        let path = |i| dir.join(format!("rs-v-shard-{}.json", i)).to_string_lossy().into_owned();
+        let outcome = |name: &str, extension: &str, error: Option<&str>| Outcome {
+            name: name.to_string(), extension: extension.to_string(), error: error.map(str::to_string),
+        };
+        write_results(&path(1), "act", shards[0], &[outcome("add-01.elf", "I", None),
+                                                   outcome("mul-01.elf", "M", Some("word 3: <x>"))]).unwrap();
+        write_results(&path(2), "act", shards[1], &[outcome("sub-01.elf", "I", None)]).unwrap();
+        let (suite, shard, outcomes) = parse_results(&path(1), &fs::read_to_string(path(1)).unwrap()).unwrap();
+        assert_eq!((suite.as_str(), shard, outcomes.len()), ("act", shards[0], 2));
+        assert_eq!(outcomes[1].error.as_deref(), Some("word 3: <x>"));
+
+        let html_path = path(0).replace(".json", ".html");
+        assert_eq!(merge(&["--html".to_string(), html_path.clone(), path(1), path(2)]), Ok(1));
+        let page = fs::read_to_string(&html_path).unwrap();
+        assert!(page.contains("missing shard 3/3 of act"));
+        assert!(page.contains("<td>act</td><td>I</td><td>2</td><td>0</td><td>2</td>"));
+        assert!(page.contains("<code>mul-01.elf</code>: word 3: &lt;x&gt;"));
+        assert!(merge(&[path(1), path(1)]).is_err());
+    }
+}
@@ -0,0 +1,68 @@
+//! Host signals for the run loop: handlers only set a flag, the loop polls it
+//! at an instruction boundary
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static PROGRESS: AtomicBool = AtomicBool::new(false);
+static INTERRUPT: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+mod sys {
+    extern "C" {
+        pub fn signal(signum: i32, handler: usize) -> usize;
+    }
+
+    pub const SIG_DFL: usize = 0;
+    pub const SIGINT: i32 = 2;
+    #[cfg(target_os = "linux")]
+    pub const SIGUSR1: i32 = 10;
+    #[cfg(not(target_os = "linux"))]
+    pub const SIGUSR1: i32 = 30;
+    /// Ctrl+T on the BSDs and macOS, Linux has no SIGINFO
+    #[cfg(not(target_os = "linux"))]
+    pub const SIGINFO: i32 = 29;
+}
+
+#[cfg(unix)]
+extern "C" fn on_progress(_signum: i32) {
+    PROGRESS.store(true, Ordering::Relaxed);
+}
+
+/// A second Ctrl-C before the loop gets to the first kills the process, for
+/// a guest stuck inside one step (a blocking console read)
+#[cfg(unix)]
+extern "C" fn on_interrupt(_signum: i32) {
+    INTERRUPT.store(true, Ordering::Relaxed);
+    unsafe {
+        sys::signal(sys::SIGINT, sys::SIG_DFL);
+    }
+}
+
+/// Makes SIGUSR1 (and Ctrl+T where there is SIGINFO) request a progress
+/// report instead of killing the process; a no-op off Unix
+pub(crate) fn catch_progress() {
+    #[cfg(unix)]
+    unsafe {
+        sys::signal(sys::SIGUSR1, on_progress as extern "C" fn(i32) as usize);
+        #[cfg(not(target_os = "linux"))]
+        sys::signal(sys::SIGINFO, on_progress as extern "C" fn(i32) as usize);
+    }
+}
+
+/// Makes the next Ctrl-C stop the run instead of killing the process
+pub(crate) fn catch_interrupt() {
+    #[cfg(unix)]
+    unsafe {
+        sys::signal(sys::SIGINT, on_interrupt as extern "C" fn(i32) as usize);
+    }
+}
+
+/// Whether a progress report was requested since the last call
+pub(crate) fn progress_requested() -> bool {
+    PROGRESS.swap(false, Ordering::Relaxed)
+}
+
+/// Whether Ctrl-C was pressed since the last call
+pub(crate) fn interrupted() -> bool {
+    INTERRUPT.swap(false, Ordering::Relaxed)
+}
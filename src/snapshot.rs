@@ -0,0 +1,315 @@
+use std::fs;
+
+use crate::json::{self, Value};
+use crate::{loader, Config, CoreState, Csr, Engine, Platform, RUN_MEMORY_SIZE};
+
+/// First line of a snapshot file, a JSON header line and the raw memory
+/// follow
+const MAGIC: &str = "rs-v snapshot 1";
+/// Differing memory ranges closer than this are reported as one
+const GAP: usize = 16;
+/// Memory ranges listed, the rest are only counted
+const MAX_RANGES: usize = 16;
+/// Hex dump rows shown per range, from the row before it
+const MAX_ROWS: usize = 8;
+
+/// Machine CSRs saved, by name and address
+const CSRS: [(&str, u16); 21] = [
+    ("mstatus", 0x300), ("misa", 0x301), ("mie", 0x304), ("mtvec", 0x305), ("mscratch", 0x340),
+    ("mepc", 0x341), ("mcause", 0x342), ("mtval", 0x343), ("mip", 0x344), ("sepc", 0x141),
+    ("mcountinhibit", 0x320), ("mseccfg", 0x747), ("medeleg", 0x302), ("mideleg", 0x303),
+    ("mcounteren", 0x306), ("stvec", 0x105), ("scounteren", 0x106), ("sscratch", 0x140),
+    ("scause", 0x142), ("stval", 0x143), ("satp", 0x180),
+];
+
+/// Saved machine state: pc and registers, CSRs, device registers and
+/// counters, and all of memory
+#[derive(PartialEq, Debug)]
+pub struct Snapshot {
+    pub pc: u32,
+    pub regs: [u32; 32],
+    /// Name and value, in a fixed order
+    pub csrs: Vec<(String, u64)>,
+    pub devices: Vec<(String, u64)>,
+    pub memory: Vec<u8>,
+}
+
+impl Snapshot {
+    pub(crate) fn capture(core: &CoreState, platform: &Platform) -> Self {
+        let mut snapshot = Self::bare(core);
+        snapshot.devices = vec![("cycles".to_string(), core.cycles), ("retired".to_string(), platform.retired)];
+        for (name, address, size) in platform.devices() {
+            let bytes = core.memory.get(address as usize..(address + size) as usize).unwrap_or_default();
+            let value = bytes.iter().rev().fold(0, |value, &byte| value << 8 | byte as u64);
+            snapshot.devices.push((name.to_string(), value));
+        }
+        snapshot
+    }
+
+    /// The state of a core run without a platform, e.g. by a campaign
+    pub(crate) fn bare(core: &CoreState) -> Self {
+        let mut csrs: Vec<(String, u64)> = CSRS.iter()
+            .map(|&(name, address)| (name.to_string(), core.get_csr_value(&Csr::get_csr(address).unwrap()) as u64))
+            .collect();
+        csrs.push(("mcycle".to_string(), core.counters.cycle(core.cycles)));
+        csrs.push(("minstret".to_string(), core.counters.instret()));
+        csrs.push(("stimecmp".to_string(), core.stimecmp));
+        csrs.push(("privilege".to_string(), core.privilege as u64));
+        for n in 0..core.pmp.entries() as u8 / 4 {
+            csrs.push((format!("pmpcfg{}", n), core.get_csr_value(&Csr::PmpCfg(n)) as u64));
+        }
+        for i in 0..core.pmp.entries() as u8 {
+            csrs.push((format!("pmpaddr{}", i), core.get_csr_value(&Csr::PmpAddr(i)) as u64));
+        }
+        for n in 3..32 {
+            csrs.push((format!("mhpmevent{}", n), core.get_csr_value(&Csr::MHpmEvent(n)) as u64));
+            csrs.push((format!("mhpmcounter{}", n), core.hpm_counter(n)));
+        }
+        let devices = vec![("cycles".to_string(), core.cycles), ("retired".to_string(), core.counters.retired)];
+        Self {pc: core.pc, regs: core.regs.iter().copied().collect::<Vec<u32>>().try_into().unwrap(), csrs, devices,
+              memory: core.memory.clone()}
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let members = |values: &[(String, u64)]| {
+            Value::Object(values.iter().map(|(name, value)| (name.clone(), (*value).into())).collect())
+        };
+        let header = Value::object(&[
+            ("pc", self.pc.into()),
+            ("x", Value::Array(self.regs.iter().map(|&reg| reg.into()).collect())),
+            ("csrs", members(&self.csrs)),
+            ("devices", members(&self.devices)),
+            ("memory", (self.memory.len() as u64).into()),
+        ]);
+        let mut bytes = format!("{}\n{}\n", MAGIC, header).into_bytes();
+        bytes.extend(&self.memory);
+        bytes
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        let rest = bytes.strip_prefix(format!("{}\n", MAGIC).as_bytes()).ok_or("not an rs-v snapshot")?;
+        let end = rest.iter().position(|&byte| byte == b'\n').ok_or("truncated snapshot header")?;
+        let header = json::parse(std::str::from_utf8(&rest[..end]).map_err(|e| e.to_string())?)?;
+        let members = |key: &str| match header.get(key) {
+            Some(Value::Object(members)) => members.iter()
+                .map(|(name, value)| match value {
+                    Value::Number(n) => Ok((name.clone(), *n as u64)),
+                    _ => Err(format!("bad `{}` value {}", key, name)),
+                })
+                .collect(),
+            _ => Err(format!("missing `{}`", key)),
+        };
+        let regs: Vec<u32> = match header.get("x") {
+            Some(Value::Array(x)) => x.iter().filter_map(Value::as_u32).collect(),
+            _ => Vec::new(),
+        };
+        let memory = rest[end + 1..].to_vec();
+        if header.get("memory") != Some(&Value::Number(memory.len() as f64)) {
+            return Err("memory size doesn't match the header".to_string());
+        }
+        Ok(Self {
+            pc: header.get("pc").and_then(Value::as_u32).ok_or("missing `pc`")?,
+            regs: regs.try_into().map_err(|_| "`x` must be 32 registers")?,
+            csrs: members("csrs")?,
+            devices: members("devices")?,
+            memory,
+        })
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let bytes = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        Self::parse(&bytes).map_err(|e| format!("{}: {}", path, e))
+    }
+}
+
+/// Differing named values of two sections, `a -> b`
+fn diff_values(title: &str, a: &[(String, u64)], b: &[(String, u64)]) -> String {
+    let mut report = String::new();
+    for (name, value) in a {
+        match b.iter().find(|(other, _)| other == name) {
+            Some((_, other)) if other == value => {}
+            Some((_, other)) => report += &format!("  {:<14} 0x{:08x} -> 0x{:08x}\n", name, value, other),
+            None => report += &format!("  {:<14} 0x{:08x} -> (missing)\n", name, value),
+        }
+    }
+    for (name, value) in b.iter().filter(|(name, _)| !a.iter().any(|(other, _)| other == name)) {
+        report += &format!("  {:<14} (missing) -> 0x{:08x}\n", name, value);
+    }
+    match report.is_empty() {
+        true => report,
+        false => format!("{}:\n{}", title, report),
+    }
+}
+
+/// Ranges of differing bytes in the common part of `a` and `b`
+fn ranges(a: &[u8], b: &[u8]) -> Vec<std::ops::Range<usize>> {
+    let mut ranges: Vec<std::ops::Range<usize>> = Vec::new();
+    for i in (0..a.len().min(b.len())).filter(|&i| a[i] != b[i]) {
+        match ranges.last_mut() {
+            Some(range) if i - range.end < GAP => range.end = i + 1,
+            _ => ranges.push(i..i + 1),
+        }
+    }
+    ranges
+}
+
+fn row(label: &str, memory: &[u8], start: usize, other: &[u8]) -> String {
+    let bytes: Vec<String> = (start..start + 16).map(|i| match (memory.get(i), other.get(i)) {
+        (Some(byte), Some(other)) if byte != other => format!("{:02x}*", byte),
+        (Some(byte), _) => format!("{:02x} ", byte),
+        (None, _) => "   ".to_string(),
+    }).collect();
+    format!("    {} 0x{:08x}  {}\n", label, start, bytes.concat().trim_end())
+}
+
+/// Report of everything differing between `a` and `b`, empty if nothing does
+pub fn diff(a: &Snapshot, b: &Snapshot) -> String {
+    let mut report = String::new();
+    let mut registers = String::new();
+    if a.pc != b.pc {
+        registers += &format!("  {:<14} 0x{:08x} -> 0x{:08x}\n", "pc", a.pc, b.pc);
+    }
+    for i in (0..32).filter(|&i| a.regs[i] != b.regs[i]) {
+        let name = format!("{} (x{})", CoreState::reg_name(i), i);
+        registers += &format!("  {:<14} 0x{:08x} -> 0x{:08x}\n", name, a.regs[i], b.regs[i]);
+    }
+    if !registers.is_empty() {
+        report += &format!("registers:\n{}", registers);
+    }
+    report += &diff_values("csrs", &a.csrs, &b.csrs);
+    report += &diff_values("devices", &a.devices, &b.devices);
+
+    let ranges = ranges(&a.memory, &b.memory);
+    if a.memory.len() != b.memory.len() {
+        report += &format!("memory size 0x{:x} -> 0x{:x}, comparing the first 0x{:x} bytes\n", a.memory.len(),
+                           b.memory.len(), a.memory.len().min(b.memory.len()));
+    }
+    if !ranges.is_empty() {
+        let bytes: usize = ranges.iter()
+            .map(|range| (range.start..range.end).filter(|&i| a.memory[i] != b.memory[i]).count())
+            .sum();
+        report += &format!("memory: {} bytes differ in {} ranges\n", bytes, ranges.len());
+    }
+    for range in ranges.iter().take(MAX_RANGES) {
+        report += &format!("  0x{:08x}..0x{:08x} ({} bytes)\n", range.start, range.end, range.end - range.start);
+        // a row of context on each side
+        let first = (range.start / 16).saturating_sub(1) * 16;
+        let last = (range.end.div_ceil(16) * 16 + 16).min(a.memory.len().min(b.memory.len()).div_ceil(16) * 16);
+        let rows: Vec<usize> = (first..last).step_by(16).collect();
+        for &start in rows.iter().take(MAX_ROWS) {
+            match a.memory[start..].iter().zip(&b.memory[start..]).take(16).all(|(x, y)| x == y) {
+                true => report += &row(" ", &a.memory, start, &b.memory),
+                false => {
+                    report += &row("a", &a.memory, start, &b.memory);
+                    report += &row("b", &b.memory, start, &a.memory);
+                }
+            }
+        }
+        if rows.len() > MAX_ROWS {
+            report += &format!("    ... {} more rows\n", rows.len() - MAX_ROWS);
+        }
+    }
+    if ranges.len() > MAX_RANGES {
+        report += &format!("  ... {} more ranges\n", ranges.len() - MAX_RANGES);
+    }
+    report
+}
+
+/// `rs-v diff-state a.snap b.snap`: prints the registers, CSRs, device
+/// registers and counters that differ between two snapshots, then the
+/// differing memory ranges as hex dumps with a row of context, differing
+/// bytes marked with `*`. Exits 1 if anything differs.
+pub fn diff_state(args: &[String]) -> Result<i32, String> {
+    let [a, b] = args else {
+        return Err("usage: diff-state a.snap b.snap".to_string());
+    };
+    let report = diff(&Snapshot::load(a)?, &Snapshot::load(b)?);
+    if report.is_empty() {
+        println!("{} and {} are identical", a, b);
+        return Ok(0);
+    }
+    print!("--- {}\n+++ {}\n{}", a, b, report);
+    Ok(1)
+}
+
+/// `rs-v snapshot [--steps n] out.snap program [args]`: runs a bare-metal
+/// program until it exits (or for `n` instructions) and saves the machine
+/// state to `out.snap` for `diff-state`. Returns the guest exit code, 0 if
+/// stopped by `--steps`.
+pub fn run(args: &[String], config: Config) -> Result<i32, String> {
+    let mut steps = u64::MAX;
+    let mut args = args.iter();
+    let mut rest = Vec::new();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--steps" => steps = value()?.parse().map_err(|_| "bad --steps")?,
+            _ => {
+                rest.push(arg.clone());
+                rest.extend(args.cloned());
+                break;
+            }
+        }
+    }
+    let [out, program @ ..] = &rest[..] else {
+        return Err("missing snapshot file".to_string());
+    };
+    let path = program.first().ok_or("missing program")?;
+    let mut core = CoreState::new(config.memory_size.unwrap_or(RUN_MEMORY_SIZE));
+    let image = loader::load_segments(&mut core, path)?;
+    core.pc = image.entry;
+    core.lenient = config.lenient;
+    core.timing = config.timing;
+    let mut platform = Platform::new(program, &image, &mut core);
+    // blocks would run past the step count
+    let engine = if steps == u64::MAX {config.engine} else {Engine::Step};
+    let mut code = 0;
+    while platform.retired < steps {
+        if let Some(exit) = platform.step(&mut core, engine) {
+            code = exit;
+            break;
+        }
+    }
+    let snapshot = Snapshot::capture(&core, &platform);
+    fs::write(out, snapshot.to_bytes()).map_err(|e| format!("{}: {}", out, e))?;
+    Ok(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{self, OP_IMM};
+    use crate::test_utils::machine;
+
+    #[test]
+    fn snapshots_round_trip_and_diff() {
+        let mut core = machine(0, &[encode::i(OP_IMM, 0b000, 10, 0, 5), encode::csr(0b001, 0, 10, 0x340)], &[]);
+        let platform = Platform {semihosting: crate::semihosting::Semihosting::new(String::new()), htif: None,
+                                 retired: 0, host_ecalls: false, sbi: None};
+        let a = Snapshot::capture(&core, &platform);
+        assert_eq!(Snapshot::parse(&a.to_bytes()), Ok(Snapshot::capture(&core, &platform)));
+        assert!(Snapshot::parse(b"rs-v snapshot 1\n{}\n").is_err());
+        assert_eq!(diff(&a, &a), "");
+
+        core.execute();
+        core.execute();
+        core.memory[0x105] = 0xEE;
+        let b = Snapshot::capture(&core, &platform);
+        assert_eq!(diff(&a, &b), "\
+            registers:\n\
+            \x20 pc             0x00000000 -> 0x00000008\n\
+            \x20 a0 (x10)       0x00000000 -> 0x00000005\n\
+            csrs:\n\
+            \x20 mscratch       0x00000000 -> 0x00000005\n\
+            \x20 mcycle         0x00000000 -> 0x00000002\n\
+            \x20 minstret       0x00000000 -> 0x00000002\n\
+            devices:\n\
+            \x20 cycles         0x00000000 -> 0x00000002\n\
+            memory: 1 bytes differ in 1 ranges\n\
+            \x20 0x00000105..0x00000106 (1 bytes)\n\
+            \x20   \x20 0x000000f0  00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\n\
+            \x20   a 0x00000100  00 00 00 00 00 00*00 00 00 00 00 00 00 00 00 00\n\
+            \x20   b 0x00000100  00 00 00 00 00 ee*00 00 00 00 00 00 00 00 00 00\n\
+            \x20   \x20 0x00000110  00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\n");
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+use crate::csr_file::{MCAUSE, MEPC, MTVEC};
+use crate::coverage::mnemonic;
+use crate::{CoreState, Instruction, Observer};
+
+/// Executed-instruction statistics: counts per mnemonic, branch outcomes,
+/// load/store sizes and traps by cause. The report is written to `path` when
+/// the statistics are dropped, as JSON if the path ends in `.json`.
+pub struct Stats {
+    path: String,
+    mnemonics: BTreeMap<String, u64>,
+    taken: u64,
+    not_taken: u64,
+    // size in bytes to count
+    loads: BTreeMap<u32, u64>,
+    stores: BTreeMap<u32, u64>,
+    traps: BTreeMap<u32, u64>,
+    // pc of the previous instruction and whether it was a branch, its outcome
+    // shows in the state seen by the next step
+    last: Option<(u32, bool)>,
+}
+
+impl Stats {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            mnemonics: BTreeMap::new(),
+            taken: 0,
+            not_taken: 0,
+            loads: BTreeMap::new(),
+            stores: BTreeMap::new(),
+            traps: BTreeMap::new(),
+            last: None,
+        }
+    }
+
+    /// Counts the outcome of the instruction at `pc`: a trap leaves pc at the
+    /// mtvec base with mepc pointing back at it
+    fn retire(&mut self, core: &CoreState, pc: u32, branch: bool) {
+        if core.pc == core.csrs.get(MTVEC) & !0b11 && core.csrs.get(MEPC) == pc && core.pc != pc.wrapping_add(4) {
+            *self.traps.entry(core.csrs.get(MCAUSE)).or_insert(0) += 1;
+        } else if branch && core.pc != pc.wrapping_add(4) {
+            self.taken += 1;
+        } else if branch {
+            self.not_taken += 1;
+        }
+    }
+
+    pub fn report(&self) -> String {
+        let total: u64 = self.mnemonics.values().sum();
+        let mut report = format!("instructions: {}\n", total);
+        let mut by_count: Vec<_> = self.mnemonics.iter().collect();
+        by_count.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (name, count) in by_count {
+            report += &format!("  {:<8} {:>12} {:5.1}%\n", name, count, 100.0 * *count as f64 / total as f64);
+        }
+        report += &format!("branches: {} taken, {} not taken\n", self.taken, self.not_taken);
+        for (name, sizes) in [("loads", &self.loads), ("stores", &self.stores)] {
+            let sizes: Vec<String> = sizes.iter().map(|(size, count)| format!("{}B {}", size, count)).collect();
+            report += &format!("{}: {}\n", name, sizes.join(", "));
+        }
+        report += "traps:\n";
+        for (cause, count) in &self.traps {
+            report += &format!("  cause {:<2} {}\n", cause, count);
+        }
+        report
+    }
+
+    pub fn json(&self) -> String {
+        fn object<K: ToString>(entries: impl Iterator<Item = (K, u64)>) -> String {
+            let entries: Vec<String> = entries.map(|(k, v)| format!("\"{}\": {}", k.to_string(), v)).collect();
+            format!("{{{}}}", entries.join(", "))
+        }
+        format!(
+            "{{\"instructions\": {}, \"mnemonics\": {}, \"branches\": {{\"taken\": {}, \"not_taken\": {}}}, \
+             \"loads\": {}, \"stores\": {}, \"traps\": {}}}\n",
+            self.mnemonics.values().sum::<u64>(),
+            object(self.mnemonics.iter().map(|(k, &v)| (k, v))),
+            self.taken,
+            self.not_taken,
+            object(self.loads.iter().map(|(k, &v)| (k, v))),
+            object(self.stores.iter().map(|(k, &v)| (k, v))),
+            object(self.traps.iter().map(|(k, &v)| (k, v))),
+        )
+    }
+}
+
+impl Observer for Stats {
+    fn step(&mut self, core: &CoreState) {
+        if let Some((pc, branch)) = self.last.take() {
+            self.retire(core, pc, branch);
+        }
+        let instruction = match core.memory.get(core.pc as usize..core.pc as usize + 4) {
+            Some(bytes) => CoreState::decode_with(u32::from_le_bytes(bytes.try_into().unwrap()), core.lenient),
+            None => return,
+        };
+        let Ok(instruction) = instruction else {
+            self.last = Some((core.pc, false));
+            return;
+        };
+        *self.mnemonics.entry(mnemonic(&instruction)).or_insert(0) += 1;
+        let access = match instruction {
+            Instruction::Lb(_) | Instruction::Lbu(_) => Some((&mut self.loads, 1)),
+            Instruction::Lh(_) | Instruction::Lhu(_) => Some((&mut self.loads, 2)),
+            Instruction::Lw(_) => Some((&mut self.loads, 4)),
+            Instruction::Sb(_) => Some((&mut self.stores, 1)),
+            Instruction::Sh(_) => Some((&mut self.stores, 2)),
+            Instruction::Sw(_) => Some((&mut self.stores, 4)),
+            _ => None,
+        };
+        if let Some((sizes, size)) = access {
+            *sizes.entry(size).or_insert(0) += 1;
+        }
+        let branch = matches!(instruction, Instruction::Beq(_) | Instruction::Bne(_) | Instruction::Blt(_) |
+                                           Instruction::Bge(_) | Instruction::Bltu(_) | Instruction::Bgeu(_));
+        self.last = Some((core.pc, branch));
+    }
+}
+
+impl Drop for Stats {
+    fn drop(&mut self) {
+        let report = if self.path.ends_with(".json") {self.json()} else {self.report()};
+        if let Err(e) = fs::write(&self.path, report) {
+            eprintln!("{}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{self, ECALL, LOAD};
+    use crate::test_utils::machine;
+
+    #[test]
+    fn counts_branches_accesses_and_traps() {
+        let program = [
+            encode::b(0b000, 0, 0, 8),
+            0,
+            encode::b(0b001, 0, 0, 8),
+            encode::i(LOAD, 0b100, 1, 0, 0),
+            encode::s(0b010, 0, 1, 0x40),
+            ECALL,
+        ];
+        let mut core = machine(0, &program, &[]);
+        core.csrs.set(MTVEC, 0x80);
+        let mut stats = Stats::new("/dev/null");
+        for _ in 0..5 {
+            stats.step(&core);
+            core.execute();
+        }
+        stats.step(&core);
+        let report = stats.report();
+        assert!(report.starts_with("instructions: 5\n"), "{}", report);
+        assert!(report.contains("branches: 1 taken, 1 not taken\n"));
+        assert!(report.contains("loads: 1B 1\nstores: 4B 1\n"));
+        assert!(report.contains("  cause 11 1\n"));
+        assert!(stats.json().contains("\"branches\": {\"taken\": 1, \"not_taken\": 1}"));
+    }
+}
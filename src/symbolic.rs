@@ -0,0 +1,546 @@
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use crate::difftest::Rng;
+use crate::script::{parse_number, parse_reg};
+use crate::{load_bare_metal, Config, CoreState, Engine, Instruction, Platform};
+
+/// Runs explored before giving up
+const PATHS: usize = 256;
+/// Symbolic branches of a run that may be forked, later ones are followed
+/// concretely
+const DEPTH: usize = 64;
+const STEPS: u64 = 1 << 20;
+/// Random assignments the solver tries after the guided ones
+const ATTEMPTS: usize = 256;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Op {
+    Add,
+    Sub,
+    Sll,
+    Slt,
+    Sltu,
+    Xor,
+    Srl,
+    Sra,
+    Or,
+    And,
+    Eq,
+}
+
+fn apply(op: Op, a: u32, b: u32) -> u32 {
+    match op {
+        Op::Add => a.wrapping_add(b),
+        Op::Sub => a.wrapping_sub(b),
+        Op::Sll => a << (b & 31),
+        Op::Slt => ((a as i32) < (b as i32)) as u32,
+        Op::Sltu => (a < b) as u32,
+        Op::Xor => a ^ b,
+        Op::Srl => a >> (b & 31),
+        Op::Sra => ((a as i32) >> (b & 31)) as u32,
+        Op::Or => a | b,
+        Op::And => a & b,
+        Op::Eq => (a == b) as u32,
+    }
+}
+
+#[derive(Debug)]
+enum Expr {
+    Const(u32),
+    /// Index into the symbolic inputs
+    Input(usize),
+    Binary(Op, Rc<Expr>, Rc<Expr>),
+}
+
+impl Expr {
+    fn binary(op: Op, a: Rc<Expr>, b: Rc<Expr>) -> Rc<Expr> {
+        match (&*a, &*b) {
+            (Expr::Const(a), Expr::Const(b)) => Rc::new(Expr::Const(apply(op, *a, *b))),
+            _ => Rc::new(Expr::Binary(op, a, b)),
+        }
+    }
+
+    fn constant(value: u32) -> Rc<Expr> {
+        Rc::new(Expr::Const(value))
+    }
+
+    fn eval(&self, values: &[u32]) -> u32 {
+        match self {
+            Expr::Const(value) => *value,
+            Expr::Input(i) => values[*i],
+            Expr::Binary(op, a, b) => apply(*op, a.eval(values), b.eval(values)),
+        }
+    }
+
+    fn symbolic(&self) -> bool {
+        !matches!(self, Expr::Const(_))
+    }
+
+    /// Inputs and constants the expression uses
+    fn leaves(&self, inputs: &mut HashSet<usize>, constants: &mut HashSet<u32>) {
+        match self {
+            Expr::Const(value) => {
+                constants.insert(*value);
+            }
+            Expr::Input(i) => {
+                inputs.insert(*i);
+            }
+            Expr::Binary(_, a, b) => {
+                a.leaves(inputs, constants);
+                b.leaves(inputs, constants);
+            }
+        }
+    }
+
+    /// Input assignments that make the expression evaluate to `target` when
+    /// it is an invertible chain (add, sub and xor of constants, shifts, or
+    /// of disjoint parts as when bytes are assembled into a word)
+    fn invert(&self, target: u32, assignments: &mut Vec<(usize, u32)>) {
+        let constant = |e: &Expr| match e {
+            Expr::Const(value) => Some(*value),
+            _ => None,
+        };
+        match self {
+            Expr::Const(_) => {}
+            Expr::Input(i) => assignments.push((*i, target)),
+            Expr::Binary(op, a, b) => match (op, constant(a), constant(b)) {
+                (Op::Add, _, Some(c)) => a.invert(target.wrapping_sub(c), assignments),
+                (Op::Add, Some(c), _) => b.invert(target.wrapping_sub(c), assignments),
+                (Op::Sub, _, Some(c)) => a.invert(target.wrapping_add(c), assignments),
+                (Op::Sub, Some(c), _) => b.invert(c.wrapping_sub(target), assignments),
+                (Op::Xor, _, Some(c)) => a.invert(target ^ c, assignments),
+                (Op::Xor, Some(c), _) => b.invert(target ^ c, assignments),
+                (Op::Sll, _, Some(c)) => a.invert(target >> (c & 31), assignments),
+                (Op::Srl | Op::Sra, _, Some(c)) => a.invert(target << (c & 31), assignments),
+                (Op::And, _, Some(_)) => a.invert(target, assignments),
+                (Op::Or, _, _) => {
+                    a.invert(target, assignments);
+                    b.invert(target, assignments);
+                }
+                _ => {}
+            },
+        }
+    }
+}
+
+/// A symbolic branch: `condition` evaluated to nonzero or not on this path
+struct Constraint {
+    pc: u32,
+    condition: Rc<Expr>,
+    holds: bool,
+}
+
+impl Constraint {
+    fn check(&self, values: &[u32]) -> bool {
+        (self.condition.eval(values) != 0) == self.holds
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Input {
+    Register(usize),
+    Byte(u32),
+}
+
+impl Input {
+    fn name(&self) -> String {
+        match self {
+            Input::Register(reg) => CoreState::reg_name(*reg),
+            Input::Byte(address) => format!("[0x{:08x}]", address),
+        }
+    }
+
+    fn limit(&self) -> u32 {
+        match self {
+            Input::Register(_) => u32::MAX,
+            Input::Byte(_) => 0xff,
+        }
+    }
+}
+
+/// Symbolic shadow of the registers and memory bytes, updated before each
+/// instruction executes concretely. Values the subset doesn't model (CSRs,
+/// ecall results, loads through symbolic addresses) become concrete.
+struct Shadow {
+    registers: Vec<Option<Rc<Expr>>>,
+    memory: HashMap<u32, Rc<Expr>>,
+    constraints: Vec<Constraint>,
+}
+
+impl Shadow {
+    fn new(inputs: &[Input]) -> Self {
+        let mut shadow = Self {registers: vec![None; 32], memory: HashMap::new(), constraints: Vec::new()};
+        for (i, input) in inputs.iter().enumerate() {
+            let input_expr = Rc::new(Expr::Input(i));
+            match *input {
+                Input::Register(reg) => shadow.registers[reg] = Some(input_expr),
+                Input::Byte(address) => {
+                    shadow.memory.insert(address, input_expr);
+                }
+            }
+        }
+        shadow
+    }
+
+    fn operand(&self, core: &CoreState, reg: usize) -> Rc<Expr> {
+        self.registers[reg].clone().unwrap_or_else(|| Expr::constant(core.regs[reg]))
+    }
+
+    fn set(&mut self, rd: usize, value: Option<Rc<Expr>>) {
+        if rd != 0 {
+            self.registers[rd] = value.filter(|value| value.symbolic());
+        }
+    }
+
+    fn alu(&mut self, core: &CoreState, op: Op, rd: usize, rs1: usize, b: Rc<Expr>) {
+        if self.registers[rs1].is_none() && !b.symbolic() {
+            return self.set(rd, None);
+        }
+        self.set(rd, Some(Expr::binary(op, self.operand(core, rs1), b)));
+    }
+
+    fn step(&mut self, core: &CoreState) {
+        let Some(bytes) = core.memory.get(core.pc as usize..core.pc as usize + 4) else {
+            return;
+        };
+        let Ok(instruction) = CoreState::decode_with(u32::from_le_bytes(bytes.try_into().unwrap()), true) else {
+            return;
+        };
+        let regs = &core.regs;
+        match instruction {
+            Instruction::Lui(a) | Instruction::Auipc(a) | Instruction::Jal(a) => self.set(a.rd, None),
+            Instruction::Jalr(a) => self.set(a.rd, None),
+            Instruction::Beq(a) | Instruction::Bne(a) | Instruction::Blt(a) |
+            Instruction::Bge(a) | Instruction::Bltu(a) | Instruction::Bgeu(a) => {
+                if self.registers[a.rs1].is_none() && self.registers[a.rs2].is_none() {
+                    return;
+                }
+                let op = match instruction {
+                    Instruction::Beq(_) | Instruction::Bne(_) => Op::Eq,
+                    Instruction::Blt(_) | Instruction::Bge(_) => Op::Slt,
+                    _ => Op::Sltu,
+                };
+                self.constraints.push(Constraint {
+                    pc: core.pc,
+                    condition: Expr::binary(op, self.operand(core, a.rs1), self.operand(core, a.rs2)),
+                    holds: apply(op, regs[a.rs1], regs[a.rs2]) != 0,
+                });
+            }
+            Instruction::Lb(a) | Instruction::Lh(a) | Instruction::Lw(a) | Instruction::Lbu(a) | Instruction::Lhu(a) => {
+                let size = match instruction {
+                    Instruction::Lw(_) => 4,
+                    Instruction::Lh(_) | Instruction::Lhu(_) => 2,
+                    _ => 1,
+                };
+                let address = regs[a.rs1].wrapping_add(a.imm as u32);
+                if (0..size).all(|i| !self.memory.contains_key(&address.wrapping_add(i))) {
+                    return self.set(a.rd, None);
+                }
+                let mut value = Expr::constant(0);
+                for i in 0..size {
+                    let byte = address.wrapping_add(i);
+                    let byte = self.memory.get(&byte).cloned()
+                        .unwrap_or_else(|| Expr::constant(core.memory.get(byte as usize).copied().unwrap_or(0) as u32));
+                    value = Expr::binary(Op::Or, value, Expr::binary(Op::Sll, byte, Expr::constant(8 * i)));
+                }
+                if let Instruction::Lb(_) | Instruction::Lh(_) = instruction {
+                    let shift = Expr::constant(32 - 8 * size);
+                    value = Expr::binary(Op::Sra, Expr::binary(Op::Sll, value, shift.clone()), shift);
+                }
+                self.set(a.rd, Some(value));
+            }
+            Instruction::Sb(a) | Instruction::Sh(a) | Instruction::Sw(a) => {
+                let size = match instruction {
+                    Instruction::Sw(_) => 4,
+                    Instruction::Sh(_) => 2,
+                    _ => 1,
+                };
+                let address = regs[a.rs1].wrapping_add(a.imm as u32);
+                for i in 0..size {
+                    match &self.registers[a.rs2] {
+                        Some(value) => {
+                            let byte = Expr::binary(Op::Srl, value.clone(), Expr::constant(8 * i));
+                            self.memory.insert(address.wrapping_add(i), Expr::binary(Op::And, byte, Expr::constant(0xff)));
+                        }
+                        None => {
+                            self.memory.remove(&address.wrapping_add(i));
+                        }
+                    }
+                }
+            }
+            Instruction::Addi(a) | Instruction::Slti(a) | Instruction::Sltiu(a) | Instruction::Xori(a) |
+            Instruction::Ori(a) | Instruction::Andi(a) => {
+                let op = match instruction {
+                    Instruction::Addi(_) => Op::Add,
+                    Instruction::Slti(_) => Op::Slt,
+                    Instruction::Sltiu(_) => Op::Sltu,
+                    Instruction::Xori(_) => Op::Xor,
+                    Instruction::Ori(_) => Op::Or,
+                    _ => Op::And,
+                };
+                self.alu(core, op, a.rd, a.rs1, Expr::constant(a.imm as u32));
+            }
+            Instruction::Slli(a) | Instruction::Srli(a) | Instruction::Srai(a) => {
+                let op = match instruction {
+                    Instruction::Slli(_) => Op::Sll,
+                    Instruction::Srli(_) => Op::Srl,
+                    _ => Op::Sra,
+                };
+                self.alu(core, op, a.rd, a.rs1, Expr::constant(a.shamt as u32));
+            }
+            Instruction::Add(a) | Instruction::Sub(a) | Instruction::Sll(a) | Instruction::Slt(a) |
+            Instruction::Sltu(a) | Instruction::Xor(a) | Instruction::Srl(a) | Instruction::Sra(a) |
+            Instruction::Or(a) | Instruction::And(a) => {
+                // x ^ x and x - x don't depend on x
+                if a.rs1 == a.rs2 && matches!(instruction, Instruction::Xor(_) | Instruction::Sub(_)) {
+                    return self.set(a.rd, None);
+                }
+                let op = match instruction {
+                    Instruction::Add(_) => Op::Add,
+                    Instruction::Sub(_) => Op::Sub,
+                    Instruction::Sll(_) => Op::Sll,
+                    Instruction::Slt(_) => Op::Slt,
+                    Instruction::Sltu(_) => Op::Sltu,
+                    Instruction::Xor(_) => Op::Xor,
+                    Instruction::Srl(_) => Op::Srl,
+                    Instruction::Sra(_) => Op::Sra,
+                    Instruction::Or(_) => Op::Or,
+                    _ => Op::And,
+                };
+                let b = self.operand(core, a.rs2);
+                self.alu(core, op, a.rd, a.rs1, b);
+            }
+            Instruction::Csrrw(a) | Instruction::Csrrs(a) | Instruction::Csrrc(a) |
+            Instruction::Csrrwi(a) | Instruction::Csrrsi(a) | Instruction::Csrrci(a) => self.set(a.rd, None),
+            // semihosting and the HTIF proxy return results in a0 and a1
+            Instruction::Ecall | Instruction::Ebreak => {
+                self.set(10, None);
+                self.set(11, None);
+            }
+            Instruction::Fence | Instruction::FenceI | Instruction::FenceTso | Instruction::Pause |
+            Instruction::Mret | Instruction::Sret | Instruction::Wfi | Instruction::SfenceVma(_) => {}
+        }
+    }
+}
+
+/// Input values satisfying all `constraints`, searched from `start`, which
+/// satisfies all but the last: guided by inverting the last condition, then
+/// the constants it compares against, then at random
+fn solve(constraints: &[Constraint], start: &[u32], limits: &[u32], rng: &mut Rng) -> Option<Vec<u32>> {
+    let check = |values: &[u32]| constraints.iter().all(|constraint| constraint.check(values));
+    let last = constraints.last()?;
+    let mut tries: Vec<Vec<(usize, u32)>> = Vec::new();
+    if let Expr::Binary(op, a, b) = &*last.condition {
+        let (left, right) = (a.eval(start), b.eval(start));
+        // equal for ==, just below or at the bound for < and >=
+        let offsets: &[u32] = if *op == Op::Eq {&[0, 1]} else {&[0, 1, u32::MAX]};
+        for &offset in offsets {
+            for (side, other) in [(a, right), (b, left)] {
+                let mut assignments = Vec::new();
+                side.invert(other.wrapping_add(offset), &mut assignments);
+                tries.push(assignments);
+            }
+        }
+    }
+    let (mut inputs, mut constants) = (HashSet::new(), HashSet::new());
+    last.condition.leaves(&mut inputs, &mut constants);
+    let mut inputs: Vec<usize> = inputs.into_iter().collect();
+    inputs.sort();
+    let mut constants: Vec<u32> = constants.into_iter().collect();
+    constants.sort();
+    for &input in &inputs {
+        for &constant in &constants {
+            for value in [constant, constant.wrapping_add(1), constant.wrapping_sub(1), !constant] {
+                tries.push(vec![(input, value)]);
+            }
+        }
+    }
+    for _ in 0..ATTEMPTS {
+        tries.push(inputs.iter().map(|&input| (input, rng.next())).collect());
+    }
+    tries.into_iter().find_map(|assignments| {
+        let mut values = start.to_vec();
+        for (input, value) in assignments {
+            values[input] = value & limits[input];
+        }
+        check(&values).then_some(values)
+    })
+}
+
+/// What one concrete run with symbolic shadow found
+struct Path {
+    constraints: Vec<Constraint>,
+    reached: bool,
+}
+
+struct Explorer<'a> {
+    args: &'a [String],
+    memory_size: Option<usize>,
+    inputs: Vec<Input>,
+    target: u32,
+}
+
+impl Explorer<'_> {
+    fn load(&self) -> Result<(CoreState, Platform), String> {
+        let (mut core, image) = load_bare_metal(&self.args[0], self.memory_size)?;
+        let mut platform = Platform::new(self.args, &image, &mut core);
+        platform.quiet();
+        Ok((core, platform))
+    }
+
+    fn run(&self, values: &[u32]) -> Result<Path, String> {
+        let (mut core, mut platform) = self.load()?;
+        for (input, &value) in self.inputs.iter().zip(values) {
+            match *input {
+                Input::Register(reg) => core.regs.write(reg, value),
+                Input::Byte(address) => core.memory[address as usize] = value as u8,
+            }
+        }
+        let mut shadow = Shadow::new(&self.inputs);
+        for _ in 0..STEPS {
+            if core.pc == self.target {
+                return Ok(Path {constraints: shadow.constraints, reached: true});
+            }
+            shadow.step(&core);
+            if platform.step(&mut core, Engine::Step).is_some() {
+                break;
+            }
+        }
+        Ok(Path {constraints: shadow.constraints, reached: false})
+    }
+}
+
+/// Concolic search for inputs that make a bare-metal program reach a pc.
+/// `args` is `inputs target program [args]`: the symbolic inputs are
+/// registers and `base+size` memory ranges, e.g. `a0,0x2000+4`, the target
+/// an address or symbol. Each run executes concretely while the inputs are
+/// followed symbolically through a subset of RV32I; every symbolic branch is
+/// forked by solving for inputs that take the other side (up to `DEPTH` per
+/// run and `PATHS` runs). Returns 0 with the inputs printed if the target is
+/// reached, else 1.
+pub fn run(args: &[String], config: Config) -> Result<i32, String> {
+    let (spec, args) = args.split_first().ok_or("missing symbolic inputs, e.g. a0,0x2000+4")?;
+    let (target, args) = args.split_first().ok_or("missing target pc or symbol")?;
+    let path = args.first().ok_or("missing program")?;
+    let (core, image) = load_bare_metal(path, config.memory_size)?;
+    let target = match image.symbols.get(target.as_str()) {
+        Some(&address) => address,
+        None => parse_number(target).map_err(|_| format!("`{}` is neither a symbol nor an address", target))?,
+    };
+    let mut inputs = Vec::new();
+    for input in spec.split(',').filter(|input| !input.is_empty()) {
+        match input.split_once('+') {
+            Some((base, size)) => {
+                let (base, size) = (parse_number(base)?, parse_number(size)?);
+                if base as usize + size as usize > core.memory.len() {
+                    return Err(format!("`{}` is outside memory", input));
+                }
+                inputs.extend((base..base + size).map(Input::Byte));
+            }
+            None => match parse_reg(input)? {
+                0 => return Err("x0 can't be symbolic".to_string()),
+                reg => inputs.push(Input::Register(reg)),
+            },
+        }
+    }
+    if inputs.is_empty() {
+        return Err("no symbolic inputs".to_string());
+    }
+    let start: Vec<u32> = inputs.iter().map(|input| match *input {
+        Input::Register(reg) => core.regs[reg],
+        Input::Byte(address) => core.memory[address as usize] as u32,
+    }).collect();
+    let limits: Vec<u32> = inputs.iter().map(Input::limit).collect();
+    let explorer = Explorer {args, memory_size: config.memory_size, inputs, target};
+
+    let mut rng = Rng(1);
+    // inputs to run and the first branch they may fork, depth first
+    let mut pending = vec![(start, 0)];
+    let mut tried = HashSet::new();
+    let (mut runs, mut unsolved, mut cut) = (0, 0, 0);
+    while let Some((values, bound)) = pending.pop() {
+        if runs == PATHS {
+            break;
+        }
+        runs += 1;
+        let path = explorer.run(&values)?;
+        if path.reached {
+            let values: Vec<String> = explorer.inputs.iter().zip(&values)
+                .map(|(input, value)| format!("{}=0x{:x}", input.name(), value))
+                .collect();
+            println!("0x{:08x} reached on run {}: {}", target, runs, values.join(" "));
+            return Ok(0);
+        }
+        if path.constraints.len() > DEPTH {
+            cut += 1;
+        }
+        let mut constraints = path.constraints;
+        constraints.truncate(DEPTH);
+        for i in bound..constraints.len() {
+            constraints[i].holds = !constraints[i].holds;
+            // a branch sequence already forked from another run
+            let decisions: Vec<(u32, bool)> = constraints[..=i].iter().map(|c| (c.pc, c.holds)).collect();
+            if tried.insert(decisions) {
+                match solve(&constraints[..=i], &values, &limits, &mut rng) {
+                    Some(model) => pending.push((model, i + 1)),
+                    None => unsolved += 1,
+                }
+            }
+            constraints[i].holds = !constraints[i].holds;
+        }
+    }
+    let bounded = !pending.is_empty() || unsolved > 0 || cut > 0;
+    println!("0x{:08x} not reached in {} runs{}", target, runs, match bounded {
+        true => format!(" ({} branches unsolved, {} runs past the fork depth, {} runs left)", unsolved, cut,
+                        pending.len()),
+        false => ", every path explored".to_string(),
+    });
+    Ok(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{self, LOAD, OP_IMM};
+    use crate::test_utils::machine;
+
+    #[test]
+    fn branches_are_forked_and_solved() {
+        // lbu t0, 0x100(x0); xori t0, t0, 0x55; addi t1, x0, 0x12; bne t0, t1, 8;
+        // sltu t2, a0, t1; beq t2, x0, 8
+        let program = [encode::i(LOAD, 0b100, 5, 0, 0x100), encode::i(OP_IMM, 0b100, 5, 5, 0x55),
+                       encode::i(OP_IMM, 0b000, 6, 0, 0x12), encode::b(0b001, 5, 6, 8),
+                       encode::r(0, 0b011, 7, 10, 6), encode::b(0b000, 7, 0, 8)];
+        let mut core = machine(0, &program, &[(10, 0x40)]);
+        let inputs = [Input::Byte(0x100), Input::Register(10)];
+        let mut shadow = Shadow::new(&inputs);
+        while core.pc < 0x18 {
+            shadow.step(&core);
+            core.execute();
+        }
+        // [0x100] is 0, so bne is taken and skips the sltu
+        assert_eq!(core.pc, 0x1C);
+        assert_eq!(shadow.constraints.len(), 1);
+        let mut rng = Rng(1);
+        let mut constraints = shadow.constraints;
+        constraints[0].holds = !constraints[0].holds;
+        let model = solve(&constraints, &[0, 0x40], &[0xff, u32::MAX], &mut rng).unwrap();
+        assert_eq!(model, [0x12 ^ 0x55, 0x40]);
+
+        // with the model the second branch depends on a0 < 0x12
+        let mut core = machine(0, &program, &[(10, 0x40)]);
+        core.memory[0x100] = model[0] as u8;
+        let mut shadow = Shadow::new(&inputs);
+        while core.pc < 0x18 {
+            shadow.step(&core);
+            core.execute();
+        }
+        assert_eq!(shadow.constraints.len(), 2);
+        let mut constraints = shadow.constraints;
+        assert!(constraints[1].holds);
+        constraints[1].holds = false;
+        let model = solve(&constraints, &model, &[0xff, u32::MAX], &mut rng).unwrap();
+        assert!(model[1] < 0x12 && model[0] == 0x47);
+    }
+}
@@ -0,0 +1,68 @@
+//! Machines running inline test programs
+
+use crate::csr_file::{MCAUSE, MEPC, MTVAL, MTVEC};
+use crate::CoreState;
+
+pub const MEMORY_SIZE: usize = 4096;
+
+/// Machine with `program` at `pc` and the given registers preset
+pub fn machine(pc: u32, program: &[u32], regs: &[(usize, u32)]) -> CoreState {
+    let mut core = CoreState::new(MEMORY_SIZE);
+    for (i, word) in program.iter().enumerate() {
+        let address = pc as usize + 4 * i;
+        core.memory[address..address + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    core.pc = pc;
+    for &(reg, value) in regs {
+        core.regs.write(reg, value);
+    }
+    core
+}
+
+/// Runs `steps` instructions of `program` placed at `pc`
+pub fn run(pc: u32, program: &[u32], regs: &[(usize, u32)], steps: usize) -> CoreState {
+    let mut core = machine(pc, program, regs);
+    for _ in 0..steps {
+        core.execute();
+    }
+    core
+}
+
+/// Runs a single instruction at address 0
+pub fn step(instruction: u32, regs: &[(usize, u32)]) -> CoreState {
+    run(0, &[instruction], regs, 1)
+}
+
+pub fn word(core: &CoreState, address: u32) -> u32 {
+    let start = address as usize;
+    u32::from_le_bytes(core.memory[start..start + 4].try_into().unwrap())
+}
+
+/// Spike-style commit log line for the instruction at pc: pc, raw bits,
+/// written registers and stored words, then the trap if one was taken
+pub fn commit(core: &mut CoreState) -> String {
+    let pc = core.pc;
+    let instruction = word(core, pc);
+    let regs = core.regs;
+    core.execute();
+
+    let mut line = format!("0x{:08x} (0x{:08x})", pc, instruction);
+    for (i, (old, new)) in regs.iter().zip(core.regs.iter()).enumerate() {
+        if old != new {
+            line += &format!(" x{} 0x{:08x}", i, new);
+        }
+    }
+    if instruction & 0x7F == crate::encode::STORE {
+        if let Some((address, size)) = core.last_access {
+            let start = address as usize;
+            let bytes = &core.memory[start..start + size as usize];
+            line += &format!(" mem 0x{:08x} 0x{}", address,
+                             bytes.iter().rev().map(|b| format!("{:02x}", b)).collect::<String>());
+        }
+    }
+    // a trap lands on the mtvec base with mepc at the faulting instruction
+    if core.pc == core.csrs.get(MTVEC) & !0b11 && core.csrs.get(MEPC) == pc && instruction != crate::encode::MRET {
+        line += &format!(" trap {} mtval 0x{:08x}", core.csrs.get(MCAUSE), core.csrs.get(MTVAL));
+    }
+    line
+}
@@ -0,0 +1,161 @@
+use crate::encode::{BRANCH, JAL, JALR, LOAD, OP, OP_IMM, STORE, SYSTEM};
+use crate::hpm::Event;
+use crate::CoreState;
+
+/// Cycle cost of executed instructions, added to `CoreState::cycles` after
+/// each one. Without a model every instruction takes one cycle.
+pub trait TimingModel {
+    /// Cycles for `instruction` (raw bits) at `pc`; `core` is the state after
+    /// it executed, its pc shows taken branches and traps
+    fn cycles(&mut self, core: &CoreState, pc: u32, instruction: u32) -> u64;
+
+    /// Running total of a model event (mispredictions, cache misses) for
+    /// the mhpmcounters
+    fn count(&self, _event: Event) -> u64 {
+        0
+    }
+}
+
+/// Single-issue in-order pipeline: a latency per instruction class, a penalty
+/// for taken branches and jumps, and a stall when an instruction reads the
+/// destination of the load right before it
+pub struct InOrder {
+    pub alu: u64,
+    pub load: u64,
+    pub load_use: u64,
+    pub store: u64,
+    pub branch: u64,
+    pub taken: u64,
+    pub jump: u64,
+    pub mul: u64,
+    pub div: u64,
+    pub system: u64,
+    // rd of the previous instruction if it was a load
+    load_rd: Option<u32>,
+}
+
+impl Default for InOrder {
+    fn default() -> Self {
+        Self {
+            alu: 1,
+            load: 1,
+            load_use: 1,
+            store: 1,
+            branch: 1,
+            taken: 2,
+            jump: 2,
+            mul: 3,
+            div: 34,
+            system: 3,
+            load_rd: None,
+        }
+    }
+}
+
+impl InOrder {
+    /// `inorder[:name=cycles,...]`, e.g. `inorder:mul=4,div=20,load_use=2`
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut model = Self::default();
+        let settings = match spec.split_once(':') {
+            Some(("inorder", settings)) => settings,
+            None if spec == "inorder" => "",
+            _ => return Err(format!("unknown timing model `{}`", spec)),
+        };
+        for setting in settings.split(',').filter(|s| !s.is_empty()) {
+            let (name, value) = setting.split_once('=').ok_or(format!("expected name=cycles, got `{}`", setting))?;
+            let value = value.parse().map_err(|_| format!("bad cycle count `{}`", value))?;
+            *match name {
+                "alu" => &mut model.alu,
+                "load" => &mut model.load,
+                "load_use" => &mut model.load_use,
+                "store" => &mut model.store,
+                "branch" => &mut model.branch,
+                "taken" => &mut model.taken,
+                "jump" => &mut model.jump,
+                "mul" => &mut model.mul,
+                "div" => &mut model.div,
+                "system" => &mut model.system,
+                _ => return Err(format!("unknown latency `{}`", name)),
+            } = value;
+        }
+        Ok(model)
+    }
+}
+
+impl TimingModel for InOrder {
+    fn cycles(&mut self, core: &CoreState, pc: u32, instruction: u32) -> u64 {
+        let opcode = instruction & 0x7F;
+        let rd = (instruction >> 7) & 0x1F;
+        let funct3 = (instruction >> 12) & 0b111;
+        let rs1 = (instruction >> 15) & 0x1F;
+        let rs2 = (instruction >> 20) & 0x1F;
+
+        let sources = match opcode {
+            OP | STORE | BRANCH => [Some(rs1), Some(rs2)],
+            OP_IMM | LOAD | JALR => [Some(rs1), None],
+            SYSTEM if funct3 & 0b100 == 0 => [Some(rs1), None],
+            _ => [None, None],
+        };
+        let stall = match self.load_rd {
+            Some(load_rd) if sources.contains(&Some(load_rd)) => self.load_use,
+            _ => 0,
+        };
+        self.load_rd = (opcode == LOAD && rd != 0).then_some(rd);
+
+        let taken = core.pc != pc.wrapping_add(4);
+        stall + match opcode {
+            LOAD => self.load,
+            STORE => self.store,
+            BRANCH if taken => self.branch + self.taken,
+            BRANCH => self.branch,
+            JAL | JALR => self.jump,
+            // M extension, funct3 bit 2 selects div/rem
+            OP if instruction >> 25 == 1 && funct3 & 0b100 != 0 => self.div,
+            OP if instruction >> 25 == 1 => self.mul,
+            SYSTEM => self.system,
+            _ => self.alu,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode;
+    use crate::test_utils::machine;
+
+    #[test]
+    fn latencies_and_load_use_stall() {
+        let mut model = InOrder::parse("inorder:div=20").unwrap();
+        let mut core = CoreState::new(64);
+        let mut cycles = |instruction, pc, next_pc| {
+            core.pc = next_pc;
+            model.cycles(&core, pc, instruction)
+        };
+        assert_eq!(cycles(encode::i(LOAD, 0b010, 5, 1, 0), 0, 4), 1);
+        // add x6, x5, x0 reads the loaded x5
+        assert_eq!(cycles(encode::r(0, 0b000, 6, 5, 0), 4, 8), 2);
+        assert_eq!(cycles(encode::r(0, 0b000, 6, 5, 0), 8, 12), 1);
+        assert_eq!(cycles(encode::b(0b000, 0, 0, 8), 12, 20), 3);
+        assert_eq!(cycles(encode::b(0b001, 0, 0, 8), 20, 24), 1);
+        // div x1, x2, x3
+        assert_eq!(cycles(encode::r(1, 0b100, 1, 2, 3), 24, 28), 20);
+        assert!(InOrder::parse("inorder:fpu=3").is_err());
+        assert!(InOrder::parse("ooo").is_err());
+    }
+
+    #[test]
+    fn core_counts_model_cycles() {
+        // lw x5, 0x40(x0); add x6, x5, x0; illegal
+        let program = [encode::i(LOAD, 0b010, 5, 0, 0x40), encode::r(0, 0b000, 6, 5, 0), 0];
+        let mut core = machine(0, &program, &[]);
+        core.execute();
+        assert_eq!(core.cycles, 1);
+        let mut core = machine(0, &program, &[]);
+        core.timing = Some(Box::new(InOrder::default()));
+        for _ in 0..3 {
+            core.execute();
+        }
+        assert_eq!(core.cycles, 1 + 2 + 1);
+    }
+}
@@ -0,0 +1,309 @@
+use std::fs;
+
+use crate::difftest::{self, Op, Program, BASE, MAX_STEPS, SIGNATURE, TOHOST};
+use crate::encode::{self, AUIPC, LOAD, LUI, OP, OP_IMM, STORE};
+use crate::shard::{self, Outcome, Shard};
+use crate::snapshot::Snapshot;
+use crate::{triage, Config, CoreState, Engine, RunOutcome};
+
+// the code has to stay below the data page
+const MAX_LENGTH: usize = 512;
+
+/// Architectural result of a program body computed without the emulator:
+/// x0..x31 and the scratch buffer
+struct Expected {
+    regs: [u32; 32],
+    scratch: Vec<u8>,
+}
+
+fn model(program: &Program) -> Expected {
+    let mut regs = [0; 32];
+    regs[1..31].copy_from_slice(&program.regs);
+    regs[31] = SIGNATURE;
+    let mut scratch = program.scratch.clone();
+    let prologue = program.body_code().len() - program.body.len();
+
+    let mut i = 0;
+    while i < program.body.len() {
+        let pc = BASE + 4 * (prologue + i) as u32;
+        i += 1;
+        let word = match program.body[i - 1] {
+            Op::Word(word) => word,
+            Op::Branch {funct3, rs1, rs2, skip} => {
+                let (a, b) = (regs[rs1 as usize], regs[rs2 as usize]);
+                let taken = match funct3 {
+                    0b000 => a == b,
+                    0b001 => a != b,
+                    0b100 => (a as i32) < (b as i32),
+                    0b101 => (a as i32) >= (b as i32),
+                    0b110 => a < b,
+                    _ => a >= b,
+                };
+                if taken {
+                    i = (i + skip as usize).min(program.body.len());
+                }
+                continue;
+            }
+        };
+
+        let rd = (word >> 7 & 31) as usize;
+        let funct3 = word >> 12 & 7;
+        let a = regs[(word >> 15 & 31) as usize];
+        let b = regs[(word >> 20 & 31) as usize];
+        let imm = ((word as i32) >> 20) as u32;
+        let alt = word >> 30 & 1 == 1;
+        let alu = |a: u32, b: u32, sub: bool| match funct3 {
+            0b000 if sub => a.wrapping_sub(b),
+            0b000 => a.wrapping_add(b),
+            0b001 => a << (b & 31),
+            0b010 => ((a as i32) < (b as i32)) as u32,
+            0b011 => (a < b) as u32,
+            0b100 => a ^ b,
+            0b101 if alt => ((a as i32) >> (b & 31)) as u32,
+            0b101 => a >> (b & 31),
+            0b110 => a | b,
+            _ => a & b,
+        };
+        match word & 0x7F {
+            OP => regs[rd] = alu(a, b, alt),
+            OP_IMM => regs[rd] = alu(a, imm, false),
+            LUI => regs[rd] = word & 0xFFFF_F000,
+            AUIPC => regs[rd] = pc.wrapping_add(word & 0xFFFF_F000),
+            LOAD => {
+                let offset = (a.wrapping_add(imm) - SIGNATURE) as usize;
+                let size = 1 << (funct3 & 0b11);
+                let mut bytes = [0; 4];
+                bytes[..size].copy_from_slice(&scratch[offset..offset + size]);
+                let value = u32::from_le_bytes(bytes);
+                regs[rd] = match funct3 {
+                    0b000 => value as i8 as u32,
+                    0b001 => value as i16 as u32,
+                    _ => value,
+                };
+            }
+            STORE => {
+                let imm = (imm & !31) | (word >> 7 & 31);
+                let offset = (a.wrapping_add(imm) - SIGNATURE) as usize;
+                let size = 1 << (funct3 & 0b11);
+                scratch[offset..offset + size].copy_from_slice(&b.to_le_bytes()[..size]);
+            }
+            opcode => unreachable!("generator emitted opcode 0x{:02x}", opcode),
+        }
+        regs[0] = 0;
+    }
+    Expected {regs, scratch}
+}
+
+/// Body followed by a check of x1..x30 and every scratch word against the
+/// model. Exits through tohost with 0, or with the failing check: 1..30 for a
+/// register, 31 + n for scratch word n.
+fn self_checking(program: &Program) -> Vec<u32> {
+    let expected = model(program);
+    let mut code = program.body_code();
+    let mut fails = Vec::new();
+    let mut check = |code: &mut Vec<u32>, reg: u32, value: u32, index: i32| {
+        code.extend(encode::li(31, value));
+        code.push(encode::b(0b000, reg, 31, 12));
+        code.push(encode::i(OP_IMM, 0b000, 31, 0, index));
+        fails.push(code.len());
+        code.push(0);
+    };
+    for reg in 1..31 {
+        check(&mut code, reg, expected.regs[reg as usize], reg as i32);
+    }
+    // x29 and x30 are already checked
+    code.extend(encode::li(30, SIGNATURE));
+    for (n, word) in expected.scratch.chunks_exact(4).enumerate() {
+        code.push(encode::i(LOAD, 0b010, 29, 30, 4 * n as i32));
+        check(&mut code, 29, u32::from_le_bytes(word.try_into().unwrap()), 31 + n as i32);
+    }
+
+    // pass: tohost = 1, fail: tohost = index << 1 | 1
+    code.push(encode::i(OP_IMM, 0b000, 31, 0, 0));
+    let fail = code.len();
+    code.push(encode::i(OP_IMM, 0b001, 31, 31, 1));
+    code.push(encode::i(OP_IMM, 0b110, 31, 31, 1));
+    code.extend(encode::li(1, TOHOST));
+    code.push(encode::s(0b010, 1, 31, 0));
+    code.push(encode::s(0b010, 1, 0, 4));
+    code.push(encode::j(0, 0));
+    for at in fails {
+        code[at] = encode::j(0, 4 * (fail as i32 - at as i32));
+    }
+    code
+}
+
+/// Runs self-checking code in memory, returns the failing check index or 0
+fn run_program(program: &Program, engine: Engine) -> Result<u32, String> {
+    run_code(&self_checking(program), &program.scratch, engine)
+}
+
+fn run_code(code: &[u32], scratch: &[u8], engine: Engine) -> Result<u32, String> {
+    execute(code, scratch, engine).1
+}
+
+/// The core as it stopped and the result of the run
+fn execute(code: &[u32], scratch: &[u8], engine: Engine) -> (CoreState, Result<u32, String>) {
+    let mut core = difftest::load(code, scratch);
+    let tohost = TOHOST as usize;
+    let mut steps = 0;
+    loop {
+        match u32::from_le_bytes(core.memory[tohost..tohost + 4].try_into().unwrap()) {
+            0 => {}
+            value => return (core, Ok(value >> 1)),
+        }
+        if steps > MAX_STEPS {
+            let error = format!("no exit after {} steps, pc 0x{:08x}", steps, core.pc);
+            return (core, Err(error));
+        }
+        steps += core.dispatch(engine);
+    }
+}
+
+/// Shrinks a failing program while it keeps failing and saves it with the
+/// state it stops in to the corpus `dir`
+fn save_failure(dir: &str, program: &Program, engine: Engine) -> Result<String, String> {
+    let smallest = difftest::shrink(program, &mut |p: &Program| run_program(p, engine) != Ok(0));
+    let code = self_checking(&smallest);
+    let (core, _) = execute(&code, &smallest.scratch, engine);
+    triage::save(dir, &difftest::elf(&code, &smallest.scratch), &Snapshot::bare(&core))
+}
+
+fn check_name(index: u32) -> String {
+    match index {
+        1..=30 => crate::CoreState::reg_name(index as usize),
+        n => format!("scratch[{}]", 4 * (n - 31)),
+    }
+}
+
+/// `rs-v torture [--seed n] [--count n] [--length n] [--save dir]
+/// [--corpus dir] [--shard i/n] [--results file] [elf...]`: runs
+/// self-checking random programs in bulk, or the given torture ELFs (tohost
+/// exit code 0 passes), and reports the failures, shrunk into the corpus if
+/// given
+pub fn run(args: &[String], engine: Engine) -> Result<i32, String> {
+    let mut seed = 1;
+    let mut count = 1000;
+    let mut length = 64;
+    let mut save = None;
+    let mut corpus = None;
+    let mut shard = Shard::default();
+    let mut results_path = None;
+    let mut elfs = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--seed" => seed = value()?.parse().map_err(|_| "bad --seed")?,
+            "--count" => count = value()?.parse().map_err(|_| "bad --count")?,
+            "--length" => length = value()?.parse().map_err(|_| "bad --length")?,
+            "--save" => save = Some(value()?.clone()),
+            "--corpus" => corpus = Some(value()?.clone()),
+            "--shard" => shard = Shard::parse(value()?)?,
+            "--results" => results_path = Some(value()?.clone()),
+            _ if arg.starts_with("--") => return Err(format!("unknown torture argument `{}`", arg)),
+            _ => elfs.push(arg.clone()),
+        }
+    }
+    if length > MAX_LENGTH {
+        return Err(format!("--length is at most {}", MAX_LENGTH));
+    }
+
+    let mut outcomes = Vec::new();
+    if !elfs.is_empty() {
+        for (_, path) in elfs.iter().enumerate().filter(|&(i, _)| shard.contains(i)) {
+            let config = Config {engine, ..Config::default()};
+            let error = match crate::run(std::slice::from_ref(path), config) {
+                RunOutcome::Exited(0) => None,
+                outcome => Some(outcome.to_string()),
+            };
+            if let Some(error) = &error {
+                println!("{}: {}", path, error);
+            }
+            outcomes.push(Outcome {name: path.clone(), extension: "torture".to_string(), error});
+        }
+        return finish(&outcomes, shard, results_path);
+    }
+
+    if let Some(dir) = &save {
+        fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir, e))?;
+    }
+    for (_, seed) in (seed..seed + count).enumerate().filter(|&(i, _)| shard.contains(i)) {
+        let program = Program::generate(seed, length);
+        if let Some(dir) = &save {
+            let path = format!("{}/torture-{}.elf", dir, seed);
+            fs::write(&path, difftest::elf(&self_checking(&program), &program.scratch))
+                .map_err(|e| format!("{}: {}", path, e))?;
+        }
+        let error = match run_program(&program, engine) {
+            Ok(0) => None,
+            Ok(index) => Some(format!("{} differs from the model", check_name(index))),
+            Err(e) => Some(e),
+        };
+        if let Some(error) = &error {
+            println!("seed {}: {}", seed, error);
+            if let Some(dir) = &corpus {
+                println!("  saved as {}.elf", save_failure(dir, &program, engine)?);
+            }
+        }
+        outcomes.push(Outcome {name: format!("seed {}", seed), extension: "torture".to_string(), error});
+    }
+    finish(&outcomes, shard, results_path)
+}
+
+/// Prints the totals and writes the `--results` file
+fn finish(outcomes: &[Outcome], shard: Shard, results_path: Option<String>) -> Result<i32, String> {
+    let failed = outcomes.iter().filter(|outcome| outcome.error.is_some()).count();
+    println!("{} of {} programs passed", outcomes.len() - failed, outcomes.len());
+    if let Some(path) = results_path {
+        shard::write_results(&path, "torture", shard, outcomes)?;
+    }
+    Ok((failed > 0) as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::difftest::SCRATCH_SIZE;
+
+    #[test]
+    fn emulator_agrees_with_the_model() {
+        for seed in 1..200 {
+            let program = Program::generate(seed, 64);
+            assert_eq!(run_program(&program, Engine::Block), Ok(0), "seed {}", seed);
+            assert_eq!(run_program(&program, Engine::Step), Ok(0), "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn checks_report_the_differing_word() {
+        let program = Program {regs: (1..31).collect(), scratch: vec![0; SCRATCH_SIZE as usize], body: Vec::new()};
+        let mut scratch = program.scratch.clone();
+        scratch[13] = 1;
+        assert_eq!(run_code(&self_checking(&program), &scratch, Engine::Block), Ok(31 + 3));
+        assert_eq!(check_name(31 + 3), "scratch[12]");
+    }
+
+    #[test]
+    fn failures_shrink_into_the_corpus() {
+        let mut program = Program::generate(5, 24);
+        // mul, which isn't decoded: the trap never returns
+        program.body[9] = Op::Word(encode::r(1, 0b000, 5, 6, 7));
+        let dir = std::env::temp_dir().join("rs-v-torture-corpus").to_string_lossy().into_owned();
+        let _ = fs::remove_dir_all(&dir);
+        let stem = save_failure(&dir, &program, Engine::Block).unwrap();
+        let snapshot = Snapshot::load(&format!("{}.snap", stem)).unwrap();
+        assert!(snapshot.csrs.contains(&("mcause".to_string(), 2)));
+        assert_eq!(run(&[format!("{}.elf", stem)], Engine::Block), Ok(1));
+        assert_eq!(save_failure(&dir, &program, Engine::Block), Ok(stem));
+    }
+
+    #[test]
+    fn saved_elf_passes() {
+        let program = Program::generate(11, 32);
+        let path = std::env::temp_dir().join("rs-v-torture-test.elf");
+        fs::write(&path, difftest::elf(&self_checking(&program), &program.scratch)).unwrap();
+        let path = path.to_string_lossy().into_owned();
+        assert_eq!(run(&[path], Engine::Block), Ok(0));
+    }
+}
@@ -0,0 +1,136 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::disasm::{fold, pseudo};
+use crate::loader::{function_at, Function, Image};
+use crate::{CoreState, Observer};
+
+/// False when built without the `trace` feature, run loops test it before
+/// touching the tracer so the per-instruction check compiles away
+pub const ENABLED: bool = cfg!(feature = "trace");
+
+/// Buffered per-instruction trace, written to a file or `-` for stdout
+pub struct Tracer {
+    out: BufWriter<Box<dyn Write>>,
+}
+
+impl Tracer {
+    pub fn open(path: &str) -> io::Result<Self> {
+        if !ENABLED {
+            return Err(io::Error::other("rs-v was built without the `trace` feature"));
+        }
+        let out: Box<dyn Write> = match path {
+            "-" => Box::new(io::stdout()),
+            _ => Box::new(File::create(path)?),
+        };
+        Ok(Self {out: BufWriter::with_capacity(1 << 16, out)})
+    }
+
+    pub fn step(&mut self, core: &CoreState) {
+        let _ = writeln!(self.out, "{}", core);
+    }
+
+    pub fn note(&mut self, text: &str) {
+        let _ = writeln!(self.out, "{}", text);
+    }
+}
+
+/// Per-instruction trace as symbolized assembly: pseudo-instructions are
+/// folded, a `lui`/`auipc` and the instruction retiring right after it that
+/// completes a `li`, `la`, `call`, `tail` or global load become one line
+pub struct AsmTrace {
+    out: BufWriter<Box<dyn Write>>,
+    functions: Vec<Function>,
+    // pc and word of a `lui`/`auipc` waiting for its pair
+    pending: Option<(u32, u32)>,
+}
+
+impl AsmTrace {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let Tracer {out} = Tracer::open(path)?;
+        Ok(Self {out, functions: Vec::new(), pending: None})
+    }
+
+    fn line(&mut self, pc: u32, text: &str) {
+        let symbol = match function_at(&self.functions, pc) {
+            Some(i) if pc == self.functions[i].start => self.functions[i].name.clone(),
+            Some(i) => format!("{}+0x{:x}", self.functions[i].name, pc - self.functions[i].start),
+            None => "?".to_string(),
+        };
+        let _ = writeln!(self.out, "0x{:08x} {:<20} {}", pc, symbol, text);
+    }
+
+    fn flush_pending(&mut self) {
+        if let Some((pc, word)) = self.pending.take() {
+            let text = pseudo(word, pc, &self.functions);
+            self.line(pc, &text);
+        }
+    }
+}
+
+impl Observer for AsmTrace {
+    fn loaded(&mut self, image: &Image) {
+        self.functions = image.functions.clone();
+    }
+
+    fn step(&mut self, core: &CoreState) {
+        let pc = core.pc;
+        let Some(bytes) = core.memory.get(pc as usize..pc as usize + 4) else {
+            self.flush_pending();
+            return self.line(pc, "<fetch fault>");
+        };
+        let word = u32::from_le_bytes(bytes.try_into().unwrap());
+        if let Some((first_pc, first)) = self.pending {
+            if pc == first_pc.wrapping_add(4) {
+                if let Some(text) = fold(first, word, first_pc, &self.functions) {
+                    self.pending = None;
+                    return self.line(first_pc, &text);
+                }
+            }
+            self.flush_pending();
+        }
+        // lui and auipc
+        if matches!(word & 0x7F, 0b0110111 | 0b0010111) {
+            self.pending = Some((pc, word));
+            return;
+        }
+        let text = pseudo(word, pc, &self.functions);
+        self.line(pc, &text);
+    }
+}
+
+impl Drop for AsmTrace {
+    fn drop(&mut self) {
+        self.flush_pending();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{self, AUIPC, JALR, OP_IMM};
+    use crate::test_utils::machine;
+
+    #[test]
+    fn pairs_are_folded() {
+        // auipc ra, 0; jalr ra, 16(ra); addi a0, zero, 1; ret; f: addi a0, zero, 1; ret
+        let program = [encode::u(AUIPC, 1, 0), encode::i(JALR, 0b000, 1, 1, 16), encode::i(OP_IMM, 0b000, 10, 0, 1),
+                       encode::i(JALR, 0b000, 0, 1, 0), encode::i(OP_IMM, 0b000, 10, 0, 1),
+                       encode::i(JALR, 0b000, 0, 1, 0)];
+        let mut core = machine(0, &program, &[]);
+        let path = std::env::temp_dir().join("rs-v-asm-trace.txt");
+        let mut trace = AsmTrace::open(path.to_str().unwrap()).unwrap();
+        trace.functions = vec![Function {start: 0, size: 16, name: "main".to_string()},
+                               Function {start: 16, size: 8, name: "f".to_string()}];
+        for _ in 0..5 {
+            trace.step(&core);
+            core.execute();
+        }
+        drop(trace);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(),
+                   "0x00000000 main                 call 0x10 <f>\n\
+                    0x00000010 f                    li a0, 1\n\
+                    0x00000014 f+0x4                ret\n\
+                    0x00000008 main+0x8             li a0, 1\n");
+    }
+}
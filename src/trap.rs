@@ -0,0 +1,221 @@
+use crate::csr_file::{status, MEDELEG, MEPC, MIDELEG, MIE, MTVAL, MTVEC, SCAUSE, SEPC, STVAL, STVEC};
+use crate::{Cause, CoreState, Privilege, INTERRUPTS};
+
+/// An exception raised by an instruction or its fetch: the cause and what
+/// goes into mtval. Handlers return it instead of writing the CSRs, the trap
+/// controller below takes it.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) struct Exception {
+    pub cause: Cause,
+    pub tval: u32,
+}
+
+impl Exception {
+    pub(crate) fn new(cause: Cause, tval: u32) -> Self {
+        Self {cause, tval}
+    }
+
+    /// mtval is the instruction word
+    pub(crate) fn illegal(word: u32) -> Self {
+        Self::new(Cause::IllegalInstruction, word)
+    }
+
+    pub(crate) fn fetch_access(address: u32) -> Self {
+        Self::new(Cause::InstructionAccessFault, address)
+    }
+}
+
+// The trap controller: every trap entry and return goes through here. It is
+// consulted once per step, for the exception the instruction (or its fetch)
+// raised, then at the boundary before the next instruction for the
+// highest-priority pending interrupt. medeleg and mideleg send the traps
+// of S- and U-mode to S-mode; debug mode belongs here too once it exists.
+
+/// Takes an exception raised by the instruction at pc into M-mode, or
+/// S-mode if it is delegated, noting a double fault if it is the first
+/// instruction of the handler
+pub(crate) fn take_exception(core: &mut CoreState, exception: Exception) {
+    let delegated = core.privilege != Privilege::Machine &&
+        core.csrs.get(MEDELEG) & exception.cause.bit() != 0;
+    let base = core.csrs.get(if delegated {STVEC} else {MTVEC}) & !0b11;
+    if core.pc == base {
+        core.double_fault = true;
+    }
+    if delegated {
+        enter_supervisor(core, exception.cause, exception.tval);
+    } else {
+        enter(core, exception.cause, exception.tval);
+    }
+    // exceptions enter at BASE in both mtvec modes
+    core.pc = base;
+}
+
+/// Enters the highest-priority pending and enabled interrupt, clearing its
+/// pending bit. Those not delegated are enabled by mstatus.MIE in M-mode and
+/// always below it; the delegated ones by mstatus.SIE in S-mode, always in
+/// U-mode and never in M-mode. The M-level ones go first.
+pub(crate) fn take_interrupt(core: &mut CoreState) -> bool {
+    let pending = core.mip & core.csrs.get(MIE);
+    let delegated = core.csrs.get(MIDELEG);
+    let machine = match core.privilege {
+        Privilege::Machine => core.csrs.status(status::MIE),
+        _ => true,
+    };
+    let supervisor = match core.privilege {
+        Privilege::Machine => false,
+        Privilege::Supervisor => core.csrs.status(status::SIE),
+        Privilege::User => true,
+    };
+    let (enabled, tvec) = if machine && pending & !delegated != 0 {
+        (pending & !delegated, MTVEC)
+    } else if supervisor && pending & delegated != 0 {
+        (pending & delegated, STVEC)
+    } else {
+        return false;
+    };
+    let cause = Cause::highest(INTERRUPTS.into_iter().filter(|cause| enabled & cause.bit() != 0)).unwrap();
+    let bit = cause.bit();
+    core.mip &= !bit;
+    if tvec == STVEC {
+        enter_supervisor(core, cause, 0);
+    } else {
+        enter(core, cause, 0);
+    }
+    let tvec = core.csrs.get(tvec);
+    // vectored mode enters interrupts at BASE + 4 * cause
+    core.pc = match tvec & 0b11 {
+        1 => (tvec & !0b11).wrapping_add(4 * bit.trailing_zeros()),
+        _ => tvec & !0b11,
+    };
+    true
+}
+
+/// Saves pc, the cause and the interrupt enable stack and switches to
+/// M-mode; the caller picks the handler address
+fn enter(core: &mut CoreState, cause: Cause, tval: u32) {
+    core.csrs.set(MEPC, core.pc);
+    core.csrs.set_cause(cause);
+    core.csrs.set(MTVAL, tval);
+    core.csrs.set_status(status::MPIE, core.csrs.status(status::MIE));
+    core.csrs.set_status(status::MIE, false);
+    core.csrs.set_mpp(core.privilege);
+    core.privilege = Privilege::Machine;
+}
+
+/// `enter` for a delegated trap, with the S-mode CSRs
+fn enter_supervisor(core: &mut CoreState, cause: Cause, tval: u32) {
+    core.csrs.set(SEPC, core.pc);
+    core.csrs.set(SCAUSE, CoreState::get_cause_value(&cause));
+    core.csrs.set(STVAL, tval);
+    core.csrs.set_status(status::SPIE, core.csrs.status(status::SIE));
+    core.csrs.set_status(status::SIE, false);
+    core.csrs.set_spp(core.privilege);
+    core.privilege = Privilege::Supervisor;
+}
+
+/// MRET: back to the privilege mode in MPP, which becomes U, at mepc;
+/// leaving M-mode clears MPRV
+pub(crate) fn mret(core: &mut CoreState) {
+    core.csrs.set_status(status::MIE, core.csrs.status(status::MPIE));
+    core.csrs.set_status(status::MPIE, true);
+    core.privilege = core.csrs.mpp();
+    if core.privilege != Privilege::Machine {
+        core.csrs.set_status(status::MPRV, false);
+    }
+    core.csrs.set_mpp(Privilege::User);
+    core.pc = core.csrs.get(MEPC);
+}
+
+/// SRET: back to the privilege mode in SPP, which becomes U, at sepc,
+/// clearing MPRV
+pub(crate) fn sret(core: &mut CoreState) {
+    core.csrs.set_status(status::MPRV, false);
+    core.csrs.set_status(status::SIE, core.csrs.status(status::SPIE));
+    core.csrs.set_status(status::SPIE, true);
+    core.privilege = core.csrs.spp();
+    core.csrs.set_spp(Privilege::User);
+    core.pc = core.csrs.get(SEPC);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupts::{MEI, MTI, SSI, STI};
+    use crate::test_utils::machine;
+
+    #[test]
+    fn interrupts_wait_for_mie_only_in_m_mode() {
+        let mut core = machine(0x10, &[], &[]);
+        core.csrs.set(MTVEC, 0x40);
+        core.csrs.set(MIE, SSI | STI | MTI);
+        core.mip = SSI | STI;
+        assert!(!take_interrupt(&mut core));
+        // below M-mode M-level interrupts are always enabled, SSI outranks STI
+        core.privilege = Privilege::User;
+        assert!(take_interrupt(&mut core));
+        assert_eq!((core.pc, core.csrs.cause(), core.mip), (0x40, Cause::SupervisorSoftwareInterrupt, STI));
+        core.csrs.set_status(status::MIE, true);
+        core.mip |= MTI;
+        assert!(take_interrupt(&mut core));
+        assert_eq!((core.csrs.cause(), core.mip), (Cause::MachineTimerInterrupt, STI));
+    }
+
+    #[test]
+    fn delegated_traps_enter_s_mode_from_below_m_mode() {
+        let mut core = machine(0x10, &[], &[]);
+        core.csrs.set(MTVEC, 0x40);
+        core.csrs.set(STVEC, 0x81);
+        core.csrs.set(MEDELEG, Cause::Breakpoint.bit());
+        core.csrs.set(MIDELEG, SSI);
+        core.csrs.set(MIE, SSI);
+        // M-mode traps stay in M-mode, delegated interrupts wait
+        core.mip = SSI;
+        core.csrs.set_status(status::MIE, true);
+        assert!(!take_interrupt(&mut core));
+        take_exception(&mut core, Exception::new(Cause::Breakpoint, 0x10));
+        assert_eq!((core.pc, core.privilege), (0x40, Privilege::Machine));
+
+        core.pc = 0x10;
+        core.privilege = Privilege::User;
+        take_exception(&mut core, Exception::new(Cause::Breakpoint, 0x10));
+        assert_eq!((core.pc, core.csrs.get(SEPC), core.csrs.get(SCAUSE), core.csrs.get(STVAL)), (0x80, 0x10, 3, 0x10));
+        assert_eq!((core.privilege, core.csrs.spp()), (Privilege::Supervisor, Privilege::User));
+        // in S-mode only once SIE is set, vectored through stvec
+        assert!(!take_interrupt(&mut core));
+        core.csrs.set_status(status::SIE, true);
+        assert!(take_interrupt(&mut core));
+        assert_eq!((core.pc, core.csrs.get(SCAUSE), core.csrs.spp()), (0x84, 1 << 31 | 1, Privilege::Supervisor));
+        assert!(!core.csrs.status(status::SIE) && core.csrs.status(status::SPIE));
+        sret(&mut core);
+        assert_eq!((core.pc, core.privilege), (0x80, Privilege::Supervisor));
+    }
+
+    #[test]
+    fn exceptions_then_interrupts_by_priority() {
+        let mut core = machine(0x10, &[], &[]);
+        core.csrs.set(MTVEC, 0x41);
+        core.csrs.set(MIE, MTI | MEI);
+        core.csrs.set_status(status::MIE, true);
+        core.privilege = Privilege::User;
+        take_exception(&mut core, Exception::illegal(0xdead));
+        assert_eq!((core.pc, core.csrs.get(MEPC), core.csrs.get(MTVAL), core.csrs.cause()),
+                   (0x40, 0x10, 0xdead, Cause::IllegalInstruction));
+        assert_eq!((core.privilege, core.csrs.mpp(), core.csrs.status(status::MPIE)),
+                   (Privilege::Machine, Privilege::User, true));
+        assert!(!core.double_fault);
+
+        // masked in M-mode until the handler returns
+        core.mip = MTI | MEI;
+        assert!(!take_interrupt(&mut core));
+        core.csrs.set(MEPC, 0x14);
+        mret(&mut core);
+        assert_eq!((core.pc, core.privilege), (0x14, Privilege::User));
+        assert!(take_interrupt(&mut core));
+        assert_eq!((core.pc, core.csrs.cause(), core.mip), (0x40 + 4 * 11, Cause::MachineExternalInterrupt, MTI));
+
+        // raising again at the handler's first instruction is a double fault
+        core.pc = 0x40;
+        take_exception(&mut core, Exception::new(Cause::Breakpoint, 0x40));
+        assert!(core.double_fault);
+    }
+}
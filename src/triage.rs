@@ -0,0 +1,44 @@
+use std::fs;
+
+use crate::snapshot::Snapshot;
+
+/// FNV-1a, stable across builds and hosts unlike the std hasher
+fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3))
+}
+
+/// Saves a failure found by a fuzzing campaign to the corpus `dir`: the
+/// reproducing program as `<hash>.elf` and the machine state it ended in as
+/// `<hash>.snap`, for `diff-state` and reruns without the fuzzer. The name
+/// is the hash of the program, so finding the same failure again overwrites
+/// it instead of piling up copies. Returns the path without the extension.
+pub(crate) fn save(dir: &str, elf: &[u8], snapshot: &Snapshot) -> Result<String, String> {
+    fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir, e))?;
+    let stem = format!("{}/{:016x}", dir, hash(elf));
+    for (extension, bytes) in [("elf", elf.to_vec()), ("snap", snapshot.to_bytes())] {
+        let path = format!("{}.{}", stem, extension);
+        fs::write(&path, bytes).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    Ok(stem)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::machine;
+
+    #[test]
+    fn names_follow_the_program() {
+        assert_eq!(hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+        let dir = std::env::temp_dir().join("rs-v-triage-test").to_string_lossy().into_owned();
+        let _ = fs::remove_dir_all(&dir);
+        let snapshot = Snapshot::bare(&machine(0, &[], &[]));
+        let stem = save(&dir, b"one", &snapshot).unwrap();
+        assert_eq!(save(&dir, b"one", &snapshot), Ok(stem.clone()));
+        assert_ne!(save(&dir, b"two", &snapshot), Ok(stem.clone()));
+        assert_eq!(fs::read(format!("{}.elf", stem)).unwrap(), b"one");
+        assert_eq!(Snapshot::load(&format!("{}.snap", stem)), Ok(snapshot));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 4);
+    }
+}
@@ -0,0 +1,159 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::interrupts::{MEI, MSI, MTI};
+use crate::script::{parse_number, parse_reg};
+use crate::{CoreState, Observer};
+
+/// Signals dumped when the spec lists none
+const DEFAULT_SIGNALS: &str = "pc,irq";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Source {
+    Pc,
+    Register(usize),
+    /// Pending bit of an interrupt line in mip
+    Line(u32),
+    /// Memory word at an address
+    Word(u32),
+}
+
+struct Signal {
+    name: String,
+    width: u32,
+    source: Source,
+    last: Option<u32>,
+}
+
+impl Source {
+    fn sample(self, core: &CoreState) -> u32 {
+        match self {
+            Source::Pc => core.pc,
+            Source::Register(index) => core.regs[index],
+            Source::Line(bit) => (core.mip & bit != 0) as u32,
+            Source::Word(address) => core.memory.get(address as usize..address as usize + 4)
+                .map_or(0, |bytes| u32::from_le_bytes(bytes.try_into().unwrap())),
+        }
+    }
+}
+
+/// VCD identifier code of the `index`th signal, base 94 over the printable
+/// characters
+fn code(mut index: usize) -> String {
+    let mut code = String::new();
+    loop {
+        code.push((b'!' + (index % 94) as u8) as char);
+        index /= 94;
+        if index == 0 {
+            return code;
+        }
+        index -= 1;
+    }
+}
+
+/// Waveform of selected architectural state for GTKWave: pc, registers,
+/// the mip interrupt lines and memory words, sampled before every
+/// instruction with one timestep per cycle (10 ns, the nominal 100 MHz
+/// clock). Only changes are written.
+pub struct Vcd {
+    out: BufWriter<File>,
+    signals: Vec<Signal>,
+    started: bool,
+}
+
+impl Vcd {
+    /// `spec` is `file[:signal,...]` with `pc`, register names, `irq` for
+    /// the software, timer and external lines and addresses of words, e.g.
+    /// `run.vcd:pc,a0,irq,0x1000`
+    pub fn open(spec: &str) -> Result<Self, String> {
+        let (path, signals) = spec.split_once(':').unwrap_or((spec, DEFAULT_SIGNALS));
+        let mut parsed = Vec::new();
+        for name in signals.split(',').filter(|name| !name.is_empty()) {
+            let signal = |name: &str, width, source| Signal {name: name.to_string(), width, source, last: None};
+            match name {
+                "pc" => parsed.push(signal("pc", 32, Source::Pc)),
+                "irq" => parsed.extend([("msi", MSI), ("mti", MTI), ("mei", MEI)]
+                    .map(|(name, bit)| signal(name, 1, Source::Line(bit)))),
+                _ if name.starts_with("0x") => {
+                    let address = parse_number(name)?;
+                    parsed.push(signal(&format!("mem_{:08x}", address), 32, Source::Word(address)));
+                }
+                _ => parsed.push(signal(name, 32, Source::Register(parse_reg(name)?))),
+            }
+        }
+        let out = BufWriter::new(File::create(path).map_err(|e| format!("{}: {}", path, e))?);
+        Ok(Self {out, signals: parsed, started: false})
+    }
+
+    fn header(&mut self) -> std::io::Result<()> {
+        writeln!(self.out, "$version rs-v $end\n$timescale 10ns $end\n$scope module core $end")?;
+        for (i, signal) in self.signals.iter().enumerate() {
+            writeln!(self.out, "$var wire {} {} {} $end", signal.width, code(i), signal.name)?;
+        }
+        writeln!(self.out, "$upscope $end\n$enddefinitions $end")
+    }
+
+    fn sample(&mut self, core: &CoreState) -> std::io::Result<()> {
+        let mut changes = String::new();
+        for (i, signal) in self.signals.iter_mut().enumerate() {
+            let value = signal.source.sample(core);
+            if signal.last == Some(value) {
+                continue;
+            }
+            signal.last = Some(value);
+            match signal.width {
+                1 => changes += &format!("{}{}\n", value, code(i)),
+                _ => changes += &format!("b{:b} {}\n", value, code(i)),
+            }
+        }
+        if !changes.is_empty() {
+            write!(self.out, "#{}\n{}", core.cycles, changes)?;
+        }
+        Ok(())
+    }
+}
+
+impl Observer for Vcd {
+    fn step(&mut self, core: &CoreState) {
+        if !self.started {
+            self.started = true;
+            let _ = self.header();
+        }
+        let _ = self.sample(core);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{self, OP_IMM};
+    use crate::test_utils::machine;
+
+    #[test]
+    fn dumps_changes_per_cycle() {
+        assert_eq!([code(0), code(93), code(94), code(95)], ["!", "~", "!!", "\"!"]);
+        let path = std::env::temp_dir().join("rs-v-vcd-test.vcd");
+        let spec = format!("{}:pc,a0,irq,0x100", path.display());
+        // addi a0, a0, 1; sw a0, 0x100(x0); addi x0, x0, 0
+        let program = [encode::i(OP_IMM, 0b000, 10, 10, 1), encode::s(0b010, 0, 10, 0x100),
+                       encode::i(OP_IMM, 0b000, 0, 0, 0)];
+        let mut core = machine(0, &program, &[]);
+        let mut vcd = Vcd::open(&spec).unwrap();
+        for _ in 0..3 {
+            vcd.step(&core);
+            core.execute();
+            core.mip = MTI;
+        }
+        drop(vcd);
+        let dump = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(dump, "$version rs-v $end\n$timescale 10ns $end\n$scope module core $end\n\
+                          $var wire 32 ! pc $end\n$var wire 32 \" a0 $end\n$var wire 1 # msi $end\n\
+                          $var wire 1 $ mti $end\n$var wire 1 % mei $end\n$var wire 32 & mem_00000100 $end\n\
+                          $upscope $end\n$enddefinitions $end\n\
+                          #0\nb0 !\nb0 \"\n0#\n0$\n0%\nb0 &\n\
+                          #1\nb100 !\nb1 \"\n1$\n\
+                          #2\nb1000 !\nb1 &\n");
+        assert!(Vcd::open("/dev/null:q9").is_err());
+    }
+}
@@ -0,0 +1,217 @@
+use crate::script::{parse_number, parse_reg};
+use crate::{CoreState, Observer};
+
+/// Watch expression, 32-bit wrapping arithmetic over registers, pc and
+/// memory: `x10+x11`, `mem32(sp+8) & 0xff`, `pc`. Operators bind like C:
+/// `*`, then `+ -`, `<< >>`, `&`, `^`, `|`; `-` and `~` are unary too.
+#[derive(Debug, PartialEq)]
+enum Expr {
+    Number(u32),
+    Reg(usize),
+    Pc,
+    /// memN(address), N in bytes; bytes outside memory read as 0
+    Mem(u8, Box<Expr>),
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(char, Box<Expr>, Box<Expr>),
+}
+
+/// Binary operators from the loosest binding, `<` and `>` stand for the
+/// shifts
+const LEVELS: [&[char]; 6] = [&['|'], &['^'], &['&'], &['<', '>'], &['+', '-'], &['*']];
+
+struct Parser<'a> {
+    text: &'a str,
+}
+
+impl Parser<'_> {
+    fn skip(&mut self) {
+        self.text = self.text.trim_start();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip();
+        let found = self.text.starts_with(token);
+        if found {
+            self.text = &self.text[token.len()..];
+        }
+        found
+    }
+
+    /// The operator of `level` next in the text
+    fn operator(&mut self, level: usize) -> Option<char> {
+        self.skip();
+        let op = *LEVELS[level].iter().find(|&&op| match op {
+            '<' => self.text.starts_with("<<"),
+            '>' => self.text.starts_with(">>"),
+            op => self.text.starts_with(op),
+        })?;
+        self.text = &self.text[if matches!(op, '<' | '>') {2} else {1}..];
+        Some(op)
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        while let Some(op) = self.operator(level) {
+            left = Expr::Binary(op, Box::new(left), Box::new(self.binary(level + 1)?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat("-") {
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        if self.eat("~") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let expr = self.binary(0)?;
+            return if self.eat(")") {Ok(expr)} else {Err("missing `)`".to_string())};
+        }
+        self.skip();
+        let end = self.text.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(self.text.len());
+        let word = &self.text[..end];
+        self.text = &self.text[end..];
+        let size = match word {
+            "" => return Err("missing operand".to_string()),
+            "pc" => return Ok(Expr::Pc),
+            "mem8" => 1,
+            "mem16" => 2,
+            "mem32" => 4,
+            word if word.starts_with(|c: char| c.is_ascii_digit()) => return Ok(Expr::Number(parse_number(word)?)),
+            word => return Ok(Expr::Reg(parse_reg(word)?)),
+        };
+        if !self.eat("(") {
+            return Err(format!("`{}` needs an address in parentheses", word));
+        }
+        let address = self.binary(0)?;
+        if !self.eat(")") {
+            return Err("missing `)`".to_string());
+        }
+        Ok(Expr::Mem(size, Box::new(address)))
+    }
+}
+
+impl Expr {
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut parser = Parser {text};
+        let expr = parser.binary(0)?;
+        parser.skip();
+        match parser.text {
+            "" => Ok(expr),
+            rest => Err(format!("unexpected `{}`", rest)),
+        }
+    }
+
+    fn eval(&self, core: &CoreState) -> u32 {
+        match self {
+            Expr::Number(value) => *value,
+            Expr::Reg(index) => core.regs[*index],
+            Expr::Pc => core.pc,
+            Expr::Mem(size, address) => {
+                let start = address.eval(core) as usize;
+                let bytes = core.memory.get(start..start + *size as usize).unwrap_or_default();
+                bytes.iter().rev().fold(0, |value, &byte| value << 8 | byte as u32)
+            }
+            Expr::Neg(expr) => expr.eval(core).wrapping_neg(),
+            Expr::Not(expr) => !expr.eval(core),
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (a.eval(core), b.eval(core));
+                match op {
+                    '|' => a | b,
+                    '^' => a ^ b,
+                    '&' => a & b,
+                    '<' => a.wrapping_shl(b),
+                    '>' => a.wrapping_shr(b),
+                    '+' => a.wrapping_add(b),
+                    '-' => a.wrapping_sub(b),
+                    _ => a.wrapping_mul(b),
+                }
+            }
+        }
+    }
+}
+
+/// Prints a watch expression to stderr whenever its value changes, with the
+/// instruction that changed it; a lighter alternative to a full trace
+pub struct Watch {
+    text: String,
+    expr: Expr,
+    // value and pc at the last step
+    last: Option<(u32, u32)>,
+    steps: u64,
+}
+
+impl Watch {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let expr = Expr::parse(text).map_err(|e| format!("`{}`: {}", text, e))?;
+        Ok(Self {text: text.to_string(), expr, last: None, steps: 0})
+    }
+
+    /// The line to print for the state before the next instruction, if the
+    /// value changed
+    fn check(&mut self, core: &CoreState) -> Option<String> {
+        let value = self.expr.eval(core);
+        self.steps += 1;
+        match self.last.replace((value, core.pc)) {
+            None => Some(format!("watch {} = 0x{:08x}", self.text, value)),
+            Some((old, pc)) if old != value => Some(format!("watch {} = 0x{:08x} (was 0x{:08x}) after 0x{:08x}, step {}",
+                                                            self.text, value, old, pc, self.steps - 1)),
+            Some(_) => None,
+        }
+    }
+}
+
+/// The value of an expression in the current state, for the monitor
+pub(crate) fn evaluate(text: &str, core: &CoreState) -> Result<u32, String> {
+    Ok(Expr::parse(text)?.eval(core))
+}
+
+impl Observer for Watch {
+    fn step(&mut self, core: &CoreState) {
+        if let Some(line) = self.check(core) {
+            eprintln!("{}", line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{self, OP_IMM};
+    use crate::test_utils::machine;
+
+    #[test]
+    fn expressions_parse_and_change() {
+        let expr = |text| Expr::parse(text).unwrap();
+        let core = machine(0, &[], &[(10, 3), (11, 4)]);
+        assert_eq!(expr("x10 + a1 * 2").eval(&core), 11);
+        assert_eq!(expr("(a0 + a1) * 2 - 1").eval(&core), 13);
+        assert_eq!(expr("1 << 4 | 1 & ~0").eval(&core), 17);
+        assert_eq!(expr("-1 >> 28").eval(&core), 0xF);
+        assert_eq!(Expr::parse("mem32 4"), Err("`mem32` needs an address in parentheses".to_string()));
+        assert_eq!(Expr::parse("a0 +"), Err("missing operand".to_string()));
+        assert!(Expr::parse("x32").is_err() && Expr::parse("a0 a1").is_err());
+
+        // sw a0, 0x100(zero); addi a1, a1, 0; sh a1, 0x102(zero)
+        let program = [encode::s(0b010, 0, 10, 0x100), encode::i(OP_IMM, 0b000, 11, 11, 0),
+                       encode::s(0b001, 0, 11, 0x102)];
+        let mut core = machine(0, &program, &[(10, 3), (11, 4)]);
+        let mut watch = Watch::parse("mem32(0x80 + 0x80)").unwrap();
+        let mut lines = Vec::new();
+        for _ in 0..3 {
+            lines.extend(watch.check(&core));
+            core.execute();
+        }
+        lines.extend(watch.check(&core));
+        assert_eq!(lines, [
+            "watch mem32(0x80 + 0x80) = 0x00000000",
+            "watch mem32(0x80 + 0x80) = 0x00000003 (was 0x00000000) after 0x00000000, step 1",
+            "watch mem32(0x80 + 0x80) = 0x00040003 (was 0x00000003) after 0x00000008, step 3",
+        ]);
+    }
+}